@@ -0,0 +1,968 @@
+use super::*;
+use chrono::{DateTime, Utc};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, OpenOptions};
+use std::future::Future;
+use std::io::IsTerminal;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct AIConfig {
+    /// Local path, `file://` URL, or `http(s)://` URL of a model server
+    pub model_path: String,
+    /// URL that completion requests are POSTed to
+    pub api_endpoint: String,
+    /// Most tokens in one AI response
+    #[schemars(range(min = 1))]
+    pub max_tokens: usize,
+    /// Sampling temperature, from 0.0 (most deterministic) to 2.0
+    #[schemars(range(min = 0.0, max = 2.0))]
+    pub temperature: f32,
+    /// Save the AI conversation to the state directory. Turn off for privacy.
+    #[serde(default = "default_persist_context")]
+    pub persist_context: bool,
+    /// Maximum AI requests per minute; 0 disables the limit
+    #[serde(default = "default_requests_per_minute")]
+    pub requests_per_minute: u32,
+    /// What to do with requests over the limit
+    #[serde(default)]
+    pub rate_limit_mode: RateLimitMode,
+    /// Prompt for turning natural language into a command; `{input}` is
+    /// replaced by what the user typed, and `{context}` by `prompt_context`,
+    /// which otherwise goes before the prompt
+    #[serde(default = "default_interpret_prompt_template")]
+    pub interpret_prompt_template: String,
+    /// Ask for the command as JSON along with a one-line reason, shown as
+    /// "running `X` because Y". A reply that isn't such JSON is taken as
+    /// the command, as when this is off.
+    #[serde(default = "default_structured_response")]
+    pub structured_response: bool,
+    /// Prompt for `help --ai`; `{input}` is replaced by the command
+    #[serde(default = "default_explain_prompt_template")]
+    pub explain_prompt_template: String,
+    /// Local path or URL of the JSON list of models for `update-models`
+    #[serde(default)]
+    pub models_manifest: String,
+    /// Where `update-models` keeps downloaded models
+    #[serde(default = "default_models_dir")]
+    pub models_dir: PathBuf,
+    /// The directory, files, OS and recent commands to tell the AI about
+    #[serde(default)]
+    pub prompt_context: aicontext::ContextConfig,
+    /// Rough limit on the output piped into a `?` question, counting four
+    /// characters as a token. The rest is left out, with a notice.
+    #[serde(default = "default_max_input_tokens")]
+    pub max_input_tokens: usize,
+    /// Sent as a bearer token with every request. Better kept out of the
+    /// main configuration, in `key_file` or `secrets.toml`.
+    #[serde(default, skip_serializing)]
+    pub api_key: Option<String>,
+    /// Where secrets are read from: a `.toml` file is merged over the whole
+    /// configuration, any other file holds just `api_key`. Defaults to
+    /// `secrets.toml` beside the configuration file.
+    #[serde(default)]
+    pub key_file: Option<PathBuf>,
+}
+
+pub const DEFAULT_INTERPRET_PROMPT: &str =
+    "Convert this natural language command to a shell command: '{input}'";
+
+/// Added to the interpret prompt with `structured_response`
+const STRUCTURED_RESPONSE_PROMPT: &str =
+    "Reply with only a JSON object: {\"command\": \"<the shell command>\", \
+     \"reason\": \"<one short line on why it does what was asked>\"}";
+
+/// Prompt for `interpret_batch`; `{inputs}` is replaced by the numbered inputs
+const BATCH_INTERPRET_PROMPT: &str =
+    "Convert each of these numbered natural language commands to a shell command. \
+     Reply with one line per command, as `N: command`, and nothing else.\n{inputs}";
+
+/// Prompt for `ask_about`
+const ASK_PROMPT: &str =
+    "Answer this question about the command output below, briefly and in plain text.\n\
+     Question: {question}\nOutput:\n{input}";
+
+/// Characters counted as one token for `max_input_tokens`
+const CHARS_PER_TOKEN: usize = 4;
+
+pub const DEFAULT_EXPLAIN_PROMPT: &str =
+    "Explain in two or three sentences what the shell command '{input}' does \
+     and show one common usage example.";
+
+fn default_persist_context() -> bool {
+    true
+}
+
+pub fn default_structured_response() -> bool {
+    true
+}
+
+fn default_requests_per_minute() -> u32 {
+    60
+}
+
+pub fn default_max_input_tokens() -> usize {
+    1000
+}
+
+fn default_interpret_prompt_template() -> String {
+    DEFAULT_INTERPRET_PROMPT.to_string()
+}
+
+pub fn default_models_dir() -> PathBuf {
+    PathBuf::from("/usr/share/obsidian/models")
+}
+
+fn default_explain_prompt_template() -> String {
+    DEFAULT_EXPLAIN_PROMPT.to_string()
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum RateLimitMode {
+    /// Wait until the limit allows another request
+    #[default]
+    Queue,
+    /// Fail the request immediately
+    Reject,
+}
+
+/// How long to wait for a remote model server to answer at startup
+const MODEL_CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+/// How often the waiting indicator updates; nothing is shown for requests
+/// that finish sooner
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Where `AIConfig::model_path` points
+enum ModelLocation {
+    Local(PathBuf),
+    Remote(reqwest::Url),
+}
+
+fn model_location(model_path: &str) -> Result<ModelLocation> {
+    // Anything that doesn't parse as a URL is a plain path; so are one-letter
+    // "schemes", which are Windows drive letters
+    let url = match reqwest::Url::parse(model_path) {
+        Ok(url) if url.scheme().len() > 1 => url,
+        _ => return Ok(ModelLocation::Local(PathBuf::from(model_path))),
+    };
+    
+    match url.scheme() {
+        "file" => url
+            .to_file_path()
+            .map(ModelLocation::Local)
+            .map_err(|()| anyhow::anyhow!("Invalid model_path URL {}", model_path)),
+        "http" | "https" => Ok(ModelLocation::Remote(url)),
+        scheme => anyhow::bail!("Unsupported model_path scheme '{}' in {}", scheme, model_path),
+    }
+}
+
+/// Returned when a request is rejected by the client-side rate limit
+#[derive(Debug, thiserror::Error)]
+#[error("AI rate limit of {0} requests per minute reached")]
+pub struct RateLimited(pub u32);
+
+/// Who said a message in the AI conversation
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    User,
+    Assistant,
+}
+
+/// One turn of the AI conversation, as sent to the backend and saved to disk
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ContextMessage {
+    pub role: Role,
+    pub content: String,
+    pub timestamp: DateTime<Utc>,
+}
+
+/// Where completion requests go: `HttpBackend` posts them to the configured
+/// endpoint, and `aimock::MockBackend` answers from fixtures, for tests
+pub trait AIBackend {
+    /// The response text for `request`, adding to `received` the tokens of
+    /// it that have arrived so far
+    async fn complete(&self, request: &CompletionRequest<'_>, received: &AtomicUsize) -> Result<String>;
+}
+
+/// The backend an `AIEngine` uses, chosen when it is created
+pub enum Backend {
+    Http(HttpBackend),
+    Mock(aimock::MockBackend),
+}
+
+impl AIBackend for Backend {
+    async fn complete(&self, request: &CompletionRequest<'_>, received: &AtomicUsize) -> Result<String> {
+        match self {
+            Backend::Http(backend) => backend.complete(request, received).await,
+            Backend::Mock(backend) => backend.complete(request, received).await,
+        }
+    }
+}
+
+/// Posts requests to `api_endpoint` as JSON. With `stream` set, the
+/// endpoint may answer with one JSON object per line, each holding the next
+/// token or few of the response; a single object for the whole response is
+/// read the same.
+pub struct HttpBackend {
+    client: reqwest::Client,
+    endpoint: String,
+    api_key: Option<String>,
+}
+
+impl AIBackend for HttpBackend {
+    async fn complete(&self, request: &CompletionRequest<'_>, received: &AtomicUsize) -> Result<String> {
+        let mut post = self.client.post(&self.endpoint).json(request);
+        if let Some(key) = &self.api_key {
+            post = post.bearer_auth(key);
+        }
+        let mut response = post
+            .send()
+            .await
+            .with_context(|| format!("Failed to reach AI endpoint {}", self.endpoint))?
+            .error_for_status()?;
+        
+        let mut body = StreamedResponse::default();
+        while let Some(chunk) = response.chunk().await.context("Failed to read the AI response")? {
+            body.push(&chunk, received);
+        }
+        body.finish().context("Invalid response from AI endpoint")
+    }
+}
+
+pub struct AIEngine {
+    config: AIConfig,
+    client: reqwest::Client,
+    backend: Backend,
+    /// Conversation so far, sent with every request
+    context: Vec<ContextMessage>,
+    /// JSONL file each turn is appended to, if persistence is enabled
+    context_file: Option<PathBuf>,
+    /// Applied to what is written to `context_file`
+    redactor: Redactor,
+    rate_limiter: Option<RateLimiter>,
+    /// Show how far a request has got on stderr
+    progress: bool,
+    /// Print each prompt on stderr before it is sent
+    show_prompts: bool,
+}
+
+impl AIEngine {
+    pub fn new(config: &AIConfig) -> Result<Self> {
+        let client = reqwest::Client::new();
+        let backend = match aimock::MockBackend::from_env()? {
+            Some(mock) => Backend::Mock(mock),
+            None => Backend::Http(HttpBackend {
+                client: client.clone(),
+                endpoint: config.api_endpoint.clone(),
+                api_key: config.api_key.clone(),
+            }),
+        };
+        Ok(AIEngine {
+            config: config.clone(),
+            client,
+            backend,
+            context: Vec::new(),
+            context_file: None,
+            redactor: Redactor::default(),
+            rate_limiter: RateLimiter::per_minute(config.requests_per_minute),
+            progress: false,
+            show_prompts: false,
+        })
+    }
+    
+    /// Mask secrets with `redactor` in the context file. The conversation
+    /// sent to the model is unchanged.
+    pub fn set_redactor(&mut self, redactor: Redactor) {
+        self.redactor = redactor;
+    }
+    
+    /// Show a status line with the tokens received and the elapsed time while
+    /// waiting for a response, cleared once it arrives, and have responses
+    /// streamed so there are tokens to count. Only takes effect when stderr is a terminal.
+    pub fn set_progress(&mut self, enabled: bool) {
+        self.progress = enabled && io::stderr().is_terminal();
+    }
+    
+    /// Print every prompt on stderr as it is sent, e.g. for `--verbose`
+    pub fn set_show_prompts(&mut self, enabled: bool) {
+        self.show_prompts = enabled;
+    }
+    
+    /// The prompt context settings, for building the context passed to
+    /// `interpret_command`
+    pub fn context_config(&self) -> &aicontext::ContextConfig {
+        &self.config.prompt_context
+    }
+    
+    /// Start the conversation for this session.
+    ///
+    /// With `replay`, the context is loaded from a previous session's file and
+    /// new turns are appended to it. Otherwise a fresh file is used under the
+    /// state directory.
+    pub fn start_session(&mut self, replay: Option<&Path>) -> Result<()> {
+        if let Some(path) = replay {
+            self.context = load_context(path)?;
+        }
+        
+        if !self.config.persist_context {
+            return Ok(());
+        }
+        
+        self.context_file = Some(match replay {
+            Some(path) => path.to_path_buf(),
+            None => config::state_dir().join("ai-context").join(format!(
+                "{}-{}.jsonl",
+                Utc::now().format("%Y%m%dT%H%M%S"),
+                uuid::Uuid::new_v4().simple()
+            )),
+        });
+        
+        Ok(())
+    }
+    
+    /// Replace the configured sampling settings for this run, e.g. from
+    /// `--max-tokens` and `--temperature`
+    pub fn override_sampling(&mut self, max_tokens: Option<usize>, temperature: Option<f32>) {
+        if let Some(max_tokens) = max_tokens {
+            self.config.max_tokens = max_tokens;
+        }
+        if let Some(temperature) = temperature {
+            self.config.temperature = temperature;
+        }
+        
+        tracing::info!(
+            max_tokens = self.config.max_tokens,
+            temperature = %self.config.temperature,
+            "AI sampling settings"
+        );
+    }
+    
+    /// Check that the configured model is available, so a misconfiguration
+    /// shows up at startup rather than on the first interpretation
+    pub async fn initialize(&self) -> Result<()> {
+        if self.is_mock() {
+            return Ok(());
+        }
+        
+        match model_location(&self.config.model_path)? {
+            ModelLocation::Local(path) => {
+                if !path.is_file() {
+                    anyhow::bail!("AI model not found at {}", path.display());
+                }
+            }
+            ModelLocation::Remote(url) => {
+                self.client
+                    .head(url.clone())
+                    .timeout(MODEL_CHECK_TIMEOUT)
+                    .send()
+                    .await
+                    .and_then(|response| response.error_for_status())
+                    .map_err(|e| {
+                        let e = anyhow::Error::from(e);
+                        anyhow::anyhow!("AI model server {} is not available: {}", url, e.root_cause())
+                    })?;
+            }
+        }
+        
+        Ok(())
+    }
+    
+    /// Whether responses come from `aimock` fixtures rather than the endpoint
+    /// Send requests to `backend` from now on
+    #[cfg(test)]
+    pub fn set_backend(&mut self, backend: Backend) {
+        self.backend = backend;
+    }
+    
+    pub fn is_mock(&self) -> bool {
+        matches!(self.backend, Backend::Mock(_))
+    }
+    
+    /// Number of messages in the conversation so far
+    pub fn context_len(&self) -> usize {
+        self.context.len()
+    }
+    
+    /// Interpret a natural language command.
+    ///
+    /// Fails when the endpoint is unavailable, the rate limit rejects the
+    /// request (`RateLimited`) or no command comes back; the caller decides
+    /// what to fall back to, such as `offline_interpretation`.
+    ///
+    /// `context` describes where the command will run, from `aicontext::build`.
+    pub async fn interpret_command(&mut self, input: &str, context: &str) -> Result<Interpretation> {
+        // Use AI to interpret natural language command
+        let template = &self.config.interpret_prompt_template;
+        let mut prompt = template.replace("{input}", input);
+        if template.contains("{context}") {
+            prompt = prompt.replace("{context}", context);
+        } else if !context.is_empty() {
+            prompt = format!("{}\n\n{}", context, prompt);
+        }
+        if self.config.structured_response {
+            prompt = format!("{}\n{}", prompt, STRUCTURED_RESPONSE_PROMPT);
+        }
+        
+        let interpretation = parse_interpretation(&self.complete(&prompt).await?);
+        anyhow::ensure!(!interpretation.command.is_empty(), "the AI returned no command");
+        Ok(interpretation)
+    }
+    
+    /// Interpret several inputs in one request, for scripts, returning a
+    /// command or an error for each in order.
+    ///
+    /// The outer error is the request itself failing. A line the reply skips
+    /// or leaves empty fails on its own, leaving the others usable.
+    pub async fn interpret_batch(&mut self, inputs: &[String], context: &str) -> Result<Vec<Result<String>>> {
+        if inputs.is_empty() {
+            return Ok(Vec::new());
+        }
+        
+        let numbered: Vec<String> = inputs
+            .iter()
+            .enumerate()
+            .map(|(i, input)| format!("{}: {}", i + 1, input.replace('\n', " ")))
+            .collect();
+        let mut prompt = BATCH_INTERPRET_PROMPT.replace("{inputs}", &numbered.join("\n"));
+        if !context.is_empty() {
+            prompt = format!("{}\n\n{}", context, prompt);
+        }
+        
+        let response = self.complete(&prompt).await?;
+        Ok(parse_batch(&response, inputs.len()))
+    }
+    
+    /// Answer `question` about `input`, the output piped into a `?` stage.
+    /// `input` should already be cut to `input_budget`.
+    pub async fn ask_about(&mut self, question: &str, input: &str) -> Result<String> {
+        let prompt = ASK_PROMPT.replace("{question}", question).replace("{input}", input);
+        let answer = self.complete(&prompt).await?;
+        anyhow::ensure!(!answer.is_empty(), "the AI returned no answer");
+        Ok(answer)
+    }
+    
+    /// As much of `input` as fits in `max_input_tokens`, ending at a line
+    /// break where there is one
+    pub fn input_budget<'i>(&self, input: &'i str) -> &'i str {
+        let budget = self.config.max_input_tokens * CHARS_PER_TOKEN;
+        if input.len() <= budget {
+            return input;
+        }
+        let mut end = budget;
+        while !input.is_char_boundary(end) {
+            end -= 1;
+        }
+        match input[..end].rfind('\n') {
+            Some(newline) => &input[..newline + 1],
+            None => &input[..end],
+        }
+    }
+    
+    /// Ask the AI backend for a short explanation of what a command does
+    pub async fn explain_command(&mut self, command: &str) -> Result<String> {
+        let prompt = self.config.explain_prompt_template.replace("{input}", command);
+        
+        self.complete(&prompt).await
+    }
+    
+    /// Send a prompt and the conversation so far to the configured API
+    /// endpoint, recording both sides of the exchange on success
+    #[tracing::instrument(name = "ai_request", skip_all, fields(endpoint = %self.config.api_endpoint))]
+    async fn complete(&mut self, prompt: &str) -> Result<String> {
+        self.wait_for_rate_limit().await?;
+        
+        let request = CompletionRequest {
+            prompt,
+            context: &self.context,
+            max_tokens: self.config.max_tokens,
+            temperature: self.config.temperature,
+            stream: self.progress,
+        };
+        
+        tracing::debug!(prompt, context_messages = self.context.len(), "sending AI request");
+        if self.show_prompts {
+            eprintln!("📤 Sending to the AI, after {} earlier messages:", self.context.len());
+            eprintln!("{}", prompt);
+        }
+        
+        let start = Instant::now();
+        let received = AtomicUsize::new(0);
+        let response = if self.progress {
+            with_progress(self.backend.complete(&request, &received), &received).await
+        } else {
+            self.backend.complete(&request, &received).await
+        };
+        let latency_ms = start.elapsed().as_millis() as u64;
+        match &response {
+            Ok(_) => tracing::info!(latency_ms, "AI request completed"),
+            Err(e) => tracing::warn!(latency_ms, error = %format!("{:#}", e), "AI request failed"),
+        }
+        
+        let response = response?.trim().to_string();
+        self.record(Role::User, prompt);
+        self.record(Role::Assistant, &response);
+        
+        Ok(response)
+    }
+    
+    /// Take a request slot from the rate limiter, queueing or rejecting per config
+    async fn wait_for_rate_limit(&mut self) -> Result<()> {
+        let Some(limiter) = self.rate_limiter.as_mut() else {
+            return Ok(());
+        };
+        
+        loop {
+            match limiter.try_acquire() {
+                Ok(()) => return Ok(()),
+                Err(_) if self.config.rate_limit_mode == RateLimitMode::Reject => {
+                    return Err(RateLimited(self.config.requests_per_minute).into());
+                }
+                Err(wait) => {
+                    tracing::debug!(wait_ms = wait.as_millis() as u64, "waiting for AI rate limit");
+                    tokio::time::sleep(wait).await
+                }
+            }
+        }
+    }
+    
+    /// Add a turn to the conversation and append it to the context file
+    fn record(&mut self, role: Role, content: &str) {
+        let message = ContextMessage {
+            role,
+            content: content.to_string(),
+            timestamp: Utc::now(),
+        };
+        
+        if let Some(path) = &self.context_file {
+            let saved = ContextMessage {
+                content: self.redactor.redact(content).into_owned(),
+                ..message.clone()
+            };
+            if let Err(e) = append_message(path, &saved) {
+                eprintln!("⚠️  Failed to save AI context to {}: {}", path.display(), e);
+            }
+        }
+        
+        self.context.push(message);
+    }
+    
+    pub async fn update_models(&self) -> Result<()> {
+        outln!("Downloading latest AI models...");
+        // Implementation for model updates
+        Ok(())
+    }
+}
+
+/// Wait for `request`, showing on stderr how many tokens of the response it
+/// has `received` and how long it has taken until it finishes
+async fn with_progress<T>(request: impl Future<Output = T>, received: &AtomicUsize) -> T {
+    let start = Instant::now();
+    tokio::pin!(request);
+    let mut ticks = tokio::time::interval_at(tokio::time::Instant::now() + PROGRESS_INTERVAL, PROGRESS_INTERVAL);
+    let mut shown = false;
+    
+    loop {
+        tokio::select! {
+            output = &mut request => {
+                if shown {
+                    eprint!("\r\x1B[2K");
+                }
+                return output;
+            }
+            _ = ticks.tick() => {
+                let tokens = received.load(Ordering::Relaxed);
+                eprint!(
+                    "\r\x1B[2K⏳ Waiting for the AI… {} token{}, {}s",
+                    tokens,
+                    if tokens == 1 { "" } else { "s" },
+                    start.elapsed().as_secs()
+                );
+                shown = true;
+            }
+        }
+    }
+}
+
+/// Token bucket allowing bursts of up to `capacity` requests
+struct RateLimiter {
+    capacity: f64,
+    tokens: f64,
+    refill_per_sec: f64,
+    last_refill: Instant,
+}
+
+impl RateLimiter {
+    fn per_minute(requests: u32) -> Option<Self> {
+        if requests == 0 {
+            return None;
+        }
+        
+        let capacity = requests as f64;
+        Some(RateLimiter {
+            capacity,
+            tokens: capacity,
+            refill_per_sec: capacity / 60.0,
+            last_refill: Instant::now(),
+        })
+    }
+    
+    /// Take a token, or return how long until one is available
+    fn try_acquire(&mut self) -> std::result::Result<(), Duration> {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.refill_per_sec).min(self.capacity);
+        self.last_refill = now;
+        
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - self.tokens) / self.refill_per_sec))
+        }
+    }
+}
+
+/// A command from `interpret_command`, and why the AI chose it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Interpretation {
+    pub command: String,
+    /// One line from a structured reply, worded to follow "because"
+    pub reason: Option<String>,
+}
+
+/// The command and reason of a `{"command": ..., "reason": ...}` reply,
+/// fenced or not. Any other reply is all command, as `clean_interpretation`
+/// finds it.
+pub fn parse_interpretation(response: &str) -> Interpretation {
+    #[derive(Deserialize)]
+    struct Structured {
+        command: String,
+        #[serde(default)]
+        reason: Option<String>,
+    }
+    
+    let mut text = response.trim();
+    if let Some((_, fenced)) = text.split_once("```") {
+        let block = fenced.split_once("```").map_or(fenced, |(block, _)| block);
+        text = block.trim().strip_prefix("json").unwrap_or(block).trim();
+    }
+    // Only a reply that is an object is structured; `awk '{print $1}'` isn't
+    let structured = (text.starts_with('{') && text.ends_with('}'))
+        .then(|| serde_json::from_str::<Structured>(text).ok())
+        .flatten();
+    
+    match structured {
+        Some(structured) => {
+            let reason = structured.reason.as_deref().and_then(|reason| reason.lines().map(str::trim).find(|line| !line.is_empty()));
+            Interpretation {
+                command: clean_interpretation(&structured.command),
+                reason: reason.map(|reason| {
+                    let reason = reason.strip_prefix("because ").or_else(|| reason.strip_prefix("Because ")).unwrap_or(reason);
+                    reason.trim_end_matches('.').to_string()
+                }),
+            }
+        }
+        None => Interpretation {
+            command: clean_interpretation(response),
+            reason: None,
+        },
+    }
+}
+
+/// The command in an AI response, without the markdown fences, `$ ` prompts
+/// and "Here is the command:" lines models tend to add. Commands on separate
+/// lines are joined with `; `, so they can't pass for a single one.
+pub fn clean_interpretation(response: &str) -> String {
+    let mut text = response.trim();
+    
+    // Only the first fenced block is the command; the rest is explanation
+    if let Some((_, fenced)) = text.split_once("```") {
+        let block = fenced.split_once("```").map_or(fenced, |(block, _)| block);
+        text = match block.split_once('\n') {
+            Some((language, rest)) if matches!(language.trim(), "" | "bash" | "sh" | "shell" | "zsh" | "console") => rest,
+            _ => block,
+        };
+    }
+    
+    let commands: Vec<_> = text
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.ends_with(':'))
+        .map(|line| line.strip_prefix("$ ").unwrap_or(line))
+        .map(|line| match line.strip_prefix('`').and_then(|line| line.strip_suffix('`')) {
+            Some(inner) if !inner.contains('`') => inner,
+            _ => line,
+        })
+        .collect();
+    commands.join("; ")
+}
+
+/// Match the `N: command` lines of a batch reply to the `count` inputs.
+/// `N.` and `N)` are accepted too; the first answer for a line wins.
+fn parse_batch(response: &str, count: usize) -> Vec<Result<String>> {
+    let mut commands: Vec<Option<String>> = vec![None; count];
+    for line in response.lines() {
+        let line = line.trim();
+        let digits = line.find(|c: char| !c.is_ascii_digit()).unwrap_or(line.len());
+        let Ok(n) = line[..digits].parse::<usize>() else {
+            continue;
+        };
+        let rest = line[digits..].trim_start();
+        let Some(rest) = rest.strip_prefix([':', '.', ')']) else {
+            continue;
+        };
+        
+        let command = clean_interpretation(rest);
+        if let Some(slot @ None) = n.checked_sub(1).and_then(|i| commands.get_mut(i)) {
+            if !command.is_empty() {
+                *slot = Some(command);
+            }
+        }
+    }
+    
+    commands
+        .into_iter()
+        .enumerate()
+        .map(|(i, command)| command.ok_or_else(|| anyhow::anyhow!("the AI returned no command for line {}", i + 1)))
+        .collect()
+}
+
+/// Simple keyword rules used when no AI backend is reachable
+pub fn offline_interpretation(input: &str) -> String {
+    match input.to_lowercase() {
+        s if s.contains("find") && s.contains("file") => {
+            "find . -type f".to_string()
+        }
+        s if s.contains("process") => {
+            "ps aux".to_string()
+        }
+        s if s.contains("install") => {
+            "apt install".to_string()
+        }
+        _ => input.to_string(),
+    }
+}
+
+/// Read a conversation saved by a previous session
+fn load_context(path: &Path) -> Result<Vec<ContextMessage>> {
+    let contents = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read AI context {}", path.display()))?;
+    
+    contents
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .enumerate()
+        .map(|(i, line)| {
+            serde_json::from_str(line)
+                .with_context(|| format!("Invalid AI context at {}:{}", path.display(), i + 1))
+        })
+        .collect()
+}
+
+fn append_message(path: &Path, message: &ContextMessage) -> Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    writeln!(file, "{}", serde_json::to_string(message)?)?;
+    Ok(())
+}
+
+#[derive(Serialize)]
+pub struct CompletionRequest<'a> {
+    pub prompt: &'a str,
+    pub context: &'a [ContextMessage],
+    pub max_tokens: usize,
+    pub temperature: f32,
+    /// Ask for the response a line at a time, as it is generated
+    pub stream: bool,
+}
+
+#[derive(Deserialize)]
+struct CompletionResponse {
+    response: String,
+}
+
+/// A response body read as it arrives. Each complete line that is a
+/// `CompletionResponse` is a part of the response; a body that isn't made
+/// of such lines, e.g. one object spread over several lines, is parsed
+/// whole at the end.
+#[derive(Default)]
+struct StreamedResponse {
+    body: Vec<u8>,
+    /// Where the first line not yet parsed starts in `body`
+    parsed: usize,
+    text: String,
+    /// A line wasn't a `CompletionResponse`
+    whole: bool,
+}
+
+impl StreamedResponse {
+    fn push(&mut self, chunk: &[u8], received: &AtomicUsize) {
+        self.body.extend_from_slice(chunk);
+        while !self.whole {
+            let Some(end) = self.body[self.parsed..].iter().position(|&byte| byte == b'\n') else {
+                break;
+            };
+            let line = &self.body[self.parsed..self.parsed + end];
+            self.parsed += end + 1;
+            if line.trim_ascii().is_empty() {
+                continue;
+            }
+            match serde_json::from_slice::<CompletionResponse>(line) {
+                Ok(part) => {
+                    self.text.push_str(&part.response);
+                    received.fetch_add(1, Ordering::Relaxed);
+                }
+                Err(_) => self.whole = true,
+            }
+        }
+    }
+    
+    fn finish(self) -> Result<String> {
+        let rest = &self.body[self.parsed..];
+        if self.whole || (self.text.is_empty() && self.parsed == 0) {
+            return Ok(serde_json::from_slice::<CompletionResponse>(&self.body)?.response);
+        }
+        let mut text = self.text;
+        if !rest.trim_ascii().is_empty() {
+            text.push_str(&serde_json::from_slice::<CompletionResponse>(rest)?.response);
+        }
+        Ok(text)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    /// An engine with the default settings, answering from `fixtures`
+    fn mock_engine(fixtures: &str, configure: impl FnOnce(&mut AIConfig)) -> AIEngine {
+        let tmp = tempfile::tempdir().unwrap();
+        let mut config = ShellConfig::load(tmp.path().join("config.toml").to_str().unwrap()).unwrap().ai_config;
+        configure(&mut config);
+        let mut engine = AIEngine::new(&config).unwrap();
+        engine.backend = Backend::Mock(aimock::MockBackend::from_fixtures(fixtures).unwrap());
+        engine
+    }
+    
+    #[tokio::test]
+    async fn interpretations_come_from_the_backend() {
+        let fixtures = r#"
+            [responses]
+            "show disk usage" = "```sh\n$ df -h\n```"
+            "biggest files" = '{"command": "du -a | sort -n | tail", "reason": "Because du lists sizes."}'
+            "nothing useful" = "  "
+        "#;
+        let mut engine = mock_engine(fixtures, |_| {});
+        
+        let interpretation = engine.interpret_command("show disk usage", "cwd: /tmp").await.unwrap();
+        assert_eq!(interpretation.command, "df -h");
+        let interpretation = engine.interpret_command("biggest files", "").await.unwrap();
+        assert_eq!(interpretation.command, "du -a | sort -n | tail");
+        assert_eq!(interpretation.reason.as_deref(), Some("du lists sizes"));
+        
+        // Both sides of each exchange are kept for the next request
+        assert_eq!(engine.context_len(), 4);
+        
+        let error = engine.interpret_command("nothing useful", "").await.unwrap_err();
+        assert_eq!(error.to_string(), "the AI returned no command");
+        assert!(engine.interpret_command("unknown request", "").await.is_err());
+    }
+    
+    #[tokio::test]
+    async fn batches_fail_line_by_line() {
+        let fixtures = "[responses]\n\"1: list files\" = \"1: ls\\n3: pwd\"";
+        let mut engine = mock_engine(fixtures, |_| {});
+        let inputs = ["list files", "do the impossible", "where am I"].map(String::from);
+        
+        let commands = engine.interpret_batch(&inputs, "").await.unwrap();
+        assert_eq!(commands[0].as_deref().unwrap(), "ls");
+        assert!(commands[1].is_err());
+        assert_eq!(commands[2].as_deref().unwrap(), "pwd");
+        assert!(engine.interpret_batch(&[], "").await.unwrap().is_empty());
+    }
+    
+    #[tokio::test]
+    async fn the_rate_limit_can_reject() {
+        let mut engine = mock_engine("default = \"true\"", |config| {
+            config.requests_per_minute = 1;
+            config.rate_limit_mode = RateLimitMode::Reject;
+        });
+        
+        assert!(engine.explain_command("true").await.is_ok());
+        let error = engine.explain_command("true").await.unwrap_err();
+        assert!(matches!(error.downcast_ref::<RateLimited>(), Some(RateLimited(1))));
+    }
+    
+    fn streamed(chunks: &[&str]) -> (Result<String>, usize) {
+        let received = AtomicUsize::new(0);
+        let mut body = StreamedResponse::default();
+        for chunk in chunks {
+            body.push(chunk.as_bytes(), &received);
+        }
+        (body.finish(), received.into_inner())
+    }
+    
+    #[test]
+    fn streamed_responses_are_counted_as_they_arrive() {
+        let (text, tokens) = streamed(&["{\"response\":\"ls\"}\n{\"resp", "onse\":\" -la\"}\n", "{\"response\":\"\",\"done\":true}"]);
+        assert_eq!((text.unwrap().as_str(), tokens), ("ls -la", 2));
+        
+        // One object, on one line or several
+        let (text, tokens) = streamed(&["{\"response\": \"ls\"}"]);
+        assert_eq!((text.unwrap().as_str(), tokens), ("ls", 0));
+        let (text, _) = streamed(&["{\n  \"response\": ", "\"ls\"\n}\n"]);
+        assert_eq!(text.unwrap(), "ls");
+        
+        assert!(streamed(&["not json\n"]).0.is_err());
+        assert!(streamed(&[""]).0.is_err());
+    }
+    
+    #[tokio::test]
+    async fn the_http_backend_reads_streamed_responses() {
+        use std::io::{BufRead, BufReader};
+        use std::net::TcpListener;
+        
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = format!("http://{}/ai", listener.local_addr().unwrap());
+        let server = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut request = String::new();
+            while reader.read_line(&mut request).unwrap() > 2 {
+                request.clear();
+            }
+            let mut stream = stream;
+            write!(stream, "HTTP/1.1 200 OK\r\nContent-Type: application/x-ndjson\r\nConnection: close\r\n\r\n").unwrap();
+            for part in ["echo", " hi"] {
+                writeln!(stream, "{{\"response\":\"{}\"}}", part).unwrap();
+                stream.flush().unwrap();
+                std::thread::sleep(Duration::from_millis(20));
+            }
+        });
+        
+        let backend = HttpBackend {
+            client: reqwest::Client::new(),
+            endpoint,
+            api_key: None,
+        };
+        let request = CompletionRequest {
+            prompt: "say hi",
+            context: &[],
+            max_tokens: 10,
+            temperature: 0.0,
+            stream: true,
+        };
+        let received = AtomicUsize::new(0);
+        assert_eq!(backend.complete(&request, &received).await.unwrap(), "echo hi");
+        assert_eq!(received.into_inner(), 2);
+        server.join().unwrap();
+    }
+}
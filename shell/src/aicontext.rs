@@ -0,0 +1,171 @@
+use super::*;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::fs;
+
+/// What interpretation prompts say about where the command will run, so
+/// "compress this folder" knows which folder and what is in it. Off unless
+/// `enabled`.
+///
+/// ```toml
+/// [ai_config.prompt_context]
+/// enabled = true
+/// recent_commands = 3
+/// ```
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct ContextConfig {
+    /// Add the context to interpretation prompts
+    #[serde(default)]
+    pub enabled: bool,
+    /// Include the operating system
+    #[serde(default = "default_true")]
+    pub os: bool,
+    /// Include the current directory, with the home directory shown as `~`
+    #[serde(default = "default_true")]
+    pub directory: bool,
+    /// Include the names of the files in the current directory
+    #[serde(default = "default_true")]
+    pub files: bool,
+    /// How many recent commands to include, with secrets masked
+    #[serde(default = "default_recent_commands")]
+    pub recent_commands: usize,
+    /// Rough limit on the size of the context, counting four characters as
+    /// a token. Files and commands that don't fit are left out.
+    #[serde(default = "default_max_tokens")]
+    pub max_tokens: usize,
+    /// Send less: only the current directory's own name, no hidden files and
+    /// no recent commands
+    #[serde(default)]
+    pub private: bool,
+}
+
+impl Default for ContextConfig {
+    fn default() -> Self {
+        ContextConfig {
+            enabled: false,
+            os: true,
+            directory: true,
+            files: true,
+            recent_commands: default_recent_commands(),
+            max_tokens: default_max_tokens(),
+            private: false,
+        }
+    }
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_recent_commands() -> usize {
+    5
+}
+
+fn default_max_tokens() -> usize {
+    200
+}
+
+/// Characters counted as one token for `max_tokens`
+const CHARS_PER_TOKEN: usize = 4;
+
+/// The context to put in an interpretation prompt, or an empty string when
+/// it is disabled. `recent` is the command history, oldest first.
+pub fn build(config: &ContextConfig, recent: &[String], redactor: &Redactor) -> String {
+    if !config.enabled {
+        return String::new();
+    }
+    
+    let mut budget = config.max_tokens * CHARS_PER_TOKEN;
+    let mut lines = vec!["Context:".to_string()];
+    let mut add = |line: String, budget: &mut usize| {
+        if line.len() < *budget {
+            *budget -= line.len() + 1;
+            lines.push(line);
+        }
+    };
+    
+    if config.os {
+        add(format!("- OS: {}", std::env::consts::OS), &mut budget);
+    }
+    let cwd = env::current_dir().ok();
+    if let Some(cwd) = cwd.as_deref().filter(|_| config.directory) {
+        add(format!("- Current directory: {}", directory_name(cwd, config.private)), &mut budget);
+    }
+    if let Some(cwd) = cwd.as_deref().filter(|_| config.files) {
+        // Leave room for the recent commands
+        let share = if config.private || config.recent_commands == 0 { budget } else { budget / 2 };
+        if let Some(files) = file_listing(cwd, config.private, share) {
+            add(files, &mut budget);
+        }
+    }
+    if !config.private && config.recent_commands > 0 {
+        let start = recent.len().saturating_sub(config.recent_commands);
+        let mut commands: Vec<String> = recent[start..].iter().map(|command| redactor.redact(command).into_owned()).collect();
+        // Drop the oldest until they fit
+        while !commands.is_empty() {
+            let line = format!("- Recent commands: {}", commands.join(" ; "));
+            if line.len() < budget {
+                add(line, &mut budget);
+                break;
+            }
+            commands.remove(0);
+        }
+    }
+    
+    if lines.len() == 1 {
+        return String::new();
+    }
+    lines.join("\n")
+}
+
+fn directory_name(cwd: &Path, private: bool) -> String {
+    if private {
+        return cwd.file_name().map_or_else(|| "/".to_string(), |name| name.to_string_lossy().into_owned());
+    }
+    match env::var_os("HOME").map(PathBuf::from) {
+        Some(home) if cwd == home => "~".to_string(),
+        Some(home) => match cwd.strip_prefix(&home) {
+            Ok(rest) => format!("~/{}", rest.display()),
+            Err(_) => cwd.display().to_string(),
+        },
+        None => cwd.display().to_string(),
+    }
+}
+
+/// `- Files here: a, b/, …`, sorted, with directories marked by `/`, cut
+/// to `budget` characters
+fn file_listing(dir: &Path, private: bool, budget: usize) -> Option<String> {
+    let mut names: Vec<String> = fs::read_dir(dir)
+        .ok()?
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if private && name.starts_with('.') {
+                return None;
+            }
+            let is_dir = entry.file_type().is_ok_and(|kind| kind.is_dir());
+            Some(if is_dir { format!("{}/", name) } else { name })
+        })
+        .collect();
+    names.sort();
+    
+    if names.is_empty() {
+        return Some("- Files here: none".to_string());
+    }
+    let mut line = "- Files here: ".to_string();
+    for (i, name) in names.iter().enumerate() {
+        let more = format!(" (and {} more)", names.len() - i);
+        if line.len() + name.len() + 2 + more.len() > budget {
+            if i == 0 {
+                return None;
+            }
+            line.push_str(&more);
+            break;
+        }
+        if i > 0 {
+            line.push_str(", ");
+        }
+        line.push_str(name);
+    }
+    Some(line)
+}
@@ -0,0 +1,149 @@
+//! A stand-in AI backend that answers from a fixtures file, so flows that
+//! depend on the AI (interpretation, confirmations, `ai_fallback`) can be
+//! tested without a network or a model. It is used when `OBSIDIAN_AI_MOCK=1`,
+//! with the fixtures read from `OBSIDIAN_AI_MOCK_FIXTURES`:
+//!
+//! ```toml
+//! # Sent when no input matches; without it those requests fail
+//! default = "echo unmatched"
+//!
+//! [responses]
+//! "find all text files" = "find . -name '*.txt'"
+//! "tidy the logs" = "mkdir old; mv *.log old"
+//! ```
+//!
+//! The response is the one whose input appears in the prompt, the longest if
+//! several do, so an entry matches however the prompt template wraps it.
+//! `interpret` with several inputs sends them in one prompt, as `1: ...`
+//! lines, and needs a fixture for that whole request.
+//!
+//! ```sh
+//! OBSIDIAN_AI_MOCK=1 OBSIDIAN_AI_MOCK_FIXTURES=ai.toml obsidian-shell -a -c 'find all text files'
+//! ```
+
+use super::*;
+use ai::{AIBackend, CompletionRequest};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fs;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Set to `1` to use the mock backend
+pub const MOCK_VAR: &str = "OBSIDIAN_AI_MOCK";
+/// Path of the fixtures file
+pub const FIXTURES_VAR: &str = "OBSIDIAN_AI_MOCK_FIXTURES";
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Fixtures {
+    #[serde(default)]
+    default: Option<String>,
+    /// Responses keyed by the input they answer
+    #[serde(default)]
+    responses: BTreeMap<String, String>,
+}
+
+pub struct MockBackend {
+    fixtures: Fixtures,
+}
+
+impl MockBackend {
+    /// The mock backend if `OBSIDIAN_AI_MOCK` asks for it. Without a fixtures
+    /// file every request fails, as an unreachable endpoint would.
+    pub fn from_env() -> Result<Option<Self>> {
+        let enabled = env::var(MOCK_VAR).is_ok_and(|value| !value.is_empty() && value != "0");
+        if !enabled {
+            return Ok(None);
+        }
+        
+        let Some(path) = env::var_os(FIXTURES_VAR).map(PathBuf::from) else {
+            return Ok(Some(MockBackend { fixtures: Fixtures::default() }));
+        };
+        let contents = fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read AI mock fixtures {}", path.display()))?;
+        let mock = MockBackend::from_fixtures(&contents)
+            .with_context(|| format!("Invalid AI mock fixtures {}", path.display()))?;
+        Ok(Some(mock))
+    }
+    
+    /// The mock backend answering from the fixtures TOML in `contents`
+    pub fn from_fixtures(contents: &str) -> Result<Self> {
+        Ok(MockBackend {
+            fixtures: toml::from_str(contents)?,
+        })
+    }
+}
+
+impl AIBackend for MockBackend {
+    async fn complete(&self, request: &CompletionRequest<'_>, received: &AtomicUsize) -> Result<String> {
+        let matched = self
+            .fixtures
+            .responses
+            .iter()
+            .filter(|(input, _)| request.prompt.contains(input.as_str()))
+            // The first of equally long inputs, in sorted order, wins
+            .fold(None, |best: Option<(&String, &String)>, entry| match best {
+                Some(best) if best.0.len() >= entry.0.len() => Some(best),
+                _ => Some(entry),
+            });
+        
+        match matched.map(|(_, response)| response).or(self.fixtures.default.as_ref()) {
+            Some(response) => {
+                received.fetch_add(response.split_whitespace().count(), Ordering::Relaxed);
+                Ok(response.clone())
+            }
+            None => anyhow::bail!("no mock AI response matches the prompt ({} is set)", MOCK_VAR),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    const FIXTURES: &str = r#"
+        [responses]
+        "list files" = "ls"
+        "list files by size" = "ls -S"
+        "b" = "second"
+        "a" = "first"
+    "#;
+    
+    async fn answer(mock: &MockBackend, prompt: &str) -> Result<String> {
+        let request = CompletionRequest {
+            prompt,
+            context: &[],
+            max_tokens: 100,
+            temperature: 0.0,
+            stream: false,
+        };
+        mock.complete(&request, &AtomicUsize::new(0)).await
+    }
+    
+    #[tokio::test]
+    async fn the_longest_matching_input_answers() {
+        let mock = MockBackend::from_fixtures(FIXTURES).unwrap();
+        assert_eq!(answer(&mock, "Turn this into a command: list files\n").await.unwrap(), "ls");
+        assert_eq!(answer(&mock, "Command for: list files by size, please").await.unwrap(), "ls -S");
+        // Ties go to the first in sorted order
+        assert_eq!(answer(&mock, "b a").await.unwrap(), "first");
+    }
+    
+    #[tokio::test]
+    async fn unmatched_prompts_get_the_default_or_fail() {
+        let mock = MockBackend::from_fixtures(FIXTURES).unwrap();
+        let error = answer(&mock, "nothing here").await.unwrap_err();
+        assert!(error.to_string().contains(MOCK_VAR));
+        
+        let mock = MockBackend::from_fixtures("default = \"echo unmatched\"").unwrap();
+        assert_eq!(answer(&mock, "anything").await.unwrap(), "echo unmatched");
+        assert!(answer(&MockBackend { fixtures: Fixtures::default() }, "x").await.is_err());
+    }
+    
+    #[test]
+    fn fixtures_with_unknown_keys_are_refused() {
+        assert!(MockBackend::from_fixtures("[response]\nx = \"y\"").is_err());
+        assert!(MockBackend::from_fixtures("default = 1").is_err());
+        assert!(MockBackend::from_fixtures("").is_ok());
+    }
+}
@@ -0,0 +1,348 @@
+use super::*;
+use parser::{Connector, TokenKind};
+use std::collections::BTreeMap;
+
+/// Returned when an alias can't be defined
+#[derive(Debug, thiserror::Error)]
+pub enum AliasError {
+    #[error("invalid name {0:?}")]
+    InvalidName(String),
+    #[error("cycle {}", .0.join(" → "))]
+    Cycle(Vec<String>),
+    #[error("nested more than {} deep: {}", .0.len(), .0.join(" → "))]
+    TooDeep(Vec<String>),
+}
+
+/// Command aliases, expanded in command position like POSIX shells
+#[derive(Debug)]
+pub struct Aliases {
+    aliases: BTreeMap<String, String>,
+    /// Most aliases expanded inside one another
+    max_depth: usize,
+}
+
+impl Aliases {
+    /// Aliases from the `[aliases]` config table, in name order. Those that
+    /// can't be defined, such as the one closing a cycle, are left out and
+    /// returned with the reason.
+    pub fn from_config(aliases: &BTreeMap<String, String>, max_depth: usize) -> (Self, Vec<(String, AliasError)>) {
+        let mut result = Aliases {
+            aliases: BTreeMap::new(),
+            max_depth: max_depth.max(1),
+        };
+        let mut skipped = Vec::new();
+        for (name, value) in aliases {
+            if let Err(e) = result.set(name, value) {
+                skipped.push((name.clone(), e));
+            }
+        }
+        (result, skipped)
+    }
+    
+    /// Define or replace an alias.
+    ///
+    /// An alias may start with its own name (`ls='ls --color'`), which is
+    /// expanded once. Chains that lead back to it (`a='b'`, `b='a'`) are refused.
+    pub fn set(&mut self, name: &str, value: &str) -> Result<(), AliasError> {
+        if !is_valid_name(name) {
+            return Err(AliasError::InvalidName(name.to_string()));
+        }
+        
+        let mut path = vec![name.to_string()];
+        if self.leads_back(name, value, &mut path) {
+            return Err(AliasError::Cycle(path));
+        }
+        
+        self.aliases.insert(name.to_string(), value.to_string());
+        Ok(())
+    }
+    
+    /// Remove an alias, returning whether it existed
+    pub fn remove(&mut self, name: &str) -> bool {
+        self.aliases.remove(name).is_some()
+    }
+    
+    pub fn clear(&mut self) {
+        self.aliases.clear();
+    }
+    
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.aliases.get(name).map(String::as_str)
+    }
+    
+    /// All aliases, sorted by name
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.aliases.iter().map(|(name, value)| (name.as_str(), value.as_str()))
+    }
+    
+    /// Whether following the command words of `value` reaches `target` again,
+    /// leaving the offending chain in `path`
+    fn leads_back(&self, target: &str, value: &str, path: &mut Vec<String>) -> bool {
+        // A chain this long fails when it is expanded anyway
+        if path.len() > self.max_depth {
+            return false;
+        }
+        
+        for (_, command) in parser::split_chain(value) {
+            let word = first_word(&command);
+            
+            // Starting with its own name is the usual `ls='ls --color'` form
+            if word == target && path.len() > 1 {
+                path.push(word.to_string());
+                return true;
+            }
+            
+            if path.iter().any(|name| name == word) {
+                continue;
+            }
+            
+            if let Some(next) = self.aliases.get(word) {
+                path.push(word.to_string());
+                if self.leads_back(target, next, path) {
+                    return true;
+                }
+                path.pop();
+            }
+        }
+        
+        false
+    }
+    
+    /// Expand aliases in command position of every command in `chain`.
+    ///
+    /// An alias is not expanded again inside its own expansion, so this always
+    /// terminates, and more than `max_depth` aliases inside one another is an
+    /// error. Alias values may themselves contain `;`, `&&` and `||`.
+    pub fn expand(&self, chain: Vec<(Connector, String)>) -> Result<Vec<(Connector, String)>, AliasError> {
+        let mut expanded = Vec::new();
+        for (connector, command) in chain {
+            self.expand_command(connector, &command, &mut Vec::new(), &mut expanded)?;
+        }
+        Ok(expanded)
+    }
+    
+    /// `line` with its aliases expanded, written back as one line. A line
+    /// without aliases, or whose aliases fail to expand, is returned as typed.
+    pub fn expand_line(&self, line: &str) -> String {
+        let chain = parser::split_chain(line);
+        let Ok(expanded) = self.expand(chain.clone()) else {
+            return line.to_string();
+        };
+        if expanded == chain {
+            return line.to_string();
+        }
+        
+        let mut text = String::new();
+        for (i, (connector, command)) in expanded.iter().enumerate() {
+            if i > 0 {
+                text.push_str(match connector {
+                    Connector::Always => "; ",
+                    Connector::And => " && ",
+                    Connector::Or => " || ",
+                });
+            }
+            text.push_str(command);
+        }
+        text
+    }
+    
+    fn expand_command<'a>(
+        &'a self,
+        connector: Connector,
+        command: &str,
+        active: &mut Vec<&'a str>,
+        expanded: &mut Vec<(Connector, String)>,
+    ) -> Result<(), AliasError> {
+        let word = first_word(command);
+        let Some((name, value)) = self.aliases.get_key_value(word) else {
+            expanded.push((connector, command.to_string()));
+            return Ok(());
+        };
+        
+        if active.contains(&name.as_str()) {
+            expanded.push((connector, command.to_string()));
+            return Ok(());
+        }
+        if active.len() == self.max_depth {
+            return Err(AliasError::TooDeep(active.iter().map(|name| name.to_string()).collect()));
+        }
+        
+        active.push(name);
+        let rest = &command[word.len()..];
+        let text = with_arguments(name, value, rest).unwrap_or_else(|| format!("{}{}", value, rest));
+        for (i, (inner, part)) in parser::split_chain(&text).into_iter().enumerate() {
+            // The first command takes the place of the alias in the outer chain
+            let connector = if i == 0 { connector } else { inner };
+            self.expand_command(connector, &part, active, expanded)?;
+        }
+        active.pop();
+        Ok(())
+    }
+}
+
+/// `value` with `$1`…`$9`, `${n}`, `$#`, `$@` and `$*` taken from the words
+/// of `rest`, as in a script. Redirections and later pipeline stages are
+/// kept after it. `None` if `value` has no placeholders, so `rest` is
+/// appended as for a plain alias.
+fn with_arguments(name: &str, value: &str, rest: &str) -> Option<String> {
+    // Every placeholder expands to something other than itself, even with
+    // no arguments
+    if parser::expand_positional(value, &[]) == value {
+        return None;
+    }
+    
+    let stages = parser::split_pipeline(rest);
+    let (head, tail) = stages.split_first()?;
+    let tokens = parser::tokenize(head);
+    let redirections = tokens
+        .iter()
+        .find(|token| matches!(token.kind, TokenKind::Redirect(_)))
+        .map_or(head.len(), |token| token.span.start);
+    
+    let mut params = vec![name.to_string()];
+    params.extend(tokens.into_iter().filter(|token| token.span.end <= redirections).filter_map(|token| match token.kind {
+        TokenKind::Word(word) => Some(word),
+        TokenKind::Redirect(_) => None,
+    }));
+    let mut text = parser::expand_positional(value, &params);
+    if redirections < head.len() {
+        text.push(' ');
+        text.push_str(&head[redirections..]);
+    }
+    for stage in tail {
+        text.push_str(" | ");
+        text.push_str(stage);
+    }
+    Some(text)
+}
+
+fn first_word(command: &str) -> &str {
+    command.split_whitespace().next().unwrap_or("")
+}
+
+fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && !name.chars().any(|c| c.is_whitespace() || "=;&|'\"\\$`".contains(c))
+}
+
+/// Split `name=value` from the `alias` builtin, removing one level of quotes
+/// around the value
+pub fn parse_definition(arg: &str) -> Option<(&str, &str)> {
+    let (name, value) = arg.split_once('=')?;
+    let value = value.trim();
+    
+    let unquoted = ['\'', '"'].iter().find_map(|&quote| {
+        value.strip_prefix(quote)?.strip_suffix(quote)
+    });
+    
+    Some((name.trim(), unquoted.unwrap_or(value)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    fn table(entries: &[(&str, &str)]) -> BTreeMap<String, String> {
+        entries.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect()
+    }
+    
+    fn expand(aliases: &Aliases, line: &str) -> Result<Vec<String>, AliasError> {
+        let expanded = aliases.expand(parser::split_chain(line))?;
+        Ok(expanded.into_iter().map(|(_, command)| command).collect())
+    }
+    
+    #[test]
+    fn config_cycles_are_skipped() {
+        let (aliases, skipped) = Aliases::from_config(&table(&[("a", "b"), ("b", "a"), ("ll", "ls -l")]), 16);
+        assert_eq!(aliases.get("a"), Some("b"));
+        assert_eq!(aliases.get("b"), None);
+        assert_eq!(aliases.get("ll"), Some("ls -l"));
+        assert_eq!(skipped.len(), 1);
+        assert_eq!(skipped[0].0, "b");
+        assert!(matches!(&skipped[0].1, AliasError::Cycle(path) if path == &["b", "a", "b"]));
+    }
+    
+    #[test]
+    fn config_cycles_through_chains_are_skipped() {
+        let (aliases, skipped) = Aliases::from_config(&table(&[("x", "echo; y"), ("y", "true && z"), ("z", "x")]), 16);
+        assert_eq!(aliases.iter().count(), 2);
+        assert_eq!(skipped.len(), 1);
+        assert!(matches!(&skipped[0].1, AliasError::Cycle(path) if path == &["z", "x", "y", "z"]));
+    }
+    
+    #[test]
+    fn invalid_names_are_skipped() {
+        let (aliases, skipped) = Aliases::from_config(&table(&[("bad name", "ls"), ("ok", "ls")]), 16);
+        assert_eq!(aliases.iter().collect::<Vec<_>>(), vec![("ok", "ls")]);
+        assert!(matches!(&skipped[0].1, AliasError::InvalidName(name) if name == "bad name"));
+    }
+    
+    #[test]
+    fn an_alias_may_start_with_its_own_name() {
+        let mut aliases = Aliases::from_config(&BTreeMap::new(), 16).0;
+        aliases.set("ls", "ls --color").unwrap();
+        assert_eq!(expand(&aliases, "ls -a").unwrap(), vec!["ls --color -a"]);
+        assert!(aliases.set("a", "ls; a").is_ok());
+        assert!(matches!(aliases.set("ls", "a"), Err(AliasError::Cycle(_))));
+    }
+    
+    /// The words `line` runs with after expanding `alias name='value'`
+    fn words_after(name: &str, value: &str, line: &str) -> Vec<String> {
+        let (aliases, _) = Aliases::from_config(&table(&[(name, value)]), 16);
+        let expanded = expand(&aliases, line).unwrap();
+        assert_eq!(expanded.len(), 1, "{:?}", expanded);
+        parser::split_words(&expanded[0])
+    }
+    
+    #[test]
+    fn arguments_keep_their_quoting_when_substituted() {
+        let gc = r#"git commit -m "$1""#;
+        assert_eq!(words_after("gc", gc, r#"gc "fix the bug""#), ["git", "commit", "-m", "fix the bug"]);
+        assert_eq!(
+            words_after("gc", gc, r#"gc 'say "hi" to $USER \o/'"#),
+            ["git", "commit", "-m", r#"say "hi" to $USER \o/"#]
+        );
+        assert_eq!(words_after("gc", gc, "gc \"it's\""), ["git", "commit", "-m", "it's"]);
+        
+        // Unquoted, a value is still one word
+        assert_eq!(words_after("e", "echo $1 $2", "e 'a  b' \"c;d\""), ["echo", "a  b", "c;d"]);
+        // A missing argument is empty, and `$#` counts what was given
+        assert_eq!(words_after("e", "echo \"[$2]\" $#", "e one"), ["echo", "[]", "1"]);
+    }
+    
+    #[test]
+    fn all_arguments_expand_as_in_sh() {
+        let args = "x 'a b' c";
+        assert_eq!(words_after("x", "printf '<%s>' \"$@\"", args), ["printf", "<%s>", "a b", "c"]);
+        assert_eq!(words_after("x", "printf '<%s>' \"$*\"", args), ["printf", "<%s>", "a b c"]);
+        assert_eq!(words_after("x", "printf '<%s>' $@", args), ["printf", "<%s>", "a b", "c"]);
+        assert_eq!(words_after("x", "printf '<%s>' \"-$@-\"", args), ["printf", "<%s>", "-a b", "c-"]);
+        assert_eq!(words_after("x", "printf '<%s>' \"$@\"", "x"), ["printf", "<%s>"]);
+        // Single quotes keep placeholders as written
+        assert_eq!(words_after("x", "echo '$1' \"$1\"", "x v"), ["echo", "$1", "v"]);
+    }
+    
+    #[test]
+    fn redirections_and_later_stages_stay_after_the_expansion() {
+        let (aliases, _) = Aliases::from_config(&table(&[("gc", "git commit -m \"$1\"")]), 16);
+        assert_eq!(expand(&aliases, "gc wip > log 2>&1 | tail -1").unwrap(), ["git commit -m \"wip\" > log 2>&1 | tail -1"]);
+        
+        // Without placeholders the rest is appended, as for any alias
+        let (aliases, _) = Aliases::from_config(&table(&[("ll", "ls -l")]), 16);
+        assert_eq!(expand(&aliases, "ll 'my dir' > out").unwrap(), ["ls -l 'my dir' > out"]);
+    }
+    
+    #[test]
+    fn nesting_past_the_limit_fails_to_expand() {
+        let chain: Vec<(String, String)> = (0..5).map(|i| (format!("a{}", i), format!("a{} x", i + 1))).collect();
+        let chain: BTreeMap<String, String> = chain.into_iter().collect();
+        
+        let (deep, skipped) = Aliases::from_config(&chain, 3);
+        assert!(skipped.is_empty());
+        assert!(matches!(expand(&deep, "a0"), Err(AliasError::TooDeep(path)) if path.len() == 3));
+        assert_eq!(expand(&deep, "a3").unwrap(), vec!["a5 x x"]);
+        
+        let (shallow, _) = Aliases::from_config(&chain, 5);
+        assert_eq!(expand(&shallow, "a0 y").unwrap(), vec!["a5 x x x x x y"]);
+    }
+}
@@ -0,0 +1,75 @@
+use super::*;
+
+/// Latency statistics over a set of samples
+pub struct Stats {
+    pub mean: Duration,
+    pub median: Duration,
+    pub p99: Duration,
+    pub min: Duration,
+    pub max: Duration,
+}
+
+impl Stats {
+    /// Summarize `samples`, or `None` if there are none
+    pub fn from_samples(samples: &mut [Duration]) -> Option<Self> {
+        if samples.is_empty() {
+            return None;
+        }
+        
+        samples.sort();
+        let total: Duration = samples.iter().sum();
+        
+        Some(Stats {
+            mean: total / samples.len() as u32,
+            median: percentile(samples, 50.0),
+            p99: percentile(samples, 99.0),
+            min: samples[0],
+            max: samples[samples.len() - 1],
+        })
+    }
+}
+
+/// Nearest-rank percentile of sorted, non-empty `samples`
+fn percentile(samples: &[Duration], p: f64) -> Duration {
+    let rank = (p / 100.0 * samples.len() as f64).ceil() as usize;
+    samples[rank.clamp(1, samples.len()) - 1]
+}
+
+/// Run `command` through the executor `warmup + iterations` times and print
+/// latency statistics for the timed iterations. Returns 1 if any run failed.
+pub async fn run(shell: &mut ObsidianShell, command: &str, iterations: u32, warmup: u32) -> Result<i32> {
+    // Warmup runs fill caches (binary lookup, page cache) and are not measured
+    for _ in 0..warmup {
+        shell.execute_command(command, None).await?;
+    }
+    
+    let mut samples = Vec::with_capacity(iterations as usize);
+    let mut failures = 0;
+    for _ in 0..iterations {
+        let start = Instant::now();
+        let result = shell.execute_command(command, None).await?;
+        samples.push(start.elapsed());
+        
+        if result.exit_code != 0 {
+            failures += 1;
+        }
+    }
+    
+    let Some(stats) = Stats::from_samples(&mut samples) else {
+        return Ok(0);
+    };
+    
+    outln!("⏱️  {} ({} iterations, {} warmup)", command, iterations, warmup);
+    outln!("  mean:   {:.3?}", stats.mean);
+    outln!("  median: {:.3?}", stats.median);
+    outln!("  p99:    {:.3?}", stats.p99);
+    outln!("  min:    {:.3?}", stats.min);
+    outln!("  max:    {:.3?}", stats.max);
+    
+    if failures > 0 {
+        eprintln!("⚠️  {} of {} runs exited with a non-zero status", failures, iterations);
+        return Ok(1);
+    }
+    
+    Ok(0)
+}
@@ -0,0 +1,47 @@
+use super::*;
+
+/// System clipboard for the `copy` and `paste` builtins.
+///
+/// Opened on first use and kept open, since on X11 the copied text is only
+/// available while the process that set it holds the clipboard.
+#[derive(Default)]
+pub struct Clipboard {
+    #[cfg(feature = "clipboard")]
+    inner: Option<arboard::Clipboard>,
+}
+
+impl Clipboard {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    
+    #[cfg(feature = "clipboard")]
+    fn open(&mut self) -> Result<&mut arboard::Clipboard> {
+        if self.inner.is_none() {
+            let clipboard = arboard::Clipboard::new()
+                .context("No clipboard available (is a display server running?)")?;
+            self.inner = Some(clipboard);
+        }
+        Ok(self.inner.as_mut().unwrap())
+    }
+    
+    #[cfg(feature = "clipboard")]
+    pub fn copy(&mut self, text: &str) -> Result<()> {
+        self.open()?.set_text(text).context("Failed to set the clipboard")
+    }
+    
+    #[cfg(feature = "clipboard")]
+    pub fn paste(&mut self) -> Result<String> {
+        self.open()?.get_text().context("Failed to read the clipboard")
+    }
+    
+    #[cfg(not(feature = "clipboard"))]
+    pub fn copy(&mut self, _text: &str) -> Result<()> {
+        anyhow::bail!("Built without the `clipboard` feature")
+    }
+    
+    #[cfg(not(feature = "clipboard"))]
+    pub fn paste(&mut self) -> Result<String> {
+        anyhow::bail!("Built without the `clipboard` feature")
+    }
+}
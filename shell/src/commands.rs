@@ -0,0 +1,749 @@
+use super::*;
+use config::{CommandPolicy, PolicyError, RemoteConfig, SandboxConfig};
+use encoding_rs::Encoding;
+use std::process::{ExitStatus, Stdio};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::process::{Child, Command};
+use tokio::task::JoinHandle;
+
+/// Exit status reported when a command is refused by the policy
+pub const EXIT_NOT_EXECUTABLE: i32 = 126;
+/// Exit status reported when a program cannot be found
+pub const EXIT_NOT_FOUND: i32 = 127;
+
+/// Outcome of running a command or a line of input
+#[derive(Debug, Clone, Default)]
+pub struct CommandResult {
+    pub stdout: String,
+    pub stderr: String,
+    pub exit_code: i32,
+    pub duration: Duration,
+    /// Command the AI rewrote the input into, if any
+    pub interpreted: Option<String>,
+    /// Why the AI chose `interpreted`, when it said
+    pub interpretation_reason: Option<String>,
+    /// Typos fixed from the `corrections` table, as `from → to`
+    pub corrected: Vec<String>,
+    /// The shell a `!!` line was handed to instead of being parsed here
+    pub delegated_to: Option<String>,
+    /// Why AI interpretation failed, if it was attempted and failed
+    pub ai_error: Option<String>,
+    /// What was done instead after `ai_error`
+    pub ai_fallback: Option<String>,
+    /// Notices for the user that are not part of the command's output
+    pub warnings: Vec<String>,
+    /// Set when the input asked the shell to exit
+    pub exit_requested: bool,
+    /// Display the output directly even if it would normally be paged
+    pub skip_pager: bool,
+    /// Show only the lines of `stdout` this keeps, from a `g/pattern/` suffix
+    pub line_filter: Option<linefilter::LineFilter>,
+    /// CPU and memory used by the external commands that ran, if known
+    pub usage: Option<ResourceUsage>,
+    /// How long each stage of a pipeline ran, in order
+    pub stage_timings: Vec<StageTiming>,
+    /// Output that was shown as the command ran, so isn't in `stdout` and
+    /// `stderr`, as far as it was kept
+    pub streamed: Streamed,
+}
+
+impl CommandResult {
+    /// All of stdout that was kept: what was streamed, then the rest
+    pub fn all_stdout(&self) -> Cow<'_, str> {
+        join_streamed(&self.streamed.stdout, &self.stdout)
+    }
+    
+    /// All of stderr that was kept, as `all_stdout`
+    pub fn all_stderr(&self) -> Cow<'_, str> {
+        join_streamed(&self.streamed.stderr, &self.stderr)
+    }
+}
+
+fn join_streamed<'a>(streamed: &'a str, rest: &'a str) -> Cow<'a, str> {
+    if streamed.is_empty() {
+        Cow::Borrowed(rest)
+    } else {
+        Cow::Owned(format!("{}{}", streamed, rest))
+    }
+}
+
+/// The start and end of what a command wrote to the terminal as it ran
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Streamed {
+    pub stdout: String,
+    pub stderr: String,
+}
+
+impl Streamed {
+    pub fn append(&mut self, other: Streamed) {
+        self.stdout.push_str(&other.stdout);
+        self.stderr.push_str(&other.stderr);
+    }
+}
+
+/// What happens to the output of a pipeline as it runs
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Output {
+    /// Kept whole in the result
+    Capture,
+    /// Written to this process's stdout and stderr as it arrives, keeping
+    /// `limit` bytes of each stream of each stage in `CommandResult::streamed`
+    Tee { limit: usize },
+}
+
+/// One pipeline stage and how long it ran
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StageTiming {
+    pub command: String,
+    pub duration: Duration,
+}
+
+/// Resources used by a child process, from `getrusage`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ResourceUsage {
+    pub user_time: Duration,
+    pub system_time: Duration,
+    /// Peak resident set size in KiB. Only known when the command set a new
+    /// high for this shell's children, as the OS keeps one peak for all of
+    /// them, and went above the shell's own peak, which a freshly spawned child
+    /// is charged with before it starts the program.
+    pub max_rss_kib: Option<u64>,
+}
+
+impl ResourceUsage {
+    /// Usage of two commands run one after the other, e.g. pipeline stages
+    pub fn combine(a: Option<Self>, b: Option<Self>) -> Option<Self> {
+        match (a, b) {
+            (Some(a), Some(b)) => Some(ResourceUsage {
+                user_time: a.user_time + b.user_time,
+                system_time: a.system_time + b.system_time,
+                max_rss_kib: a.max_rss_kib.max(b.max_rss_kib),
+            }),
+            (a, b) => a.or(b),
+        }
+    }
+}
+
+/// One program of a pipeline, with its `NAME=value` environment
+pub struct Invocation<'a> {
+    pub program: &'a str,
+    pub args: &'a [&'a str],
+    pub env: Vec<(String, String)>,
+}
+
+#[derive(Clone)]
+pub struct CommandExecutor {
+    policy: CommandPolicy,
+    sandbox: SandboxConfig,
+    remote: RemoteConfig,
+    /// Masks secrets in logged command lines
+    redactor: Redactor,
+    /// Directory commands run in for the `in` builtin, instead of the shell's
+    working_dir: Option<PathBuf>,
+    /// What output that isn't UTF-8 is decoded from
+    encoding: &'static Encoding,
+}
+
+impl CommandExecutor {
+    pub fn new(
+        policy: CommandPolicy,
+        sandbox: SandboxConfig,
+        remote: RemoteConfig,
+        redactor: Redactor,
+        encoding: &'static Encoding,
+    ) -> Self {
+        CommandExecutor {
+            policy,
+            sandbox,
+            remote,
+            redactor,
+            working_dir: None,
+            encoding,
+        }
+    }
+    
+    /// Run commands in `dir` instead of the shell's current directory, or in
+    /// that again with `None`; returns the directory this replaces. The
+    /// sandbox's `working_dir` wins over it.
+    pub fn set_working_dir(&mut self, dir: Option<PathBuf>) -> Option<PathBuf> {
+        std::mem::replace(&mut self.working_dir, dir)
+    }
+    
+    /// The directory `set_working_dir` last set, if any
+    pub fn working_dir(&self) -> Option<&Path> {
+        self.working_dir.as_deref()
+    }
+    
+    /// Run `program` with `args`, applying `env` to the child process only.
+    /// `stdin` is written to the child's standard input; without it the child
+    /// reads nothing.
+    ///
+    /// A command that runs but fails is still `Ok`; check `CommandResult::exit_code`.
+    /// `Err` means the command could not be started at all (see `exit_code_for_error`).
+    #[tracing::instrument(name = "exec", skip(self, args, env, stdin))]
+    pub async fn execute(
+        &self,
+        program: &str,
+        args: &[&str],
+        env: &[(String, String)],
+        stdin: Option<String>,
+    ) -> Result<CommandResult> {
+        // Checked here, after alias and AI expansion, so rewriting can't bypass it
+        self.check(program)?;
+        let command = self.command(program, args, env)?;
+        self.run(command, stdin).await
+    }
+    
+    /// Run the command line `command` on `host` through ssh. The remote
+    /// shell parses it, so quoting and `NAME=value` prefixes work as written;
+    /// `program` is its first word, which the policy applies to as it would
+    /// locally. ssh itself runs in the sandbox like any other command.
+    #[tracing::instrument(name = "exec_remote", skip(self, command, stdin))]
+    pub async fn execute_remote(
+        &self,
+        host: &str,
+        program: &str,
+        command: &str,
+        stdin: Option<String>,
+    ) -> Result<CommandResult> {
+        self.check(program)?;
+        
+        let Some((ssh, ssh_args)) = self.remote.ssh_command.split_first() else {
+            anyhow::bail!("No ssh command configured in [remote]");
+        };
+        let mut args: Vec<&str> = ssh_args.iter().map(String::as_str).collect();
+        args.push(self.remote.destination(host));
+        args.push(command);
+        
+        let command = self.command(ssh, &args, &[])?;
+        self.run(command, stdin).await
+    }
+    
+    /// Run the command line `line` with `shell -c`, for syntax this shell
+    /// doesn't parse. The policy applies to `programs`, the commands found in
+    /// it, as it would if they ran directly; the shell itself runs in the
+    /// sandbox like any other command.
+    pub async fn execute_in_shell(
+        &self,
+        shell: &str,
+        programs: &[String],
+        line: &str,
+        stdin: Option<String>,
+    ) -> Result<CommandResult> {
+        for program in programs {
+            self.check(program)?;
+        }
+        let command = self.command(shell, &["-c", line], &[])?;
+        self.run(command, stdin).await
+    }
+    
+    /// Run `stages` together, each one's stdout connected to the next one's
+    /// stdin by an OS pipe. A producer faster than its consumer blocks once
+    /// the pipe is full instead of its output piling up in memory, and gets
+    /// SIGPIPE if the consumer exits first, as with `yes | head`.
+    ///
+    /// The result has the last stage's stdout and exit code and the stderr of
+    /// every stage, or with `Output::Tee`, the part of them that was kept.
+    /// Stages that couldn't be started are returned with their index; as in
+    /// POSIX shells the others still run, the next one with no input.
+    #[tracing::instrument(name = "exec_pipeline", skip_all, fields(stages = stages.len()))]
+    pub async fn execute_pipeline(
+        &self,
+        stages: &[Invocation<'_>],
+        stdin: Option<String>,
+        output: Output,
+    ) -> (CommandResult, Vec<(usize, anyhow::Error)>) {
+        let start = Instant::now();
+        let usage_before = children_usage();
+        let mut children: Vec<Option<Child>> = Vec::new();
+        let mut started = Vec::new();
+        let mut failed = Vec::new();
+        let mut previous: Option<Stdio> = None;
+        
+        for (i, stage) in stages.iter().enumerate() {
+            let input = match previous.take() {
+                Some(pipe) => pipe,
+                None if i == 0 && stdin.is_some() => Stdio::piped(),
+                None => Stdio::null(),
+            };
+            started.push(Instant::now());
+            let spawned = self.check(stage.program).and_then(|()| {
+                let mut command = self.command(stage.program, stage.args, &stage.env)?;
+                let child = command
+                    .stdin(input)
+                    .stdout(Stdio::piped())
+                    .stderr(Stdio::piped())
+                    .spawn()
+                    .inspect_err(|e| tracing::info!(error = %e, program = stage.program, "failed to start command"))
+                    .context("Failed to execute command")?;
+                Ok(child)
+            });
+            let mut child = match spawned {
+                Ok(child) => child,
+                Err(e) => {
+                    failed.push((i, e));
+                    children.push(None);
+                    continue;
+                }
+            };
+            
+            if i + 1 < stages.len() {
+                match child.stdout.take().map(TryInto::<Stdio>::try_into) {
+                    Some(Ok(stdout)) => previous = Some(stdout),
+                    Some(Err(e)) => failed.push((i, anyhow::Error::from(e).context("Failed to connect pipeline"))),
+                    None => {}
+                }
+            }
+            children.push(Some(child));
+        }
+        
+        // As in `run`, written concurrently so the first stage can't deadlock
+        let first = children.first_mut().and_then(Option::as_mut).and_then(|child| child.stdin.take());
+        if let (Some(input), Some(mut pipe)) = (stdin, first) {
+            tokio::spawn(async move {
+                let _ = pipe.write_all(input.as_bytes()).await;
+            });
+        }
+        // Every stderr and the last stdout are read while the stages run, so
+        // none of them stalls on a full pipe
+        let stderr: Vec<JoinHandle<Vec<u8>>> = children
+            .iter_mut()
+            .map(|child| {
+                let stream = child.as_mut().and_then(|child| child.stderr.take());
+                match output {
+                    Output::Capture => read_all(stream),
+                    Output::Tee { limit } => tee::tee(stream, io::stderr(), limit, self.encoding),
+                }
+            })
+            .collect();
+        let stream = children.last_mut().and_then(Option::as_mut).and_then(|child| child.stdout.take());
+        let stdout = match output {
+            Output::Capture => read_all(stream),
+            Output::Tee { limit } => tee::tee(stream, io::stdout(), limit, self.encoding),
+        };
+        
+        // Waited for together, so each stage's time is its own rather than
+        // that of the slowest stage before it
+        let waits: Vec<_> = children
+            .into_iter()
+            .zip(started)
+            .map(|(child, started)| {
+                child.map(|mut child| tokio::spawn(async move { (child.wait().await.ok(), started.elapsed()) }))
+            })
+            .collect();
+        let mut status = None;
+        let mut stage_timings = Vec::new();
+        for (stage, wait) in stages.iter().zip(waits) {
+            let (exited, duration) = match wait {
+                Some(wait) => wait.await.unwrap_or((None, Duration::ZERO)),
+                None => (None, Duration::ZERO),
+            };
+            status = exited;
+            let mut command = vec![stage.program];
+            command.extend(stage.args);
+            stage_timings.push(StageTiming {
+                command: command.join(" "),
+                duration,
+            });
+        }
+        let stdout = stdout.await.unwrap_or_default();
+        let mut stderr_text = String::new();
+        for stage in stderr {
+            stderr_text.push_str(&self.decode(&stage.await.unwrap_or_default()));
+        }
+        
+        let mut result = CommandResult {
+            exit_code: status.map_or(0, exit_code),
+            duration: start.elapsed(),
+            usage: usage_before.zip(children_usage()).map(|(before, after)| after.since(&before)),
+            stage_timings,
+            ..Default::default()
+        };
+        let (stdout_text, stderr_text) = (self.decode(&stdout), stderr_text);
+        match output {
+            Output::Capture => (result.stdout, result.stderr) = (stdout_text, stderr_text),
+            Output::Tee { .. } => result.streamed = Streamed { stdout: stdout_text, stderr: stderr_text },
+        }
+        tracing::info!(
+            exit_code = result.exit_code,
+            duration_ms = result.duration.as_millis() as u64,
+            "pipeline finished"
+        );
+        
+        (result, failed)
+    }
+    
+    /// Whether the policy lets `program` run
+    pub fn check(&self, program: &str) -> Result<()> {
+        if let Err(e) = self.policy.check(program) {
+            tracing::info!(error = %e, "command refused by policy");
+            return Err(e.into());
+        }
+        Ok(())
+    }
+    
+    /// `program` with `args`, checked against the policy and confined by the
+    /// sandbox, for callers that run it themselves outside the runtime
+    pub fn prepare(&self, program: &str, args: &[&str], env: &[(String, String)]) -> Result<std::process::Command> {
+        self.check(program)?;
+        self.std_command(program, args, env)
+    }
+    
+    /// Start `command` and collect its output, feeding it `stdin`
+    async fn run(&self, mut command: Command, stdin: Option<String>) -> Result<CommandResult> {
+        let start = Instant::now();
+        let usage_before = children_usage();
+        let mut child = command
+            .stdin(if stdin.is_some() { Stdio::piped() } else { Stdio::null() })
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .inspect_err(|e| tracing::info!(error = %e, "failed to start command"))
+            .context("Failed to execute command")?;
+        
+        // Written concurrently so a child that fills its stdout pipe before
+        // reading all of its input can't deadlock; a child that exits without
+        // reading everything just closes the pipe early
+        if let (Some(input), Some(mut pipe)) = (stdin, child.stdin.take()) {
+            tokio::spawn(async move {
+                let _ = pipe.write_all(input.as_bytes()).await;
+            });
+        }
+        
+        let output = child
+            .wait_with_output()
+            .await
+            .context("Failed to execute command")?;
+        
+        let result = CommandResult {
+            stdout: self.decode(&output.stdout),
+            stderr: self.decode(&output.stderr),
+            exit_code: exit_code(output.status),
+            duration: start.elapsed(),
+            usage: usage_before.zip(children_usage()).map(|(before, after)| after.since(&before)),
+            ..Default::default()
+        };
+        tracing::info!(
+            exit_code = result.exit_code,
+            duration_ms = result.duration.as_millis() as u64,
+            "command finished"
+        );
+        
+        Ok(result)
+    }
+    
+    /// A program's output as text: as it is when it is UTF-8, and otherwise
+    /// converted from the configured encoding
+    fn decode(&self, bytes: &[u8]) -> String {
+        match std::str::from_utf8(bytes) {
+            Ok(text) => text.to_string(),
+            Err(_) => self.encoding.decode_without_bom_handling(bytes).0.into_owned(),
+        }
+    }
+    
+    /// Build the invocation of `program`, wrapped and confined as the sandbox
+    /// config says
+    fn command(&self, program: &str, args: &[&str], env: &[(String, String)]) -> Result<Command> {
+        let mut command = Command::from(self.std_command(program, args, env)?);
+        // A caller that gives up on the command, on Ctrl-C or a timeout, stops it
+        command.kill_on_drop(true);
+        Ok(command)
+    }
+    
+    fn std_command(&self, program: &str, args: &[&str], env: &[(String, String)]) -> Result<std::process::Command> {
+        let mut command = match self.sandbox.wrapper.split_first() {
+            Some((wrapper, wrapper_args)) => {
+                let mut command = std::process::Command::new(wrapper);
+                command.args(wrapper_args).arg(program);
+                command
+            }
+            None => std::process::Command::new(program),
+        };
+        command.args(args);
+        
+        if let Some(allowlist) = &self.sandbox.env_allowlist {
+            command.env_clear();
+            for name in allowlist {
+                if let Some(value) = env::var_os(name) {
+                    command.env(name, value);
+                }
+            }
+        }
+        // Before the command's own assignments, so `NO_COLOR= ls` still wins
+        command.envs(ui::child_env().iter().copied());
+        command.envs(env.iter().map(|(name, value)| (name, value)));
+        
+        if let Some(dir) = &self.sandbox.working_dir {
+            if !dir.is_dir() {
+                anyhow::bail!("Sandbox working directory {} does not exist", dir.display());
+            }
+            command.current_dir(dir);
+        } else if let Some(dir) = &self.working_dir {
+            command.current_dir(dir);
+        }
+        
+        if self.sandbox.is_active() {
+            let logged = format!("{:?}", command);
+            tracing::debug!(command = %self.redactor.redact(&logged), "sandboxed command");
+        }
+        Ok(command)
+    }
+}
+
+/// Read `stream` to the end in the background
+fn read_all(stream: Option<impl AsyncRead + Unpin + Send + 'static>) -> JoinHandle<Vec<u8>> {
+    tokio::spawn(async move {
+        let mut buf = Vec::new();
+        if let Some(mut stream) = stream {
+            let _ = stream.read_to_end(&mut buf).await;
+        }
+        buf
+    })
+}
+
+/// Totals for all waited-for children, which commands run one at a time
+/// turn into per-command figures
+#[derive(Debug, Clone, Copy)]
+struct ChildrenUsage {
+    user_time: Duration,
+    system_time: Duration,
+    max_rss_kib: u64,
+    /// Peak of the shell itself
+    shell_max_rss_kib: u64,
+}
+
+impl ChildrenUsage {
+    /// What was used between `before` and this snapshot
+    fn since(&self, before: &ChildrenUsage) -> ResourceUsage {
+        ResourceUsage {
+            user_time: self.user_time.saturating_sub(before.user_time),
+            system_time: self.system_time.saturating_sub(before.system_time),
+            max_rss_kib: (self.max_rss_kib > before.max_rss_kib.max(self.shell_max_rss_kib))
+                .then_some(self.max_rss_kib),
+        }
+    }
+}
+
+#[cfg(unix)]
+fn children_usage() -> Option<ChildrenUsage> {
+    use nix::sys::resource::{getrusage, UsageWho};
+    use nix::sys::time::TimeValLike;
+    
+    let usage = getrusage(UsageWho::RUSAGE_CHILDREN).ok()?;
+    let shell = getrusage(UsageWho::RUSAGE_SELF).ok()?;
+    let micros = |time: nix::sys::time::TimeVal| Duration::from_micros(time.num_microseconds().max(0) as u64);
+    
+    // Reported in bytes on macOS and KiB elsewhere
+    let kib = |max_rss: nix::libc::c_long| {
+        let max_rss = max_rss.max(0) as u64;
+        if cfg!(target_os = "macos") { max_rss / 1024 } else { max_rss }
+    };
+    
+    Some(ChildrenUsage {
+        user_time: micros(usage.user_time()),
+        system_time: micros(usage.system_time()),
+        max_rss_kib: kib(usage.max_rss()),
+        shell_max_rss_kib: kib(shell.max_rss()),
+    })
+}
+
+#[cfg(not(unix))]
+fn children_usage() -> Option<ChildrenUsage> {
+    None
+}
+
+/// Convert a process exit status into a shell-style exit code
+pub fn exit_code(status: ExitStatus) -> i32 {
+    if let Some(code) = status.code() {
+        return code;
+    }
+    
+    // Killed by a signal: report 128 + signal number like POSIX shells
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::ExitStatusExt;
+        if let Some(signal) = status.signal() {
+            return 128 + signal;
+        }
+    }
+    
+    1
+}
+
+/// Exit code to report for a command that could not be started
+pub fn exit_code_for_error(error: &anyhow::Error) -> i32 {
+    if error.downcast_ref::<PolicyError>().is_some() {
+        return EXIT_NOT_EXECUTABLE;
+    }
+    
+    match error.root_cause().downcast_ref::<io::Error>().map(|e| e.kind()) {
+        Some(io::ErrorKind::NotFound) => EXIT_NOT_FOUND,
+        Some(io::ErrorKind::PermissionDenied) => EXIT_NOT_EXECUTABLE,
+        _ => 1,
+    }
+}
+
+/// Split leading `NAME=value` assignments off a command line.
+///
+/// `FOO=bar RUST_LOG=debug cargo run` yields `[("FOO", "bar"), ("RUST_LOG", "debug")]`
+/// and `["cargo", "run"]`. Assignments are only recognised before the program name.
+pub fn split_env_assignments<'a>(parts: &'a [&'a str]) -> (Vec<(String, String)>, &'a [&'a str]) {
+    let mut env = Vec::new();
+    let mut rest = parts;
+    
+    while let Some((first, tail)) = rest.split_first() {
+        match parse_assignment(first) {
+            Some((name, value)) => {
+                env.push((name.to_string(), value.to_string()));
+                rest = tail;
+            }
+            None => break,
+        }
+    }
+    
+    (env, rest)
+}
+
+/// Parse a single `NAME=value` token, where NAME is a valid variable name
+fn parse_assignment(token: &str) -> Option<(&str, &str)> {
+    let (name, value) = token.split_once('=')?;
+    is_valid_name(name).then_some((name, value))
+}
+
+/// Whether `name` is a valid environment variable name (`[A-Za-z_][A-Za-z0-9_]*`)
+pub fn is_valid_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    let valid_start = chars.next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_');
+    valid_start && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    fn split(line: &str) -> (Vec<(String, String)>, Vec<String>) {
+        let parts: Vec<&str> = line.split_whitespace().collect();
+        let (env, rest) = split_env_assignments(&parts);
+        (env, rest.iter().map(|part| part.to_string()).collect())
+    }
+    
+    fn pair(name: &str, value: &str) -> (String, String) {
+        (name.to_string(), value.to_string())
+    }
+    
+    #[test]
+    fn assignments_stack_before_the_program() {
+        let (env, rest) = split("A=1 B=2 cmd C=3");
+        assert_eq!(env, vec![pair("A", "1"), pair("B", "2")]);
+        assert_eq!(rest, ["cmd", "C=3"]);
+    }
+    
+    #[test]
+    fn assignments_alone_leave_no_program() {
+        let (env, rest) = split("A=1");
+        assert_eq!(env, vec![pair("A", "1")]);
+        assert!(rest.is_empty());
+        
+        let (env, _) = split("EMPTY= cmd");
+        assert_eq!(env, vec![pair("EMPTY", "")]);
+    }
+    
+    #[test]
+    fn invalid_names_are_not_assignments() {
+        for line in ["=x cmd", "1A=x cmd", "A-B=x cmd", "cmd"] {
+            let (env, rest) = split(line);
+            assert!(env.is_empty(), "{line}");
+            assert_eq!(rest[0], line.split_whitespace().next().unwrap(), "{line}");
+        }
+        assert!(is_valid_name("_private"));
+        assert!(is_valid_name("RUST_LOG2"));
+        assert!(!is_valid_name(""));
+        assert!(!is_valid_name("2X"));
+    }
+    
+    fn executor() -> CommandExecutor {
+        CommandExecutor::new(
+            CommandPolicy::default(),
+            SandboxConfig::default(),
+            RemoteConfig::default(),
+            Redactor::default(),
+            encoding_rs::UTF_8,
+        )
+    }
+    
+    fn stage<'a>(program: &'a str, args: &'a [&'a str]) -> Invocation<'a> {
+        Invocation {
+            program,
+            args,
+            env: Vec::new(),
+        }
+    }
+    
+    #[tokio::test]
+    async fn assignments_reach_only_the_child() {
+        let executor = executor();
+        let env = vec![pair("OBSIDIAN_TEST_A", "1"), pair("OBSIDIAN_TEST_B", "2")];
+        let result = executor
+            .execute("sh", &["-c", "echo $OBSIDIAN_TEST_A $OBSIDIAN_TEST_B"], &env, None)
+            .await
+            .unwrap();
+        assert_eq!(result.stdout, "1 2\n");
+        assert!(env::var_os("OBSIDIAN_TEST_A").is_none());
+    }
+    
+    #[tokio::test]
+    async fn a_fast_producer_waits_for_a_slow_consumer() {
+        // 256 MB, far more than a pipe holds. Buffered in the shell it would
+        // all be written before the consumer wakes up; through a pipe the
+        // producer blocks once it is full and is killed by SIGPIPE when the
+        // consumer leaves.
+        let producer = [
+            "-c",
+            "dd if=/dev/zero bs=65536 count=4096 2>/dev/null; echo \"producer status $?\" >&2",
+        ];
+        let consumer = ["-c", "sleep 1; head -c 10 | wc -c"];
+        let (result, failed) = executor()
+            .execute_pipeline(&[stage("sh", &producer), stage("sh", &consumer)], None, Output::Capture)
+            .await;
+        
+        assert!(failed.is_empty());
+        assert_eq!(result.stdout.trim(), "10");
+        assert_eq!(result.stderr, "producer status 141\n");
+        assert_eq!(result.exit_code, 0);
+    }
+    
+    #[tokio::test]
+    async fn long_streams_pass_through_whole() {
+        let (result, _) = executor()
+            .execute_pipeline(
+                &[
+                    stage("yes", &["0123456789"]),
+                    stage("head", &["-n", "200000"]),
+                    stage("wc", &["-c"]),
+                ],
+                None,
+                Output::Capture,
+            )
+            .await;
+        assert_eq!(result.stdout.trim(), "2200000");
+        assert_eq!(result.stage_timings.len(), 3);
+    }
+    
+    #[tokio::test]
+    async fn stages_that_fail_to_start_are_reported() {
+        let (result, failed) = executor()
+            .execute_pipeline(
+                &[stage("/nonexistent/program", &[]), stage("cat", &[])],
+                Some("ignored".to_string()),
+                Output::Capture,
+            )
+            .await;
+        assert_eq!(failed.len(), 1);
+        assert_eq!(failed[0].0, 0);
+        assert_eq!(exit_code_for_error(&failed[0].1), EXIT_NOT_FOUND);
+        // The next stage still runs, with no input
+        assert_eq!(result.stdout, "");
+        assert_eq!(result.exit_code, 0);
+    }
+}
@@ -0,0 +1,402 @@
+use super::*;
+use std::collections::HashMap;
+use std::fs;
+use std::io::Read;
+use std::process::Stdio;
+use std::thread;
+
+/// How long a `complete -C` generator may run before it is killed
+const GENERATOR_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Completes the arguments of one command.
+///
+/// `args` holds the words between the command name and the word being
+/// completed; `word` is the (possibly empty) partial word. Returned candidates
+/// replace `word` entirely; `None` falls back to completing file paths.
+pub trait Completer: Send + Sync {
+    fn complete(&self, args: &[&str], word: &str) -> Option<Vec<String>>;
+    
+    /// The `complete` arguments that register this completer, for
+    /// `complete -p`; `None` for the built-in ones
+    fn spec(&self) -> Option<String> {
+        None
+    }
+}
+
+pub struct CommandCompletion {
+    completers: HashMap<String, Box<dyn Completer>>,
+}
+
+impl CommandCompletion {
+    pub fn new() -> Self {
+        let mut completion = CommandCompletion {
+            completers: HashMap::new(),
+        };
+        
+        completion.register("git", Box::new(SubcommandCompleter::new(GIT_SUBCOMMANDS)));
+        completion.register("cargo", Box::new(SubcommandCompleter::new(CARGO_SUBCOMMANDS)));
+        for command in ["cd", "pushd", "rmdir"] {
+            completion.register(command, Box::new(DirectoryCompleter));
+        }
+        
+        completion
+    }
+    
+    /// Register a completer for arguments of `command`, replacing any existing one
+    pub fn register(&mut self, command: &str, completer: Box<dyn Completer>) {
+        self.completers.insert(command.to_string(), completer);
+    }
+    
+    /// Remove the completer for `command`, returning whether there was one
+    pub fn unregister(&mut self, command: &str) -> bool {
+        self.completers.remove(command).is_some()
+    }
+    
+    /// The `complete` command line that re-creates the completer of
+    /// `command`, if it was registered with one
+    pub fn spec(&self, command: &str) -> Option<String> {
+        let spec = self.completers.get(command)?.spec()?;
+        Some(format!("complete {} {}\n", spec, parser::quote(command)))
+    }
+    
+    /// `spec` for every command that has one, sorted
+    pub fn specs(&self) -> Vec<String> {
+        let mut specs: Vec<String> = self.completers.keys().filter_map(|command| self.spec(command)).collect();
+        specs.sort();
+        specs
+    }
+    
+    /// Complete the last word of `input`
+    pub fn complete(&self, input: &str) -> Vec<String> {
+        let mut words: Vec<&str> = input.split_whitespace().collect();
+        
+        // A trailing space means a new, empty word is being completed
+        let word = if input.is_empty() || input.ends_with(char::is_whitespace) {
+            ""
+        } else {
+            words.pop().unwrap_or("")
+        };
+        
+        let Some((command, args)) = words.split_first() else {
+            return complete_program(word);
+        };
+        
+        self.completers
+            .get(*command)
+            .and_then(|completer| completer.complete(args, word))
+            .unwrap_or_else(|| complete_path(word, false))
+    }
+}
+
+/// Completes a fixed list of subcommands in the first argument position
+pub struct SubcommandCompleter {
+    subcommands: &'static [&'static str],
+}
+
+impl SubcommandCompleter {
+    pub fn new(subcommands: &'static [&'static str]) -> Self {
+        SubcommandCompleter { subcommands }
+    }
+}
+
+impl Completer for SubcommandCompleter {
+    fn complete(&self, args: &[&str], word: &str) -> Option<Vec<String>> {
+        if !args.is_empty() {
+            return None;
+        }
+        
+        let candidates: Vec<String> = self
+            .subcommands
+            .iter()
+            .filter(|subcommand| subcommand.starts_with(word))
+            .map(|subcommand| subcommand.to_string())
+            .collect();
+        (!candidates.is_empty()).then_some(candidates)
+    }
+}
+
+/// Completes directories only, for commands such as `cd` that take no files
+pub struct DirectoryCompleter;
+
+impl Completer for DirectoryCompleter {
+    fn complete(&self, _args: &[&str], word: &str) -> Option<Vec<String>> {
+        Some(complete_path(word, true))
+    }
+}
+
+/// Completes any argument from a fixed list of words, for `complete -W`
+pub struct WordListCompleter {
+    words: Vec<String>,
+}
+
+impl WordListCompleter {
+    pub fn new(words: &str) -> Self {
+        WordListCompleter {
+            words: words.split_whitespace().map(String::from).collect(),
+        }
+    }
+}
+
+impl Completer for WordListCompleter {
+    fn complete(&self, _args: &[&str], word: &str) -> Option<Vec<String>> {
+        Some(self.words.iter().filter(|candidate| candidate.starts_with(word)).cloned().collect())
+    }
+    
+    fn spec(&self) -> Option<String> {
+        Some(format!("-W {}", parser::quote(&self.words.join(" "))))
+    }
+}
+
+/// Completes with the lines a command prints, for `complete -C`. As in bash,
+/// the command gets the command name, the word being completed and the word
+/// before it as arguments, and the line so far in `COMP_LINE`. It runs like
+/// any other command line, under the policy and in the sandbox.
+pub struct GeneratorCompleter {
+    executor: CommandExecutor,
+    program: String,
+    generator: String,
+}
+
+impl GeneratorCompleter {
+    pub fn new(executor: CommandExecutor, program: &str, generator: &str) -> Self {
+        GeneratorCompleter {
+            executor,
+            program: program.to_string(),
+            generator: generator.to_string(),
+        }
+    }
+    
+    /// What the generator prints, or `None` if it can't be run, the policy
+    /// refuses it or it takes longer than `GENERATOR_TIMEOUT`
+    fn run(&self, args: &[&str], word: &str) -> Option<String> {
+        for program in line_programs(&self.generator) {
+            self.executor.check(&program).ok()?;
+        }
+        let previous = args.last().copied().unwrap_or(&self.program);
+        let line: Vec<&str> = [self.program.as_str()].into_iter().chain(args.iter().copied()).chain([word]).collect();
+        let line = line.join(" ");
+        let script = format!(
+            "{} {} {} {}",
+            self.generator,
+            parser::quote(&self.program),
+            parser::quote(word),
+            parser::quote(previous)
+        );
+        
+        let env = [("COMP_POINT".to_string(), line.len().to_string()), ("COMP_LINE".to_string(), line)];
+        let mut child = self
+            .executor
+            .prepare("sh", &["-c", &script], &env)
+            .ok()?
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .ok()?;
+        // Read while it runs, so a long list can't fill the pipe and stall it
+        let mut stdout = child.stdout.take()?;
+        let reader = thread::spawn(move || {
+            let mut output = String::new();
+            stdout.read_to_string(&mut output).map(|_| output)
+        });
+        
+        let deadline = Instant::now() + GENERATOR_TIMEOUT;
+        while child.try_wait().ok()?.is_none() {
+            if Instant::now() >= deadline {
+                let _ = child.kill();
+                let _ = child.wait();
+                return None;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        reader.join().ok()?.ok()
+    }
+}
+
+impl Completer for GeneratorCompleter {
+    fn complete(&self, args: &[&str], word: &str) -> Option<Vec<String>> {
+        let output = self.run(args, word)?;
+        Some(
+            output
+                .lines()
+                .filter(|candidate| !candidate.is_empty() && candidate.starts_with(word))
+                .map(String::from)
+                .collect(),
+        )
+    }
+    
+    fn spec(&self) -> Option<String> {
+        Some(format!("-C {}", parser::quote(&self.generator)))
+    }
+}
+
+const GIT_SUBCOMMANDS: &[&str] = &[
+    "add", "bisect", "blame", "branch", "checkout", "cherry-pick", "clone", "commit",
+    "config", "diff", "fetch", "grep", "init", "log", "merge", "mv", "pull", "push",
+    "rebase", "remote", "reset", "restore", "revert", "rm", "show", "stash", "status",
+    "switch", "tag", "worktree",
+];
+
+const CARGO_SUBCOMMANDS: &[&str] = &[
+    "add", "bench", "build", "check", "clean", "clippy", "doc", "fetch", "fix", "fmt",
+    "init", "install", "metadata", "new", "publish", "remove", "run", "search", "test",
+    "tree", "uninstall", "update",
+];
+
+/// Complete a program name from `$PATH`, or a path if the word contains `/`
+fn complete_program(word: &str) -> Vec<String> {
+    if word.contains('/') {
+        return complete_path(word, false);
+    }
+    
+    let mut programs: Vec<String> = env::var_os("PATH")
+        .map(|path| env::split_paths(&path).collect::<Vec<_>>())
+        .unwrap_or_default()
+        .iter()
+        .filter_map(|dir| fs::read_dir(dir).ok())
+        .flatten()
+        .filter_map(|entry| entry.ok())
+        .filter(is_executable)
+        .filter_map(|entry| entry.file_name().into_string().ok())
+        .filter(|name| name.starts_with(word))
+        .collect();
+    
+    programs.sort();
+    programs.dedup();
+    programs
+}
+
+/// Complete a file or directory path; directories get a trailing `/`.
+/// With `dirs_only`, files are left out.
+fn complete_path(word: &str, dirs_only: bool) -> Vec<String> {
+    let (dir, prefix) = match word.rfind('/') {
+        Some(index) => (&word[..=index], &word[index + 1..]),
+        None => ("", word),
+    };
+    
+    let search_dir = if dir.is_empty() { "." } else { dir };
+    let Ok(entries) = fs::read_dir(search_dir) else {
+        return vec![];
+    };
+    
+    let mut candidates: Vec<String> = entries
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| {
+            let name = entry.file_name().into_string().ok()?;
+            // Hidden files are only offered when explicitly asked for
+            if !name.starts_with(prefix) || (name.starts_with('.') && !prefix.starts_with('.')) {
+                return None;
+            }
+            
+            // Follows symlinks, so a link to a directory counts as one
+            let is_dir = entry.path().is_dir();
+            if dirs_only && !is_dir {
+                return None;
+            }
+            Some(format!("{}{}{}", dir, name, if is_dir { "/" } else { "" }))
+        })
+        .collect();
+    
+    candidates.sort();
+    candidates
+}
+
+#[cfg(unix)]
+fn is_executable(entry: &fs::DirEntry) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    entry
+        .metadata()
+        .is_ok_and(|meta| meta.is_file() && meta.permissions().mode() & 0o111 != 0)
+}
+
+#[cfg(not(unix))]
+fn is_executable(entry: &fs::DirEntry) -> bool {
+    entry.metadata().is_ok_and(|meta| meta.is_file())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn subcommands_complete_the_first_argument_only() {
+        let completion = CommandCompletion::new();
+        assert_eq!(completion.complete("git che"), vec!["checkout", "cherry-pick"]);
+        assert_eq!(completion.complete("cargo cl"), vec!["clean", "clippy"]);
+        
+        // Past the subcommand, arguments are paths
+        let tmp = tempfile::tempdir().unwrap();
+        fs::write(tmp.path().join("notes.txt"), "").unwrap();
+        let word = format!("{}/no", tmp.path().display());
+        assert_eq!(completion.complete(&format!("git add {}", word)), vec![format!("{}/notes.txt", tmp.path().display())]);
+    }
+    
+    #[test]
+    fn directory_completion_leaves_out_files() {
+        let tmp = tempfile::tempdir().unwrap();
+        fs::create_dir(tmp.path().join("src")).unwrap();
+        fs::write(tmp.path().join("setup.sh"), "").unwrap();
+        fs::write(tmp.path().join(".secret"), "").unwrap();
+        let dir = tmp.path().display();
+        
+        let completion = CommandCompletion::new();
+        assert_eq!(completion.complete(&format!("cd {}/s", dir)), vec![format!("{}/src/", dir)]);
+        assert_eq!(
+            completion.complete(&format!("cat {}/s", dir)),
+            vec![format!("{}/setup.sh", dir), format!("{}/src/", dir)]
+        );
+        assert_eq!(completion.complete(&format!("cat {}/.s", dir)), vec![format!("{}/.secret", dir)]);
+    }
+    
+    #[test]
+    fn word_lists_round_trip_through_their_spec() {
+        let mut completion = CommandCompletion::new();
+        completion.register("deploy", Box::new(WordListCompleter::new("staging production preview")));
+        assert_eq!(completion.complete("deploy p"), vec!["production", "preview"]);
+        assert_eq!(completion.spec("deploy").as_deref(), Some("complete -W 'staging production preview' deploy\n"));
+        assert!(completion.spec("git").is_none());
+        
+        assert!(completion.unregister("deploy"));
+        assert!(!completion.unregister("deploy"));
+    }
+    
+    fn executor(blocked: &[&str]) -> CommandExecutor {
+        let policy = config::CommandPolicy {
+            blocked_commands: blocked.iter().map(|name| name.to_string()).collect(),
+            ..Default::default()
+        };
+        CommandExecutor::new(
+            policy,
+            config::SandboxConfig::default(),
+            config::RemoteConfig::default(),
+            Redactor::default(),
+            encoding_rs::UTF_8,
+        )
+    }
+    
+    #[test]
+    fn generators_get_the_words_around_the_cursor() {
+        let generator = GeneratorCompleter::new(
+            executor(&[]),
+            "svc",
+            "f() { printf '%s\\n' \"$1:$2:$3\" \"$COMP_LINE\" alpha beta; }; f",
+        );
+        assert_eq!(generator.complete(&["start"], "").unwrap(), vec!["svc::start", "svc start ", "alpha", "beta"]);
+        assert_eq!(generator.complete(&[], "b").unwrap(), vec!["beta"]);
+        assert_eq!(generator.spec().unwrap(), format!("-C {}", parser::quote(&generator.generator)));
+    }
+    
+    #[test]
+    fn generators_obey_the_policy() {
+        assert!(GeneratorCompleter::new(executor(&["printf"]), "svc", "printf 'a\\n'").complete(&[], "").is_none());
+        assert!(GeneratorCompleter::new(executor(&["sh"]), "svc", "echo a").complete(&[], "").is_none());
+        assert!(GeneratorCompleter::new(executor(&["printf"]), "svc", "echo a").complete(&[], "").is_some());
+    }
+    
+    #[test]
+    fn hung_generators_are_stopped() {
+        let start = Instant::now();
+        assert!(GeneratorCompleter::new(executor(&[]), "svc", "f() { sleep 30; }; f").complete(&[], "").is_none());
+        assert!(start.elapsed() < GENERATOR_TIMEOUT + Duration::from_secs(1));
+    }
+}
@@ -0,0 +1,873 @@
+use super::*;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+/// System-wide policy file. When it exists, its command lists replace the
+/// user's and cannot be overridden from the user configuration.
+pub const SYSTEM_POLICY_PATH: &str = "/etc/obsidian-shell/policy.toml";
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+pub struct ShellConfig {
+    /// Interpret natural language input with the AI
+    pub ai_enabled: bool,
+    /// Start the terminal GUI instead of the line editor
+    pub gui_enabled: bool,
+    /// File the command history is kept in
+    pub history_path: String,
+    /// Save lines to history with their aliases expanded, so they still work
+    /// where the aliases aren't defined
+    #[serde(default)]
+    pub history_expand_aliases: bool,
+    /// History file format. 2 stores when each command ran and keeps
+    /// multi-line commands whole; 1 is one command per line, for tools that
+    /// read the file. A format 1 file is upgraded to 2 when loaded, keeping
+    /// the original as `<history_path>.v1.bak`; a file already in format 2
+    /// stays that way.
+    #[serde(default = "default_history_format")]
+    #[schemars(range(min = 1, max = 2))]
+    pub history_format: u32,
+    /// Mask common secrets, like `Authorization` headers and `*_TOKEN=`
+    /// values, before lines are saved to history, recordings, the AI context
+    /// file and logs
+    #[serde(default = "default_redact_defaults")]
+    pub redact_defaults: bool,
+    /// Extra regexes for secrets to mask; a `secret` group limits the mask to
+    /// that part of the match
+    #[serde(default)]
+    pub redact_patterns: Vec<String>,
+    /// The AI backend
+    pub ai_config: ai::AIConfig,
+    /// Other backends for `interpret --compare`, e.g. `[ai_backends.ollama]`,
+    /// each holding `ai_config` settings merged over it. The main `api_key`
+    /// is not passed on; a backend that needs one sets its own.
+    #[serde(default)]
+    #[schemars(with = "BTreeMap<String, serde_json::Map<String, serde_json::Value>>")]
+    pub ai_backends: BTreeMap<String, toml::Table>,
+    /// Recording `good`/`bad` verdicts on AI interpretations
+    #[serde(default)]
+    pub ai_feedback: feedback::FeedbackConfig,
+    /// Load `.env` from the current directory at startup and after `cd`
+    #[serde(default)]
+    pub load_dotenv: bool,
+    /// Also load `~/.env` at startup, before the current directory's
+    #[serde(default)]
+    pub load_home_dotenv: bool,
+    /// Show output taller than the terminal through `$PAGER`
+    #[serde(default)]
+    pub pager_enabled: bool,
+    /// Right-aligned prompt text, e.g. `"{status} {duration} {git_branch}"`.
+    /// Empty disables it. See `prompt::render` for the placeholders.
+    #[serde(default)]
+    pub rprompt_format: String,
+    /// Compact prompt that replaces the full one, right prompt included, once
+    /// a line is entered, keeping scrollback short. Takes the same placeholders
+    /// as `rprompt_format`; empty keeps the full prompt.
+    #[serde(default)]
+    pub transient_prompt: String,
+    /// Named prompt segments, each used as `{name}` in a prompt format, e.g.
+    /// `git = " on {git_branch}"`. A segment is left out entirely, decoration
+    /// and all, when any placeholder in it expands to nothing.
+    #[serde(default)]
+    pub prompt_segments: BTreeMap<String, String>,
+    /// Milliseconds a slow prompt component such as `{git_branch}` is reused
+    /// before being computed again; 0 computes it for every prompt
+    #[serde(default = "default_prompt_cache_ms")]
+    pub prompt_cache_ms: u64,
+    /// Suppress the banner and AI notices, leaving command output and errors
+    #[serde(default)]
+    pub quiet: bool,
+    /// Welcome text shown instead of the default banner, or the path of a file
+    /// holding it. `{user}`, `{time}` and the other prompt placeholders are
+    /// expanded. `""` shows nothing.
+    #[serde(default)]
+    pub motd: Option<String>,
+    /// How the working directory is shown in the prompt
+    #[serde(default)]
+    pub prompt_dir: PromptDir,
+    /// What to do with an AI interpretation at the interactive prompt
+    #[serde(default)]
+    pub ai_apply: AiApply,
+    /// Let an AI interpretation chain several commands with `;`, `&&` or `||`.
+    /// Unless the input asked for it, the chain must still be confirmed.
+    #[serde(default)]
+    pub ai_allow_chaining: bool,
+    /// What runs when AI interpretation fails
+    #[serde(default)]
+    pub ai_fallback: AiFallback,
+    /// Seconds to wait for a y/N confirmation before answering no; 0 waits forever
+    #[serde(default = "default_confirm_timeout_secs")]
+    pub confirm_timeout_secs: u64,
+    /// Seconds without input at the prompt before the session ends or locks; 0 disables
+    #[serde(default)]
+    pub idle_timeout_secs: u64,
+    /// What happens when `idle_timeout_secs` elapses
+    #[serde(default)]
+    pub idle_action: IdleAction,
+    /// Command that must succeed to unlock after an idle timeout
+    #[serde(default = "default_idle_lock_command")]
+    pub idle_lock_command: String,
+    /// Mark prompts, input and output with OSC 133 escapes, which terminals
+    /// such as iTerm2, WezTerm, kitty and VS Code use to jump between commands
+    #[serde(default)]
+    pub shell_integration: bool,
+    /// When the shell colours its output: `auto` on a terminal unless
+    /// `NO_COLOR` is set, `always` or `never`. `--color` overrides it.
+    #[serde(default)]
+    pub color: ui::ColorChoice,
+    /// Set the terminal title to the running command, and back to
+    /// `obsidian: <dir>` at the prompt. Off by default, as terminals that
+    /// don't understand the OSC 0 escape can't be detected.
+    #[serde(default)]
+    pub terminal_title: bool,
+    /// Key map of the line editor
+    #[serde(default)]
+    pub edit_mode: EditMode,
+    /// Extra keys for a few editor actions
+    #[serde(default)]
+    pub keybindings: Keybindings,
+    /// Aliases defined at startup, e.g. `ll = "ls -la"`
+    #[serde(default)]
+    pub aliases: BTreeMap<String, String>,
+    /// Typos fixed in the command word before anything else looks at it, e.g.
+    /// `gti = "git"`; the fix is printed. Setting the table replaces the
+    /// defaults, and `nocorrect <command>` skips it.
+    #[serde(default = "default_corrections")]
+    pub corrections: BTreeMap<String, String>,
+    /// Builtins to turn off, so a program of the same name on `PATH` runs
+    /// instead, e.g. `["clear"]`. Listing a word that makes the AI read a
+    /// line, such as `find`, stops it doing so. A command word is looked up
+    /// as an alias first, then as a builtin, then on `PATH`.
+    #[serde(default)]
+    pub disabled_builtins: Vec<String>,
+    /// Commands that only work inside the shell process, such as `export`
+    /// and `umask`. Without a builtin of the same name they are refused with
+    /// an explanation, since as a separate program they would change nothing.
+    #[serde(default = "default_shell_only_commands")]
+    pub shell_only_commands: Vec<String>,
+    /// Shell that `!! <command>` lines are handed to as typed, for syntax
+    /// this shell doesn't parse. Defaults to `$SHELL`, or /bin/sh when that
+    /// is unset or is this shell.
+    #[serde(default)]
+    pub fallback_shell: Option<String>,
+    /// Most bytes of a command's stdout and stderr kept in `$_OUT` and
+    /// `$_ERR`; longer output is cut at the last line break before the limit
+    #[serde(default = "default_output_variable_limit")]
+    pub output_variable_limit: usize,
+    /// Most bytes kept of each output stream of a command whose output is
+    /// shown as it runs, half from the start and half from the end. What is
+    /// kept goes to `$_OUT`, session recordings and from there to the AI.
+    #[serde(default = "default_stream_capture_limit")]
+    pub stream_capture_limit: usize,
+    /// Encoding of program output that isn't valid UTF-8, such as `"latin1"`
+    /// or `"shift_jis"`, converted to UTF-8 before it is shown or captured.
+    /// `"locale"` takes it from `LC_ALL`, `LC_CTYPE` or `LANG`. Unset, such
+    /// output is read as UTF-8 with invalid bytes replaced.
+    #[serde(default)]
+    pub output_encoding: Option<String>,
+    /// How deeply aliases may expand into other aliases, and builtins such
+    /// as `time` and `on-change` run other commands, before the line fails
+    /// with an error
+    #[serde(default = "default_max_expansion_depth")]
+    #[schemars(range(min = 1))]
+    pub max_expansion_depth: usize,
+    /// Most jobs started with `&` that run at once; later ones wait for one
+    /// to finish. Defaults to the number of CPUs.
+    #[serde(default = "default_max_background_jobs")]
+    #[schemars(range(min = 1))]
+    pub max_background_jobs: usize,
+    /// Character that starts a comment running to the end of the line, as
+    /// `#` does in sh: at the start of a word and outside quotes. Empty turns
+    /// comments off, for example so `#` can be typed in AI requests.
+    #[serde(default = "default_comment_char")]
+    #[schemars(length(max = 1))]
+    pub comment_char: String,
+    /// Make `rm` move files to the trash instead of deleting them;
+    /// `rm --permanent` still deletes
+    #[serde(default)]
+    pub trash_rm: bool,
+    /// Trash directory; defaults to the desktop's, `~/.local/share/Trash`
+    #[serde(default)]
+    pub trash_dir: Option<PathBuf>,
+    /// Run when a program can't be found, with its name added as the last
+    /// argument; a builtin or a command, e.g. `/usr/lib/command-not-found`.
+    /// Set it to `suggest-package` to use `package_map`.
+    #[serde(default)]
+    pub command_not_found: Option<String>,
+    /// TOML file mapping programs to the packages that provide them, e.g.
+    /// `rg = "ripgrep"`, for the `suggest-package` builtin
+    #[serde(default)]
+    pub package_map: Option<PathBuf>,
+    /// Restricted environment external commands run in
+    #[serde(default)]
+    pub sandbox: SandboxConfig,
+    /// Hosts for `@host command`
+    #[serde(default)]
+    pub remote: RemoteConfig,
+    #[serde(flatten)]
+    pub command_policy: CommandPolicy,
+    /// Settings for one OS, e.g. `[os.macos]`, merged over the rest of the
+    /// file. Names are Rust's: `linux`, `macos`, `windows`, `freebsd` and so
+    /// on, or `unix` for all Unix-like systems, which applies first.
+    #[serde(default)]
+    #[schemars(with = "BTreeMap<String, serde_json::Map<String, serde_json::Value>>")]
+    pub os: BTreeMap<String, toml::Table>,
+    /// Settings for one machine, e.g. `[host.myserver]`, merged over the rest
+    /// of the file and `os` sections. Names match the hostname ignoring case;
+    /// the part before the first dot works too, and the full name wins.
+    #[serde(default)]
+    #[schemars(with = "BTreeMap<String, serde_json::Map<String, serde_json::Value>>")]
+    pub host: BTreeMap<String, toml::Table>,
+    /// Set when the command policy came from `SYSTEM_POLICY_PATH`
+    #[serde(skip)]
+    pub policy_locked: bool,
+}
+
+fn default_history_format() -> u32 {
+    history::FORMAT_VERSION
+}
+
+fn default_shell_only_commands() -> Vec<String> {
+    [
+        "export", "unset", "set", "readonly", "declare", "typeset", "local", "umask", "ulimit",
+        "shopt", "trap", "hash", "shift", "bg", "fg", "jobs", "wait", "disown", "cd", "source", ".",
+    ]
+    .into_iter()
+    .map(String::from)
+    .collect()
+}
+
+fn default_corrections() -> BTreeMap<String, String> {
+    [("sl", "ls"), ("gti", "git"), ("cd..", "cd .."), ("grpe", "grep"), ("claer", "clear")]
+        .into_iter()
+        .map(|(typo, fix)| (typo.to_string(), fix.to_string()))
+        .collect()
+}
+
+/// The character set of the locale, as in `de_DE.ISO-8859-1@euro`
+fn locale_encoding() -> Option<&'static encoding_rs::Encoding> {
+    let locale = ["LC_ALL", "LC_CTYPE", "LANG"]
+        .into_iter()
+        .find_map(|name| env::var(name).ok().filter(|value| !value.is_empty()))?;
+    let charset = locale.split_once('.')?.1;
+    let charset = charset.split_once('@').map_or(charset, |(charset, _)| charset);
+    encoding_rs::Encoding::for_label(charset.as_bytes())
+}
+
+fn default_comment_char() -> String {
+    "#".to_string()
+}
+
+fn default_max_expansion_depth() -> usize {
+    32
+}
+
+fn default_max_background_jobs() -> usize {
+    std::thread::available_parallelism().map_or(4, usize::from)
+}
+
+fn default_output_variable_limit() -> usize {
+    64 * 1024
+}
+
+fn default_stream_capture_limit() -> usize {
+    256 * 1024
+}
+
+fn default_redact_defaults() -> bool {
+    true
+}
+
+fn default_confirm_timeout_secs() -> u64 {
+    30
+}
+
+fn default_prompt_cache_ms() -> u64 {
+    1000
+}
+
+fn default_idle_lock_command() -> String {
+    // Asks for the user's password even if sudo has it cached
+    "sudo -k -v".to_string()
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum AiApply {
+    /// Run the interpreted command right away
+    #[default]
+    Auto,
+    /// Show the interpreted command and ask before running it
+    Confirm,
+    /// Put the interpreted command on the input line for editing
+    Edit,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum AiFallback {
+    /// Run the guess of the built-in keyword rules, or the input itself when
+    /// none of them match
+    #[default]
+    OfflineRules,
+    /// Run the input as typed
+    ExecuteLiteral,
+    /// Run nothing
+    Abort,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum EditMode {
+    #[default]
+    Emacs,
+    Vi,
+}
+
+/// Keys bound to editor actions, on top of their defaults, which stay bound:
+///
+/// ```toml
+/// [keybindings]
+/// accept-suggestion = "ctrl-f"  # default: → at the end of the line
+/// history-search = "ctrl-s"     # default: Ctrl-R
+/// clear-screen = "alt-l"        # default: Ctrl-L
+/// palette = "ctrl-o"            # default: F1
+/// ```
+///
+/// Keys are written like `ctrl-r`, `alt-f`, `f2` or `ctrl-up`; anything else
+/// is rejected when the configuration loads.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct Keybindings {
+    /// Take the grey suggestion from history into the line
+    #[serde(default)]
+    pub accept_suggestion: Option<editor::KeySpec>,
+    /// Search history backwards for what is typed next
+    #[serde(default)]
+    pub history_search: Option<editor::KeySpec>,
+    /// Clear the screen, keeping the line being typed
+    #[serde(default)]
+    pub clear_screen: Option<editor::KeySpec>,
+    /// Open the full-screen fuzzy finder over history, e.g. `ctrl-r` to use
+    /// it instead of reverse search; it falls back to reverse search in a
+    /// small terminal
+    #[serde(default)]
+    pub history_finder: Option<editor::KeySpec>,
+    /// Open the command palette, a searchable list of builtins, common
+    /// actions and recent commands; default: F1
+    #[serde(default)]
+    pub palette: Option<editor::KeySpec>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum IdleAction {
+    /// End the session
+    #[default]
+    Exit,
+    /// Clear the screen and run `idle_lock_command`; the session ends if it fails
+    Lock,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum PromptDir {
+    /// Path with the home directory shortened to `~`
+    #[default]
+    Home,
+    /// Full absolute path
+    Full,
+    /// Last path component only
+    Name,
+}
+
+impl ShellConfig {
+    /// Each of `ai_backends` by name, as full settings
+    pub fn ai_backends(&self) -> Vec<(String, Result<ai::AIConfig>)> {
+        self.ai_backends
+            .iter()
+            .map(|(name, overrides)| {
+                let config = toml::Table::try_from(&self.ai_config)
+                    .map_err(anyhow::Error::from)
+                    .and_then(|mut table| {
+                        merge_table(&mut table, overrides);
+                        toml::Value::Table(table)
+                            .try_into()
+                            .with_context(|| format!("Invalid settings in [ai_backends.{}]", name))
+                    });
+                (name.clone(), config)
+            })
+            .collect()
+    }
+    
+    /// How long confirmations wait for an answer
+    pub fn confirm_timeout(&self) -> Option<Duration> {
+        match self.confirm_timeout_secs {
+            0 => None,
+            secs => Some(Duration::from_secs(secs)),
+        }
+    }
+    
+    /// The encoding named by `output_encoding`, UTF-8 when it is unset or
+    /// the locale doesn't name another
+    pub fn output_encoding(&self) -> Result<&'static encoding_rs::Encoding> {
+        let label = match self.output_encoding.as_deref() {
+            None => return Ok(encoding_rs::UTF_8),
+            Some("locale") => return Ok(locale_encoding().unwrap_or(encoding_rs::UTF_8)),
+            Some(label) => label,
+        };
+        encoding_rs::Encoding::for_label(label.as_bytes())
+            .with_context(|| format!("Unknown output_encoding '{}'", label))
+    }
+    
+    /// How long prompt components are reused
+    pub fn prompt_cache_ttl(&self) -> Duration {
+        Duration::from_millis(self.prompt_cache_ms)
+    }
+    
+    /// How long the prompt waits for input before `idle_action`
+    pub fn idle_timeout(&self) -> Option<Duration> {
+        match self.idle_timeout_secs {
+            0 => None,
+            secs => Some(Duration::from_secs(secs)),
+        }
+    }
+    
+    #[tracing::instrument(name = "config_load")]
+    pub fn load(path: &str) -> Result<Self> {
+        // Default configuration
+        let mut config = ShellConfig {
+            ai_enabled: true,
+            gui_enabled: false,
+            history_path: "~/.obsidian-shell-history".to_string(),
+            history_expand_aliases: false,
+            history_format: default_history_format(),
+            redact_defaults: default_redact_defaults(),
+            redact_patterns: Vec::new(),
+            ai_config: ai::AIConfig {
+                model_path: "/usr/share/obsidian/models/llm.onnx".to_string(),
+                api_endpoint: "http://localhost:8000/ai".to_string(),
+                max_tokens: 512,
+                temperature: 0.7,
+                persist_context: true,
+                requests_per_minute: 60,
+                rate_limit_mode: ai::RateLimitMode::Queue,
+                interpret_prompt_template: ai::DEFAULT_INTERPRET_PROMPT.to_string(),
+                structured_response: ai::default_structured_response(),
+                explain_prompt_template: ai::DEFAULT_EXPLAIN_PROMPT.to_string(),
+                models_manifest: String::new(),
+                models_dir: ai::default_models_dir(),
+                prompt_context: aicontext::ContextConfig::default(),
+                max_input_tokens: ai::default_max_input_tokens(),
+                api_key: None,
+                key_file: None,
+            },
+            ai_backends: BTreeMap::new(),
+            load_dotenv: false,
+            load_home_dotenv: false,
+            pager_enabled: false,
+            rprompt_format: String::new(),
+            transient_prompt: String::new(),
+            prompt_segments: BTreeMap::new(),
+            prompt_cache_ms: default_prompt_cache_ms(),
+            quiet: false,
+            motd: None,
+            prompt_dir: PromptDir::Home,
+            ai_apply: AiApply::Auto,
+            ai_allow_chaining: false,
+            ai_fallback: AiFallback::OfflineRules,
+            confirm_timeout_secs: default_confirm_timeout_secs(),
+            idle_timeout_secs: 0,
+            idle_action: IdleAction::Exit,
+            idle_lock_command: default_idle_lock_command(),
+            shell_integration: false,
+            color: ui::ColorChoice::Auto,
+            terminal_title: false,
+            edit_mode: EditMode::Emacs,
+            keybindings: Keybindings::default(),
+            aliases: BTreeMap::new(),
+            corrections: default_corrections(),
+            disabled_builtins: Vec::new(),
+            shell_only_commands: default_shell_only_commands(),
+            fallback_shell: None,
+            output_variable_limit: default_output_variable_limit(),
+            stream_capture_limit: default_stream_capture_limit(),
+            output_encoding: None,
+            max_expansion_depth: default_max_expansion_depth(),
+            max_background_jobs: default_max_background_jobs(),
+            comment_char: default_comment_char(),
+            trash_rm: false,
+            trash_dir: None,
+            command_not_found: None,
+            package_map: None,
+            sandbox: SandboxConfig::default(),
+            ai_feedback: feedback::FeedbackConfig::default(),
+            remote: RemoteConfig::default(),
+            command_policy: CommandPolicy::default(),
+            os: BTreeMap::new(),
+            host: BTreeMap::new(),
+            policy_locked: false,
+        };
+        
+        // Try to load from file if it exists
+        let mut table = match std::fs::read_to_string(path) {
+            Ok(contents) => {
+                let mut table: toml::Table = toml::from_str(&contents)
+                    .context("Failed to parse configuration file")?;
+                apply_conditional_sections(&mut table);
+                tracing::info!("loaded configuration file");
+                Some(table)
+            }
+            Err(e) => {
+                tracing::info!(error = %e, "no configuration file; using defaults");
+                None
+            }
+        };
+        
+        // Secrets go over everything else
+        let key_file = table
+            .as_ref()
+            .and_then(|table| table.get("ai_config")?.get("key_file")?.as_str())
+            .map(expand_directory);
+        let explicit = key_file.is_some();
+        let key_file = key_file.unwrap_or_else(|| expand_directory(path).with_file_name("secrets.toml"));
+        if let Some(secrets) = load_secrets(&key_file, explicit)? {
+            let table = match &mut table {
+                Some(table) => table,
+                None => table.insert(toml::Table::try_from(&config)?),
+            };
+            merge_table(table, &secrets);
+        }
+        
+        if let Some(table) = table {
+            config = toml::Value::Table(table)
+                .try_into()
+                .context("Failed to parse configuration file")?;
+        }
+        
+        config.apply_system_policy(Path::new(SYSTEM_POLICY_PATH))?;
+        
+        Ok(config)
+    }
+    
+    /// Replace the command policy with the system one, if it exists
+    fn apply_system_policy(&mut self, path: &Path) -> Result<()> {
+        let contents = match std::fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => {
+                return Err(e).with_context(|| {
+                    format!("Failed to read system policy {}", path.display())
+                })
+            }
+        };
+        
+        self.command_policy = toml::from_str(&contents)
+            .with_context(|| format!("Failed to parse system policy {}", path.display()))?;
+        self.policy_locked = true;
+        tracing::info!(path = %path.display(), "applied system command policy");
+        
+        Ok(())
+    }
+}
+
+/// Merge the `[os.*]` and then the `[host.*]` sections that match this
+/// machine over the rest of `table`, least specific first
+fn apply_conditional_sections(table: &mut toml::Table) {
+    let os = vec![std::env::consts::FAMILY.to_string(), std::env::consts::OS.to_string()];
+    let mut host = Vec::new();
+    if let Some(hostname) = hostname() {
+        if let Some((short, _)) = hostname.split_once('.') {
+            host.push(short.to_string());
+        }
+        host.push(hostname);
+    }
+    
+    for (kind, names) in [("os", os), ("host", host)] {
+        let Some(toml::Value::Table(sections)) = table.get(kind).cloned() else {
+            continue;
+        };
+        for name in &names {
+            let matching = sections.iter().filter(|(section, _)| section.eq_ignore_ascii_case(name));
+            for (section, overrides) in matching {
+                if let toml::Value::Table(overrides) = overrides {
+                    tracing::info!(section = %format!("{}.{}", kind, section), "applied conditional configuration");
+                    merge_table(table, overrides);
+                }
+            }
+        }
+    }
+}
+
+/// The secrets in `path` as configuration to merge, or `None` if there is
+/// no such file and it wasn't named `explicitly`. A `.toml` file has the
+/// shape of the configuration; any other file holds just the API key.
+fn load_secrets(path: &Path, explicitly: bool) -> Result<Option<toml::Table>> {
+    let contents = match std::fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound && !explicitly => return Ok(None),
+        Err(e) => return Err(e).with_context(|| format!("Failed to read secrets {}", path.display())),
+    };
+    warn_if_readable(path);
+    tracing::info!(path = %path.display(), "loaded secrets");
+    
+    if path.extension().is_some_and(|extension| extension == "toml") {
+        let secrets = toml::from_str(&contents)
+            .with_context(|| format!("Failed to parse secrets {}", path.display()))?;
+        return Ok(Some(secrets));
+    }
+    let key = contents.lines().next().unwrap_or_default().trim();
+    anyhow::ensure!(!key.is_empty(), "No API key in {}", path.display());
+    let mut ai_config = toml::Table::new();
+    ai_config.insert("api_key".to_string(), toml::Value::String(key.to_string()));
+    let mut secrets = toml::Table::new();
+    secrets.insert("ai_config".to_string(), toml::Value::Table(ai_config));
+    Ok(Some(secrets))
+}
+
+/// Warn when other users can read the secrets in `path`
+#[cfg(unix)]
+fn warn_if_readable(path: &Path) {
+    use std::os::unix::fs::PermissionsExt;
+    
+    let Ok(metadata) = std::fs::metadata(path) else {
+        return;
+    };
+    if metadata.permissions().mode() & 0o004 != 0 {
+        eprintln!(
+            "⚠️  {} is readable by every user; restrict it with chmod 600 {}",
+            path.display(),
+            path.display()
+        );
+    }
+}
+
+#[cfg(not(unix))]
+fn warn_if_readable(_path: &Path) {}
+
+/// Overwrite `base` with `overrides`, merging tables key by key
+fn merge_table(base: &mut toml::Table, overrides: &toml::Table) {
+    for (key, value) in overrides {
+        match (base.get_mut(key), value) {
+            (Some(toml::Value::Table(base)), toml::Value::Table(value)) => merge_table(base, value),
+            _ => {
+                base.insert(key.clone(), value.clone());
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+fn hostname() -> Option<String> {
+    nix::unistd::gethostname().ok()?.into_string().ok()
+}
+
+#[cfg(not(unix))]
+fn hostname() -> Option<String> {
+    env::var("COMPUTERNAME").ok()
+}
+
+/// Directory for session state such as saved AI conversations.
+///
+/// `$XDG_STATE_HOME/obsidian-shell`, falling back to `~/.local/state/obsidian-shell`.
+pub fn state_dir() -> PathBuf {
+    let base = env::var_os("XDG_STATE_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".local/state")))
+        .unwrap_or_else(env::temp_dir);
+    
+    base.join("obsidian-shell")
+}
+
+/// Directory for data that can be rebuilt, such as looked-up help.
+///
+/// `$XDG_CACHE_HOME/obsidian-shell`, falling back to `~/.cache/obsidian-shell`.
+pub fn cache_dir() -> PathBuf {
+    let base = env::var_os("XDG_CACHE_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".cache")))
+        .unwrap_or_else(env::temp_dir);
+    
+    base.join("obsidian-shell")
+}
+
+/// Which programs the shell is allowed to run.
+///
+/// Entries are either bare program names (`rm`) or absolute paths (`/usr/bin/rm`).
+/// An empty `allowed_commands` list allows everything not explicitly blocked.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, JsonSchema)]
+pub struct CommandPolicy {
+    /// Programs that may run; empty allows all but the blocked ones
+    #[serde(default)]
+    pub allowed_commands: Vec<String>,
+    /// Programs that may never run
+    #[serde(default)]
+    pub blocked_commands: Vec<String>,
+}
+
+/// How external commands are confined. Everything is off by default.
+///
+/// ```toml
+/// [sandbox]
+/// wrapper = ["unshare", "--user", "--net", "--"]
+/// env_allowlist = ["PATH", "HOME", "TERM", "LANG"]
+/// working_dir = "/srv/jail"
+/// ```
+#[derive(Debug, Clone, Default, Serialize, Deserialize, JsonSchema)]
+pub struct SandboxConfig {
+    /// Command and arguments put in front of every external command, e.g. an
+    /// `unshare`, `nsjail` or `sudo -u` invocation
+    #[serde(default)]
+    pub wrapper: Vec<String>,
+    /// When set, commands start with an empty environment except these
+    /// variables; `NAME=value` prefixes on the command line still apply
+    #[serde(default)]
+    pub env_allowlist: Option<Vec<String>>,
+    /// Directory commands run in, instead of the shell's current directory
+    #[serde(default)]
+    pub working_dir: Option<PathBuf>,
+}
+
+impl SandboxConfig {
+    pub fn is_active(&self) -> bool {
+        !self.wrapper.is_empty() || self.env_allowlist.is_some() || self.working_dir.is_some()
+    }
+}
+
+/// How `@host command` reaches other machines.
+///
+/// ```toml
+/// [remote]
+/// ssh_command = ["ssh", "-o", "BatchMode=yes"]
+/// [remote.hosts]
+/// prod = "deploy@prod.example.com"
+/// ```
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct RemoteConfig {
+    /// ssh invocation; the destination and the command are appended
+    #[serde(default = "default_ssh_command")]
+    pub ssh_command: Vec<String>,
+    /// Short names for SSH destinations. Other names go to ssh as written, so
+    /// hosts from `~/.ssh/config` work too, with its users, ports and keys.
+    #[serde(default)]
+    pub hosts: BTreeMap<String, String>,
+}
+
+impl Default for RemoteConfig {
+    fn default() -> Self {
+        RemoteConfig {
+            ssh_command: default_ssh_command(),
+            hosts: BTreeMap::new(),
+        }
+    }
+}
+
+impl RemoteConfig {
+    /// SSH destination for `host`
+    pub fn destination<'a>(&'a self, host: &'a str) -> &'a str {
+        self.hosts.get(host).map_or(host, String::as_str)
+    }
+}
+
+fn default_ssh_command() -> Vec<String> {
+    vec!["ssh".to_string()]
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum PolicyError {
+    #[error("'{0}' is blocked by the command policy")]
+    Blocked(String),
+    #[error("'{0}' is not in the list of allowed commands")]
+    NotAllowed(String),
+}
+
+impl CommandPolicy {
+    /// Check a program name against the allow and block lists
+    pub fn check(&self, program: &str) -> std::result::Result<(), PolicyError> {
+        let name = Path::new(program)
+            .file_name()
+            .and_then(|name| name.to_str())
+            .unwrap_or(program);
+        let resolved = which::which(program).ok();
+        
+        let matches = |entry: &String| {
+            entry == name || entry == program || resolved.as_deref().is_some_and(|path| Path::new(entry) == path)
+        };
+        
+        if self.blocked_commands.iter().any(matches) {
+            return Err(PolicyError::Blocked(name.to_string()));
+        }
+        
+        if !self.allowed_commands.is_empty() && !self.allowed_commands.iter().any(matches) {
+            return Err(PolicyError::NotAllowed(name.to_string()));
+        }
+        
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    fn policy(allowed: &[&str], blocked: &[&str]) -> CommandPolicy {
+        CommandPolicy {
+            allowed_commands: allowed.iter().map(|name| name.to_string()).collect(),
+            blocked_commands: blocked.iter().map(|name| name.to_string()).collect(),
+        }
+    }
+    
+    #[test]
+    fn empty_policy_allows_everything() {
+        assert!(policy(&[], &[]).check("ls").is_ok());
+    }
+    
+    #[test]
+    fn blocked_wins_over_allowed() {
+        let policy = policy(&["rm", "ls"], &["rm"]);
+        assert!(matches!(policy.check("rm"), Err(PolicyError::Blocked(name)) if name == "rm"));
+        assert!(policy.check("ls").is_ok());
+    }
+    
+    #[test]
+    fn allowlist_refuses_the_rest() {
+        let policy = policy(&["ls"], &[]);
+        assert!(matches!(policy.check("cat"), Err(PolicyError::NotAllowed(name)) if name == "cat"));
+    }
+    
+    #[test]
+    fn paths_match_by_basename() {
+        let policy = policy(&[], &["rm"]);
+        assert!(matches!(policy.check("/bin/rm"), Err(PolicyError::Blocked(name)) if name == "rm"));
+        assert!(policy.check("/bin/rmdir").is_ok());
+    }
+    
+    #[test]
+    fn names_match_entries_by_resolved_path() {
+        let sh = which::which("sh").unwrap();
+        let policy = policy(&[], &[sh.to_str().unwrap()]);
+        assert!(policy.check("sh").is_err());
+    }
+    
+    #[test]
+    fn system_policy_replaces_and_locks() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("policy.toml");
+        std::fs::write(&path, "blocked_commands = [\"curl\"]\n").unwrap();
+        let defaults = || ShellConfig::load(dir.path().join("config.toml").to_str().unwrap()).unwrap();
+        
+        let mut config = defaults();
+        config.command_policy = policy(&[], &["ls"]);
+        config.apply_system_policy(&path).unwrap();
+        assert!(config.policy_locked);
+        assert!(config.command_policy.check("ls").is_ok());
+        assert!(config.command_policy.check("curl").is_err());
+        
+        let mut config = defaults();
+        config.apply_system_policy(&dir.path().join("absent.toml")).unwrap();
+        assert!(!config.policy_locked);
+    }
+}
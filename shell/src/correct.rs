@@ -0,0 +1,38 @@
+//! Fixes for frequent typos, such as `sl` for `ls`, from the `corrections`
+//! table. Unlike the `command_not_found` handler's suggestions this is an
+//! exact lookup of the command word: it happens before aliases, builtins
+//! and `PATH` are consulted, and the corrected command runs straight away.
+
+use super::*;
+use parser::Connector;
+use std::collections::BTreeMap;
+
+/// `chain` with the command word of each command replaced from `table`,
+/// adding `from → to` to `applied` for each one fixed
+pub fn apply(
+    table: &BTreeMap<String, String>,
+    chain: Vec<(Connector, String)>,
+    applied: &mut Vec<String>,
+) -> Vec<(Connector, String)> {
+    chain
+        .into_iter()
+        .map(|(connector, command)| {
+            let word = command.split_whitespace().next().unwrap_or_default();
+            let Some(fix) = table.get(word) else {
+                return (connector, command);
+            };
+            applied.push(format!("{} → {}", word, fix));
+            let start = command.len() - command.trim_start().len();
+            let corrected = format!("{}{}", fix, &command[start + word.len()..]);
+            (connector, corrected)
+        })
+        .collect()
+}
+
+/// Split off a leading `nocorrect `, which skips the table for one command
+pub fn strip_prefix(input: &str) -> (bool, &str) {
+    match input.trim().strip_prefix("nocorrect ") {
+        Some(rest) => (true, rest.trim_start()),
+        None => (false, input.trim()),
+    }
+}
@@ -0,0 +1,80 @@
+use super::*;
+use std::fs;
+
+/// Parse the contents of a `.env` file into `(name, value)` pairs.
+///
+/// Supports `# comments`, an optional `export ` prefix, single-quoted values
+/// (taken literally), double-quoted values (with `\n`, `\t`, `\"` and `\\`
+/// escapes) and unquoted values, where a ` #` starts a trailing comment.
+pub fn parse(contents: &str) -> Result<Vec<(String, String)>> {
+    let mut vars = Vec::new();
+    
+    for (i, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        
+        let line = line.strip_prefix("export ").map_or(line, str::trim_start);
+        let (name, value) = line
+            .split_once('=')
+            .ok_or_else(|| anyhow::anyhow!("line {}: expected NAME=value", i + 1))?;
+        
+        let name = name.trim();
+        if !commands::is_valid_name(name) {
+            anyhow::bail!("line {}: invalid variable name '{}'", i + 1, name);
+        }
+        
+        let value = parse_value(value.trim())
+            .ok_or_else(|| anyhow::anyhow!("line {}: unterminated quote", i + 1))?;
+        vars.push((name.to_string(), value));
+    }
+    
+    Ok(vars)
+}
+
+/// Load a `.env` file into the shell's environment, returning the names set
+pub fn load(path: &Path) -> Result<Vec<String>> {
+    let contents = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+    let vars = parse(&contents).with_context(|| format!("Invalid {}", path.display()))?;
+    
+    Ok(vars
+        .into_iter()
+        .map(|(name, value)| {
+            env::set_var(&name, value);
+            name
+        })
+        .collect())
+}
+
+fn parse_value(raw: &str) -> Option<String> {
+    if let Some(rest) = raw.strip_prefix('\'') {
+        let end = rest.find('\'')?;
+        return Some(rest[..end].to_string());
+    }
+    
+    if let Some(rest) = raw.strip_prefix('"') {
+        let mut value = String::new();
+        let mut chars = rest.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '"' => return Some(value),
+                '\\' => match chars.next()? {
+                    'n' => value.push('\n'),
+                    't' => value.push('\t'),
+                    other => value.push(other),
+                },
+                c => value.push(c),
+            }
+        }
+        return None;
+    }
+    
+    // Unquoted: a `#` preceded by whitespace starts a comment
+    let value = match raw.find(" #").or_else(|| raw.find("\t#")) {
+        Some(index) => &raw[..index],
+        None => raw,
+    };
+    Some(value.trim().to_string())
+}
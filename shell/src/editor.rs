@@ -0,0 +1,614 @@
+use super::*;
+use completion::CommandCompletion;
+use config::{EditMode, ShellConfig};
+use prompt::display_width;
+use crossterm::event::{self, Event, KeyCode as TermKeyCode, KeyEventKind};
+use crossterm::terminal;
+use rustyline::completion::Completer;
+use rustyline::error::ReadlineError;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::{Hinter, HistoryHinter};
+use rustyline::history::DefaultHistory;
+use rustyline::validate::Validator;
+use rustyline::{Cmd, Context, Editor, Helper, KeyCode, KeyEvent, Modifiers};
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::io::IsTerminal;
+use std::sync::{Arc, Mutex};
+
+/// Outcome of reading one line
+pub enum Input {
+    Line(String),
+    /// The line was discarded with Ctrl-C or Esc
+    Cancelled,
+    /// End of input (Ctrl-D or a closed stdin)
+    Eof,
+    /// No key was pressed within the idle timeout
+    Idle,
+}
+
+/// OSC 133 shell integration marks
+const PROMPT_START: &str = "\x1b]133;A\x07";
+const PROMPT_END: &str = "\x1b]133;B\x07";
+const OUTPUT_START: &str = "\x1b]133;C\x07";
+
+/// Longest terminal title set, in characters
+const MAX_TITLE_LEN: usize = 80;
+
+/// Longest history suggestion shown. Every key redraws the suggestion, so a
+/// pasted multi-kilobyte command would otherwise fill the screen on each one.
+const MAX_HINT_LEN: usize = 1024;
+
+/// How long before an idle timeout the warning is shown
+const IDLE_WARNING: Duration = Duration::from_secs(10);
+
+/// Interactive line editor with history, tab completion and a right prompt
+pub struct LineEditor {
+    editor: Editor<ShellHelper, DefaultHistory>,
+    /// Emit OSC 133 shell integration marks
+    marks: bool,
+    /// Set the terminal title with `set_title`
+    titles: bool,
+    /// The history again, for the fuzzy finder; rustyline's own isn't
+    /// reachable from a key handler
+    entries: Arc<Mutex<Vec<String>>>,
+}
+
+impl LineEditor {
+    pub fn new(completion: Arc<Mutex<CommandCompletion>>, history: &[String], config: &ShellConfig) -> Result<Self> {
+        let (edit_mode, keybindings, marks) = (config.edit_mode, &config.keybindings, config.shell_integration);
+        let titles = config.terminal_title && io::stdout().is_terminal();
+        let disabled = config.disabled_builtins.clone();
+        let mode = match edit_mode {
+            EditMode::Emacs => rustyline::EditMode::Emacs,
+            EditMode::Vi => rustyline::EditMode::Vi,
+        };
+        // A short key sequence timeout lets a lone Esc through instead of
+        // waiting for it to start an Alt- combination
+        let config = rustyline::Config::builder().keyseq_timeout(50).edit_mode(mode).build();
+        let mut editor = Editor::with_config(config)?;
+        let drawn = Arc::new(Mutex::new(Drawn::default()));
+        editor.set_helper(Some(ShellHelper {
+            completion,
+            hinter: HistoryHinter::new(),
+            drawn: drawn.clone(),
+            marks,
+        }));
+        
+        // Esc discards the line, like Ctrl-C; vi needs it to leave insert mode
+        if edit_mode == EditMode::Emacs {
+            editor.bind_sequence(KeyEvent(KeyCode::Esc, Modifiers::NONE), Cmd::Interrupt);
+        }
+        
+        let actions = [
+            (&keybindings.accept_suggestion, Cmd::CompleteHint),
+            (&keybindings.history_search, Cmd::ReverseSearchHistory),
+            (&keybindings.clear_screen, Cmd::ClearScreen),
+        ];
+        for (key, action) in actions {
+            if let Some(key) = key {
+                editor.bind_sequence(key.event, action);
+            }
+        }
+        
+        let entries = Arc::new(Mutex::new(history.to_vec()));
+        if let Some(key) = &keybindings.history_finder {
+            let finder = finder::HistoryFinder { entries: entries.clone() };
+            editor.bind_sequence(key.event, rustyline::EventHandler::Conditional(Box::new(finder)));
+        }
+        let palette = palette::PaletteKey {
+            entries: entries.clone(),
+            disabled,
+        };
+        let palette_key = match &keybindings.palette {
+            Some(key) => key.event,
+            None => KeyEvent(KeyCode::F(1), Modifiers::NONE),
+        };
+        editor.bind_sequence(palette_key, rustyline::EventHandler::Conditional(Box::new(palette)));
+        
+        for command in history {
+            editor.add_history_entry(command.as_str())?;
+        }
+        
+        if io::stdout().is_terminal() {
+            redraw_on_resize(drawn);
+        }
+        
+        Ok(LineEditor { editor, marks, titles, entries })
+    }
+    
+    /// Read a line, drawing `rprompt` right-aligned when the terminal is wide enough.
+    ///
+    /// With an `idle_timeout`, gives up with `Input::Idle` if no key is pressed
+    /// in that time. Only the wait for the first key is timed: once typing
+    /// starts the line is read to the end.
+    pub fn read_line(&mut self, prompt: &str, rprompt: &str, idle_timeout: Option<Duration>) -> Result<Input> {
+        self.set_rprompt(rprompt);
+        self.mark(PROMPT_START);
+        
+        if let Some(timeout) = idle_timeout {
+            let helper = self.editor.helper();
+            // Recomputed on resize, as the right prompt moves with the width
+            let shown = || match helper {
+                Some(helper) => helper.highlight_prompt(prompt, true).into_owned(),
+                None => prompt.to_string(),
+            };
+            if io::stdin().is_terminal() && !wait_for_key(&shown, timeout)? {
+                return Ok(Input::Idle);
+            }
+        }
+        
+        self.set_reading(true);
+        let line = self.editor.readline(prompt);
+        self.set_reading(false);
+        input(line)
+    }
+    
+    /// Read a line with `initial` already in the input buffer
+    pub fn read_line_with(&mut self, prompt: &str, initial: &str) -> Result<Input> {
+        self.set_rprompt("");
+        self.mark(PROMPT_START);
+        self.set_reading(true);
+        let line = self.editor.readline_with_initial(prompt, (initial, ""));
+        self.set_reading(false);
+        input(line)
+    }
+    
+    /// Redraw the just-entered `line` after `transient` in place of `prompt`,
+    /// dropping the right prompt. The cursor must be on the row below the input.
+    pub fn collapse_prompt(&self, prompt: &str, line: &str, transient: &str) {
+        if !io::stdout().is_terminal() {
+            return;
+        }
+        let Ok((columns, _)) = terminal::size() else {
+            return;
+        };
+        
+        // The editor wraps the input onto further rows, and starts a new one
+        // when it ends exactly at the edge
+        let rows = (display_width(prompt) + display_width(line)) / (columns as usize).max(1) + 1;
+        let (start, end) = if self.marks { (PROMPT_START, PROMPT_END) } else { ("", "") };
+        out!("\x1b[{}A\r\x1b[J{}{}{}{}\n", rows, start, transient, end, line);
+        output::flush();
+    }
+    
+    /// Tell the terminal the entered command's output starts here
+    pub fn mark_output_start(&self) {
+        self.mark(OUTPUT_START);
+    }
+    
+    /// Tell the terminal the command finished with `status`
+    pub fn mark_command_end(&self, status: i32) {
+        self.mark(&format!("\x1b]133;D;{}\x07", status));
+    }
+    
+    /// Set the terminal's title, if `terminal_title` is on. Control characters
+    /// are dropped so they can't end the escape early.
+    pub fn set_title(&self, title: &str) {
+        if !self.titles {
+            return;
+        }
+        let mut title: String = title.chars().filter(|c| !c.is_control()).take(MAX_TITLE_LEN + 1).collect();
+        if title.chars().count() > MAX_TITLE_LEN {
+            title = title.chars().take(MAX_TITLE_LEN - 1).chain(['…']).collect();
+        }
+        out!("\x1b]0;{}\x07", title);
+        output::flush();
+    }
+    
+    fn mark(&self, mark: &str) {
+        if self.marks {
+            out!("{}", mark);
+            output::flush();
+        }
+    }
+    
+    /// Make `line` reachable with the up arrow
+    pub fn add_history(&mut self, line: &str) {
+        let _ = self.editor.add_history_entry(line);
+        if let Ok(mut entries) = self.entries.lock() {
+            entries.push(line.to_string());
+        }
+    }
+    
+    fn set_rprompt(&mut self, rprompt: &str) {
+        if let Some(mut drawn) = self.drawn() {
+            drawn.rprompt = rprompt.to_string();
+        }
+    }
+    
+    fn set_reading(&mut self, reading: bool) {
+        if let Some(mut drawn) = self.drawn() {
+            drawn.reading = reading;
+        }
+    }
+    
+    fn drawn(&self) -> Option<std::sync::MutexGuard<'_, Drawn>> {
+        self.editor.helper()?.drawn.lock().ok()
+    }
+}
+
+/// A key as written in the configuration: `ctrl-r`, `alt-f`, `f2`, `ctrl-up`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct KeySpec {
+    spec: String,
+    event: KeyEvent,
+}
+
+impl TryFrom<String> for KeySpec {
+    type Error = String;
+    
+    fn try_from(spec: String) -> std::result::Result<Self, String> {
+        match parse_key(&spec) {
+            Some(event) => Ok(KeySpec { spec, event }),
+            None => Err(format!(
+                "invalid key '{}'; expected something like ctrl-r, alt-f, f2 or ctrl-up",
+                spec
+            )),
+        }
+    }
+}
+
+impl From<KeySpec> for String {
+    fn from(key: KeySpec) -> String {
+        key.spec
+    }
+}
+
+// Written as the string it is parsed from
+impl schemars::JsonSchema for KeySpec {
+    fn schema_name() -> String {
+        "KeySpec".to_string()
+    }
+    
+    fn json_schema(gen: &mut schemars::gen::SchemaGenerator) -> schemars::schema::Schema {
+        String::json_schema(gen)
+    }
+}
+
+/// `ctrl-`, `alt-` and `shift-` prefixes followed by a character or a key
+/// name. A character needs Ctrl or Alt, so typing it still inserts it.
+fn parse_key(spec: &str) -> Option<KeyEvent> {
+    let spec = spec.trim().to_ascii_lowercase();
+    let (modifiers, key) = match spec.strip_suffix("--") {
+        Some(modifiers) => (modifiers, "-"),
+        None => spec.rsplit_once('-').unwrap_or(("", &spec)),
+    };
+    
+    let mut mods = Modifiers::NONE;
+    for modifier in modifiers.split('-').filter(|m| !m.is_empty()) {
+        mods |= match modifier {
+            "ctrl" | "control" => Modifiers::CTRL,
+            "alt" | "meta" => Modifiers::ALT,
+            "shift" => Modifiers::SHIFT,
+            _ => return None,
+        };
+    }
+    
+    let mut chars = key.chars();
+    let code = match (chars.next(), chars.next()) {
+        (Some(c), None) if mods.intersects(Modifiers::CTRL | Modifiers::ALT) => KeyCode::Char(c),
+        (Some(_), None) | (None, _) => return None,
+        _ => match key {
+            "tab" => KeyCode::Tab,
+            "enter" => KeyCode::Enter,
+            "esc" | "escape" => KeyCode::Esc,
+            "space" => KeyCode::Char(' '),
+            "backspace" => KeyCode::Backspace,
+            "delete" => KeyCode::Delete,
+            "insert" => KeyCode::Insert,
+            "home" => KeyCode::Home,
+            "end" => KeyCode::End,
+            "pageup" => KeyCode::PageUp,
+            "pagedown" => KeyCode::PageDown,
+            "up" => KeyCode::Up,
+            "down" => KeyCode::Down,
+            "left" => KeyCode::Left,
+            "right" => KeyCode::Right,
+            _ => KeyCode::F(key.strip_prefix('f')?.parse().ok().filter(|n| (1..=24).contains(n))?),
+        },
+    };
+    Some(KeyEvent::normalize(KeyEvent(code, mods)))
+}
+
+/// What the editor last drew, shared with the resize watcher
+#[derive(Default)]
+struct Drawn {
+    /// Set while the editor owns the terminal
+    reading: bool,
+    prompt: String,
+    line: String,
+    /// Suggestion shown after the line
+    hint: String,
+    rprompt: String,
+    /// Terminal width when the line was drawn
+    columns: usize,
+}
+
+impl Drawn {
+    /// Escape sequence that moves the right prompt to the edge of a terminal
+    /// now `columns` wide, or `None` if the editor redraws the line itself.
+    fn redraw_rprompt(&self, columns: usize) -> Option<String> {
+        if !self.reading || self.rprompt.is_empty() {
+            return None;
+        }
+        
+        // The editor only redraws when the input wraps, before or after
+        let end = display_width(&self.prompt) + display_width(&self.line) + display_width(&self.hint);
+        if end >= self.columns || end >= columns {
+            return None;
+        }
+        
+        // Clear from the end of the input, then draw it again if it still fits,
+        // leaving the cursor where it was
+        let rprompt_width = display_width(&self.rprompt);
+        let rprompt = if end + rprompt_width < columns {
+            format!("\x1b[{}G{}", columns - rprompt_width + 1, self.rprompt)
+        } else {
+            String::new()
+        };
+        Some(format!("\x1b7\r\x1b[{}G\x1b[K{}\x1b8", end + 1, rprompt))
+    }
+}
+
+/// Keep the right prompt at the terminal's edge when it is resized.
+///
+/// The editor handles SIGWINCH itself but redraws only if the input wraps,
+/// leaving the right prompt at its old column otherwise. Its own printing
+/// hook would redraw everything, but stalls on type-ahead, so this fixes up
+/// just the right prompt and leaves the input alone.
+#[cfg(unix)]
+fn redraw_on_resize(drawn: Arc<Mutex<Drawn>>) {
+    use tokio::signal::unix::{signal, SignalKind};
+    
+    // Registered after the editor's handler, which still gets called
+    let Ok(mut resized) = signal(SignalKind::window_change()) else {
+        return;
+    };
+    
+    tokio::spawn(async move {
+        while resized.recv().await.is_some() {
+            let Ok((columns, _)) = terminal::size() else {
+                continue;
+            };
+            let Ok(drawn) = drawn.lock() else {
+                break;
+            };
+            if let Some(redraw) = drawn.redraw_rprompt(columns as usize) {
+                out!("{}", redraw);
+                output::flush();
+            }
+        }
+    });
+}
+
+#[cfg(not(unix))]
+fn redraw_on_resize(_drawn: Arc<Mutex<Drawn>>) {}
+
+/// Show `prompt` and wait for a key press, leaving the key for the editor to
+/// read. Warns shortly before `timeout`; false if no key came in time.
+fn wait_for_key(prompt: &dyn Fn() -> String, timeout: Duration) -> Result<bool> {
+    out!("{}", prompt());
+    output::flush();
+    
+    // Raw mode so a single key press is enough and isn't echoed
+    terminal::enable_raw_mode()?;
+    let pressed = wait_with_warning(prompt, timeout);
+    terminal::disable_raw_mode()?;
+    
+    let pressed = pressed?;
+    if pressed {
+        // The editor draws its own prompt from the start of the line
+        out!("\r");
+    } else {
+        outln!();
+    }
+    output::flush();
+    Ok(pressed)
+}
+
+fn wait_with_warning(prompt: &dyn Fn() -> String, timeout: Duration) -> Result<bool> {
+    let warning = IDLE_WARNING.min(timeout / 2);
+    if key_within(timeout - warning, prompt)? {
+        return Ok(true);
+    }
+    
+    out!(
+        "\r\n⏱️  Idle timeout in {}s; press any key to stay\r\n{}",
+        warning.as_secs().max(1),
+        prompt()
+    );
+    output::flush();
+    key_within(warning, prompt)
+}
+
+/// Whether stdin becomes readable within `timeout`, without reading from it.
+/// `prompt` is redrawn if the terminal is resized meanwhile.
+#[cfg(unix)]
+fn key_within(timeout: Duration, prompt: &dyn Fn() -> String) -> Result<bool> {
+    use nix::errno::Errno;
+    use nix::poll::{poll, PollFd, PollFlags};
+    
+    let deadline = Instant::now() + timeout;
+    let stdin = io::stdin();
+    
+    loop {
+        let wait = deadline.saturating_duration_since(Instant::now());
+        let mut fds = [PollFd::new(&stdin, PollFlags::POLLIN)];
+        match poll(&mut fds, wait.as_millis().min(i32::MAX as u128) as i32) {
+            Ok(ready) => return Ok(ready > 0),
+            // A signal such as SIGWINCH arrived; keep waiting
+            Err(Errno::EINTR) => {
+                out!("\r\x1b[K{}", prompt());
+                output::flush();
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+/// Idle timeouts need `poll`; elsewhere the prompt waits forever
+#[cfg(not(unix))]
+fn key_within(_timeout: Duration, _prompt: &dyn Fn() -> String) -> Result<bool> {
+    Ok(true)
+}
+
+/// Ask a yes/no question answered with a single key; only `y` means yes.
+///
+/// No answer within `timeout` counts as no, and so does a stdin that isn't a
+/// terminal, so scripts can never hang on a confirmation.
+pub fn confirm(question: &str, timeout: Option<Duration>) -> Result<bool> {
+    if !io::stdin().is_terminal() {
+        outln!("{}n (not a terminal)", question);
+        return Ok(false);
+    }
+    
+    out!("{}", question);
+    output::flush();
+    
+    terminal::enable_raw_mode()?;
+    let answer = read_answer(timeout);
+    terminal::disable_raw_mode()?;
+    
+    let answer = answer?;
+    match answer {
+        Some(true) => outln!("y"),
+        Some(false) => outln!("n"),
+        None => outln!("n (timed out)"),
+    }
+    Ok(answer == Some(true))
+}
+
+/// The answer to `confirm`, or `None` on timeout
+fn read_answer(timeout: Option<Duration>) -> Result<Option<bool>> {
+    let deadline = timeout.map(|timeout| Instant::now() + timeout);
+    
+    loop {
+        let wait = match deadline {
+            Some(deadline) => deadline.saturating_duration_since(Instant::now()),
+            None => Duration::from_secs(60),
+        };
+        
+        if !event::poll(wait)? {
+            if deadline.is_some() {
+                return Ok(None);
+            }
+            continue;
+        }
+        
+        if let Event::Key(key) = event::read()? {
+            if key.kind == KeyEventKind::Press {
+                return Ok(Some(matches!(key.code, TermKeyCode::Char('y' | 'Y'))));
+            }
+        }
+    }
+}
+
+fn input(line: rustyline::Result<String>) -> Result<Input> {
+    match line {
+        Ok(line) => Ok(Input::Line(line)),
+        Err(ReadlineError::Interrupted) => Ok(Input::Cancelled),
+        Err(ReadlineError::Eof) => Ok(Input::Eof),
+        Err(e) => Err(e.into()),
+    }
+}
+
+struct ShellHelper {
+    completion: Arc<Mutex<CommandCompletion>>,
+    hinter: HistoryHinter,
+    drawn: Arc<Mutex<Drawn>>,
+    /// End the prompt with an OSC 133 mark, where the input starts
+    marks: bool,
+}
+
+impl Helper for ShellHelper {}
+
+impl Completer for ShellHelper {
+    type Candidate = String;
+    
+    fn complete(&self, line: &str, pos: usize, _ctx: &Context<'_>) -> rustyline::Result<(usize, Vec<String>)> {
+        let before = &line[..pos];
+        let start = before.rfind(char::is_whitespace).map_or(0, |i| i + 1);
+        
+        let candidates = match self.completion.lock() {
+            Ok(completion) => completion.complete(before),
+            Err(_) => vec![],
+        };
+        
+        Ok((start, candidates))
+    }
+}
+
+impl ShellHelper {
+    // The editor sizes the line from the plain prompt and clears it before
+    // drawing this one, so the right prompt is redrawn on every refresh,
+    // including after a resize.
+    fn with_rprompt<'p>(&self, prompt: &'p str, default: bool) -> Cow<'p, str> {
+        let Ok(mut drawn) = self.drawn.lock() else {
+            return Cow::Borrowed(prompt);
+        };
+        if !default || drawn.rprompt.is_empty() {
+            return Cow::Borrowed(prompt);
+        }
+        
+        let Ok((columns, _)) = crossterm::terminal::size() else {
+            return Cow::Borrowed(prompt);
+        };
+        drawn.prompt = prompt.to_string();
+        drawn.columns = columns as usize;
+        
+        // Dropped when the terminal is too narrow to fit both
+        let rprompt_width = display_width(&drawn.rprompt);
+        if display_width(prompt) + rprompt_width + 1 > columns as usize {
+            return Cow::Borrowed(prompt);
+        }
+        
+        let column = columns as usize - rprompt_width + 1;
+        Cow::Owned(format!("\x1b[{}G{}\r{}", column, drawn.rprompt, prompt))
+    }
+}
+
+impl Highlighter for ShellHelper {
+    // Marks are zero width on screen but not to the editor, so they are only
+    // added here, to what is drawn
+    fn highlight_prompt<'b, 's: 'b, 'p: 'b>(&'s self, prompt: &'p str, default: bool) -> Cow<'b, str> {
+        let prompt = self.with_rprompt(prompt, default);
+        if self.marks && default {
+            return Cow::Owned(format!("{}{}", prompt, PROMPT_END));
+        }
+        prompt
+    }
+    
+    fn highlight_hint<'h>(&self, hint: &'h str) -> Cow<'h, str> {
+        if !ui::stdout_color() {
+            return Cow::Borrowed(hint);
+        }
+        Cow::Owned(format!("\x1b[2m{}\x1b[0m", hint))
+    }
+    
+    // Called before every redraw and on each key, so the resize watcher
+    // always knows the current input
+    fn highlight_char(&self, line: &str, _pos: usize, _forced: bool) -> bool {
+        if let Ok(mut drawn) = self.drawn.lock() {
+            drawn.line.clear();
+            drawn.line.push_str(line);
+        }
+        false
+    }
+}
+
+impl Hinter for ShellHelper {
+    type Hint = String;
+    
+    /// The rest of the latest history entry starting with the line
+    fn hint(&self, line: &str, pos: usize, ctx: &Context<'_>) -> Option<String> {
+        let hint = self.hinter.hint(line, pos, ctx).filter(|hint| hint.len() <= MAX_HINT_LEN);
+        if let Ok(mut drawn) = self.drawn.lock() {
+            drawn.hint = hint.clone().unwrap_or_default();
+        }
+        hint
+    }
+}
+
+impl Validator for ShellHelper {}
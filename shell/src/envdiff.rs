@@ -0,0 +1,62 @@
+use super::*;
+use std::collections::BTreeMap;
+
+/// The environment and aliases at one moment, for `--diff` on `source` and
+/// `dotenv`
+pub struct Snapshot {
+    vars: BTreeMap<String, String>,
+    aliases: BTreeMap<String, String>,
+}
+
+impl Snapshot {
+    pub fn take(aliases: &Aliases) -> Self {
+        let vars = env::vars_os()
+            .map(|(name, value)| (name.to_string_lossy().into_owned(), value.to_string_lossy().into_owned()))
+            .collect();
+        let aliases = aliases
+            .iter()
+            .map(|(name, value)| (name.to_string(), parser::quote(value)))
+            .collect();
+        Snapshot { vars, aliases }
+    }
+    
+    /// What changed since this snapshot, sorted by name: `+` for additions,
+    /// `-` for removals and `~` for changes, with secrets masked
+    pub fn diff(&self, after: &Snapshot, redactor: &Redactor) -> String {
+        let mut diff = String::new();
+        diff_maps(&mut diff, "", &self.vars, &after.vars, redactor);
+        diff_maps(&mut diff, "alias ", &self.aliases, &after.aliases, redactor);
+        if diff.is_empty() {
+            diff.push_str("No changes\n");
+        }
+        diff
+    }
+}
+
+fn diff_maps(
+    diff: &mut String,
+    prefix: &str,
+    before: &BTreeMap<String, String>,
+    after: &BTreeMap<String, String>,
+    redactor: &Redactor,
+) {
+    // Redacted whole, so patterns that key on the name still apply
+    let shown = |name: &str, value: &str| redactor.redact(&format!("{}{}={}", prefix, name, value)).into_owned();
+    
+    let mut names: Vec<&String> = before.keys().chain(after.keys()).collect();
+    names.sort();
+    names.dedup();
+    for name in names {
+        match (before.get(name), after.get(name)) {
+            (None, Some(value)) => diff.push_str(&format!("+ {}\n", shown(name, value))),
+            (Some(value), None) => diff.push_str(&format!("- {}\n", shown(name, value))),
+            (Some(old), Some(new)) if old != new => {
+                let new = shown(name, new);
+                let assignment = format!("{}{}=", prefix, name);
+                let new = new.strip_prefix(&assignment).unwrap_or(&new);
+                diff.push_str(&format!("~ {} → {}\n", shown(name, old), new));
+            }
+            _ => {}
+        }
+    }
+}
@@ -0,0 +1,320 @@
+//! Turning a session recorded with `--record` into a Markdown or HTML page
+//! for `export-session`. Markdown drops the colours programs printed; HTML
+//! keeps them, converting SGR escape sequences into styled spans.
+
+use super::*;
+use session::SessionEntry;
+use std::fmt::Write as _;
+
+/// What a session is exported as
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Format {
+    Markdown,
+    /// A standalone page with its own styling
+    Html,
+}
+
+impl Format {
+    /// HTML for a file ending in `.html` or `.htm`, Markdown otherwise
+    pub fn for_path(path: &Path) -> Self {
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some(extension) if extension.eq_ignore_ascii_case("html") || extension.eq_ignore_ascii_case("htm") => {
+                Format::Html
+            }
+            _ => Format::Markdown,
+        }
+    }
+}
+
+/// The page for `entries`, headed by `title`
+pub fn render(entries: &[SessionEntry], format: Format, title: &str) -> String {
+    match format {
+        Format::Markdown => markdown(entries, title),
+        Format::Html => html(entries, title),
+    }
+}
+
+/// When the command was entered, how long it took and how it exited
+fn summary(entry: &SessionEntry) -> String {
+    format!(
+        "exit status {} · {} · {}",
+        entry.exit_code,
+        prompt::format_duration(Duration::from_millis(entry.duration_ms as u64)),
+        entry.timestamp.with_timezone(&chrono::Local).format("%Y-%m-%d %H:%M:%S")
+    )
+}
+
+/// A command's stdout then its stderr, ending with a line break
+fn output(entry: &SessionEntry) -> String {
+    let mut output = String::new();
+    for stream in [&entry.stdout, &entry.stderr] {
+        output.push_str(stream);
+        if !output.is_empty() && !output.ends_with('\n') {
+            output.push('\n');
+        }
+    }
+    output
+}
+
+fn markdown(entries: &[SessionEntry], title: &str) -> String {
+    let mut page = format!("# {}\n", title);
+    if entries.is_empty() {
+        page.push_str("\n_No commands were recorded._\n");
+    }
+    
+    for entry in entries {
+        page.push('\n');
+        if let Some(interpreted) = &entry.interpreted {
+            let _ = writeln!(page, "AI ran {}\n", inline_code(interpreted));
+        }
+        let block = format!("$ {}\n{}", entry.input, strip_escapes(&output(entry)));
+        let fence = "`".repeat(longest_run(&block, '`').max(2) + 1);
+        let _ = writeln!(page, "{}console\n{}{}\n", fence, block, fence);
+        let _ = writeln!(page, "_{}_", summary(entry));
+    }
+    page
+}
+
+/// `text` as Markdown inline code, delimited by more backticks than it holds
+fn inline_code(text: &str) -> String {
+    let ticks = "`".repeat(longest_run(text, '`') + 1);
+    let pad = if text.starts_with('`') || text.ends_with('`') { " " } else { "" };
+    format!("{}{}{}{}{}", ticks, pad, text, pad, ticks)
+}
+
+/// Length of the longest run of `c` in `text`
+fn longest_run(text: &str, c: char) -> usize {
+    text.split(|other| other != c).map(str::len).max().unwrap_or(0)
+}
+
+const STYLE: &str = "\
+body { font-family: system-ui, sans-serif; max-width: 60rem; margin: 2rem auto; padding: 0 1rem; color: #222; }
+h1 { font-size: 1.4rem; }
+section { margin: 1.5rem 0; }
+pre { background: #1e1e1e; color: #ddd; padding: 0.75rem 1rem; border-radius: 6px; overflow-x: auto; line-height: 1.35; }
+.prompt { color: #888; }
+.input { color: #fff; font-weight: bold; }
+.stderr { color: #f0a0a0; }
+.ai, .summary { margin: 0.3rem 0; font-size: 0.85rem; color: #666; }
+.failed { color: #b00020; }";
+
+fn html(entries: &[SessionEntry], title: &str) -> String {
+    let mut page = format!(
+        "<!DOCTYPE html>\n<html lang=\"en\">\n<head>\n<meta charset=\"utf-8\">\n<title>{0}</title>\n<style>\n{1}\n</style>\n</head>\n<body>\n<h1>{0}</h1>\n",
+        escape_html(title),
+        STYLE
+    );
+    if entries.is_empty() {
+        page.push_str("<p><em>No commands were recorded.</em></p>\n");
+    }
+    
+    for entry in entries {
+        page.push_str("<section>\n");
+        if let Some(interpreted) = &entry.interpreted {
+            let _ = writeln!(page, "<p class=\"ai\">AI ran <code>{}</code></p>", escape_html(interpreted));
+        }
+        let _ = write!(
+            page,
+            "<pre><span class=\"prompt\">$ </span><span class=\"input\">{}</span>\n{}",
+            escape_html(&entry.input),
+            ansi_to_html(&entry.stdout)
+        );
+        if !entry.stderr.is_empty() {
+            if !entry.stdout.is_empty() && !entry.stdout.ends_with('\n') {
+                page.push('\n');
+            }
+            let _ = write!(page, "<span class=\"stderr\">{}</span>", ansi_to_html(&entry.stderr));
+        }
+        let class = if entry.exit_code == 0 { "summary" } else { "summary failed" };
+        let _ = writeln!(page, "</pre>\n<p class=\"{}\">{}</p>\n</section>", class, summary(entry));
+    }
+    
+    page.push_str("</body>\n</html>\n");
+    page
+}
+
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// A piece of program output: text, or the parameters of an SGR sequence
+/// (`ESC [ ... m`) that changes how the text after it looks
+enum Piece<'a> {
+    Text(&'a str),
+    Sgr(Vec<u16>),
+}
+
+/// Split `text` at its escape sequences. Sequences other than SGR, such as
+/// cursor movement, mean nothing on a page and are dropped.
+fn pieces(text: &str) -> Vec<Piece<'_>> {
+    let mut pieces = Vec::new();
+    let mut rest = text;
+    
+    while let Some(start) = rest.find('\x1b') {
+        if start > 0 {
+            pieces.push(Piece::Text(&rest[..start]));
+        }
+        let sequence = &rest[start + 1..];
+        let Some(csi) = sequence.strip_prefix('[') else {
+            // Two-character sequence, or a lone ESC at the end
+            let mut chars = sequence.chars();
+            chars.next();
+            rest = chars.as_str();
+            continue;
+        };
+        // Parameters, then a final byte in `@`..=`~`
+        let Some(end) = csi.find(|c| ('@'..='~').contains(&c)) else {
+            return pieces;
+        };
+        if csi[end..].starts_with('m') {
+            let params = csi[..end].split([';', ':']).map(|param| param.parse().unwrap_or(0)).collect();
+            pieces.push(Piece::Sgr(params));
+        }
+        rest = &csi[end + 1..];
+    }
+    
+    if !rest.is_empty() {
+        pieces.push(Piece::Text(rest));
+    }
+    pieces
+}
+
+/// `text` without escape sequences
+fn strip_escapes(text: &str) -> String {
+    pieces(text)
+        .into_iter()
+        .filter_map(|piece| match piece {
+            Piece::Text(text) => Some(text),
+            Piece::Sgr(_) => None,
+        })
+        .collect()
+}
+
+/// How SGR sequences have left the text looking
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+struct Style {
+    bold: bool,
+    dim: bool,
+    italic: bool,
+    underline: bool,
+    foreground: Option<String>,
+    background: Option<String>,
+}
+
+impl Style {
+    fn apply(&mut self, params: &[u16]) {
+        // `ESC [ m` has one empty parameter, read as 0: a reset
+        let mut params = params.iter().copied();
+        while let Some(param) = params.next() {
+            match param {
+                0 => *self = Style::default(),
+                1 => self.bold = true,
+                2 => self.dim = true,
+                3 => self.italic = true,
+                4 => self.underline = true,
+                22 => (self.bold, self.dim) = (false, false),
+                23 => self.italic = false,
+                24 => self.underline = false,
+                30..=37 => self.foreground = Some(PALETTE[(param - 30) as usize].to_string()),
+                90..=97 => self.foreground = Some(PALETTE[(param - 90 + 8) as usize].to_string()),
+                39 => self.foreground = None,
+                40..=47 => self.background = Some(PALETTE[(param - 40) as usize].to_string()),
+                100..=107 => self.background = Some(PALETTE[(param - 100 + 8) as usize].to_string()),
+                49 => self.background = None,
+                38 => self.foreground = extended_color(&mut params),
+                48 => self.background = extended_color(&mut params),
+                _ => {}
+            }
+        }
+    }
+    
+    /// The CSS for this style; empty for plain text
+    fn css(&self) -> String {
+        let mut css = Vec::new();
+        if let Some(color) = &self.foreground {
+            css.push(format!("color: {}", color));
+        }
+        if let Some(color) = &self.background {
+            css.push(format!("background: {}", color));
+        }
+        if self.bold {
+            css.push("font-weight: bold".to_string());
+        }
+        if self.dim {
+            css.push("opacity: 0.7".to_string());
+        }
+        if self.italic {
+            css.push("font-style: italic".to_string());
+        }
+        if self.underline {
+            css.push("text-decoration: underline".to_string());
+        }
+        css.join("; ")
+    }
+}
+
+/// The 16 standard terminal colours, normal then bright
+const PALETTE: [&str; 16] = [
+    "#000000", "#cd3131", "#0dbc79", "#e5e510", "#2472c8", "#bc3fbc", "#11a8cd", "#e5e5e5",
+    "#666666", "#f14c4c", "#23d18b", "#f5f543", "#3b8eea", "#d670d6", "#29b8db", "#ffffff",
+];
+
+/// The colour of `38;5;n` or `38;2;r;g;b` (or `48;...`), from the
+/// parameters after the 38
+fn extended_color(params: &mut impl Iterator<Item = u16>) -> Option<String> {
+    match params.next()? {
+        5 => {
+            let index = params.next()?;
+            Some(match index {
+                0..=15 => PALETTE[index as usize].to_string(),
+                // 6×6×6 cube
+                16..=231 => {
+                    let level = |n: u16| if n == 0 { 0 } else { 55 + n * 40 };
+                    let n = index - 16;
+                    format!("#{:02x}{:02x}{:02x}", level(n / 36), level(n / 6 % 6), level(n % 6))
+                }
+                // Greys
+                _ => {
+                    let grey = 8 + (index.min(255) - 232) * 10;
+                    format!("#{0:02x}{0:02x}{0:02x}", grey)
+                }
+            })
+        }
+        2 => {
+            let mut channel = || params.next().map(|value| value.min(255));
+            Some(format!("#{:02x}{:02x}{:02x}", channel()?, channel()?, channel()?))
+        }
+        _ => None,
+    }
+}
+
+/// Escaped `text` with its colours and emphasis as styled spans
+fn ansi_to_html(text: &str) -> String {
+    let mut html = String::with_capacity(text.len());
+    let mut style = Style::default();
+    
+    for piece in pieces(text) {
+        match piece {
+            Piece::Sgr(params) => style.apply(&params),
+            Piece::Text(text) => {
+                let css = style.css();
+                if css.is_empty() {
+                    html.push_str(&escape_html(text));
+                } else {
+                    let _ = write!(html, "<span style=\"{}\">{}</span>", css, escape_html(text));
+                }
+            }
+        }
+    }
+    html
+}
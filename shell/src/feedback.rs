@@ -0,0 +1,157 @@
+//! Verdicts on AI interpretations, collected into a local dataset that can
+//! later supply prompt examples or fine-tuning data. Nothing is sent
+//! anywhere.
+
+use super::*;
+use chrono::{DateTime, Utc};
+use commands::CommandResult;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, OpenOptions};
+
+/// Where and when verdicts are recorded. Off unless `enabled`.
+///
+/// ```toml
+/// [ai_feedback]
+/// enabled = true
+/// ```
+#[derive(Debug, Clone, Default, Serialize, Deserialize, JsonSchema)]
+pub struct FeedbackConfig {
+    /// Record `good`/`bad` verdicts on AI interpretations
+    #[serde(default)]
+    pub enabled: bool,
+    /// The dataset, one JSON object per line; defaults to
+    /// `feedback.jsonl` in the state directory
+    #[serde(default)]
+    pub file: Option<PathBuf>,
+    /// Also record an interpretation as bad when its command exits non-zero.
+    /// A `good` given afterwards is saved as a separate line from the user.
+    #[serde(default)]
+    pub bad_on_failure: bool,
+}
+
+/// One line of the dataset
+#[derive(Debug, Serialize)]
+struct Verdict<'a> {
+    time: DateTime<Utc>,
+    input: &'a str,
+    command: &'a str,
+    /// `good` or `bad`
+    verdict: &'a str,
+    /// `user` for the builtins, `exit_status` for `bad_on_failure`
+    source: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    exit_code: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    note: Option<&'a str>,
+}
+
+/// The last interpretation, which `good` and `bad` rate
+struct Interpretation {
+    input: String,
+    command: String,
+    exit_code: Option<i32>,
+}
+
+pub struct Feedback {
+    config: FeedbackConfig,
+    redactor: Redactor,
+    last: Option<Interpretation>,
+}
+
+impl Feedback {
+    pub fn new(config: &FeedbackConfig, redactor: Redactor) -> Self {
+        Feedback {
+            config: config.clone(),
+            redactor,
+            last: None,
+        }
+    }
+    
+    fn path(&self) -> PathBuf {
+        match &self.config.file {
+            Some(file) => file.clone(),
+            None => config::state_dir().join("feedback.jsonl"),
+        }
+    }
+    
+    /// Note that the AI read `input` as `command`
+    pub fn interpreted(&mut self, input: &str, command: &str) {
+        if !self.config.enabled {
+            return;
+        }
+        self.last = Some(Interpretation {
+            input: input.to_string(),
+            command: command.to_string(),
+            exit_code: None,
+        });
+    }
+    
+    /// Note how the last interpretation's command exited, recording it as bad
+    /// with `bad_on_failure`. Returns a warning if that couldn't be saved.
+    pub fn ran(&mut self, exit_code: i32) -> Option<String> {
+        let last = self.last.as_mut()?;
+        last.exit_code = Some(exit_code);
+        if exit_code == 0 || !self.config.bad_on_failure {
+            return None;
+        }
+        
+        let last = self.last.as_ref()?;
+        self.record(last, "bad", "exit_status", None)
+            .err()
+            .map(|e| format!("Failed to save AI feedback: {:#}", e))
+    }
+    
+    /// The `good` and `bad` builtins, with an optional note
+    pub fn builtin(&mut self, verdict: &str, note: &str) -> CommandResult {
+        let fail = |message: String| CommandResult {
+            stderr: format!("❌ {}: {}\n", verdict, message),
+            exit_code: 1,
+            ..Default::default()
+        };
+        if !self.config.enabled {
+            return fail("feedback is off; set ai_feedback.enabled = true".to_string());
+        }
+        // Each interpretation is rated once
+        let Some(last) = self.last.take() else {
+            return fail("no AI interpretation to rate".to_string());
+        };
+        
+        let note = Some(note).filter(|note| !note.is_empty());
+        if let Err(e) = self.record(&last, verdict, "user", note) {
+            return fail(format!("{:#}", e));
+        }
+        let emoji = if verdict == "good" { "👍" } else { "👎" };
+        CommandResult {
+            stdout: format!("{} Recorded '{}' → '{}' as {}\n", emoji, last.input, last.command, verdict),
+            ..Default::default()
+        }
+    }
+    
+    fn record(&self, last: &Interpretation, verdict: &str, source: &str, note: Option<&str>) -> Result<()> {
+        let input = self.redactor.redact(&last.input);
+        let command = self.redactor.redact(&last.command);
+        let note = note.map(|note| self.redactor.redact(note));
+        let line = Verdict {
+            time: Utc::now(),
+            input: &input,
+            command: &command,
+            verdict,
+            source,
+            exit_code: last.exit_code,
+            note: note.as_deref(),
+        };
+        
+        let path = self.path();
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .with_context(|| format!("Failed to open {}", path.display()))?;
+        writeln!(file, "{}", serde_json::to_string(&line)?)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,145 @@
+use super::*;
+use serde::Serialize;
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, Write};
+
+/// One result written to the output FIFO, as a line of JSON
+#[derive(Serialize)]
+struct Reply<'a> {
+    command: &'a str,
+    exit_code: i32,
+    stdout: &'a str,
+    stderr: &'a str,
+    warnings: &'a [String],
+    duration_ms: u128,
+}
+
+/// The output FIFO, kept open between results while something reads it
+struct Replies<'p> {
+    path: &'p Path,
+    file: Option<File>,
+}
+
+/// Run commands read line by line from the FIFO at `path`, creating it if
+/// needed, until one of them exits the shell. When a writer closes its end
+/// the FIFO is reopened for the next one.
+///
+/// With `output`, each result is written there as a line of JSON instead of
+/// being printed. Results are dropped while nothing has it open for reading,
+/// and a reader that closes it can open it again for later ones.
+pub async fn serve(shell: &mut ObsidianShell, path: &Path, output: Option<&Path>) -> Result<i32> {
+    let mut created = Vec::new();
+    for fifo in std::iter::once(path).chain(output) {
+        if create(fifo)? {
+            created.push(fifo);
+        }
+    }
+    
+    let status = read_commands(shell, path, output).await;
+    for fifo in created {
+        let _ = fs::remove_file(fifo);
+    }
+    status
+}
+
+async fn read_commands(shell: &mut ObsidianShell, path: &Path, output: Option<&Path>) -> Result<i32> {
+    let mut replies = output.map(|path| Replies { path, file: None });
+    loop {
+        // Opening blocks until a writer opens the other end
+        let file = File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+        
+        for line in BufReader::new(file).lines() {
+            let line = line.with_context(|| format!("Failed to read {}", path.display()))?;
+            let command = line.trim();
+            if command.is_empty() {
+                continue;
+            }
+            
+            let result = shell.eval(command).await?;
+            match &mut replies {
+                Some(replies) => {
+                    if let Err(e) = replies.send(command, &result) {
+                        let command = shell.redactor.redact(command);
+                        tracing::debug!(%command, error = %format!("{:#}", e), "dropped FIFO result");
+                    }
+                }
+                None => shell.print_result(&result),
+            }
+            
+            if result.exit_requested {
+                return Ok(result.exit_code);
+            }
+        }
+    }
+}
+
+impl Replies<'_> {
+    fn send(&mut self, command: &str, result: &CommandResult) -> Result<()> {
+        let reply = Reply {
+            command,
+            exit_code: result.exit_code,
+            stdout: &result.stdout,
+            stderr: &result.stderr,
+            warnings: &result.warnings,
+            duration_ms: result.duration.as_millis(),
+        };
+        let mut line = serde_json::to_string(&reply)?;
+        line.push('\n');
+        
+        if let Some(file) = &mut self.file {
+            if file.write_all(line.as_bytes()).is_ok() {
+                return Ok(());
+            }
+            // The reader went away; a new one may have opened it since
+            self.file = None;
+        }
+        let mut file = open_for_reply(self.path)?;
+        file.write_all(line.as_bytes())?;
+        self.file = Some(file);
+        Ok(())
+    }
+}
+
+/// Make a FIFO at `path` unless one is already there; true if it was created
+#[cfg(unix)]
+fn create(path: &Path) -> Result<bool> {
+    use nix::sys::stat::Mode;
+    use std::os::unix::fs::FileTypeExt;
+    
+    match fs::metadata(path) {
+        Ok(metadata) if metadata.file_type().is_fifo() => Ok(false),
+        Ok(_) => anyhow::bail!("{} exists and is not a FIFO", path.display()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            nix::unistd::mkfifo(path, Mode::S_IRUSR | Mode::S_IWUSR)
+                .with_context(|| format!("Failed to create FIFO {}", path.display()))?;
+            Ok(true)
+        }
+        Err(e) => Err(e).with_context(|| format!("Failed to read {}", path.display())),
+    }
+}
+
+#[cfg(not(unix))]
+fn create(_path: &Path) -> Result<bool> {
+    anyhow::bail!("Command FIFOs are only supported on Unix")
+}
+
+/// Open `path` for writing, failing instead of waiting when it has no reader
+#[cfg(unix)]
+fn open_for_reply(path: &Path) -> Result<File> {
+    use nix::fcntl::{fcntl, FcntlArg, OFlag};
+    use std::os::fd::AsRawFd;
+    use std::os::unix::fs::OpenOptionsExt;
+    
+    let file = fs::OpenOptions::new()
+        .write(true)
+        .custom_flags(nix::libc::O_NONBLOCK)
+        .open(path)?;
+    // Back to blocking, so a reply bigger than the pipe buffer isn't cut off
+    fcntl(file.as_raw_fd(), FcntlArg::F_SETFL(OFlag::empty()))?;
+    Ok(file)
+}
+
+#[cfg(not(unix))]
+fn open_for_reply(_path: &Path) -> Result<File> {
+    anyhow::bail!("Command FIFOs are only supported on Unix")
+}
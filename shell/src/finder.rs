@@ -0,0 +1,278 @@
+//! Full-screen fuzzy finder over history, bound to `keybindings.history_finder`.
+//! It runs inside rustyline's key handler and puts the chosen entry in the line.
+//! The command palette uses the same screen through `pick`.
+
+use super::*;
+use crossterm::cursor::{Hide, MoveTo, Show};
+use crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use crossterm::style::{Attribute, Print, SetAttribute};
+use crossterm::terminal::{self, Clear, ClearType, EnterAlternateScreen, LeaveAlternateScreen};
+use crossterm::{execute, queue};
+use nucleo_matcher::pattern::{CaseMatching, Normalization, Pattern};
+use nucleo_matcher::{Matcher, Utf32Str};
+use rustyline::{Cmd, ConditionalEventHandler, EventContext, Movement, RepeatCount};
+use std::collections::HashSet;
+
+/// Smallest terminal the finder is drawn in; the key falls back to
+/// rustyline's reverse search below this
+const MIN_ROWS: u16 = 8;
+const MIN_COLUMNS: u16 = 30;
+/// Rows showing the whole selected entry, for ones too long for the list
+const PREVIEW_ROWS: u16 = 3;
+
+/// The key handler; `entries` is the history, oldest first
+pub struct HistoryFinder {
+    pub entries: Arc<Mutex<Vec<String>>>,
+}
+
+impl ConditionalEventHandler for HistoryFinder {
+    fn handle(&self, _event: &rustyline::Event, _n: RepeatCount, _positive: bool, ctx: &EventContext) -> Option<Cmd> {
+        if !fits() {
+            return Some(Cmd::ReverseSearchHistory);
+        }
+        
+        let entries = self.entries.lock().ok()?.clone();
+        // Newest first, each command once
+        let mut seen = HashSet::new();
+        let candidates: Vec<&str> = entries
+            .iter()
+            .rev()
+            .map(String::as_str)
+            .filter(|entry| seen.insert(*entry))
+            .collect();
+        
+        match pick(&candidates, ctx.line()) {
+            Ok(Some(i)) => Some(Cmd::Replace(Movement::WholeBuffer, Some(candidates[i].to_string()))),
+            Ok(None) => Some(Cmd::Repaint),
+            Err(e) => {
+                tracing::warn!(error = %e, "history finder failed");
+                Some(Cmd::ReverseSearchHistory)
+            }
+        }
+    }
+}
+
+/// Leaves the alternate screen when the finder closes, including on errors
+struct ScreenGuard;
+
+impl Drop for ScreenGuard {
+    fn drop(&mut self) {
+        let _ = execute!(io::stdout(), LeaveAlternateScreen, Show);
+    }
+}
+
+struct Finder<'c> {
+    candidates: &'c [&'c str],
+    query: String,
+    /// The query `matches` is for
+    searched: String,
+    /// Indices into `candidates`, best match first
+    matches: Vec<usize>,
+    /// Position in `matches`
+    selected: usize,
+    /// First match shown in the list
+    scroll: usize,
+    matcher: Matcher,
+}
+
+/// Whether the terminal is big enough for the finder
+pub fn fits() -> bool {
+    matches!(terminal::size(), Ok((columns, rows)) if columns >= MIN_COLUMNS && rows >= MIN_ROWS)
+}
+
+/// Let the user pick from `candidates`, starting with `query` as the search;
+/// the index of the chosen one, or `None` if they cancel. The terminal is
+/// already in raw mode.
+pub fn pick(candidates: &[&str], query: &str) -> Result<Option<usize>> {
+    execute!(io::stdout(), EnterAlternateScreen, Hide)?;
+    let _guard = ScreenGuard;
+    
+    let mut finder = Finder {
+        candidates,
+        query: query.to_string(),
+        searched: String::new(),
+        matches: (0..candidates.len()).collect(),
+        selected: 0,
+        scroll: 0,
+        matcher: Matcher::new(nucleo_matcher::Config::DEFAULT),
+    };
+    finder.search();
+    
+    loop {
+        let (columns, rows) = terminal::size()?;
+        finder.draw(columns, rows)?;
+        
+        // Take every key already typed before searching again, so a large
+        // history doesn't make typing lag behind
+        loop {
+            if let Event::Key(key) = event::read()? {
+                if let Some(chosen) = finder.key(key, list_rows(rows) as usize) {
+                    return Ok(chosen);
+                }
+            }
+            if !event::poll(Duration::ZERO)? {
+                break;
+            }
+        }
+        if finder.query != finder.searched {
+            finder.search();
+        }
+    }
+}
+
+/// Rows left for the list under the query line and above the preview
+fn list_rows(rows: u16) -> u16 {
+    rows.saturating_sub(PREVIEW_ROWS + 2)
+}
+
+impl Finder<'_> {
+    /// Handle a key press; `Some` once the finder is done, with the chosen
+    /// candidate's index unless it was cancelled
+    fn key(&mut self, key: KeyEvent, list_rows: usize) -> Option<Option<usize>> {
+        if key.kind != KeyEventKind::Press {
+            return None;
+        }
+        
+        match key {
+            KeyEvent { code: KeyCode::Enter, .. } => {
+                return Some(self.matches.get(self.selected).copied());
+            }
+            KeyEvent { code: KeyCode::Esc, .. } => return Some(None),
+            KeyEvent {
+                code: KeyCode::Char('c' | 'd' | 'g'),
+                modifiers: KeyModifiers::CONTROL,
+                ..
+            } => return Some(None),
+            KeyEvent { code: KeyCode::Up, .. } => self.move_by(-1),
+            KeyEvent { code: KeyCode::Char('p' | 'k'), modifiers: KeyModifiers::CONTROL, .. } => self.move_by(-1),
+            KeyEvent { code: KeyCode::Down, .. } => self.move_by(1),
+            KeyEvent { code: KeyCode::Char('n' | 'j'), modifiers: KeyModifiers::CONTROL, .. } => self.move_by(1),
+            KeyEvent { code: KeyCode::PageUp, .. } => self.move_by(-(list_rows as isize)),
+            KeyEvent { code: KeyCode::PageDown, .. } => self.move_by(list_rows as isize),
+            KeyEvent { code: KeyCode::Char('u'), modifiers: KeyModifiers::CONTROL, .. } => self.query.clear(),
+            KeyEvent { code: KeyCode::Backspace, .. } => {
+                self.query.pop();
+            }
+            KeyEvent { code: KeyCode::Char(c), modifiers, .. } if !modifiers.contains(KeyModifiers::CONTROL) => {
+                self.query.push(c);
+            }
+            _ => {}
+        }
+        None
+    }
+    
+    /// Rank the candidates against the query. Space-separated words must all
+    /// match, in any order; ties keep the newest entry first.
+    fn search(&mut self) {
+        // Typing more only narrows the matches, unless it extends a `!word`
+        // that excludes entries or follows a `$` that anchored one
+        let mut pool = if self.query.starts_with(&self.searched) && !self.query.contains(['!', '$']) {
+            std::mem::take(&mut self.matches)
+        } else {
+            (0..self.candidates.len()).collect()
+        };
+        pool.sort_unstable();
+        
+        let pattern = Pattern::parse(&self.query, CaseMatching::Smart, Normalization::Smart);
+        let mut buf = Vec::new();
+        let mut scored: Vec<(usize, u32)> = pool
+            .into_iter()
+            .filter_map(|i| {
+                let score = pattern.score(Utf32Str::new(self.candidates[i], &mut buf), &mut self.matcher)?;
+                Some((i, score))
+            })
+            .collect();
+        // Stable, so equal scores stay newest first
+        scored.sort_by_key(|&(_, score)| std::cmp::Reverse(score));
+        self.matches = scored.into_iter().map(|(i, _)| i).collect();
+        self.searched = self.query.clone();
+        self.selected = 0;
+        self.scroll = 0;
+    }
+    
+    fn move_by(&mut self, offset: isize) {
+        let last = self.matches.len().saturating_sub(1) as isize;
+        self.selected = (self.selected as isize + offset).clamp(0, last) as usize;
+    }
+    
+    fn draw(&mut self, columns: u16, rows: u16) -> Result<()> {
+        let mut stdout = io::stdout();
+        let width = columns as usize;
+        let list_rows = list_rows(rows) as usize;
+        
+        // Keep the selection in view
+        if self.selected < self.scroll {
+            self.scroll = self.selected;
+        } else if self.selected >= self.scroll + list_rows {
+            self.scroll = self.selected + 1 - list_rows;
+        }
+        
+        queue!(stdout, Clear(ClearType::All), MoveTo(0, 0))?;
+        let count = format!("{}/{}", self.matches.len(), self.candidates.len());
+        let query = fit(&format!("🔍 {}", self.query), width.saturating_sub(count.len() + 1));
+        queue!(
+            stdout,
+            Print(&query),
+            MoveTo(columns.saturating_sub(count.len() as u16), 0),
+            Print(&count)
+        )?;
+        
+        let pattern = Pattern::parse(&self.query, CaseMatching::Smart, Normalization::Smart);
+        let mut buf = Vec::new();
+        let mut indices = Vec::new();
+        for (row, &i) in self.matches.iter().enumerate().skip(self.scroll).take(list_rows) {
+            let entry = fit(&single_line(self.candidates[i]), width.saturating_sub(2));
+            let selected = row == self.selected;
+            
+            indices.clear();
+            pattern.indices(Utf32Str::new(&entry, &mut buf), &mut self.matcher, &mut indices);
+            
+            queue!(stdout, MoveTo(0, (row - self.scroll + 1) as u16))?;
+            if selected {
+                queue!(stdout, SetAttribute(Attribute::Reverse), Print("> "))?;
+            } else {
+                queue!(stdout, Print("  "))?;
+            }
+            for (position, c) in entry.chars().enumerate() {
+                if indices.contains(&(position as u32)) {
+                    queue!(stdout, SetAttribute(Attribute::Bold), Print(c), SetAttribute(Attribute::NormalIntensity))?;
+                } else {
+                    queue!(stdout, Print(c))?;
+                }
+            }
+            queue!(stdout, SetAttribute(Attribute::Reset))?;
+        }
+        
+        // The whole selected entry, wrapped
+        let preview_top = rows - PREVIEW_ROWS;
+        queue!(stdout, MoveTo(0, preview_top - 1), Print("─".repeat(width)))?;
+        if let Some(&i) = self.matches.get(self.selected) {
+            let chars: Vec<char> = single_line(self.candidates[i]).chars().collect();
+            for (row, chunk) in chars.chunks(width.max(1)).take(PREVIEW_ROWS as usize).enumerate() {
+                let line: String = chunk.iter().collect();
+                queue!(stdout, MoveTo(0, preview_top + row as u16), Print(line))?;
+            }
+        }
+        
+        stdout.flush()?;
+        Ok(())
+    }
+}
+
+/// Show a multi-line entry on one row
+fn single_line(entry: &str) -> String {
+    entry.replace('\n', " ⏎ ")
+}
+
+/// `text` cut to at most `width` columns
+fn fit(text: &str, width: usize) -> String {
+    let mut fitted = String::new();
+    for c in text.chars() {
+        fitted.push(c);
+        if prompt::display_width(&fitted) > width {
+            fitted.pop();
+            break;
+        }
+    }
+    fitted
+}
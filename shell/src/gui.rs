@@ -0,0 +1,203 @@
+//! Minimal terminal GUI: command input, scrollable output and an AI panel,
+//! all driven through `ObsidianShell::eval`.
+
+use super::*;
+use crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use crossterm::execute;
+use crossterm::terminal::{
+    disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen,
+};
+use ratatui::backend::CrosstermBackend;
+use ratatui::layout::{Constraint, Direction, Layout};
+use ratatui::style::{Color, Style};
+use ratatui::text::Line;
+use ratatui::widgets::{Block, Borders, Paragraph, Wrap};
+use ratatui::{Frame, Terminal};
+use std::time::Duration;
+
+/// How often the event loop wakes up when there is no input
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Default)]
+struct GuiState {
+    input: String,
+    output: Vec<String>,
+    /// Lines scrolled up from the bottom of the output
+    scroll: usize,
+    ai_panel: String,
+}
+
+/// Restores the terminal when the GUI exits, including on errors
+struct TerminalGuard;
+
+impl Drop for TerminalGuard {
+    fn drop(&mut self) {
+        let _ = disable_raw_mode();
+        let _ = execute!(io::stdout(), LeaveAlternateScreen);
+    }
+}
+
+/// Run the GUI until the user exits, returning the last exit status
+pub async fn run(shell: &mut ObsidianShell) -> Result<i32> {
+    enable_raw_mode()?;
+    execute!(io::stdout(), EnterAlternateScreen)?;
+    let _guard = TerminalGuard;
+    
+    let mut terminal = Terminal::new(CrosstermBackend::new(io::stdout()))?;
+    let mut state = GuiState {
+        ai_panel: "Natural language commands are interpreted here".to_string(),
+        ..Default::default()
+    };
+    
+    loop {
+        terminal.draw(|frame| draw(frame, &state))?;
+        
+        if !event::poll(POLL_INTERVAL)? {
+            continue;
+        }
+        
+        let Event::Key(key) = event::read()? else {
+            continue;
+        };
+        if key.kind != KeyEventKind::Press {
+            continue;
+        }
+        
+        match key {
+            KeyEvent { code: KeyCode::Esc, .. } => break,
+            KeyEvent {
+                code: KeyCode::Char('c' | 'd'),
+                modifiers: KeyModifiers::CONTROL,
+                ..
+            } => break,
+            KeyEvent { code: KeyCode::Enter, .. } => {
+                let input = std::mem::take(&mut state.input);
+                if input.trim().is_empty() {
+                    continue;
+                }
+                
+                let result = shell.eval(&input).await?;
+                state.record(&input, &result);
+                if result.exit_requested {
+                    break;
+                }
+            }
+            KeyEvent { code: KeyCode::Backspace, .. } => {
+                state.input.pop();
+            }
+            KeyEvent { code: KeyCode::Char(c), .. } => state.input.push(c),
+            KeyEvent { code: KeyCode::Up, .. } => state.scroll_by(1),
+            KeyEvent { code: KeyCode::Down, .. } => state.scroll_by(-1),
+            KeyEvent { code: KeyCode::PageUp, .. } => state.scroll_by(10),
+            KeyEvent { code: KeyCode::PageDown, .. } => state.scroll_by(-10),
+            _ => {}
+        }
+    }
+    
+    Ok(shell.last_status)
+}
+
+impl GuiState {
+    /// Append an evaluated command and its output
+    fn record(&mut self, input: &str, result: &CommandResult) {
+        // `clear` emits the terminal clear sequence; here it clears the output pane
+        if result.stdout.contains("\x1B[2J") {
+            self.output.clear();
+        } else {
+            self.output.push(format!("$ {}", input));
+            let stdout = match &result.line_filter {
+                Some(filter) => filter.apply(&result.stdout),
+                None => result.stdout.clone(),
+            };
+            self.output.extend(strip_ansi(&stdout).lines().map(String::from));
+            self.output.extend(strip_ansi(&result.stderr).lines().map(String::from));
+        }
+        self.scroll = 0;
+        
+        self.ai_panel = match (&result.interpreted, &result.ai_error) {
+            (Some(interpreted), _) => match &result.interpretation_reason {
+                Some(reason) => format!("🤖 {} → {}\n   because {}", input, interpreted, reason),
+                None => format!("🤖 {} → {}", input, interpreted),
+            },
+            (None, Some(e)) => match &result.ai_fallback {
+                Some(fallback) => format!("⚠️  AI interpretation failed: {}\n{}", e, fallback),
+                None => format!("⚠️  AI interpretation failed: {}", e),
+            },
+            (None, None) => String::new(),
+        };
+        for warning in &result.warnings {
+            self.ai_panel.push_str(&format!("\n⚠️  {}", warning));
+        }
+        for correction in &result.corrected {
+            self.ai_panel.push_str(&format!("\n✏️  Corrected {}", correction));
+        }
+        if let Some(shell) = &result.delegated_to {
+            self.ai_panel.push_str(&format!("\n🐚 Ran with {}", shell));
+        }
+    }
+    
+    fn scroll_by(&mut self, lines: isize) {
+        let max = self.output.len().saturating_sub(1) as isize;
+        self.scroll = (self.scroll as isize + lines).clamp(0, max) as usize;
+    }
+}
+
+fn draw(frame: &mut Frame, state: &GuiState) {
+    let chunks = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([Constraint::Min(3), Constraint::Length(3), Constraint::Length(3)])
+        .split(frame.size());
+    
+    // Show the tail of the output, shifted up by the scroll offset
+    let height = chunks[0].height.saturating_sub(2) as usize;
+    let end = state.output.len().saturating_sub(state.scroll);
+    let start = end.saturating_sub(height);
+    let lines: Vec<Line> = state.output[start..end]
+        .iter()
+        .map(|line| Line::from(line.as_str()))
+        .collect();
+    
+    let output = Paragraph::new(lines).block(
+        Block::default()
+            .borders(Borders::ALL)
+            .title(" 💠 Obsidian Shell — Esc to quit, ↑/↓ to scroll "),
+    );
+    frame.render_widget(output, chunks[0]);
+    
+    let ai_panel = Paragraph::new(state.ai_panel.as_str())
+        .style(Style::default().fg(Color::Cyan))
+        .wrap(Wrap { trim: true })
+        .block(Block::default().borders(Borders::ALL).title(" AI "));
+    frame.render_widget(ai_panel, chunks[1]);
+    
+    let input = Paragraph::new(format!("$ {}", state.input))
+        .block(Block::default().borders(Borders::ALL).title(" Command "));
+    frame.render_widget(input, chunks[2]);
+    
+    let cursor_x = chunks[2].x + 3 + state.input.chars().count() as u16;
+    frame.set_cursor(cursor_x.min(chunks[2].right().saturating_sub(2)), chunks[2].y + 1);
+}
+
+/// Drop ANSI escape sequences, which would garble the widgets
+fn strip_ansi(text: &str) -> String {
+    let mut plain = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    
+    while let Some(c) = chars.next() {
+        if c != '\x1B' {
+            plain.push(c);
+            continue;
+        }
+        
+        // CSI sequences end at the first byte in 0x40..=0x7E
+        if chars.next() == Some('[') {
+            for c in chars.by_ref() {
+                if ('\x40'..='\x7E').contains(&c) {
+                    break;
+                }
+            }
+        }
+    }
+    
+    plain
+}
@@ -0,0 +1,538 @@
+use super::*;
+use std::fs;
+use std::time::{Duration, SystemTime};
+
+/// Longest help text printed for an external command
+const MAX_HELP_LINES: usize = 30;
+/// How long to wait for tldr/man/--help before giving up
+const LOOKUP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Detailed usage for a shell builtin
+pub struct BuiltinHelp {
+    pub name: &'static str,
+    pub usage: &'static str,
+    pub summary: &'static str,
+    pub details: &'static str,
+}
+
+pub const BUILTINS: &[BuiltinHelp] = &[
+    BuiltinHelp {
+        name: "help",
+        usage: "help [--ai] [--refresh] [command]",
+        summary: "Show shell help or help for a command",
+        details: "Without an argument, prints the overview of builtins and AI features.\n\
+                  With a builtin name, prints its usage. For other commands, shows the\n\
+                  tldr page, the man page summary or the command's --help output, and\n\
+                  asks the AI when none of those are available.\n\
+                  --ai asks the AI for a summary directly.\n\
+                  What is found is cached until the command's program changes, so it\n\
+                  comes back at once and offline; --refresh looks it up again.",
+    },
+    BuiltinHelp {
+        name: "palette",
+        usage: "palette",
+        summary: "Search builtins, common actions and recent commands",
+        details: "Opens a full-screen list of builtins with their descriptions, common\n\
+                  actions such as cd - and recent commands. Typing narrows it with the\n\
+                  same fuzzy matching as the history finder, and Enter puts the choice\n\
+                  in the input line to be edited and run. F1 opens it while typing,\n\
+                  adding a pipe stage such as @json to the line; keybindings.palette\n\
+                  changes the key.",
+    },
+    BuiltinHelp {
+        name: "clear",
+        usage: "clear",
+        summary: "Clear the screen",
+        details: "Clears the terminal and moves the cursor to the top-left corner.",
+    },
+    BuiltinHelp {
+        name: "cd",
+        usage: "cd [dir | -]",
+        summary: "Change the current directory",
+        details: "Without an argument, changes to $HOME. `cd -` returns to the previous\n\
+                  directory. A leading ~ expands to $HOME. With load_dotenv enabled,\n\
+                  a .env file in the new directory is loaded.",
+    },
+    BuiltinHelp {
+        name: "pushd",
+        usage: "pushd [dir]",
+        summary: "Change directory, saving the current one on the stack",
+        details: "Pushes the current directory onto the directory stack and changes to dir,\n\
+                  then prints the stack. Without an argument, swaps the current directory\n\
+                  with the top of the stack.",
+    },
+    BuiltinHelp {
+        name: "popd",
+        usage: "popd",
+        summary: "Return to the directory on top of the stack",
+        details: "Removes the top of the directory stack, changes to it and prints the\n\
+                  stack. If it can't be entered, it is left on the stack.",
+    },
+    BuiltinHelp {
+        name: "dirs",
+        usage: "dirs",
+        summary: "List the directory stack",
+        details: "Prints the current directory followed by the directories saved by\n\
+                  pushd, most recent first, with $HOME shown as ~.",
+    },
+    BuiltinHelp {
+        name: "dotenv",
+        usage: "dotenv [--diff] [file]",
+        summary: "Load environment variables from a .env file",
+        details: "Reads KEY=value lines (default file: .env) into the shell's environment,\n\
+                  so later commands inherit them. Supports quoting, `export` prefixes and\n\
+                  # comments. --diff prints the variables added or changed instead of\n\
+                  their names.",
+    },
+    BuiltinHelp {
+        name: "source",
+        usage: "source [--diff] <file>",
+        summary: "Run a file with sh and keep the environment it sets",
+        details: "Runs the file with /bin/sh, then takes over the variables it exports\n\
+                  or unsets and the aliases it defines. `. file` does the same. What\n\
+                  the file prints is shown on stderr, and its exit status is kept.\n\
+                  sh runs in the sandbox and the command policy must allow it; a\n\
+                  system-locked policy refuses `source` altogether.\n\
+                  \n\
+                  --diff prints what changed, sorted by name: `+` for additions, `-`\n\
+                  for removals and `~` for new values, with secrets masked per the\n\
+                  redaction rules.",
+    },
+    BuiltinHelp {
+        name: "nopager",
+        usage: "nopager <command>",
+        summary: "Run a command without paging its output",
+        details: "With pager_enabled, output taller than the terminal is shown through\n\
+                  $PAGER (default `less -R`). Prefix a command with nopager to print\n\
+                  its output directly instead.",
+    },
+    BuiltinHelp {
+        name: "nocorrect",
+        usage: "nocorrect <command>",
+        summary: "Run a command without fixing typos from corrections",
+        details: "The command word is looked up in the corrections table before\n\
+                  aliases and builtins, so `gti status` runs `git status` and prints\n\
+                  the fix. Prefix a command with nocorrect to run it as typed.",
+    },
+    BuiltinHelp {
+        name: "alias",
+        usage: "alias [name[=value]]",
+        summary: "List or define aliases",
+        details: "Without an argument, lists all aliases. `alias name` shows one, and\n\
+                  `alias name='value'` defines one for this session; add permanent ones\n\
+                  to the [aliases] config table. An alias is expanded when it is the\n\
+                  first word of a command, with the rest of the command appended, or\n\
+                  put in its place with $1, $2, $# and \"$@\" (`alias gc='git commit -m \"$1\"'`).\n\
+                  It may start with its own name (`alias ls='ls --color'`), but chains\n\
+                  that lead back to it are refused.",
+    },
+    BuiltinHelp {
+        name: "unalias",
+        usage: "unalias <name>... | -a",
+        summary: "Remove aliases",
+        details: "Removes the named aliases, or all of them with -a.",
+    },
+    BuiltinHelp {
+        name: "type",
+        usage: "type [-a] <name>...",
+        summary: "Say what a command name runs",
+        details: "For each name, prints what running it would use: an alias and its\n\
+                  value, a builtin, or the program's path on PATH, checked in that\n\
+                  order. -a prints every match instead of the first. The status is 1\n\
+                  if any name is not found.",
+    },
+    BuiltinHelp {
+        name: "complete",
+        usage: "complete [-p] [name]... | -W <words> <name>... | -C <command> <name>... | -r <name>...",
+        summary: "Complete a command's arguments your way",
+        details: "With -W, Tab offers the matching words of the list (`complete -W 'start\n\
+                  stop status' svc`). With -C, it runs the command with the command name,\n\
+                  the word being completed and the word before it as arguments, and\n\
+                  COMP_LINE set, and offers the lines it prints that match; a command\n\
+                  that takes over 2 seconds is stopped. It runs under the command\n\
+                  policy and in the sandbox like any other. -r removes the completions of\n\
+                  the named commands, and alone or with -p, complete lists the ones\n\
+                  added this way. They last for the session.",
+    },
+    BuiltinHelp {
+        name: "copy",
+        usage: "<command> | copy  or  copy <text>",
+        summary: "Copy to the system clipboard",
+        details: "Puts the output of the previous pipeline stage, or the given text, on\n\
+                  the clipboard. Needs a display server and the `clipboard` build feature.",
+    },
+    BuiltinHelp {
+        name: "paste",
+        usage: "paste",
+        summary: "Print the system clipboard",
+        details: "Writes the clipboard's text to stdout, so it can be piped into another\n\
+                  command: `paste | wc -l`.",
+    },
+    BuiltinHelp {
+        name: "time",
+        usage: "time <command>",
+        summary: "Report a command's run time and resource use",
+        details: "Runs the command, then prints the wall-clock time, the user and system\n\
+                  CPU time and the peak memory of the external programs it ran. Peak\n\
+                  memory is left out when it is below the shell's own or an earlier\n\
+                  command's, as the system only tracks one peak for all of them.",
+    },
+    BuiltinHelp {
+        name: "on-change",
+        usage: "on-change [--ignore <pattern>]... <path>... -- <command>",
+        summary: "Rerun a command whenever files under the paths change",
+        details: "Watches the paths, directories recursively, and runs the command after\n\
+                  each change, once the files have stopped changing for a moment. A\n\
+                  header with the time and the changed files comes before each run.\n\
+                  --ignore skips files and directories whose name or path from the\n\
+                  watched directory matches a glob, e.g. `--ignore target/ --ignore '*.swp'`.\n\
+                  Quote a command with `&&` or `;` as one word. Ctrl-C stops watching.",
+    },
+    BuiltinHelp {
+        name: "jobs",
+        usage: "jobs",
+        summary: "List the jobs started with &",
+        details: "`<command> &` runs a pipeline of programs in the background, at most\n\
+                  max_background_jobs of them at once; the rest are queued until one\n\
+                  finishes. Lists each job as queued, running, done or with its exit\n\
+                  status. Finished jobs are listed once, followed by their output if it\n\
+                  couldn't be shown as they ran.",
+    },
+    BuiltinHelp {
+        name: "wait",
+        usage: "wait",
+        summary: "Wait for every background job to finish",
+        details: "Waits for the jobs started with &, queued ones included, and exits\n\
+                  with the status of the last one. Output of theirs that wasn't shown\n\
+                  as they ran comes with it.",
+    },
+    BuiltinHelp {
+        name: "in",
+        usage: "in <dir> -- <command>",
+        summary: "Run a command in another directory",
+        details: "Runs the command's programs, every stage of a pipeline, in the\n\
+                  directory without changing the shell's own (`in build -- make`).\n\
+                  Redirections and the paths `rm` and `tee` are given are relative to\n\
+                  it too; builtins that work on the shell's directory, like `cd` and\n\
+                  `source`, refuse to run. Not available with a remote host or a\n\
+                  sandbox working_dir.",
+    },
+    BuiltinHelp {
+        name: "retry",
+        usage: "retry [-n N] [--delay S] <command>",
+        summary: "Rerun a failing command until it succeeds",
+        details: "Runs the command up to N times, 3 by default, waiting S seconds (1\n\
+                  by default, fractions allowed) after each failure, and stops at the\n\
+                  first success. The output of failed attempts is printed as they\n\
+                  finish, and the status is the last attempt's. Ctrl-C stops retrying.",
+    },
+    BuiltinHelp {
+        name: "remote",
+        usage: "remote [host|off]",
+        summary: "Run external commands on another machine",
+        details: "With a host, runs every external command there over SSH until\n\
+                  `remote off`; the prompt shows @host meanwhile. Without an argument,\n\
+                  prints where commands run. Builtins such as cd still run locally.\n\
+                  \n\
+                  @host <command> runs a single command on host, builtins included.\n\
+                  Pipes and redirections around it stay local: in\n\
+                  `@web cat log | grep error > errors`, only cat runs on web.\n\
+                  \n\
+                  Hosts are names from [remote.hosts] in the configuration or\n\
+                  anything ssh accepts, including ~/.ssh/config aliases.",
+    },
+    BuiltinHelp {
+        name: "trash-restore",
+        usage: "trash-restore [path|name]...",
+        summary: "List the trash or restore files from it",
+        details: "Without arguments, lists what is in the trash with when it was\n\
+                  deleted and where from. With paths, moves each back to where it\n\
+                  was; a name from the trash works too. A path that exists again is\n\
+                  left alone.\n\
+                  \n\
+                  With trash_rm on, rm moves files here instead of deleting them.\n\
+                  It takes -r, -f, -d and -v; rm --permanent deletes for real.",
+    },
+    BuiltinHelp {
+        name: "trash-empty",
+        usage: "trash-empty",
+        summary: "Permanently delete everything in the trash",
+        details: "Deletes the files in the trash and the records of where they came\n\
+                  from. This cannot be undone.",
+    },
+    BuiltinHelp {
+        name: "undo",
+        usage: "undo [-l]",
+        summary: "Reverse what the last AI command did to files",
+        details: "Undoes the most recent AI-interpreted command that changed files,\n\
+                  one command per call. Only mkdir, mv and touch are understood:\n\
+                  created directories are removed if still empty, moved paths are\n\
+                  moved back if nothing has taken their place, and files touch created\n\
+                  are removed if still empty. Timestamps of touched files that already\n\
+                  existed are not restored.\n\
+                  \n\
+                  Other AI commands that change files, such as rm, cp or a `>`\n\
+                  redirection, are marked as impossible to undo when they run, and undo\n\
+                  reports them rather than reaching past them. -l lists what is\n\
+                  recorded, most recent first; the last 20 commands are kept.",
+    },
+    BuiltinHelp {
+        name: "good",
+        usage: "good [note]",
+        summary: "Record that the last AI interpretation was right",
+        details: "Appends the input, the AI's command and the verdict to the feedback\n\
+                  dataset, feedback.jsonl in the state directory unless ai_feedback.file\n\
+                  says otherwise. Secrets are masked and nothing leaves the machine.\n\
+                  Each interpretation can be rated once. Off unless ai_feedback.enabled\n\
+                  is set.",
+    },
+    BuiltinHelp {
+        name: "bad",
+        usage: "bad [note]",
+        summary: "Record that the last AI interpretation was wrong",
+        details: "Like good, with a bad verdict; the note can say what was meant. With\n\
+                  ai_feedback.bad_on_failure, interpretations whose command exits\n\
+                  non-zero are recorded as bad without asking.",
+    },
+    BuiltinHelp {
+        name: "suggest-package",
+        usage: "suggest-package <program>",
+        summary: "Name the package that provides a program",
+        details: "Looks the program up in the package_map file, a TOML table such as\n\
+                  `rg = \"ripgrep\"`, and prints nothing if it isn't listed.\n\
+                  \n\
+                  Set command_not_found = \"suggest-package\" to run it whenever a\n\
+                  program can't be found. command_not_found can name any other\n\
+                  builtin or command instead, e.g. /usr/lib/command-not-found; it is\n\
+                  run with the missing program's name and its output is shown after\n\
+                  the error.",
+    },
+    BuiltinHelp {
+        name: "@json",
+        usage: "<command> | @json [-r] [-c] [filter]",
+        summary: "Select parts of piped JSON",
+        details: "Parses the previous stage's output as JSON, or a stream of JSON\n\
+                  values, and prints what the filter selects, one value per result.\n\
+                  Filters are jq-style paths: . is the whole value, .name or .\"a b\"\n\
+                  a field, .[n] an element (negative counts from the end) and .[] each\n\
+                  element or field value, e.g. `.items[].name`. A missing field or\n\
+                  index gives null.\n\
+                  \n\
+                  -r prints strings without quotes; -c prints each value on one line.",
+    },
+    BuiltinHelp {
+        name: "?",
+        usage: "<command> | ?\"question\"",
+        summary: "Ask the AI about piped output",
+        details: "Sends the previous stage's output and the question to the AI, and\n\
+                  prints its answer, which can be piped on, e.g.\n\
+                  `ls -la | ?\"which of these is the largest\"`. Output beyond\n\
+                  ai_config.max_input_tokens (about four characters a token) is left\n\
+                  out, with a notice. A line with a ? stage is never interpreted as a\n\
+                  whole.",
+    },
+    BuiltinHelp {
+        name: "tee",
+        usage: "<command> | tee [-a] <file>...",
+        summary: "Save piped output to files and pass it on",
+        details: "Writes the output of the previous pipeline stage to each file and to\n\
+                  stdout. Files are truncated first unless -a (--append) is given.",
+    },
+    BuiltinHelp {
+        name: "history",
+        usage: "history",
+        summary: "Show command history",
+        details: "Lists the 10 most recent commands, oldest first, numbered from the\n\
+                  start of the history.\n\
+                  \n\
+                  !n recalls entry n into the input line to be edited before it runs;\n\
+                  !! recalls the last command and !-n the nth last. Add :p, as in !n:p,\n\
+                  to only print the entry. ^old^new runs the last command again with\n\
+                  the first old replaced by new.\n\
+                  \n\
+                  !! followed by a space and a command line is different: the line runs\n\
+                  as typed with $SHELL (or the fallback_shell setting), for syntax this\n\
+                  shell doesn't parse. Aliases, corrections and the AI are skipped.",
+    },
+    BuiltinHelp {
+        name: "exit",
+        usage: "exit [N]",
+        summary: "Exit the shell",
+        details: "Leaves the shell with status N (wrapped to 0-255), or with the exit\n\
+                  status of the last command when N is omitted.",
+    },
+    BuiltinHelp {
+        name: "quit",
+        usage: "quit [N]",
+        summary: "Exit the shell",
+        details: "Same as exit.",
+    },
+];
+
+/// Find the help entry for a builtin
+pub fn builtin_help(name: &str) -> Option<&'static BuiltinHelp> {
+    BUILTINS.iter().find(|builtin| builtin.name == name)
+}
+
+impl BuiltinHelp {
+    pub fn render(&self) -> String {
+        format!(
+            "{} - {}\n\nUsage: {}\n\n{}",
+            self.name, self.summary, self.usage, self.details
+        )
+    }
+}
+
+/// Help found for one command, as cached on disk
+#[derive(Debug, Serialize, Deserialize)]
+struct CachedHelp {
+    /// The program on `PATH` it describes, if there was one
+    program: Option<PathBuf>,
+    /// When `program` was last modified; the entry is stale once it changes
+    modified: Option<SystemTime>,
+    text: String,
+}
+
+/// Local documentation for an external command, from the cache when the
+/// command's program hasn't changed since it was looked up. `refresh` looks
+/// it up again regardless.
+pub async fn external_help(executor: &CommandExecutor, command: &str, refresh: bool) -> Option<String> {
+    let path = cache_path(command);
+    let program = which::which(command).ok();
+    let modified = program.as_ref().and_then(|program| fs::metadata(program).ok()?.modified().ok());
+    
+    if !refresh {
+        let cached = path
+            .as_ref()
+            .and_then(|path| fs::read(path).ok())
+            .and_then(|contents| serde_json::from_slice::<CachedHelp>(&contents).ok());
+        if let Some(cached) = cached.filter(|cached| cached.program == program && cached.modified == modified) {
+            return Some(cached.text);
+        }
+    }
+    
+    let text = look_up(executor, command, program.is_some()).await;
+    if let Some(path) = &path {
+        let result = match &text {
+            Some(text) => save(path, &CachedHelp { program, modified, text: text.clone() }),
+            // Nothing found now, so an old entry mustn't come back
+            None => match fs::remove_file(path) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+                _ => Ok(()),
+            },
+        };
+        if let Err(e) = result {
+            tracing::debug!(error = %e, path = %path.display(), "failed to update the help cache");
+        }
+    }
+    text
+}
+
+/// Where the help for `command` is cached; `None` for names that aren't a
+/// plain file name, such as paths
+fn cache_path(command: &str) -> Option<PathBuf> {
+    let plain = !command.is_empty() && !command.starts_with('.') && !command.contains(['/', '\\']);
+    plain.then(|| config::cache_dir().join("help").join(format!("{}.json", command)))
+}
+
+fn save(path: &Path, entry: &CachedHelp) -> io::Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    // Written beside the entry and renamed over it, so a reader never sees half
+    let temporary = path.with_extension("json.tmp");
+    fs::write(&temporary, serde_json::to_vec(entry)?)?;
+    fs::rename(&temporary, path)
+}
+
+/// Look up local documentation for an external command.
+///
+/// Tries `tldr`, then the NAME/SYNOPSIS/DESCRIPTION part of the man page, then
+/// `<command> --help` if it is `installed`, each run by `executor` so the
+/// command policy and sandbox apply. Returns `None` when none of them produce
+/// anything.
+async fn look_up(executor: &CommandExecutor, command: &str, installed: bool) -> Option<String> {
+    if let Some(page) = capture(executor, "tldr", &[command]).await {
+        return Some(truncate(&page));
+    }
+    
+    if let Some(page) = capture(executor, "man", &[command]).await {
+        return Some(truncate(&summarize_manpage(&page)));
+    }
+    
+    // Only run the command itself if it actually exists on PATH
+    if installed {
+        if let Some(usage) = capture(executor, command, &["--help"]).await {
+            return Some(truncate(&usage));
+        }
+    }
+    
+    None
+}
+
+/// Run a documentation command and return its output if it succeeded. One
+/// the policy refuses counts as producing nothing.
+async fn capture(executor: &CommandExecutor, program: &str, args: &[&str]) -> Option<String> {
+    let env = [("MANPAGER", "cat"), ("MANWIDTH", "80")].map(|(name, value)| (name.to_string(), value.to_string()));
+    let run = executor.execute(program, args, &env, None);
+    let result = tokio::time::timeout(LOOKUP_TIMEOUT, run).await.ok()?.ok()?;
+    if result.exit_code != 0 {
+        return None;
+    }
+    
+    let text = result.stdout.trim().to_string();
+    if text.is_empty() {
+        None
+    } else {
+        Some(text)
+    }
+}
+
+/// Keep the NAME and SYNOPSIS sections and the first paragraph of DESCRIPTION
+fn summarize_manpage(page: &str) -> String {
+    let mut summary = Vec::new();
+    let mut section = "";
+    let mut in_description = false;
+    
+    for line in page.lines() {
+        // Section headers are the only unindented lines in rendered man pages
+        if !line.is_empty() && !line.starts_with(char::is_whitespace) {
+            section = line.trim();
+            if matches!(section, "NAME" | "SYNOPSIS" | "DESCRIPTION") {
+                summary.push(line.to_string());
+            }
+            continue;
+        }
+        
+        match section {
+            "NAME" | "SYNOPSIS" => summary.push(line.to_string()),
+            "DESCRIPTION" => {
+                if line.trim().is_empty() {
+                    if in_description {
+                        break;
+                    }
+                    continue;
+                }
+                in_description = true;
+                summary.push(line.to_string());
+            }
+            _ => {}
+        }
+    }
+    
+    summary.join("\n").trim().to_string()
+}
+
+/// Cap help output at `MAX_HELP_LINES`
+fn truncate(text: &str) -> String {
+    let lines: Vec<&str> = text.lines().collect();
+    if lines.len() <= MAX_HELP_LINES {
+        return text.to_string();
+    }
+    
+    format!(
+        "{}\n... ({} more lines)",
+        lines[..MAX_HELP_LINES].join("\n"),
+        lines.len() - MAX_HELP_LINES
+    )
+}
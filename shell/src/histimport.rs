@@ -0,0 +1,151 @@
+//! Reading the history files of other shells, for `import-history`. Lines
+//! that can't be understood are skipped and counted rather than failing the
+//! import.
+
+/// Shells whose history can be imported
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Source {
+    /// `~/.bash_history`: one command per line, each optionally preceded by
+    /// a `#<unix time>` line when `HISTTIMEFORMAT` is set
+    Bash,
+    /// `~/.zsh_history`, plain or with `EXTENDED_HISTORY`'s
+    /// `: <unix time>:<duration>;<command>` lines
+    Zsh,
+    /// `~/.local/share/fish/fish_history`: `- cmd:` entries with `when:`
+    Fish,
+}
+
+/// One command read from another shell's history
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Imported {
+    /// Unix time it was run, if the file records it
+    pub time: Option<i64>,
+    pub command: String,
+}
+
+/// The commands in `contents`, oldest first, and how many lines were skipped
+pub fn parse(source: Source, contents: &[u8]) -> (Vec<Imported>, usize) {
+    match source {
+        Source::Bash => parse_bash(&String::from_utf8_lossy(contents)),
+        Source::Zsh => parse_zsh(&String::from_utf8_lossy(&unmetafy(contents))),
+        Source::Fish => parse_fish(&String::from_utf8_lossy(contents)),
+    }
+}
+
+fn parse_bash(contents: &str) -> (Vec<Imported>, usize) {
+    let mut imported = Vec::new();
+    let mut time = None;
+    
+    for line in contents.lines() {
+        if let Some(stamp) = line.strip_prefix('#').and_then(|stamp| stamp.parse().ok()) {
+            time = Some(stamp);
+            continue;
+        }
+        if line.trim().is_empty() {
+            continue;
+        }
+        imported.push(Imported {
+            time: time.take(),
+            command: line.to_string(),
+        });
+    }
+    (imported, 0)
+}
+
+fn parse_zsh(contents: &str) -> (Vec<Imported>, usize) {
+    let mut imported = Vec::new();
+    let mut skipped = 0;
+    let mut lines = contents.lines();
+    
+    while let Some(first) = lines.next() {
+        // A trailing backslash continues a multi-line command
+        let mut entry = first.to_string();
+        while entry.ends_with('\\') {
+            let Some(next) = lines.next() else { break };
+            entry.pop();
+            entry.push('\n');
+            entry.push_str(next);
+        }
+        
+        let (time, command) = match entry.strip_prefix(": ") {
+            Some(extended) => {
+                let parsed = extended.split_once(';').and_then(|(stamp, command)| {
+                    let (time, _duration) = stamp.split_once(':')?;
+                    Some((time.trim().parse().ok()?, command))
+                });
+                let Some((time, command)) = parsed else {
+                    skipped += 1;
+                    continue;
+                };
+                (Some(time), command)
+            }
+            None => (None, entry.as_str()),
+        };
+        if command.trim().is_empty() {
+            continue;
+        }
+        imported.push(Imported {
+            time,
+            command: command.to_string(),
+        });
+    }
+    (imported, skipped)
+}
+
+/// Undo zsh's escaping of bytes it uses internally: `0x83` followed by the
+/// byte XOR 32
+fn unmetafy(contents: &[u8]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(contents.len());
+    let mut iter = contents.iter();
+    while let Some(&byte) = iter.next() {
+        match byte {
+            0x83 => bytes.extend(iter.next().map(|next| next ^ 32)),
+            byte => bytes.push(byte),
+        }
+    }
+    bytes
+}
+
+fn parse_fish(contents: &str) -> (Vec<Imported>, usize) {
+    let mut imported: Vec<Imported> = Vec::new();
+    let mut skipped = 0;
+    
+    for line in contents.lines() {
+        if let Some(command) = line.strip_prefix("- cmd: ") {
+            imported.push(Imported {
+                time: None,
+                command: unescape_fish(command),
+            });
+        } else if let Some(when) = line.trim_start().strip_prefix("when: ") {
+            match (imported.last_mut(), when.trim().parse()) {
+                (Some(entry), Ok(time)) => entry.time = Some(time),
+                _ => skipped += 1,
+            }
+        } else if !(line.starts_with(char::is_whitespace) || line.trim().is_empty()) {
+            // `paths:` lists and their items are indented; anything else is
+            // not fish's format
+            skipped += 1;
+        }
+    }
+    (imported, skipped)
+}
+
+/// Fish writes newlines in a command as `\n` and backslashes as `\\`
+fn unescape_fish(command: &str) -> String {
+    let mut unescaped = String::with_capacity(command.len());
+    let mut chars = command.chars();
+    while let Some(c) = chars.next() {
+        match (c, chars.clone().next()) {
+            ('\\', Some('n')) => {
+                chars.next();
+                unescaped.push('\n');
+            }
+            ('\\', Some('\\')) => {
+                chars.next();
+                unescaped.push('\\');
+            }
+            (c, _) => unescaped.push(c),
+        }
+    }
+    unescaped
+}
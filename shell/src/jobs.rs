@@ -0,0 +1,178 @@
+//! Jobs started in the background with a trailing `&`. At most
+//! `max_background_jobs` of them run at once, each holding a slot of a
+//! semaphore while it runs; the rest are queued until a slot frees.
+
+use super::*;
+use std::future::Future;
+use tokio::sync::Semaphore;
+use tokio::task::JoinHandle;
+
+/// Where a job has got to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    /// Waiting for a slot
+    Queued,
+    Running,
+    /// Finished with this exit status
+    Done(i32),
+}
+
+impl std::fmt::Display for State {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            State::Queued => f.pad("queued"),
+            State::Running => f.pad("running"),
+            State::Done(0) => f.pad("done"),
+            State::Done(status) => f.pad(&format!("exit {}", status)),
+        }
+    }
+}
+
+struct Job {
+    id: usize,
+    command: String,
+    state: Arc<Mutex<State>>,
+    task: JoinHandle<CommandResult>,
+}
+
+/// The background jobs of a shell, numbered from 1 in the order started
+pub struct Jobs {
+    slots: Arc<Semaphore>,
+    jobs: Vec<Job>,
+    next_id: usize,
+}
+
+impl Jobs {
+    pub fn new(limit: usize) -> Self {
+        Jobs {
+            slots: Arc::new(Semaphore::new(limit.max(1))),
+            jobs: Vec::new(),
+            next_id: 1,
+        }
+    }
+    
+    /// Queue `run`, the work of `command`, to start once a slot is free,
+    /// returning the job's number
+    pub fn spawn(&mut self, command: &str, run: impl Future<Output = CommandResult> + Send + 'static) -> usize {
+        let state = Arc::new(Mutex::new(State::Queued));
+        let slots = self.slots.clone();
+        let job_state = state.clone();
+        let task = tokio::spawn(async move {
+            let _slot = slots.acquire_owned().await.expect("the job slots are never closed");
+            *job_state.lock().unwrap() = State::Running;
+            let result = run.await;
+            *job_state.lock().unwrap() = State::Done(result.exit_code);
+            result
+        });
+        
+        let id = self.next_id;
+        self.next_id += 1;
+        self.jobs.push(Job {
+            id,
+            command: command.to_string(),
+            state,
+            task,
+        });
+        id
+    }
+    
+    /// The state of each job, one per line. Finished jobs are listed this
+    /// once, followed by what they printed that wasn't shown as they ran.
+    pub async fn list(&mut self) -> CommandResult {
+        let mut listing = CommandResult::default();
+        let mut finished = Vec::new();
+        for job in std::mem::take(&mut self.jobs) {
+            let state = *job.state.lock().unwrap();
+            listing.stdout.push_str(&format!("[{}] {:<8} {}\n", job.id, state, job.command));
+            if matches!(state, State::Done(_)) {
+                finished.push(job);
+            } else {
+                self.jobs.push(job);
+            }
+        }
+        for job in finished {
+            collect(&mut listing, job).await;
+        }
+        listing.exit_code = 0;
+        listing
+    }
+    
+    /// Wait for every job to finish, returning what they printed that
+    /// wasn't shown as they ran, and the exit status of the last one
+    pub async fn wait(&mut self) -> CommandResult {
+        let mut result = CommandResult::default();
+        for job in std::mem::take(&mut self.jobs) {
+            collect(&mut result, job).await;
+        }
+        result
+    }
+}
+
+/// Add the output and exit status of `job`, waiting for it to finish
+async fn collect(result: &mut CommandResult, job: Job) {
+    match job.task.await {
+        Ok(output) => {
+            result.stdout.push_str(&output.stdout);
+            result.stderr.push_str(&output.stderr);
+            result.streamed.append(output.streamed);
+            result.warnings.extend(output.warnings);
+            result.exit_code = output.exit_code;
+        }
+        Err(e) => {
+            result.stderr.push_str(&format!("❌ [{}] {}: {}\n", job.id, job.command, e));
+            result.exit_code = 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::Notify;
+    
+    /// A job that runs until `release` is notified, then exits with `status`
+    fn held(release: &Arc<Notify>, status: i32) -> impl Future<Output = CommandResult> + Send + 'static {
+        let release = release.clone();
+        async move {
+            release.notified().await;
+            CommandResult {
+                stdout: format!("{}\n", status),
+                exit_code: status,
+                ..Default::default()
+            }
+        }
+    }
+    
+    async fn states(jobs: &Jobs) -> Vec<State> {
+        // Let the jobs take the slots that are free
+        tokio::task::yield_now().await;
+        jobs.jobs.iter().map(|job| *job.state.lock().unwrap()).collect()
+    }
+    
+    #[tokio::test]
+    async fn jobs_past_the_limit_wait_for_a_slot() {
+        let mut jobs = Jobs::new(2);
+        let releases: Vec<Arc<Notify>> = (0..3).map(|_| Arc::new(Notify::new())).collect();
+        for (i, release) in releases.iter().enumerate() {
+            assert_eq!(jobs.spawn(&format!("job {}", i), held(release, i as i32)), i + 1);
+        }
+        assert_eq!(states(&jobs).await, [State::Running, State::Running, State::Queued]);
+        assert_eq!(jobs.list().await.stdout, "[1] running  job 0\n[2] running  job 1\n[3] queued   job 2\n");
+        
+        // A finished job's slot goes to the queued one
+        releases[1].notify_one();
+        while states(&jobs).await != [State::Running, State::Done(1), State::Running] {}
+        let listing = jobs.list().await;
+        assert_eq!(listing.stdout, "[1] running  job 0\n[2] exit 1   job 1\n[3] running  job 2\n1\n");
+        assert_eq!(listing.exit_code, 0);
+        
+        // Finished jobs are listed once
+        assert_eq!(jobs.list().await.stdout, "[1] running  job 0\n[3] running  job 2\n");
+        
+        releases[0].notify_one();
+        releases[2].notify_one();
+        let result = jobs.wait().await;
+        assert_eq!((result.stdout.as_str(), result.exit_code), ("0\n2\n", 2));
+        assert_eq!(jobs.list().await.stdout, "");
+    }
+}
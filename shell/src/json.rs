@@ -0,0 +1,147 @@
+use serde_json::Value;
+
+/// One step of a path filter
+#[derive(Debug, Clone, PartialEq)]
+enum Step {
+    /// `.name` or `."name"` or `.["name"]`
+    Field(String),
+    /// `.[n]`; negative counts from the end
+    Index(i64),
+    /// `.[]`: every element of an array or value of an object
+    Iterate,
+}
+
+/// Apply a jq-style path such as `.items[0].name` or `.[].id` to every JSON
+/// value in `input`, returning the results in order.
+///
+/// As in jq, a missing field or index gives `null`; indexing a value of the
+/// wrong type is an error.
+pub fn filter(input: &str, filter: &str) -> Result<Vec<Value>, String> {
+    let steps = parse(filter)?;
+    let mut results = Vec::new();
+    
+    for value in serde_json::Deserializer::from_str(input).into_iter::<Value>() {
+        let value = value.map_err(|e| format!("invalid JSON input: {}", e))?;
+        let mut values = vec![value];
+        for step in &steps {
+            values = values
+                .into_iter()
+                .map(|value| apply(step, value))
+                .collect::<Result<Vec<_>, _>>()?
+                .into_iter()
+                .flatten()
+                .collect();
+        }
+        results.extend(values);
+    }
+    
+    Ok(results)
+}
+
+fn apply(step: &Step, value: Value) -> Result<Vec<Value>, String> {
+    match (step, value) {
+        (Step::Field(name), Value::Object(mut map)) => Ok(vec![map.remove(name).unwrap_or(Value::Null)]),
+        (Step::Field(_) | Step::Index(_), Value::Null) => Ok(vec![Value::Null]),
+        (Step::Field(name), value) => Err(format!("cannot index {} with \"{}\"", kind(&value), name)),
+        (Step::Index(index), Value::Array(mut items)) => {
+            let len = items.len() as i64;
+            let index = if *index < 0 { len + index } else { *index };
+            Ok(vec![if (0..len).contains(&index) {
+                items.swap_remove(index as usize)
+            } else {
+                Value::Null
+            }])
+        }
+        (Step::Index(index), value) => Err(format!("cannot index {} with {}", kind(&value), index)),
+        (Step::Iterate, Value::Array(items)) => Ok(items),
+        (Step::Iterate, Value::Object(map)) => Ok(map.into_iter().map(|(_, value)| value).collect()),
+        (Step::Iterate, value) => Err(format!("cannot iterate over {}", kind(&value))),
+    }
+}
+
+/// The JSON type of `value`, for error messages
+fn kind(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "boolean",
+        Value::Number(_) => "number",
+        Value::String(_) => "string",
+        Value::Array(_) => "array",
+        Value::Object(_) => "object",
+    }
+}
+
+/// Parse a path filter into its steps; `.` alone is the identity
+fn parse(filter: &str) -> Result<Vec<Step>, String> {
+    let invalid = |reason: &str| format!("invalid filter '{}': {}", filter, reason);
+    let mut steps = Vec::new();
+    let mut rest = filter.trim();
+    if !rest.starts_with('.') {
+        return Err(invalid("expected a path starting with '.'"));
+    }
+    
+    while let Some(c) = rest.chars().next() {
+        match c {
+            '.' => {
+                rest = &rest[1..];
+                if rest.starts_with('"') {
+                    let (name, tail) = quoted(rest).ok_or_else(|| invalid("unterminated string"))?;
+                    steps.push(Step::Field(name));
+                    rest = tail;
+                } else {
+                    let end = rest
+                        .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                        .unwrap_or(rest.len());
+                    let (name, tail) = rest.split_at(end);
+                    if name.starts_with(|c: char| c.is_ascii_digit()) {
+                        return Err(invalid("field names can't start with a digit; use .[n] to index"));
+                    }
+                    if !name.is_empty() {
+                        steps.push(Step::Field(name.to_string()));
+                    } else if !(tail.starts_with('[') || (tail.is_empty() && steps.is_empty())) {
+                        return Err(invalid("expected a field name after '.'"));
+                    }
+                    rest = tail;
+                }
+            }
+            '[' => {
+                let inner = rest[1..].trim_start();
+                let (step, tail) = if let Some(tail) = inner.strip_prefix(']') {
+                    (Step::Iterate, tail)
+                } else if inner.starts_with('"') {
+                    let (name, tail) = quoted(inner).ok_or_else(|| invalid("unterminated string"))?;
+                    let tail = tail.trim_start().strip_prefix(']').ok_or_else(|| invalid("expected ']'"))?;
+                    (Step::Field(name), tail)
+                } else {
+                    let (index, tail) = inner.split_once(']').ok_or_else(|| invalid("expected ']'"))?;
+                    let index = index
+                        .trim()
+                        .parse()
+                        .map_err(|_| invalid("expected an index, a string or nothing in '[]'"))?;
+                    (Step::Index(index), tail)
+                };
+                steps.push(step);
+                rest = tail;
+            }
+            _ => return Err(invalid("expected '.' or '['")),
+        }
+    }
+    
+    Ok(steps)
+}
+
+/// Split a leading JSON string off `text`, returning its value and the rest
+fn quoted(text: &str) -> Option<(String, &str)> {
+    let mut escaped = false;
+    for (i, c) in text.char_indices().skip(1) {
+        match c {
+            '\\' if !escaped => escaped = true,
+            '"' if !escaped => {
+                let name = serde_json::from_str(&text[..=i]).ok()?;
+                return Some((name, &text[i + 1..]));
+            }
+            _ => escaped = false,
+        }
+    }
+    None
+}
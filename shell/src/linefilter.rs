@@ -0,0 +1,80 @@
+use super::*;
+use parser::TokenKind;
+use regex::{Regex, RegexBuilder};
+
+/// A `g/pattern/` suffix on a line: only the output lines matching the
+/// regex are shown. It is applied when displaying, so recordings, the
+/// output FIFO and pipes within the line still see all of it.
+#[derive(Debug, Clone)]
+pub struct LineFilter {
+    pattern: Regex,
+    /// Show the lines that don't match instead (`v`)
+    invert: bool,
+}
+
+impl LineFilter {
+    /// Split a trailing `g/pattern/` word off `line`. Flags may follow the
+    /// last `/`: `i` ignores case and `v` keeps the lines that don't match.
+    /// A `/` in the pattern is written `\/`.
+    pub fn strip(line: &str) -> Result<(Option<LineFilter>, &str), String> {
+        let mut tokens = parser::tokenize(line);
+        let Some(token) = tokens.pop() else {
+            return Ok((None, line));
+        };
+        // The target of `> g/x/` is a path
+        if tokens.last().is_some_and(|before| matches!(before.kind, TokenKind::Redirect(_))) {
+            return Ok((None, line));
+        }
+        // As written, so a quoted `"g/x/"` stays an argument
+        let word = &line[token.span.clone()];
+        let command = line[..token.span.start].trim_end();
+        let Some(spec) = word.strip_prefix("g/").filter(|_| !command.is_empty()) else {
+            return Ok((None, line));
+        };
+        let Some((pattern, flags)) = split_pattern(spec) else {
+            return Ok((None, line));
+        };
+        if !flags.chars().all(|flag| matches!(flag, 'i' | 'v')) {
+            return Ok((None, line));
+        }
+        
+        let pattern = RegexBuilder::new(&pattern)
+            .case_insensitive(flags.contains('i'))
+            .build()
+            .map_err(|e| format!("invalid pattern in '{}': {}", word, e))?;
+        let filter = LineFilter {
+            pattern,
+            invert: flags.contains('v'),
+        };
+        Ok((Some(filter), command))
+    }
+    
+    /// The lines of `text` to show
+    pub fn apply(&self, text: &str) -> String {
+        text.lines()
+            .filter(|line| self.pattern.is_match(line) != self.invert)
+            .flat_map(|line| [line, "\n"])
+            .collect()
+    }
+}
+
+/// Split `pattern/flags` at the first unescaped `/`, turning `\/` into `/`
+fn split_pattern(spec: &str) -> Option<(String, &str)> {
+    let mut pattern = String::new();
+    let mut chars = spec.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '/' => return Some((pattern, &spec[i + 1..])),
+            '\\' => match chars.next() {
+                Some((_, '/')) => pattern.push('/'),
+                Some((_, escaped)) => {
+                    pattern.push('\\');
+                    pattern.push(escaped);
+                }
+                None => return None,
+            },
+            c => pattern.push(c),
+        }
+    }
+    None
+}
@@ -0,0 +1,23 @@
+use super::*;
+use tracing_subscriber::EnvFilter;
+
+/// Send logs to stderr, keeping stdout for command output.
+///
+/// `--log-level` applies to the shell's own logs and wins over `RUST_LOG`,
+/// which takes full filter directives. Without either, only warnings and
+/// errors are shown.
+pub fn init(level: Option<&str>) -> Result<()> {
+    let filter = match level {
+        Some(level) => EnvFilter::try_new(format!("warn,obsidian_shell={}", level))
+            .with_context(|| format!("Invalid log level '{}'", level))?,
+        None => EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("warn")),
+    };
+    
+    tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(io::stderr)
+        .with_ansi(ui::stderr_color())
+        .init();
+    
+    Ok(())
+}
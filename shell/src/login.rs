@@ -0,0 +1,234 @@
+use super::*;
+use std::process::Stdio;
+
+/// Profiles a POSIX login shell reads, in order. They are written for sh,
+/// so sh runs them and the environment they leave behind is imported.
+const PROFILES: &[&str] = &["/etc/profile", "$HOME/.profile"];
+
+/// Variables that describe the sh process rather than the session
+const SKIPPED: &[&str] = &["_", "PWD", "OLDPWD", "SHLVL"];
+
+/// What sh left behind after running some files
+struct Sourced {
+    /// Exit status of the last file
+    status: i32,
+    /// The exported environment, minus `SKIPPED`
+    env: Vec<(String, String)>,
+    /// Variables sh started with that the files unset
+    unset: Vec<String>,
+    /// Aliases the files defined
+    aliases: Vec<(String, String)>,
+}
+
+/// Whether the shell was started as a login shell: by `login` or `su -`,
+/// which put a `-` in front of `argv[0]`
+pub fn invoked_as_login() -> bool {
+    env::args_os().next().is_some_and(|arg0| is_login_name(&arg0))
+}
+
+/// Whether `arg0` is the name a login shell is started under
+fn is_login_name(arg0: &std::ffi::OsStr) -> bool {
+    arg0.as_encoded_bytes().starts_with(b"-")
+}
+
+/// Run the login profiles with `/bin/sh` and copy the environment they set
+/// into this process. What the profiles print is passed through to stderr.
+pub fn load_profiles() -> Result<()> {
+    let sources: Vec<String> = PROFILES
+        .iter()
+        .map(|path| format!("[ -r \"{0}\" ] && . \"{0}\"", path))
+        .collect();
+    let sourced = run_sh(Command::new("/bin/sh"), &sources.join("\n"), &[])
+        .context("Loading the login profiles failed")?;
+    import_env(&sourced.env, &[]);
+    Ok(())
+}
+
+/// The `source` builtin's work: run `path` with `/bin/sh`, then copy the
+/// environment it leaves into this process, removing what it unset.
+/// Returns its exit status and the aliases it defined.
+///
+/// sh runs like any other command, so the policy has to allow it and the
+/// sandbox confines it; variables the sandbox kept from it are left alone.
+pub fn source(executor: &CommandExecutor, path: &Path) -> Result<(i32, Vec<(String, String)>)> {
+    if !path.is_file() {
+        anyhow::bail!("{}: No such file", path.display());
+    }
+    // Absolute, since the sandbox may run sh elsewhere, and so sh doesn't
+    // look a bare name up in PATH
+    let path = env::current_dir()?.join(path);
+    
+    let sh = executor.prepare("/bin/sh", &[], &[])?;
+    let sourced = run_sh(sh, ". \"$1\"", &[path.as_os_str()])
+        .with_context(|| format!("Sourcing {} failed", path.display()))?;
+    import_env(&sourced.env, &sourced.unset);
+    Ok((sourced.status, sourced.aliases))
+}
+
+/// Run `body` with `sh`, `args` being `$1`…, and collect what it left
+/// behind. Its own output goes to stderr, leaving stdout for reporting back.
+fn run_sh(mut sh: Command, body: &str, args: &[&std::ffi::OsStr]) -> Result<Sourced> {
+    let script = format!(
+        "exec 3>&1 1>&2\nenv -0 >&3\nprintf '\\0' >&3\n{}\nstatus=$?\nalias >&3\nprintf '\\0' >&3\nenv -0 >&3\nexit $status",
+        body
+    );
+    let output = sh
+        .arg("-c")
+        .arg(script)
+        .arg("sh")
+        .args(args)
+        .stdin(Stdio::null())
+        .stderr(Stdio::inherit())
+        .output()
+        .context("Failed to run /bin/sh")?;
+    
+    // Entries are never empty, so the environment sh started with ends at
+    // the first empty one
+    let stdout = &output.stdout;
+    let Some(end) = (0..stdout.len()).find(|&i| stdout[i] == 0 && (i == 0 || stdout[i - 1] == 0)) else {
+        anyhow::bail!("/bin/sh didn't start ({})", output.status);
+    };
+    let (started, rest) = (parse_env(&stdout[..end]), &stdout[end + 1..]);
+    // Without the separator sh stopped early, e.g. on a syntax error
+    let Some(separator) = rest.iter().position(|&byte| byte == 0) else {
+        anyhow::bail!("/bin/sh stopped early ({})", output.status);
+    };
+    let (aliases, env) = rest.split_at(separator);
+    
+    let env = parse_env(&env[1..]);
+    let unset = started
+        .into_iter()
+        .filter(|(name, _)| !env.iter().any(|(kept, _)| kept == name))
+        .map(|(name, _)| name)
+        .collect();
+    // dash prints `name='value'`, bash `alias name='value'`
+    let aliases = String::from_utf8_lossy(aliases)
+        .lines()
+        .filter_map(|line| {
+            let line = line.strip_prefix("alias ").unwrap_or(line);
+            let word = parser::split_words(line).into_iter().next()?;
+            let (name, value) = word.split_once('=')?;
+            Some((name.to_string(), value.to_string()))
+        })
+        .collect();
+    
+    Ok(Sourced {
+        status: output.status.code().unwrap_or(1),
+        env,
+        unset,
+        aliases,
+    })
+}
+
+/// The variables in `env -0` output, minus `SKIPPED`
+fn parse_env(bytes: &[u8]) -> Vec<(String, String)> {
+    bytes
+        .split(|&byte| byte == 0)
+        .filter_map(|entry| {
+            let entry = String::from_utf8_lossy(entry);
+            let (name, value) = entry.split_once('=')?;
+            (!name.is_empty() && !SKIPPED.contains(&name)).then(|| (name.to_string(), value.to_string()))
+        })
+        .collect()
+}
+
+/// Set `vars` in this process's environment and remove `unset`
+fn import_env(vars: &[(String, String)], unset: &[String]) {
+    for (name, value) in vars {
+        if env::var(name).ok().as_deref() != Some(value.as_str()) {
+            env::set_var(name, value);
+        }
+    }
+    for name in unset {
+        env::remove_var(name);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::OsStr;
+    
+    #[test]
+    fn a_leading_dash_marks_a_login_shell() {
+        assert!(is_login_name(OsStr::new("-obsidian-shell")));
+        assert!(is_login_name(OsStr::new("-sh")));
+        assert!(!is_login_name(OsStr::new("obsidian-shell")));
+        assert!(!is_login_name(OsStr::new("/usr/bin/obsidian-shell")));
+        assert!(!is_login_name(OsStr::new("")));
+    }
+    
+    #[cfg(unix)]
+    #[test]
+    fn login_names_need_not_be_utf8() {
+        use std::os::unix::ffi::OsStrExt;
+        assert!(is_login_name(OsStr::from_bytes(b"-\xffshell")));
+        assert!(!is_login_name(OsStr::from_bytes(b"\xff-shell")));
+    }
+    
+    #[test]
+    fn sh_reports_its_environment_aliases_and_status() {
+        let sourced = run_sh(
+            Command::new("/bin/sh"),
+            "export OBSIDIAN_LOGIN_TEST='a b'\nalias ll='ls -l'\necho noise\n[ \"$1\" = arg ] && false",
+            &[OsStr::new("arg")],
+        )
+        .unwrap();
+        assert_eq!(sourced.status, 1);
+        assert!(sourced.env.contains(&("OBSIDIAN_LOGIN_TEST".to_string(), "a b".to_string())));
+        assert!(!sourced.env.iter().any(|(name, _)| SKIPPED.contains(&name.as_str())));
+        assert_eq!(sourced.aliases, vec![("ll".to_string(), "ls -l".to_string())]);
+    }
+    
+    #[test]
+    fn sh_reports_what_was_unset() {
+        let mut sh = Command::new("/bin/sh");
+        sh.env("OBSIDIAN_LOGIN_DROPPED", "1").env("OBSIDIAN_LOGIN_KEPT", "1");
+        let sourced = run_sh(sh, "unset OBSIDIAN_LOGIN_DROPPED", &[]).unwrap();
+        assert_eq!(sourced.status, 0);
+        assert_eq!(sourced.unset, vec!["OBSIDIAN_LOGIN_DROPPED".to_string()]);
+        assert!(sourced.env.iter().any(|(name, _)| name == "OBSIDIAN_LOGIN_KEPT"));
+        
+        let stopped = run_sh(Command::new("/bin/sh"), "exit 3", &[]);
+        assert!(stopped.is_err_and(|e| e.to_string().contains("stopped early")));
+    }
+    
+    fn executor(policy: config::CommandPolicy, sandbox: config::SandboxConfig) -> CommandExecutor {
+        CommandExecutor::new(policy, sandbox, config::RemoteConfig::default(), Redactor::default(), encoding_rs::UTF_8)
+    }
+    
+    #[test]
+    fn source_obeys_the_policy() {
+        let tmp = tempfile::tempdir().unwrap();
+        let file = tmp.path().join("env.sh");
+        std::fs::write(&file, "export OBSIDIAN_LOGIN_REFUSED=1\n").unwrap();
+        
+        let policy = config::CommandPolicy {
+            blocked_commands: vec!["sh".to_string()],
+            ..Default::default()
+        };
+        let error = source(&executor(policy, Default::default()), &file).unwrap_err();
+        assert_eq!(commands::exit_code_for_error(&error), commands::EXIT_NOT_EXECUTABLE);
+        assert!(env::var_os("OBSIDIAN_LOGIN_REFUSED").is_none());
+    }
+    
+    #[test]
+    fn source_keeps_what_the_sandbox_hid() {
+        let tmp = tempfile::tempdir().unwrap();
+        let file = tmp.path().join("env.sh");
+        std::fs::write(&file, "export OBSIDIAN_LOGIN_SANDBOXED=\"$HOME\"\n").unwrap();
+        
+        let sandbox = config::SandboxConfig {
+            env_allowlist: Some(vec!["PATH".to_string()]),
+            working_dir: Some(tmp.path().to_path_buf()),
+            ..Default::default()
+        };
+        let home = env::var_os("HOME");
+        let (status, _) = source(&executor(Default::default(), sandbox), &file).unwrap();
+        assert_eq!(status, 0);
+        // sh never saw HOME, so it neither read it nor unset it here
+        assert_eq!(env::var("OBSIDIAN_LOGIN_SANDBOXED").as_deref(), Ok(""));
+        assert_eq!(env::var_os("HOME"), home);
+        env::remove_var("OBSIDIAN_LOGIN_SANDBOXED");
+    }
+}
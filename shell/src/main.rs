@@ -1,28 +1,69 @@
-/**
- * 💠 Obsidian OS Shell - Main Entry Point
- * AI-powered shell with natural language processing capabilities
- */
+//! 💠 Obsidian OS Shell - Main Entry Point
+//! AI-powered shell with natural language processing capabilities
 
 use std::io::{self, Write};
 use std::process::Command;
+use std::borrow::Cow;
 use std::env;
-use std::path::Path;
-use tokio;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use serde::{Deserialize, Serialize};
 use anyhow::{Result, Context};
-use clap::{Parser, Subcommand};
+use clap::{CommandFactory, Parser, Subcommand};
 
+#[macro_use]
+mod output;
 mod ai;
+mod aicontext;
+mod aimock;
+mod alias;
+mod bench;
+mod clipboard;
 mod commands;
+mod help;
+mod logging;
+mod models;
+mod pager;
+mod prompt;
+mod redact;
+mod redirect;
+mod session;
+mod tee;
+mod trash;
+mod undo;
 mod history;
+mod histimport;
+mod jobs;
+mod json;
+mod linefilter;
+mod login;
+#[cfg(feature = "gui")]
+mod gui;
+mod parser;
 mod completion;
 mod config;
+mod correct;
+mod dotenv;
+mod envdiff;
+mod export;
+mod feedback;
+mod editor;
+mod fifo;
+mod finder;
+mod palette;
+mod ui;
+mod watch;
 
 use ai::AIEngine;
-use commands::CommandExecutor;
-use history::CommandHistory;
+use alias::Aliases;
+use commands::{CommandExecutor, CommandResult};
+use history::{CommandHistory, Recall};
 use completion::CommandCompletion;
-use config::ShellConfig;
+use config::{AiApply, AiFallback, IdleAction, ShellConfig};
+use editor::{Input, LineEditor};
+use redact::Redactor;
+use session::{SessionEntry, SessionRecorder};
 
 /// AI-powered shell for Obsidian OS
 #[derive(Parser)]
@@ -33,17 +74,78 @@ struct Cli {
     #[command(subcommand)]
     command: Option<Commands>,
     
-    /// Enable AI assistance
+    /// Interpret every command with the AI, not just natural-language ones
     #[arg(short, long)]
     ai: bool,
     
+    /// Disable AI interpretation entirely
+    #[arg(long, conflicts_with = "ai")]
+    no_ai: bool,
+    
     /// Enable GUI mode
     #[arg(short, long)]
     gui: bool,
     
+    /// Only print command output and errors: no banner or AI notices
+    #[arg(short, long)]
+    quiet: bool,
+    
+    /// Don't show the welcome banner or configured motd
+    #[arg(long)]
+    no_motd: bool,
+    
+    /// Print each prompt on stderr exactly as it is sent to the AI, and how
+    /// long each stage of a pipeline ran
+    #[arg(short, long)]
+    verbose: bool,
+    
     /// Configuration file path
-    #[arg(short, long, default_value = "~/.config/obsidian-shell/config.toml")]
+    #[arg(long, default_value = "~/.config/obsidian-shell/config.toml")]
     config: String,
+    
+    /// Run this command line and exit with its status, as `sh -c` does.
+    /// Any arguments after it are $0, $1 and so on.
+    #[arg(short = 'c', value_name = "COMMAND", num_args = 1.., allow_hyphen_values = true)]
+    command_string: Option<Vec<String>>,
+    
+    /// Act as a login shell, loading /etc/profile and ~/.profile first. Implied
+    /// when started with a `-` in front of the program name, as `login` does.
+    #[arg(short, long)]
+    login: bool,
+    
+    /// Continue the AI conversation saved in this context file
+    #[arg(long, value_name = "FILE")]
+    replay: Option<PathBuf>,
+    
+    /// Record every command and its output to this session file
+    #[arg(long, value_name = "FILE")]
+    record: Option<PathBuf>,
+    
+    /// Read commands from this FIFO instead of the terminal, creating it if
+    /// needed; it is reopened whenever a writer closes it
+    #[arg(long, value_name = "PATH")]
+    command_fifo: Option<PathBuf>,
+    
+    /// Write each result of --command-fifo to this FIFO as a line of JSON
+    #[arg(long, value_name = "PATH", requires = "command_fifo")]
+    output_fifo: Option<PathBuf>,
+    
+    /// When to colour output, overriding the `color` setting
+    #[arg(long, global = true, value_name = "WHEN", value_enum)]
+    color: Option<ui::ColorChoice>,
+    
+    /// Log verbosity (error, warn, info, debug, trace); overrides RUST_LOG
+    #[arg(long, value_name = "LEVEL")]
+    log_level: Option<String>,
+    
+    /// Maximum tokens per AI response, overriding the config for this run
+    #[arg(long, global = true, value_name = "N", value_parser = clap::value_parser!(u32).range(1..))]
+    max_tokens: Option<u32>,
+    
+    /// AI sampling temperature from 0.0 (most deterministic) to 2.0,
+    /// overriding the config for this run
+    #[arg(long, global = true, value_name = "T", value_parser = parse_temperature)]
+    temperature: Option<f32>,
 }
 
 #[derive(Subcommand)]
@@ -58,14 +160,127 @@ enum Commands {
         interpret: bool,
     },
     
+    /// Print the shell command the AI reads into natural language input,
+    /// without running it, e.g. `cmd=$(obsidian-shell interpret "find big logs")`
+    Interpret {
+        /// Natural language input. Several are interpreted in one request and
+        /// printed one command per line, in order, with a blank line for any
+        /// that failed; `-` reads them from stdin, one per line.
+        #[arg(required = true)]
+        inputs: Vec<String>,
+        
+        /// Ask every backend in `ai_backends`, the main one and the offline
+        /// rules, and print their answers side by side. Nothing is run.
+        #[arg(long)]
+        compare: bool,
+    },
+    
     /// Start interactive shell
     Interactive,
     
+    /// Re-run the commands of a session recorded with --record
+    Replay {
+        /// Session file to replay
+        file: PathBuf,
+        
+        /// Playback speed relative to the recording (2.0 = twice as fast)
+        #[arg(long, default_value_t = 1.0)]
+        speed: f64,
+        
+        /// Run commands back to back, ignoring recorded timing
+        #[arg(long)]
+        no_delay: bool,
+    },
+    
+    /// Write a session recorded with --record as a Markdown or HTML page,
+    /// each command in a block with its output and exit status
+    ExportSession {
+        /// Session file to export
+        file: PathBuf,
+        
+        /// File to write; stdout if not given
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+        
+        /// Defaults to HTML when the output file ends in .html or .htm
+        #[arg(long, value_enum)]
+        format: Option<export::Format>,
+    },
+    
+    /// Measure how long a command takes to run through the shell
+    Bench {
+        /// Command to run
+        command: String,
+        
+        /// Number of timed runs
+        #[arg(long, default_value_t = 100, value_parser = clap::value_parser!(u32).range(1..))]
+        iterations: u32,
+        
+        /// Untimed runs before measuring
+        #[arg(long, default_value_t = 10)]
+        warmup: u32,
+    },
+    
     /// Show shell configuration
-    Config,
+    Config {
+        #[command(subcommand)]
+        action: Option<ConfigAction>,
+    },
     
     /// Update AI models
-    UpdateModels,
+    UpdateModels {
+        /// Show which models would be downloaded, updated or skipped, and
+        /// the total download size, without downloading anything
+        #[arg(long)]
+        dry_run: bool,
+    },
+    
+    /// Merge another shell's history into this one's. Commands already in
+    /// the history are skipped, and recorded times are kept and sorted by.
+    ImportHistory {
+        /// Shell that wrote the file
+        #[arg(long, value_enum)]
+        from: histimport::Source,
+        
+        /// History file, e.g. ~/.zsh_history
+        file: PathBuf,
+    },
+    
+    /// Print a completion script for this program, e.g. for bash:
+    /// `obsidian-shell completions bash > ~/.local/share/bash-completion/completions/obsidian-shell`
+    Completions {
+        /// Shell to generate the script for
+        shell: clap_complete::Shell,
+    },
+}
+
+#[derive(Subcommand)]
+enum ConfigAction {
+    /// Print a JSON Schema of the configuration file, for editors and other
+    /// tools to validate config.toml against
+    Schema,
+}
+
+/// How AI interpretation is applied to entered commands
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum AiMode {
+    /// Interpret input that looks like natural language, if enabled in config
+    Auto,
+    /// Interpret every command
+    Always,
+    /// Never interpret
+    Off,
+}
+
+impl AiMode {
+    /// Mode selected by the `--ai`/`--no-ai` flags
+    fn from_flags(ai: bool, no_ai: bool) -> Self {
+        match (ai, no_ai) {
+            (true, _) => AiMode::Always,
+            (_, true) => AiMode::Off,
+            _ => AiMode::Auto,
+        }
+    }
 }
 
 /// Main shell structure
@@ -73,18 +288,134 @@ struct ObsidianShell {
     ai_engine: AIEngine,
     command_executor: CommandExecutor,
     history: CommandHistory,
-    completion: CommandCompletion,
+    completion: Arc<Mutex<CommandCompletion>>,
     config: ShellConfig,
+    /// Exit status of the most recent command
+    last_status: i32,
+    /// How long the most recent input took to run
+    last_duration: Option<Duration>,
+    ai_mode: AiMode,
+    /// Directory before the last `cd`, for `cd -`
+    previous_dir: Option<PathBuf>,
+    /// Directories saved by `pushd`, most recent last
+    dir_stack: Vec<PathBuf>,
+    /// Host set with `remote` that external commands run on
+    remote_host: Option<String>,
+    /// Session recording enabled with `--record`
+    recorder: Option<SessionRecorder>,
+    aliases: Aliases,
+    clipboard: clipboard::Clipboard,
+    prompt_cache: prompt::PromptCache,
+    /// Masks secrets in what is saved or logged
+    redactor: Redactor,
+    /// Set while the `command_not_found` handler runs, so a missing handler
+    /// isn't run for itself
+    handling_not_found: bool,
+    /// Pipelines being run inside one another, for `max_expansion_depth`
+    depth: usize,
+    /// What AI commands changed on disk, for `undo`
+    undo: undo::UndoStack,
+    /// The last AI interpretation, for `good` and `bad`
+    feedback: feedback::Feedback,
+    /// What the most recent command printed, for `$_OUT` and `$_ERR`
+    last_output: LastOutput,
+    /// `--verbose`: also report how long each pipeline stage took
+    verbose: bool,
+    /// `--ai` or `--no-ai`, if one of them chose `ai_mode`
+    ai_flag: Option<&'static str>,
+    /// Where questions and the output of commands still running go; without
+    /// one, nothing is asked and that output is part of the result
+    frontend: Option<Box<dyn Frontend>>,
+    /// Set while the output of the last program of a pipeline is shown as it
+    /// runs, teed by the executor
+    streaming: bool,
+    /// Pipelines started with `&`
+    jobs: jobs::Jobs,
+}
+
+/// What `eval` needs from whoever runs it while a line is running
+trait Frontend: Send {
+    /// Show the output of a command that is still going, e.g. a failed
+    /// `retry` attempt or one `on-change` run
+    fn progress(&mut self, output: &CommandResult);
+    
+    /// Ask `question` and wait for a yes or no
+    fn confirm(&mut self, question: &str) -> bool;
+    
+    /// Whether commands may write their output to this process's stdout and
+    /// stderr themselves as it arrives
+    fn streams_output(&self) -> bool;
+}
+
+/// A `Frontend` printing to the terminal and asking there
+struct Terminal {
+    quiet: bool,
+    pager_enabled: bool,
+    confirm_timeout: Option<Duration>,
+}
+
+impl Frontend for Terminal {
+    fn progress(&mut self, output: &CommandResult) {
+        show_result(output, self.quiet, self.pager_enabled);
+    }
+    
+    fn confirm(&mut self, question: &str) -> bool {
+        editor::confirm(question, self.confirm_timeout).unwrap_or(false)
+    }
+    
+    fn streams_output(&self) -> bool {
+        true
+    }
+}
+
+/// A command's output as kept for `$_OUT` and `$_ERR`, each stream cut to
+/// `output_variable_limit`
+#[derive(Debug, Default)]
+struct LastOutput {
+    stdout: String,
+    stderr: String,
+    /// Whether either stream was cut
+    truncated: bool,
+}
+
+impl LastOutput {
+    fn new(result: &CommandResult, limit: usize) -> Self {
+        let (stdout, stderr) = (result.all_stdout(), result.all_stderr());
+        let (stdout, stdout_cut) = cap_output(&stdout, limit);
+        let (stderr, stderr_cut) = cap_output(&stderr, limit);
+        LastOutput {
+            stdout: stdout.to_string(),
+            stderr: stderr.to_string(),
+            truncated: stdout_cut || stderr_cut,
+        }
+    }
 }
 
 impl ObsidianShell {
     /// Create a new shell instance
-    fn new(config_path: &str) -> Result<Self> {
+    fn new(config_path: &str, ai_mode: AiMode) -> Result<Self> {
         let config = ShellConfig::load(config_path)?;
-        let ai_engine = AIEngine::new(&config.ai_config)?;
-        let command_executor = CommandExecutor::new();
-        let history = CommandHistory::new(&config.history_path)?;
-        let completion = CommandCompletion::new();
+        ui::set_configured(config.color);
+        let redactor = Redactor::new(config.redact_defaults, &config.redact_patterns)
+            .context("Invalid redact_patterns in configuration")?;
+        let mut ai_engine = AIEngine::new(&config.ai_config)?;
+        ai_engine.set_redactor(redactor.clone());
+        let command_executor = CommandExecutor::new(
+            config.command_policy.clone(),
+            config.sandbox.clone(),
+            config.remote.clone(),
+            redactor.clone(),
+            config.output_encoding()?,
+        );
+        let history = CommandHistory::new(&config.history_path, config.history_format, redactor.clone())?;
+        let completion = Arc::new(Mutex::new(CommandCompletion::new()));
+        let (aliases, skipped) = Aliases::from_config(&config.aliases, config.max_expansion_depth);
+        for (name, e) in skipped {
+            eprintln!("⚠️  Skipping alias {} in configuration: {}", name, e);
+        }
+        let prompt_cache = prompt::PromptCache::new(config.prompt_cache_ttl());
+        let feedback = feedback::Feedback::new(&config.ai_feedback, redactor.clone());
+        let jobs = jobs::Jobs::new(config.max_background_jobs);
         
         Ok(ObsidianShell {
             ai_engine,
@@ -92,377 +423,2973 @@ impl ObsidianShell {
             history,
             completion,
             config,
+            last_status: 0,
+            last_duration: None,
+            ai_mode,
+            previous_dir: None,
+            dir_stack: Vec::new(),
+            remote_host: None,
+            recorder: None,
+            aliases,
+            clipboard: clipboard::Clipboard::new(),
+            prompt_cache,
+            redactor,
+            handling_not_found: false,
+            depth: 0,
+            undo: undo::UndoStack::default(),
+            feedback,
+            last_output: LastOutput::default(),
+            verbose: false,
+            ai_flag: None,
+            frontend: None,
+            streaming: false,
+            jobs,
         })
     }
     
+    /// Print the output of commands still running and ask questions on the
+    /// terminal from now on
+    fn use_terminal(&mut self) {
+        self.frontend = Some(Box::new(Terminal {
+            quiet: self.config.quiet,
+            pager_enabled: self.config.pager_enabled,
+            confirm_timeout: self.config.confirm_timeout(),
+        }));
+    }
+    
     /// Initialize the shell
     async fn initialize(&mut self) -> Result<()> {
-        println!("💠 Obsidian Shell v0.1.0");
-        println!("AI-powered shell for Obsidian OS");
-        println!("Type 'help' for available commands or 'exit' to quit.\n");
+        if !self.config.quiet {
+            match self.motd() {
+                Some(motd) if motd.is_empty() => {}
+                Some(motd) => outln!("{}", motd),
+                None => {
+                    outln!("💠 Obsidian Shell v0.1.0");
+                    outln!("AI-powered shell for Obsidian OS");
+                    outln!("Type 'help' for available commands, 'palette' (or F1) to search them, or 'exit' to quit.");
+                }
+            }
+            match self.ai_mode {
+                // Without a flag, the mode was `exec`'s choice
+                AiMode::Always => outln!("🤖 AI mode: interpreting every command ({})", self.ai_flag.unwrap_or("exec --interpret")),
+                AiMode::Off => outln!("🚫 AI mode: off ({})", self.ai_flag.unwrap_or("exec without --interpret")),
+                AiMode::Auto if !self.config.ai_enabled => outln!("🚫 AI mode: off (disabled in config)"),
+                AiMode::Auto => {}
+            }
+            outln!();
+        }
         
         // Load command history
         self.history.load()?;
         
+        // Opt-in .env loading; the current directory's file wins over ~/.env
+        if self.config.load_home_dotenv {
+            if let Some(home) = env::var_os("HOME") {
+                self.report_dotenv(&Path::new(&home).join(".env"));
+            }
+        }
+        if self.config.load_dotenv {
+            self.report_dotenv(Path::new(".env"));
+        }
+        
         // Initialize AI engine
-        if self.config.ai_enabled {
-            println!("🤖 Initializing AI engine...");
-            self.ai_engine.initialize().await?;
-            println!("✅ AI engine ready!");
+        if self.ai_available() {
+            if !self.config.quiet {
+                outln!("🤖 Initializing AI engine...");
+                if self.ai_engine.is_mock() {
+                    outln!("🧪 Using mock AI responses ({})", aimock::MOCK_VAR);
+                } else {
+                    outln!("Loading AI model from: {}", self.config.ai_config.model_path);
+                }
+                if self.ai_engine.context_len() > 0 {
+                    outln!("Continuing conversation with {} previous messages", self.ai_engine.context_len());
+                }
+            }
+            
+            if let Err(e) = self.ai_engine.initialize().await {
+                eprintln!("❌ {:#}", e);
+                eprintln!("⚠️  AI interpretation is disabled for this session");
+                self.ai_mode = AiMode::Off;
+            } else if !self.config.quiet {
+                outln!("✅ AI engine ready!");
+            }
         }
         
         Ok(())
     }
     
-    /// Run the interactive shell
-    async fn run_interactive(&mut self) -> Result<()> {
-        let mut buffer = String::new();
+    /// Run the GUI if requested (and built with the `gui` feature), otherwise the text shell
+    async fn run(&mut self, gui: bool) -> Result<i32> {
+        if gui || self.config.gui_enabled {
+            #[cfg(feature = "gui")]
+            return gui::run(self).await;
+            
+            #[cfg(not(feature = "gui"))]
+            eprintln!("⚠️  Built without the `gui` feature; starting the text shell");
+        }
+        
+        self.run_interactive().await
+    }
+    
+    /// Run the interactive shell, returning the exit status of the last command
+    async fn run_interactive(&mut self) -> Result<i32> {
+        self.ai_engine.set_progress(true);
+        self.use_terminal();
+        let mut editor = LineEditor::new(self.completion.clone(), &self.history.get_recent(usize::MAX)?, &self.config)?;
+        // A history entry recalled with `!n`, to be edited before it runs
+        let mut recalled: Option<String> = None;
         
         loop {
-            // Display prompt
-            self.display_prompt();
-            
-            // Read input
-            buffer.clear();
-            io::stdin().read_line(&mut buffer)?;
+            editor.set_title(&self.idle_title());
+            let (prompt, rprompt) = self.prompt();
+            let input = match recalled.take() {
+                Some(entry) => editor.read_line_with(&prompt, &entry)?,
+                None => editor.read_line(&prompt, &rprompt, self.config.idle_timeout())?,
+            };
+            let mut line = match input {
+                Input::Line(line) => {
+                    if let Some(transient) = self.transient_prompt() {
+                        editor.collapse_prompt(&prompt, &line, &transient);
+                    }
+                    line
+                }
+                Input::Cancelled => continue,
+                // End of input works like `exit`
+                Input::Eof => break,
+                Input::Idle => {
+                    if self.config.idle_action == IdleAction::Lock && self.lock() {
+                        continue;
+                    }
+                    outln!("⏱️  Logged out after {}s without input", self.config.idle_timeout_secs);
+                    break;
+                }
+            };
             
-            let input = buffer.trim();
-            if input.is_empty() {
+            // The palette puts the chosen command in the line for editing
+            if line.trim() == "palette" && !self.builtin_disabled("palette") {
+                match palette::run(&self.history.get_recent(usize::MAX)?, &self.config.disabled_builtins) {
+                    Ok(chosen) => recalled = chosen,
+                    Err(e) => eprintln!("❌ palette: {:#}", e),
+                }
                 continue;
             }
             
-            // Handle special commands
-            match input {
-                "exit" | "quit" => break,
-                "help" => self.show_help(),
-                "clear" => self.clear_screen(),
-                "history" => self.show_history(),
-                _ => {
-                    // Process command
-                    self.process_command(input).await?;
+            match self.history.recall(&line) {
+                Some(Ok(Recall::Edit(entry))) => {
+                    recalled = Some(entry);
+                    continue;
+                }
+                Some(Ok(Recall::Print(entry))) => {
+                    outln!("{}", entry);
+                    editor.add_history(&entry);
+                    self.history.add(&entry);
+                    continue;
+                }
+                Some(Ok(Recall::Run(command))) => {
+                    // Shown like bash does, so it's clear what runs
+                    outln!("{}", command);
+                    line = command;
+                }
+                Some(Err(e)) => {
+                    eprintln!("❌ {}", e);
+                    continue;
+                }
+                None => {}
+            }
+            editor.add_history(&self.history_entry(&line));
+            editor.mark_output_start();
+            
+            let result = if self.config.ai_apply != AiApply::Auto && self.wants_ai(strip_nopager(&line).1) {
+                match self.review_interpretation(&mut editor, &line).await? {
+                    Some(command) => {
+                        editor.add_history(&command);
+                        editor.set_title(&command);
+                        self.eval_with(&command, true).await?
+                    }
+                    None => continue,
                 }
+            } else {
+                editor.set_title(&line);
+                self.eval(&line).await?
+            };
+            self.print_result(&result);
+            editor.mark_command_end(result.exit_code);
+            
+            if result.exit_requested {
+                break;
             }
         }
         
-        Ok(())
+        Ok(self.last_status)
     }
     
-    /// Display the shell prompt
-    fn display_prompt(&self) {
-        let current_dir = env::current_dir()
-            .unwrap_or_else(|_| env::current_dir().unwrap_or_default());
+    /// Clear the screen and run `idle_lock_command`; true if it unlocked
+    fn lock(&self) -> bool {
+        out!("\x1B[2J\x1B[1;1H");
+        outln!("🔒 Locked after {}s without input", self.config.idle_timeout_secs);
+        output::flush();
         
-        let dir_name = current_dir
-            .file_name()
-            .and_then(|name| name.to_str())
-            .unwrap_or("~");
+        let command = &self.config.idle_lock_command;
+        let mut words = command.split_whitespace();
+        let Some(program) = words.next() else {
+            outln!("❌ No idle_lock_command is configured");
+            return false;
+        };
         
-        print!("💠 {} $ ", dir_name);
-        io::stdout().flush().unwrap();
+        match std::process::Command::new(program).args(words).status() {
+            Ok(status) if status.success() => true,
+            Ok(_) => {
+                outln!("❌ Unlock failed");
+                false
+            }
+            Err(e) => {
+                outln!("❌ Failed to run '{}': {}", command, e);
+                false
+            }
+        }
     }
     
-    /// Process a command
-    async fn process_command(&mut self, input: &str) -> Result<()> {
-        // Add to history
-        self.history.add(input);
+    /// Interpret `line` up front for `ai_apply = "confirm"` or `"edit"`, and let
+    /// the user approve or edit the command. `None` means it was cancelled.
+    async fn review_interpretation(&mut self, editor: &mut LineEditor, line: &str) -> Result<Option<String>> {
+        let (nopager, input) = strip_nopager(line);
+        let prefix = if nopager { "nopager " } else { "" };
         
-        // Check if AI interpretation is needed
-        if self.config.ai_enabled && self.should_use_ai(input) {
-            match self.ai_engine.interpret_command(input).await {
-                Ok(interpreted) => {
-                    println!("🤖 AI interpretation: {}", interpreted);
-                    self.execute_command(&interpreted).await?;
-                }
-                Err(e) => {
-                    println!("⚠️  AI interpretation failed: {}", e);
-                    println!("Executing original command...");
-                    self.execute_command(input).await?;
-                }
-            }
-        } else {
-            self.execute_command(input).await?;
+        let mut notice = CommandResult::default();
+        let Some(command) = self.interpret(input, &mut notice, true).await else {
+            self.print_result(&notice);
+            return Ok(None);
+        };
+        
+        if self.config.ai_apply == AiApply::Edit {
+            // The interpretation is shown on the input line instead
+            notice.interpreted = None;
+            self.print_result(&notice);
+            
+            return match editor.read_line_with("🤖 $ ", &command)? {
+                Input::Line(edited) if !edited.trim().is_empty() => Ok(Some(format!("{}{}", prefix, edited))),
+                _ => Ok(None),
+            };
         }
         
-        Ok(())
+        // The question names the command, so it is shown even in quiet mode
+        notice.interpreted = None;
+        self.print_result(&notice);
+        let question = match &notice.interpretation_reason {
+            Some(reason) => format!("🤖 Run '{}' because {}? [y/N] ", command, reason),
+            None => format!("🤖 Run '{}'? [y/N] ", command),
+        };
+        if editor::confirm(&question, self.config.confirm_timeout())? {
+            Ok(Some(format!("{}{}", prefix, command)))
+        } else {
+            Ok(None)
+        }
     }
     
-    /// Determine if AI should be used for command interpretation
-    fn should_use_ai(&self, input: &str) -> bool {
-        // Use AI for natural language commands
-        let natural_language_indicators = [
-            "find", "search", "show", "list", "get", "create", "delete",
-            "move", "copy", "open", "start", "stop", "install", "update"
-        ];
-        
-        let lower_input = input.to_lowercase();
-        natural_language_indicators.iter().any(|&indicator| {
-            lower_input.contains(indicator)
-        })
+    /// How `line` is saved to history: as typed, or with aliases expanded
+    fn history_entry(&self, line: &str) -> String {
+        if !self.config.history_expand_aliases {
+            return line.to_string();
+        }
+        let (nopager, command) = strip_nopager(line);
+        let prefix = if nopager { "nopager " } else { "" };
+        format!("{}{}", prefix, self.aliases.expand_line(command))
     }
     
-    /// Execute a command
-    async fn execute_command(&self, command: &str) -> Result<()> {
-        // Split command into parts
-        let parts: Vec<&str> = command.split_whitespace().collect();
-        if parts.is_empty() {
-            return Ok(());
+    /// Run one line through the full pipeline: history, AI interpretation,
+    /// `;`/`&&`/`||` chaining, builtins and execution.
+    ///
+    /// Performs no terminal I/O: output is collected in the returned result,
+    /// and anything shown or asked while the line runs goes to `frontend`.
+    async fn eval(&mut self, input: &str) -> Result<CommandResult> {
+        self.eval_with(input, false).await
+    }
+    
+    /// `eval`, or with `reviewed`, run an AI interpretation the user has
+    /// already approved or edited without interpreting it again
+    async fn eval_with(&mut self, input: &str, reviewed: bool) -> Result<CommandResult> {
+        let start = Instant::now();
+        let input = input.trim();
+        let mut result = CommandResult::default();
+        
+        if input.is_empty() {
+            return Ok(result);
         }
         
-        let (program, args) = parts.split_first().unwrap();
+        // Add to history
+        self.history.add(&self.history_entry(input));
         
-        // Execute the command
-        match self.command_executor.execute(program, args).await {
-            Ok(output) => {
-                if !output.is_empty() {
-                    println!("{}", output);
-                }
-            }
-            Err(e) => {
-                eprintln!("❌ Error executing command: {}", e);
+        // `nopager <command>` bypasses the pager for this command only
+        let (skip_pager, mut input) = strip_nopager(input);
+        result.skip_pager = skip_pager;
+        // `!! <line>` hands the line as typed to another shell
+        if let Some(line) = strip_delegate(input) {
+            self.run_delegated(line, &mut result).await?;
+            return Ok(self.finish(input, start, result));
+        }
+        if let Some(comment) = self.config.comment_char.chars().next() {
+            input = parser::strip_comment(input, comment);
+            if input.is_empty() {
+                return Ok(result);
             }
         }
+        let (no_correct, input) = correct::strip_prefix(input);
         
-        Ok(())
-    }
-    
-    /// Show help information
-    fn show_help(&self) {
-        println!("\n💠 Obsidian Shell Help");
-        println!("=====================");
-        println!("Built-in commands:");
-        println!("  help     - Show this help");
-        println!("  clear    - Clear the screen");
-        println!("  history  - Show command history");
-        println!("  exit     - Exit the shell");
-        println!("  quit     - Exit the shell");
-        println!("\nAI Features:");
-        println!("  Natural language commands are automatically interpreted");
-        println!("  Examples:");
-        println!("    'find all text files' -> 'find . -name \"*.txt\"'");
-        println!("    'show running processes' -> 'ps aux'");
-        println!("    'install python package requests' -> 'pip install requests'");
-        println!();
-    }
-    
-    /// Clear the screen
-    fn clear_screen(&self) {
-        print!("\x1B[2J\x1B[1;1H");
-        io::stdout().flush().unwrap();
-    }
-    
-    /// Show command history
-    fn show_history(&self) {
-        println!("\nCommand History:");
-        println!("================");
-        
-        match self.history.get_recent(10) {
-            Ok(history) => {
-                for (i, command) in history.iter().enumerate() {
-                    println!("{:3}: {}", i + 1, command);
-                }
+        // A trailing `g/pattern/` filters what is displayed
+        let input = match linefilter::LineFilter::strip(input) {
+            Ok((filter, input)) => {
+                result.line_filter = filter;
+                input
             }
             Err(e) => {
-                eprintln!("❌ Error loading history: {}", e);
+                result.stderr = format!("❌ {}\n", e);
+                result.exit_code = 2;
+                self.last_status = 2;
+                return Ok(result);
             }
-        }
-        println!();
-    }
-}
-
-/// Main function
-#[tokio::main]
-async fn main() -> Result<()> {
-    let cli = Cli::parse();
-    
-    match cli.command {
-        Some(Commands::Exec { command, interpret }) => {
-            // Execute single command
-            let mut shell = ObsidianShell::new(&cli.config)?;
-            shell.initialize().await?;
-            
-            if interpret {
-                // Use AI interpretation
-                match shell.ai_engine.interpret_command(&command).await {
-                    Ok(interpreted) => {
-                        println!("🤖 AI interpretation: {}", interpreted);
-                        shell.execute_command(&interpreted).await?;
-                    }
-                    Err(e) => {
-                        eprintln!("❌ AI interpretation failed: {}", e);
-                        return Err(e);
-                    }
+        };
+        
+        // Check if AI interpretation is needed
+        let mut line = input.to_string();
+        if !reviewed && self.wants_ai(input) {
+            match self.interpret(input, &mut result, false).await {
+                Some(interpreted) => line = interpreted,
+                None => {
+                    line.clear();
+                    self.last_status = 1;
                 }
-            } else {
-                shell.execute_command(&command).await?;
             }
         }
         
-        Some(Commands::Interactive) => {
-            // Run interactive shell
-            let mut shell = ObsidianShell::new(&cli.config)?;
-            shell.initialize().await?;
-            shell.run_interactive().await?;
+        result.exit_code = self.last_status;
+        // What the AI's commands change is recorded so `undo` can reverse it
+        let from_ai = reviewed || result.interpreted.is_some();
+        let mut chain = parser::split_chain(&line);
+        if !no_correct {
+            chain = correct::apply(&self.config.corrections, chain, &mut result.corrected);
         }
-        
-        Some(Commands::Config) => {
-            // Show configuration
-            let config = ShellConfig::load(&cli.config)?;
-            println!("💠 Obsidian Shell Configuration");
-            println!("===============================");
-            println!("AI Enabled: {}", config.ai_enabled);
-            println!("GUI Enabled: {}", config.gui_enabled);
-            println!("History Path: {}", config.history_path);
-            println!("Model Path: {}", config.ai_config.model_path);
+        let chain = match self.aliases.expand(chain) {
+            Ok(chain) => chain,
+            Err(e) => {
+                result.stderr = format!("❌ alias: {}\n", e);
+                result.exit_code = 2;
+                self.last_status = 2;
+                return Ok(result);
+            }
+        };
+        // Filtered or paged output has to be whole before it is shown
+        self.streaming = self.frontend.as_ref().is_some_and(|frontend| frontend.streams_output())
+            && result.line_filter.is_none()
+            && (result.skip_pager || !self.config.pager_enabled);
+        for (connector, command) in chain {
+            if !connector.should_run(self.last_status) {
+                continue;
+            }
+            
+            let command = self.expand_variables(&command, &mut result);
+            let plan = from_ai.then(|| self.undo_plan(&command));
+            let step = self.run_command(&command).await?;
+            self.last_output = LastOutput::new(&step, self.config.output_variable_limit);
+            if let Some(warning) = plan.and_then(|plan| self.undo.record(&command, plan)) {
+                result.warnings.push(warning);
+            }
+            result.stdout.push_str(&step.stdout);
+            result.stderr.push_str(&step.stderr);
+            result.streamed.append(step.streamed);
+            result.warnings.extend(step.warnings);
+            result.exit_code = step.exit_code;
+            self.last_status = step.exit_code;
+            
+            if step.exit_requested {
+                result.exit_requested = true;
+                break;
+            }
         }
+        self.streaming = false;
         
-        Some(Commands::UpdateModels) => {
-            // Update AI models
-            println!("🔄 Updating AI models...");
-            let config = ShellConfig::load(&cli.config)?;
-            let ai_engine = AIEngine::new(&config.ai_config)?;
-            ai_engine.update_models().await?;
-            println!("✅ Models updated successfully!");
+        if from_ai {
+            result.warnings.extend(self.feedback.ran(result.exit_code));
         }
         
-        None => {
-            // Default to interactive mode
-            let mut shell = ObsidianShell::new(&cli.config)?;
-            shell.initialize().await?;
-            shell.run_interactive().await?;
+        Ok(self.finish(input, start, result))
+    }
+    
+    /// Note how long `input` took, started at `start`, and record it if the
+    /// session is being recorded
+    fn finish(&mut self, input: &str, start: Instant, mut result: CommandResult) -> CommandResult {
+        result.duration = start.elapsed();
+        self.last_duration = Some(result.duration);
+        
+        if let Some(recorder) = &mut self.recorder {
+            if let Err(e) = recorder.record(input, &result) {
+                result.warnings.push(format!("Failed to record session: {:#}", e));
+            }
         }
+        result
     }
     
-    Ok(())
-}
-
-// Module implementations
-mod ai {
-    use super::*;
-    use serde::{Deserialize, Serialize};
+    /// Run `line` as typed with the fallback shell, or the `remote` host's
+    /// shell, without aliases, corrections or the AI
+    async fn run_delegated(&mut self, line: &str, result: &mut CommandResult) -> Result<()> {
+        let (shell, step) = match self.remote_host.clone() {
+            Some(host) => (format!("the shell on {}", host), self.execute_remote(&host, line, None).await?),
+            None => {
+                let shell = self.fallback_shell();
+                let step = match self.command_executor.execute_in_shell(&shell, &line_programs(line), line, None).await {
+                    Ok(step) => step,
+                    Err(e) => CommandResult {
+                        stderr: format!("❌ Error executing command with {}: {:#}\n", shell, e),
+                        exit_code: commands::exit_code_for_error(&e),
+                        ..Default::default()
+                    },
+                };
+                (shell, step)
+            }
+        };
+        
+        self.last_output = LastOutput::new(&step, self.config.output_variable_limit);
+        result.stdout = step.stdout;
+        result.stderr = step.stderr;
+        result.warnings.extend(step.warnings);
+        result.exit_code = step.exit_code;
+        result.delegated_to = Some(shell);
+        self.last_status = step.exit_code;
+        Ok(())
+    }
     
-    #[derive(Debug, Serialize, Deserialize)]
-    pub struct AIConfig {
-        pub model_path: String,
-        pub api_endpoint: String,
-        pub max_tokens: usize,
-        pub temperature: f32,
+    /// `fallback_shell`, else `$SHELL` unless it is this program, else /bin/sh
+    fn fallback_shell(&self) -> String {
+        if let Some(shell) = &self.config.fallback_shell {
+            return shell.clone();
+        }
+        // Handing a line back to this shell would only delegate it again
+        let this = env::current_exe().and_then(std::fs::canonicalize).ok();
+        env::var("SHELL")
+            .ok()
+            .filter(|shell| !shell.is_empty() && std::fs::canonicalize(shell).ok() != this)
+            .unwrap_or_else(|| "/bin/sh".to_string())
     }
     
-    pub struct AIEngine {
-        config: AIConfig,
-        client: reqwest::Client,
+    /// What `command` will change on disk, for `undo`
+    fn undo_plan(&self, command: &str) -> undo::Plan {
+        let dir = match &self.config.sandbox.working_dir {
+            Some(dir) => dir.clone(),
+            None => env::current_dir().unwrap_or_default(),
+        };
+        undo::Plan::new(command, &dir, self.remote_host.is_some())
     }
     
-    impl AIEngine {
-        pub fn new(config: &AIConfig) -> Result<Self> {
-            let client = reqwest::Client::new();
-            Ok(AIEngine {
-                config: config.clone(),
-                client,
-            })
-        }
-        
-        pub async fn initialize(&self) -> Result<()> {
-            // Initialize AI engine
-            println!("Loading AI model from: {}", self.config.model_path);
-            Ok(())
+    /// Ask the AI for the command meant by `input`, noting the interpretation,
+    /// warnings and failures on `result`. When it fails, `ai_fallback` decides
+    /// what runs instead; `None` means nothing should. An unasked-for chain of
+    /// commands is confirmed with the frontend, and declined without one,
+    /// unless the user is shown it for review anyway (`reviewed`).
+    async fn interpret(&mut self, input: &str, result: &mut CommandResult, reviewed: bool) -> Option<String> {
+        // The input itself is usually in the history already
+        let mut recent = self.history.get_recent(self.ai_engine.context_config().recent_commands + 1).unwrap_or_default();
+        if recent.last().is_some_and(|last| last == input) {
+            recent.pop();
         }
+        let context = aicontext::build(self.ai_engine.context_config(), &recent, &self.redactor);
+        let ai::Interpretation { command: interpreted, reason } = match self.ai_engine.interpret_command(input, &context).await {
+            Ok(interpretation) => interpretation,
+            Err(e) => {
+                result.ai_error = Some(e.to_string());
+                return self.fall_back(input, result);
+            }
+        };
+        self.feedback.interpreted(input, &interpreted);
         
-        pub async fn interpret_command(&self, input: &str) -> Result<String> {
-            // Use AI to interpret natural language command
-            let prompt = format!(
-                "Convert this natural language command to a shell command: '{}'",
-                input
-            );
-            
-            // For now, return a simple interpretation
-            // In a real implementation, this would call the AI model
-            let interpreted = match input.to_lowercase() {
-                s if s.contains("find") && s.contains("file") => {
-                    "find . -type f".to_string()
-                }
-                s if s.contains("process") => {
-                    "ps aux".to_string()
-                }
-                s if s.contains("install") => {
-                    "apt install".to_string()
+        let commands = parser::split_chain(&interpreted).len();
+        if commands > 1 {
+            if !self.config.ai_allow_chaining {
+                result.ai_error = Some(format!(
+                    "the AI chained {} commands ({}); set ai_allow_chaining to allow that",
+                    commands, interpreted
+                ));
+                return self.fall_back(input, result);
+            }
+            if !reviewed && !implies_chaining(input) {
+                let question = format!("🤖 The AI chained {} commands: '{}'. Run them? [y/N] ", commands, interpreted);
+                let confirmed = self.frontend.as_mut().is_some_and(|frontend| frontend.confirm(&question));
+                if !confirmed {
+                    // Declined, so no fallback runs in its place either
+                    result.ai_error = Some("chained commands were not confirmed".to_string());
+                    result.ai_fallback = Some("Nothing was run".to_string());
+                    return None;
                 }
-                _ => input.to_string(),
-            };
-            
-            Ok(interpreted)
+            }
         }
         
-        pub async fn update_models(&self) -> Result<()> {
-            println!("Downloading latest AI models...");
-            // Implementation for model updates
-            Ok(())
-        }
+        result.interpreted = Some(interpreted.clone());
+        result.interpretation_reason = reason;
+        Some(interpreted)
     }
-}
-
-mod commands {
-    use super::*;
-    use tokio::process::Command;
     
-    pub struct CommandExecutor;
+    /// The command `ai_fallback` runs for `input` after interpretation failed,
+    /// saying which on `result`
+    fn fall_back(&self, input: &str, result: &mut CommandResult) -> Option<String> {
+        let (message, command) = match self.config.ai_fallback {
+            AiFallback::OfflineRules => {
+                let guess = ai::offline_interpretation(input);
+                (format!("Running the offline rules' guess: {} (ai_fallback = \"offline_rules\")", guess), Some(guess))
+            }
+            AiFallback::ExecuteLiteral => (
+                "Executing original command (ai_fallback = \"execute_literal\")...".to_string(),
+                Some(input.to_string()),
+            ),
+            AiFallback::Abort => ("Nothing was run (ai_fallback = \"abort\")".to_string(), None),
+        };
+        result.ai_fallback = Some(message);
+        command
+    }
     
-    impl CommandExecutor {
-        pub fn new() -> Self {
-            CommandExecutor
-        }
+    /// Re-run the inputs of a recorded session.
+    ///
+    /// `speed` scales the recorded gaps between commands; `None` runs them back
+    /// to back. Every replayed command is marked so it can't be mistaken for
+    /// live input.
+    async fn replay(&mut self, entries: &[SessionEntry], speed: Option<f64>) -> Result<i32> {
+        outln!("▶️  Replaying {} recorded commands", entries.len());
         
-        pub async fn execute(&self, program: &str, args: &[&str]) -> Result<String> {
-            let output = Command::new(program)
-                .args(args)
-                .output()
-                .await
-                .context("Failed to execute command")?;
+        let mut previous: Option<&SessionEntry> = None;
+        for entry in entries {
+            if let (Some(speed), Some(previous)) = (speed, previous) {
+                // The previous command's own run time is already spent re-running it
+                let gap = (entry.timestamp - previous.timestamp).to_std().unwrap_or_default();
+                let gap = gap.saturating_sub(Duration::from_millis(previous.duration_ms as u64));
+                if speed > 0.0 {
+                    tokio::time::sleep(gap.div_f64(speed)).await;
+                }
+            }
+            previous = Some(entry);
             
-            let stdout = String::from_utf8_lossy(&output.stdout);
-            let stderr = String::from_utf8_lossy(&output.stderr);
+            outln!("▶️  [replay] $ {}", entry.input);
+            let result = self.eval(&entry.input).await?;
+            self.print_result(&result);
             
-            if !output.status.success() {
-                return Err(anyhow::anyhow!("Command failed: {}", stderr));
+            if result.exit_code != entry.exit_code {
+                outln!(
+                    "▶️  [replay] exit status {} (recorded: {})",
+                    result.exit_code, entry.exit_code
+                );
+            }
+            if result.exit_requested {
+                break;
             }
-            
-            Ok(stdout.to_string())
         }
+        
+        outln!("▶️  Replay finished");
+        Ok(self.last_status)
     }
-}
-
-mod history {
-    use super::*;
-    use std::fs::OpenOptions;
-    use std::io::{BufRead, BufReader, Write};
     
-    pub struct CommandHistory {
-        path: String,
-        commands: Vec<String>,
+    /// Print the output of an `eval` call
+    fn print_result(&self, result: &CommandResult) {
+        show_result(result, self.config.quiet, self.config.pager_enabled);
     }
     
-    impl CommandHistory {
-        pub fn new(path: &str) -> Result<Self> {
-            Ok(CommandHistory {
-                path: path.to_string(),
-                commands: Vec::new(),
-            })
-        }
-        
-        pub fn load(&mut self) -> Result<()> {
-            if let Ok(file) = std::fs::File::open(&self.path) {
-                let reader = BufReader::new(file);
-                for line in reader.lines() {
-                    if let Ok(command) = line {
-                        self.commands.push(command);
-                    }
-                }
+    /// Show `output` of a command that is still going on `frontend`, or
+    /// without one, keep it in `held` for the final result
+    fn report(&mut self, held: &mut CommandResult, output: CommandResult) {
+        match &mut self.frontend {
+            Some(frontend) => frontend.progress(&output),
+            None => {
+                held.stdout.push_str(&output.stdout);
+                held.stderr.push_str(&output.stderr);
+                held.warnings.extend(output.warnings);
             }
-            Ok(())
         }
-        
+    }
+    
+    /// The main prompt and the expanded `rprompt_format`
+    fn prompt(&self) -> (String, String) {
+        let current_dir = prompt::working_dir();
+        let dir = match &current_dir {
+            Some(dir) => prompt::display_dir(dir, self.config.prompt_dir),
+            None => "?".to_string(),
+        };
+        
+        let context = prompt::PromptContext {
+            cwd: current_dir.as_deref(),
+            last_status: self.last_status,
+            last_duration: self.last_duration,
+            cache: &self.prompt_cache,
+            segments: &self.config.prompt_segments,
+        };
+        let rprompt = prompt::render(&self.config.rprompt_format, &context);
+        
+        // Commands go elsewhere, so say so before anything else
+        let remote = match &self.remote_host {
+            Some(host) => format!("@{} ", host),
+            None => String::new(),
+        };
+        
+        (format!("💠 {}{} $ ", remote, dir), rprompt)
+    }
+    
+    /// The terminal title while waiting at the prompt
+    fn idle_title(&self) -> String {
+        match prompt::working_dir() {
+            Some(dir) => format!("obsidian: {}", prompt::display_dir(&dir, config::PromptDir::Home)),
+            None => "obsidian".to_string(),
+        }
+    }
+    
+    /// The expanded `transient_prompt`, or `None` when it is off
+    fn transient_prompt(&self) -> Option<String> {
+        if self.config.transient_prompt.is_empty() {
+            return None;
+        }
+        
+        let cwd = env::current_dir().ok();
+        let context = prompt::PromptContext {
+            cwd: cwd.as_deref(),
+            last_status: self.last_status,
+            last_duration: self.last_duration,
+            cache: &self.prompt_cache,
+            segments: &self.config.prompt_segments,
+        };
+        Some(prompt::render(&self.config.transient_prompt, &context))
+    }
+    
+    /// The configured welcome text with placeholders expanded, read from a
+    /// file if `motd` names one; `None` means the default banner
+    fn motd(&self) -> Option<String> {
+        let motd = self.config.motd.as_deref()?;
+        let text = match std::fs::read_to_string(motd) {
+            Ok(contents) => contents,
+            Err(_) => motd.to_string(),
+        };
+        
+        let cwd = env::current_dir().ok();
+        let context = prompt::PromptContext {
+            cwd: cwd.as_deref(),
+            last_status: self.last_status,
+            last_duration: self.last_duration,
+            cache: &self.prompt_cache,
+            segments: &self.config.prompt_segments,
+        };
+        Some(prompt::render(text.trim_end_matches('\n'), &context))
+    }
+    
+    /// Whether the AI can be used at all this session
+    fn ai_available(&self) -> bool {
+        match self.ai_mode {
+            AiMode::Always => true,
+            AiMode::Off => false,
+            AiMode::Auto => self.config.ai_enabled,
+        }
+    }
+    
+    /// `command` with `$?`, `$_OUT` and `$_ERR` replaced by the last
+    /// command's exit status, stdout and stderr
+    fn expand_variables(&self, command: &str, result: &mut CommandResult) -> String {
+        let mut used_output = false;
+        let expanded = parser::expand_variables(command, |name| match name {
+            "?" => Some(self.last_status.to_string()),
+            "_OUT" | "_ERR" => {
+                used_output = true;
+                let output = if name == "_OUT" { &self.last_output.stdout } else { &self.last_output.stderr };
+                Some(output.clone())
+            }
+            _ => None,
+        });
+        
+        if used_output && self.last_output.truncated {
+            result.warnings.push(format!(
+                "$_OUT and $_ERR hold at most {} bytes of output each; raise output_variable_limit for more",
+                self.config.output_variable_limit
+            ));
+        }
+        expanded
+    }
+    
+    /// Whether `input` should go through AI interpretation in the current mode
+    fn wants_ai(&self, input: &str) -> bool {
+        // Notes given to `good` and `bad` describe the AI's answer, they
+        // aren't requests
+        let word = input.split_whitespace().next().unwrap_or_default();
+        if matches!(word, "good" | "bad") && !self.builtin_disabled(word) {
+            return false;
+        }
+        // A `?` stage already asks the AI, about its input
+        let asks = parser::split_chain(input).iter().any(|(_, command)| {
+            parser::split_pipeline(command).iter().any(|stage| stage.trim_start().starts_with('?'))
+        });
+        if asks {
+            return false;
+        }
+        
+        match self.ai_mode {
+            AiMode::Always => true,
+            AiMode::Off => false,
+            AiMode::Auto => self.config.ai_enabled && self.should_use_ai(input),
+        }
+    }
+    
+    /// Determine if AI should be used for command interpretation
+    fn should_use_ai(&self, input: &str) -> bool {
+        // Use AI for natural language commands
+        let natural_language_indicators = [
+            "find", "search", "show", "list", "get", "create", "delete",
+            "move", "copy", "open", "start", "stop", "install", "update"
+        ];
+        
+        let lower_input = input.to_lowercase();
+        natural_language_indicators.iter().any(|&indicator| {
+            lower_input.contains(indicator) && !self.builtin_disabled(indicator)
+        })
+    }
+    
+    /// Run a single command of a chain, which may be a `|` pipeline
+    async fn run_command(&mut self, command: &str) -> Result<CommandResult> {
+        // As in POSIX shells, a leading `time` covers the whole pipeline
+        let timed = command
+            .strip_prefix("time")
+            .filter(|rest| rest.is_empty() || rest.starts_with(char::is_whitespace))
+            .filter(|_| !self.builtin_disabled("time"));
+        if let Some(rest) = timed {
+            return self.time_builtin(rest.trim(), None).await;
+        }
+        if let Some(command) = parser::strip_background(command) {
+            return Ok(self.background(command));
+        }
+        
+        self.run_pipeline(command, None).await
+    }
+    
+    /// Start `command`, a pipeline of programs, as a background job. Its
+    /// output is shown as it runs where the output of the line would be;
+    /// otherwise, or when the last program's output is redirected, it comes
+    /// with the `jobs` or `wait` that reports the job finished.
+    fn background(&mut self, command: &str) -> CommandResult {
+        let mut stages = parser::split_pipeline(command);
+        let redirections = match stages.last().map(|last| redirect::parse(last)) {
+            Some(Ok((last, redirections))) => {
+                *stages.last_mut().unwrap() = last;
+                redirections
+            }
+            Some(Err(e)) => {
+                return CommandResult {
+                    stderr: format!("❌ {}\n", e),
+                    exit_code: 2,
+                    ..Default::default()
+                }
+            }
+            None => redirect::Redirections::default(),
+        };
+        if let Some(stage) = stages.iter().find(|stage| !self.streamable(stage)) {
+            return CommandResult {
+                stderr: format!("❌ &: only programs can run in the background, not `{}`\n", stage.trim()),
+                exit_code: 2,
+                ..Default::default()
+            };
+        }
+        
+        let output = if self.streaming && redirections == redirect::Redirections::default() {
+            commands::Output::Tee { limit: self.config.stream_capture_limit }
+        } else {
+            commands::Output::Capture
+        };
+        let executor = self.command_executor.clone();
+        let dir = executor.working_dir().map(Path::to_path_buf);
+        let words: Vec<Vec<String>> = stages.iter().map(|stage| parser::split_words(stage)).collect();
+        let id = self.jobs.spawn(command, async move {
+            let parts: Vec<Vec<&str>> = words
+                .iter()
+                .map(|words| words.iter().map(String::as_str).collect())
+                .collect();
+            let invocations = invocations(&parts);
+            let (mut result, failed) = executor.execute_pipeline(&invocations, None, output).await;
+            for (i, e) in failed {
+                result.stderr.push_str(&format!("❌ Error executing command: {:#}\n", e));
+                if i + 1 == invocations.len() {
+                    result.exit_code = commands::exit_code_for_error(&e);
+                }
+            }
+            redirect::apply(&mut result, &redirections, dir.as_deref());
+            result
+        });
+        
+        CommandResult {
+            stderr: if self.config.quiet { String::new() } else { format!("[{}] {}\n", id, command) },
+            ..Default::default()
+        }
+    }
+    
+    /// Split `command` on `|` and run it, feeding `stdin` to the first stage
+    async fn run_pipeline(&mut self, command: &str, stdin: Option<String>) -> Result<CommandResult> {
+        let stages = parser::split_pipeline(command);
+        if stages.len() > 1 && stages.iter().any(|stage| stage.is_empty()) {
+            return Ok(CommandResult {
+                stderr: "❌ syntax error near `|`\n".to_string(),
+                exit_code: 2,
+                ..Default::default()
+            });
+        }
+        // `time`, `on-change` and the `command_not_found` handler come back
+        // through here for the commands they run
+        let max_depth = self.config.max_expansion_depth.max(1);
+        if self.depth >= max_depth {
+            return Ok(CommandResult {
+                stderr: format!("❌ commands nested more than {} deep (max_expansion_depth)\n", max_depth),
+                exit_code: 2,
+                ..Default::default()
+            });
+        }
+        
+        self.depth += 1;
+        let result = self.execute_pipeline(&stages, stdin).await;
+        self.depth -= 1;
+        result
+    }
+    
+    /// Run pipeline stages in order, feeding each one's stdout to the next.
+    /// Neighbouring plain external programs run together over OS pipes, so
+    /// they stream; other stages get the whole of the previous output.
+    ///
+    /// The exit code is the last stage's; stderr and warnings are collected
+    /// from every stage.
+    async fn execute_pipeline(&mut self, stages: &[String], stdin: Option<String>) -> Result<CommandResult> {
+        let mut result = CommandResult::default();
+        let mut input = stdin;
+        let mut i = 0;
+        
+        while i < stages.len() {
+            let run = stages[i..].iter().take_while(|stage| self.streamable(stage)).count();
+            // Programs at the end of the pipeline can show their output themselves
+            let tee = self.streaming && run > 0 && i + run == stages.len();
+            let piped = run > 1 || tee;
+            let started = Instant::now();
+            let mut step = if piped {
+                self.run_streamed(&stages[i..i + run], input.take(), tee).await?
+            } else {
+                self.run_stage(&stages[i], input.take()).await?
+            };
+            if piped {
+                for (timing, stage) in step.stage_timings.iter_mut().zip(&stages[i..]) {
+                    timing.command = stage.clone();
+                }
+                result.stage_timings.append(&mut step.stage_timings);
+            } else {
+                result.stage_timings.push(commands::StageTiming {
+                    command: stages[i].clone(),
+                    duration: started.elapsed(),
+                });
+            }
+            i += run.max(1);
+            result.stderr.push_str(&step.stderr);
+            result.streamed.append(step.streamed);
+            result.warnings.extend(step.warnings);
+            result.exit_code = step.exit_code;
+            result.usage = commands::ResourceUsage::combine(result.usage, step.usage);
+            input = Some(step.stdout);
+            
+            // `exit` only leaves the shell when it runs on its own, as in POSIX shells
+            result.exit_requested = step.exit_requested && stages.len() == 1;
+        }
+        
+        if self.verbose && stages.len() > 1 {
+            result.stderr.push_str(&stage_report(&result.stage_timings));
+        }
+        result.stdout = input.unwrap_or_default();
+        Ok(result)
+    }
+    
+    /// Whether `stage` is an external program that can run over a pipe:
+    /// no builtin, `@`/`?` stage, redirection or remote host in the way
+    fn streamable(&self, stage: &str) -> bool {
+        let stage = stage.trim();
+        if self.remote_host.is_some() || stage.starts_with(['@', '?']) {
+            return false;
+        }
+        if !matches!(redirect::parse(stage), Ok((_, redirections)) if redirections == redirect::Redirections::default()) {
+            return false;
+        }
+        
+        let word = stage.split_whitespace().next().unwrap_or_default();
+        if self.is_builtin(word) {
+            return false;
+        }
+        if self.config.shell_only_commands.iter().any(|name| name == word) {
+            return false;
+        }
+        
+        let words = parser::split_words(stage);
+        let parts: Vec<&str> = words.iter().map(String::as_str).collect();
+        !commands::split_env_assignments(&parts).1.is_empty()
+    }
+    
+    /// Run `stages`, all `streamable`, connected by OS pipes. With `tee`,
+    /// their output is shown as it arrives.
+    async fn run_streamed(&mut self, stages: &[String], stdin: Option<String>, tee: bool) -> Result<CommandResult> {
+        let words: Vec<Vec<String>> = stages.iter().map(|stage| parser::split_words(stage)).collect();
+        let parts: Vec<Vec<&str>> = words
+            .iter()
+            .map(|words| words.iter().map(String::as_str).collect())
+            .collect();
+        let invocations = invocations(&parts);
+        
+        let output = if tee {
+            commands::Output::Tee { limit: self.config.stream_capture_limit }
+        } else {
+            commands::Output::Capture
+        };
+        let (mut result, failed) = self.command_executor.execute_pipeline(&invocations, stdin, output).await;
+        let mut errors = CommandResult::default();
+        for (i, e) in failed {
+            errors.stderr.push_str(&format!("❌ Error executing command: {:#}\n", e));
+            let exit_code = commands::exit_code_for_error(&e);
+            if exit_code == commands::EXIT_NOT_FOUND {
+                self.command_not_found(invocations[i].program, &mut errors).await?;
+            }
+            if i + 1 == invocations.len() {
+                result.exit_code = exit_code;
+            }
+        }
+        result.stderr.insert_str(0, &errors.stderr);
+        result.warnings.extend(errors.warnings);
+        Ok(result)
+    }
+    
+    /// Run one pipeline stage with its redirections. `stdin` is the previous
+    /// stage's output, if any.
+    async fn run_stage(&mut self, command: &str, stdin: Option<String>) -> Result<CommandResult> {
+        // Redirections after `in <dir> --` are the command's, made in `dir`
+        if command.split_whitespace().next() == Some("in") && self.is_builtin("in") {
+            return self.run_simple(command.trim(), stdin).await;
+        }
+        
+        let (command, redirections) = match redirect::parse(command) {
+            Ok(parsed) => parsed,
+            Err(e) => {
+                return Ok(CommandResult {
+                    stderr: format!("❌ syntax error: {}\n", e),
+                    exit_code: 2,
+                    ..Default::default()
+                })
+            }
+        };
+        
+        let mut result = self.run_simple(&command, stdin).await?;
+        redirect::apply(&mut result, &redirections, self.command_executor.working_dir());
+        Ok(result)
+    }
+    
+    /// Run a command without pipes or redirections, handling builtins
+    async fn run_simple(&mut self, command: &str, stdin: Option<String>) -> Result<CommandResult> {
+        // `@json` is a builtin, not a host
+        let json = command
+            .strip_prefix("@json")
+            .filter(|rest| rest.is_empty() || rest.starts_with(char::is_whitespace));
+        if let Some(arg) = json {
+            return Ok(self.json_builtin(arg.trim(), stdin));
+        }
+        
+        // `command | ?"question"` asks the AI about the command's output
+        if let Some(question) = command.strip_prefix('?') {
+            return Ok(self.ask_builtin(question, stdin).await);
+        }
+        
+        // `@host command` runs this one command remotely, builtins included
+        if let Some(remote) = command.strip_prefix('@') {
+            let (host, command) = remote
+                .split_once(char::is_whitespace)
+                .map_or((remote, ""), |(host, command)| (host, command.trim()));
+            if host.is_empty() || command.is_empty() {
+                return Ok(CommandResult {
+                    stderr: "❌ usage: @host <command>\n".to_string(),
+                    exit_code: 2,
+                    ..Default::default()
+                });
+            }
+            return self.execute_remote(host, command, stdin).await;
+        }
+        
+        let (builtin, arg) = command
+            .split_once(char::is_whitespace)
+            .map_or((command, ""), |(builtin, arg)| (builtin, arg.trim()));
+        if self.builtin_disabled(builtin) {
+            return self.run_external(command, stdin).await;
+        }
+        
+        // These work on the shell's own directory, which `in` leaves alone
+        let own_dir = ["cd", "pushd", "popd", "dotenv", "source", ".", "on-change"];
+        if let Some(dir) = self.command_executor.working_dir().filter(|_| own_dir.contains(&builtin)) {
+            return Ok(CommandResult {
+                stderr: format!("❌ in: {} works in the shell's own directory, not {}\n", builtin, dir.display()),
+                exit_code: 1,
+                ..Default::default()
+            });
+        }
+        
+        // Handle special commands
+        let result = match builtin {
+            "exit" | "quit" => match parse_exit_status(arg, self.last_status) {
+                Ok(status) => CommandResult {
+                    exit_code: status,
+                    exit_requested: true,
+                    ..Default::default()
+                },
+                Err(e) => CommandResult {
+                    stderr: format!("❌ {}: {}\n", builtin, e),
+                    exit_code: 2,
+                    ..Default::default()
+                },
+            },
+            "help" => self.command_help(arg).await,
+            "time" => self.time_builtin(arg, stdin).await?,
+            "retry" => self.retry_builtin(arg, stdin).await?,
+            "in" => self.in_builtin(arg, stdin).await?,
+            "on-change" => self.on_change_builtin(arg).await?,
+            "jobs" if arg.is_empty() => self.jobs.list().await,
+            "wait" if arg.is_empty() => self.jobs.wait().await,
+            "clear" if arg.is_empty() => CommandResult {
+                stdout: "\x1B[2J\x1B[1;1H".to_string(),
+                ..Default::default()
+            },
+            "history" if arg.is_empty() => self.history_listing(),
+            "cd" => self.change_directory(arg),
+            "pushd" => self.pushd_builtin(arg),
+            "popd" if arg.is_empty() => self.popd_builtin(),
+            "dirs" if arg.is_empty() => CommandResult {
+                stdout: self.dirs_listing(),
+                ..Default::default()
+            },
+            "dotenv" => self.dotenv_builtin(arg),
+            "source" | "." => self.source_builtin(builtin, arg),
+            "alias" => self.alias_builtin(arg),
+            "unalias" => self.unalias_builtin(arg),
+            "type" => self.type_builtin(arg),
+            "complete" => self.complete_builtin(arg),
+            "copy" => self.copy_builtin(arg, stdin),
+            "tee" => self.tee_builtin(arg, stdin),
+            "paste" if arg.is_empty() => self.paste_builtin(),
+            "remote" => self.remote_builtin(arg),
+            "rm" if self.config.trash_rm => self.rm_builtin(arg, stdin).await,
+            "trash-restore" => self.trash_restore_builtin(arg),
+            "trash-empty" if arg.is_empty() => self.trash_empty_builtin(),
+            "suggest-package" => self.suggest_package(arg),
+            "undo" => self.undo.builtin(arg),
+            "good" | "bad" => self.feedback.builtin(builtin, arg),
+            "palette" => CommandResult {
+                stderr: "❌ palette: only available at the interactive prompt\n".to_string(),
+                exit_code: 1,
+                ..Default::default()
+            },
+            _ => self.run_external(command, stdin).await?,
+        };
+        
+        Ok(result)
+    }
+    
+    /// Run `command` as a program, here or on the `remote` host
+    async fn run_external(&mut self, command: &str, stdin: Option<String>) -> Result<CommandResult> {
+        let program = command.split_whitespace().next().unwrap_or_default();
+        if self.config.shell_only_commands.iter().any(|name| name == program) {
+            return Ok(shell_only(program, self.builtin_disabled(program)));
+        }
+        
+        match self.remote_host.clone() {
+            Some(host) => self.execute_remote(&host, command, stdin).await,
+            None => self.execute_command(command, stdin).await,
+        }
+    }
+    
+    /// `path` as a builtin should use it: relative paths are taken from the
+    /// directory `in` is running commands in, if any
+    fn resolve(&self, path: &str) -> PathBuf {
+        match self.command_executor.working_dir() {
+            Some(dir) => dir.join(path),
+            None => PathBuf::from(path),
+        }
+    }
+    
+    /// Whether `word` runs a builtin rather than a program
+    fn is_builtin(&self, word: &str) -> bool {
+        let builtin = help::BUILTINS.iter().any(|builtin| builtin.name == word)
+            || word == "."
+            || (word == "rm" && self.config.trash_rm);
+        builtin && !self.builtin_disabled(word)
+    }
+    
+    /// Whether `name` is listed in `disabled_builtins`
+    fn builtin_disabled(&self, name: &str) -> bool {
+        self.config.disabled_builtins.iter().any(|disabled| disabled == name)
+    }
+    
+    /// The `cd` builtin
+    fn change_directory(&mut self, arg: &str) -> CommandResult {
+        let mut result = CommandResult::default();
+        
+        let target = match arg {
+            "-" => match &self.previous_dir {
+                Some(dir) => {
+                    result.stdout = format!("{}\n", dir.display());
+                    dir.clone()
+                }
+                None => {
+                    result.stderr = "❌ cd: no previous directory\n".to_string();
+                    result.exit_code = 1;
+                    return result;
+                }
+            },
+            _ => expand_directory(arg),
+        };
+        
+        self.enter_directory("cd", &target, &mut result);
+        result
+    }
+    
+    /// Make `target` the current directory for `builtin`, reporting into
+    /// `result`; false if it couldn't be entered
+    fn enter_directory(&mut self, builtin: &str, target: &Path, result: &mut CommandResult) -> bool {
+        let current = env::current_dir().ok();
+        if let Err(e) = env::set_current_dir(target) {
+            result.stderr = format!("❌ {}: {}: {}\n", builtin, target.display(), e);
+            result.exit_code = 1;
+            return false;
+        }
+        
+        self.prompt_cache.clear();
+        if let Some(current) = current {
+            env::set_var("OLDPWD", &current);
+            self.previous_dir = Some(current);
+        }
+        if let Ok(cwd) = env::current_dir() {
+            env::set_var("PWD", cwd);
+        }
+        
+        if self.config.load_dotenv && Path::new(".env").is_file() {
+            match dotenv::load(Path::new(".env")) {
+                Ok(names) => result
+                    .warnings
+                    .push(format!("Loaded {} variables from .env", names.len())),
+                Err(e) => result.warnings.push(format!("{:#}", e)),
+            }
+        }
+        
+        true
+    }
+    
+    /// The `pushd` builtin: save the current directory and change to `arg`,
+    /// or with no argument swap it with the top of the stack
+    fn pushd_builtin(&mut self, arg: &str) -> CommandResult {
+        let mut result = CommandResult::default();
+        let current = match env::current_dir() {
+            Ok(current) => current,
+            Err(e) => {
+                result.stderr = format!("❌ pushd: {}\n", e);
+                result.exit_code = 1;
+                return result;
+            }
+        };
+        
+        let target = if arg.is_empty() {
+            match self.dir_stack.pop() {
+                Some(dir) => dir,
+                None => {
+                    result.stderr = "❌ pushd: directory stack is empty\n".to_string();
+                    result.exit_code = 1;
+                    return result;
+                }
+            }
+        } else {
+            expand_directory(arg)
+        };
+        
+        if !self.enter_directory("pushd", &target, &mut result) {
+            if arg.is_empty() {
+                self.dir_stack.push(target);
+            }
+            return result;
+        }
+        self.dir_stack.push(current);
+        result.stdout = self.dirs_listing();
+        result
+    }
+    
+    /// The `popd` builtin: change back to the directory on top of the stack
+    fn popd_builtin(&mut self) -> CommandResult {
+        let mut result = CommandResult::default();
+        let Some(target) = self.dir_stack.pop() else {
+            result.stderr = "❌ popd: directory stack is empty\n".to_string();
+            result.exit_code = 1;
+            return result;
+        };
+        
+        // A directory that has gone away stays on the stack, so the rest of
+        // it isn't lost behind an error
+        if !self.enter_directory("popd", &target, &mut result) {
+            self.dir_stack.push(target);
+            return result;
+        }
+        result.stdout = self.dirs_listing();
+        result
+    }
+    
+    /// The current directory followed by the stack, most recent first, as
+    /// `dirs` shows it
+    fn dirs_listing(&self) -> String {
+        let current = env::current_dir().unwrap_or_default();
+        let dirs: Vec<_> = std::iter::once(&current)
+            .chain(self.dir_stack.iter().rev())
+            .map(|dir| prompt::display_dir(dir, config::PromptDir::Home))
+            .collect();
+        format!("{}\n", dirs.join(" "))
+    }
+    
+    /// The `dotenv` builtin: load a `.env` file on demand
+    fn dotenv_builtin(&self, arg: &str) -> CommandResult {
+        let (diff, arg) = strip_diff(arg);
+        let path = if arg.is_empty() { ".env" } else { arg };
+        
+        let before = diff.then(|| envdiff::Snapshot::take(&self.aliases));
+        match dotenv::load(Path::new(path)) {
+            Ok(names) => CommandResult {
+                stdout: match before {
+                    Some(before) => before.diff(&envdiff::Snapshot::take(&self.aliases), &self.redactor),
+                    None => format!("Loaded {} variables from {}: {}\n", names.len(), path, names.join(" ")),
+                },
+                ..Default::default()
+            },
+            Err(e) => CommandResult {
+                stderr: format!("❌ dotenv: {:#}\n", e),
+                exit_code: 1,
+                ..Default::default()
+            },
+        }
+    }
+    
+    /// The `source` builtin: run a file with /bin/sh and keep the environment
+    /// and aliases it leaves, or with `--diff`, also print what changed
+    fn source_builtin(&mut self, builtin: &str, arg: &str) -> CommandResult {
+        let (diff, path) = strip_diff(arg);
+        if path.is_empty() {
+            return CommandResult {
+                stderr: format!("❌ usage: {} [--diff] <file>\n", builtin),
+                exit_code: 2,
+                ..Default::default()
+            };
+        }
+        
+        // A sourced file can run anything, which a locked policy can't vet
+        if self.config.policy_locked {
+            return CommandResult {
+                stderr: format!("❌ {}: not allowed while {} locks the command policy\n", builtin, config::SYSTEM_POLICY_PATH),
+                exit_code: commands::EXIT_NOT_EXECUTABLE,
+                ..Default::default()
+            };
+        }
+        
+        let before = diff.then(|| envdiff::Snapshot::take(&self.aliases));
+        let mut result = CommandResult::default();
+        match login::source(&self.command_executor, Path::new(path)) {
+            Ok((status, aliases)) => {
+                result.exit_code = status;
+                for (name, value) in aliases {
+                    if let Err(e) = self.aliases.set(&name, &value) {
+                        result.stderr.push_str(&format!("❌ {}: alias {}: {}\n", builtin, name, e));
+                    }
+                }
+            }
+            Err(e) => {
+                result.stderr = format!("❌ {}: {:#}\n", builtin, e);
+                result.exit_code = commands::exit_code_for_error(&e);
+            }
+        }
+        if let Some(before) = before {
+            result.stdout = before.diff(&envdiff::Snapshot::take(&self.aliases), &self.redactor);
+        }
+        result
+    }
+    
+    /// The `alias` builtin: list, show or define aliases for this session
+    fn alias_builtin(&mut self, arg: &str) -> CommandResult {
+        if arg.is_empty() {
+            let stdout = self
+                .aliases
+                .iter()
+                .map(|(name, value)| format!("alias {}='{}'\n", name, value))
+                .collect();
+            return CommandResult { stdout, ..Default::default() };
+        }
+        
+        let Some((name, value)) = alias::parse_definition(arg) else {
+            return match self.aliases.get(arg) {
+                Some(value) => CommandResult {
+                    stdout: format!("alias {}='{}'\n", arg, value),
+                    ..Default::default()
+                },
+                None => CommandResult {
+                    stderr: format!("❌ alias: {}: not found\n", arg),
+                    exit_code: 1,
+                    ..Default::default()
+                },
+            };
+        };
+        
+        match self.aliases.set(name, value) {
+            Ok(()) => CommandResult::default(),
+            Err(e) => CommandResult {
+                stderr: format!("❌ alias: {}\n", e),
+                exit_code: 1,
+                ..Default::default()
+            },
+        }
+    }
+    
+    /// The `retry` builtin: run a command until it succeeds or the attempts
+    /// run out. Failed attempts are printed as they finish; the last one is
+    /// the result.
+    async fn retry_builtin(&mut self, arg: &str, stdin: Option<String>) -> Result<CommandResult> {
+        let (attempts, delay, command) = match parse_retry(arg) {
+            Ok(parsed) => parsed,
+            Err(e) => {
+                return Ok(CommandResult {
+                    stderr: format!("❌ retry: {}\n", e),
+                    exit_code: 2,
+                    ..Default::default()
+                })
+            }
+        };
+        let interrupted = CommandResult {
+            exit_code: 130,
+            ..Default::default()
+        };
+        let mut ctrl_c = std::pin::pin!(tokio::signal::ctrl_c());
+        // Failed attempts' output, when there is no frontend to show it
+        let mut earlier = CommandResult::default();
+        
+        let mut attempt = 1;
+        loop {
+            let mut result = tokio::select! {
+                _ = &mut ctrl_c => return Ok(interrupted),
+                result = Box::pin(self.run_pipeline(command, stdin.clone())) => result?,
+            };
+            if result.exit_code == 0 || result.exit_requested || attempt == attempts {
+                result.stdout.insert_str(0, &earlier.stdout);
+                result.stderr.insert_str(0, &earlier.stderr);
+                earlier.warnings.append(&mut result.warnings);
+                result.warnings = earlier.warnings;
+                return Ok(result);
+            }
+            
+            let message = format!(
+                "🔁 retry: attempt {}/{} failed with status {}; retrying in {}\n",
+                attempt,
+                attempts,
+                result.exit_code,
+                prompt::format_duration(delay)
+            );
+            self.report(&mut earlier, result);
+            self.report(&mut earlier, CommandResult { stderr: message, ..Default::default() });
+            tokio::select! {
+                _ = &mut ctrl_c => return Ok(interrupted),
+                _ = tokio::time::sleep(delay) => {}
+            }
+            attempt += 1;
+        }
+    }
+    
+    /// The `in` builtin: run a command's programs in another directory,
+    /// leaving the shell's where it is
+    async fn in_builtin(&mut self, arg: &str, stdin: Option<String>) -> Result<CommandResult> {
+        let fail = |message: String, exit_code| CommandResult {
+            stderr: format!("❌ in: {}\n", message),
+            exit_code,
+            ..Default::default()
+        };
+        let (dir, command) = match parse_in(arg) {
+            Ok(parsed) => parsed,
+            Err(e) => return Ok(fail(e.to_string(), 2)),
+        };
+        let path = env::current_dir().unwrap_or_default().join(expand_directory(&dir));
+        match std::fs::metadata(&path) {
+            Ok(meta) if meta.is_dir() => {}
+            Ok(_) => return Ok(fail(format!("{}: not a directory", dir), 1)),
+            Err(e) => return Ok(fail(format!("{}: {}", dir, e), 1)),
+        }
+        if self.remote_host.is_some() {
+            return Ok(fail("commands run on the remote host; `cd` there instead".to_string(), 1));
+        }
+        if self.config.sandbox.working_dir.is_some() {
+            return Ok(fail("the sandbox's working_dir is used for every command".to_string(), 1));
+        }
+        
+        let previous = self.command_executor.set_working_dir(Some(path));
+        let result = Box::pin(self.run_pipeline(command, stdin)).await;
+        self.command_executor.set_working_dir(previous);
+        result
+    }
+    
+    /// The `time` builtin: run a command, then report on stderr how long it
+    /// took and the CPU and memory its external commands used
+    async fn time_builtin(&mut self, command: &str, stdin: Option<String>) -> Result<CommandResult> {
+        let start = Instant::now();
+        let mut result = Box::pin(self.run_pipeline(command, stdin)).await?;
+        let real = start.elapsed();
+        
+        let mut report = format!("⏱️  real {}", prompt::format_duration(real));
+        if let Some(usage) = result.usage {
+            report.push_str(&format!(
+                "  user {}  sys {}",
+                prompt::format_duration(usage.user_time),
+                prompt::format_duration(usage.system_time)
+            ));
+            if let Some(kib) = usage.max_rss_kib {
+                report.push_str(&format!("  max RSS {:.1} MiB", kib as f64 / 1024.0));
+            }
+        }
+        result.stderr.push_str(&report);
+        result.stderr.push('\n');
+        Ok(result)
+    }
+    
+    /// The `on-change` builtin: run a command each time files under the
+    /// given paths change, printing its output, until Ctrl-C
+    async fn on_change_builtin(&mut self, arg: &str) -> Result<CommandResult> {
+        let fail = |message: String, exit_code| CommandResult {
+            stderr: format!("❌ on-change: {}\n", message),
+            exit_code,
+            ..Default::default()
+        };
+        let spec = match watch::parse(arg) {
+            Ok(spec) => spec,
+            Err(e) => return Ok(fail(e, 2)),
+        };
+        if let Some(missing) = spec.watch.roots.iter().find(|root| !root.exists()) {
+            return Ok(fail(format!("{}: no such file or directory", missing.display()), 1));
+        }
+        // It runs until interrupted, so each run has to be shown as it ends
+        if self.frontend.is_none() {
+            return Ok(fail("nothing here can show each run's output".to_string(), 1));
+        }
+        
+        let roots: Vec<String> = spec.watch.roots.iter().map(|root| root.display().to_string()).collect();
+        let mut shown = CommandResult::default();
+        let message = format!("👀 Watching {} for changes; press Ctrl-C to stop\n", roots.join(", "));
+        self.report(&mut shown, CommandResult { stderr: message, ..Default::default() });
+        let mut changes = match spec.watch.subscribe() {
+            Ok(changes) => changes,
+            Err(e) => return Ok(fail(format!("can't watch {}: {}", roots.join(", "), e), 1)),
+        };
+        let mut interrupted = std::pin::pin!(tokio::signal::ctrl_c());
+        
+        loop {
+            // Changes made while the command runs are queued and trigger the
+            // next run
+            let changed = tokio::select! {
+                _ = &mut interrupted => break,
+                changed = changes.next() => changed,
+            };
+            let changed = match changed {
+                Some(Ok(changed)) => changed,
+                Some(Err(e)) => {
+                    let warning = format!("⚠️  on-change: {}\n", e);
+                    self.report(&mut shown, CommandResult { stderr: warning, ..Default::default() });
+                    continue;
+                }
+                None => return Ok(fail("the watcher stopped".to_string(), 1)),
+            };
+            
+            let header = watch::header(&changed, &spec.command);
+            self.report(&mut shown, CommandResult { stderr: format!("{}\n", header), ..Default::default() });
+            tokio::select! {
+                _ = &mut interrupted => break,
+                result = self.run_watched(&spec.command) => {
+                    let mut result = result?;
+                    result.skip_pager = true;
+                    self.report(&mut shown, result);
+                }
+            }
+        }
+        
+        Ok(CommandResult {
+            exit_code: 130,
+            ..Default::default()
+        })
+    }
+    
+    /// Run the command of `on-change`, which may be a `;`/`&&`/`||` chain
+    async fn run_watched(&mut self, command: &str) -> Result<CommandResult> {
+        let mut result = CommandResult::default();
+        let chain = match self.aliases.expand(parser::split_chain(command)) {
+            Ok(chain) => chain,
+            Err(e) => {
+                result.stderr = format!("❌ alias: {}\n", e);
+                result.exit_code = 2;
+                return Ok(result);
+            }
+        };
+        let mut status = 0;
+        for (connector, command) in chain {
+            if !connector.should_run(status) {
+                continue;
+            }
+            let step = Box::pin(self.run_command(&command)).await?;
+            result.stdout.push_str(&step.stdout);
+            result.stderr.push_str(&step.stderr);
+            result.streamed.append(step.streamed);
+            result.warnings.extend(step.warnings);
+            status = step.exit_code;
+        }
+        result.exit_code = status;
+        Ok(result)
+    }
+    
+    /// The `copy` builtin: put piped input, or the arguments, on the clipboard
+    fn copy_builtin(&mut self, arg: &str, stdin: Option<String>) -> CommandResult {
+        let text = match stdin {
+            Some(text) => text,
+            None if !arg.is_empty() => arg.to_string(),
+            None => {
+                return CommandResult {
+                    stderr: "❌ copy: nothing to copy; pipe output into it, e.g. `ls | copy`\n".to_string(),
+                    exit_code: 1,
+                    ..Default::default()
+                }
+            }
+        };
+        
+        match self.clipboard.copy(&text) {
+            Ok(()) => CommandResult::default(),
+            Err(e) => CommandResult {
+                stderr: format!("❌ copy: {:#}\n", e),
+                exit_code: 1,
+                ..Default::default()
+            },
+        }
+    }
+    
+    /// The `tee` builtin: pass piped input through, also writing it to files
+    fn tee_builtin(&self, arg: &str, stdin: Option<String>) -> CommandResult {
+        let mut append = false;
+        let mut files = Vec::new();
+        for word in parser::split_words(arg) {
+            match word.as_str() {
+                "-a" | "--append" => append = true,
+                _ => files.push(word),
+            }
+        }
+        
+        let input = stdin.unwrap_or_default();
+        let mut result = CommandResult::default();
+        
+        // Every file is attempted even if one fails, like tee(1)
+        for path in &files {
+            if let Err(e) = redirect::write_file(&self.resolve(path), &input, append) {
+                result.stderr.push_str(&format!("❌ tee: {}: {}\n", path, e));
+                result.exit_code = 1;
+            }
+        }
+        
+        result.stdout = input;
+        result
+    }
+    
+    /// `rm` with `trash_rm` on: move the files to the trash. `--permanent`
+    /// runs the real rm with the other arguments instead.
+    async fn rm_builtin(&self, arg: &str, stdin: Option<String>) -> CommandResult {
+        let words = parser::split_words(arg);
+        let options = words.iter().position(|word| word == "--").unwrap_or(words.len());
+        
+        if words[..options].iter().any(|word| word == "--permanent") {
+            let args: Vec<&str> = words
+                .iter()
+                .enumerate()
+                .filter(|&(i, word)| i >= options || word != "--permanent")
+                .map(|(_, word)| word.as_str())
+                .collect();
+            return match self.command_executor.execute("rm", &args, &[], stdin).await {
+                Ok(result) => result,
+                Err(e) => CommandResult {
+                    stderr: format!("❌ Error executing command: {:#}\n", e),
+                    exit_code: commands::exit_code_for_error(&e),
+                    ..Default::default()
+                },
+            };
+        }
+        
+        // The policy still decides whether rm may be used at all
+        if let Err(e) = self.command_executor.check("rm") {
+            return CommandResult {
+                stderr: format!("❌ Error executing command: {:#}\n", e),
+                exit_code: commands::exit_code_for_error(&e),
+                ..Default::default()
+            };
+        }
+        
+        let unsupported = |option: &str| CommandResult {
+            stderr: format!("❌ rm: {} is not supported with the trash; add --permanent to delete\n", option),
+            exit_code: 1,
+            ..Default::default()
+        };
+        let (mut recursive, mut force, mut empty_dirs, mut verbose) = (false, false, false, false);
+        for word in &words[..options] {
+            let flags = match word.as_str() {
+                "--recursive" => "r",
+                "--force" => "f",
+                "--dir" => "d",
+                "--verbose" => "v",
+                flags => match flags.strip_prefix('-') {
+                    // `-` is a file name
+                    None | Some("") => continue,
+                    Some(long) if long.starts_with('-') => return unsupported(word),
+                    Some(flags) => flags,
+                },
+            };
+            for flag in flags.chars() {
+                match flag {
+                    'r' | 'R' => recursive = true,
+                    'f' => force = true,
+                    'd' => empty_dirs = true,
+                    'v' => verbose = true,
+                    _ => return unsupported(word),
+                }
+            }
+        }
+        
+        let paths: Vec<&String> = words[..options]
+            .iter()
+            .filter(|word| !word.starts_with('-') || word.as_str() == "-")
+            .chain(words.iter().skip(options + 1))
+            .collect();
+        let mut result = CommandResult::default();
+        if paths.is_empty() && !force {
+            result.stderr = "❌ rm: missing operand\n".to_string();
+            result.exit_code = 1;
+        }
+        
+        let trash = trash::Trash::new(self.config.trash_dir.as_deref());
+        for path in paths {
+            let fail = |result: &mut CommandResult, reason: &str| {
+                result.stderr.push_str(&format!("❌ rm: cannot remove '{}': {}\n", path, reason));
+                result.exit_code = 1;
+            };
+            
+            if Path::new(path).file_name().is_none() || path.ends_with("/.") {
+                fail(&mut result, "refusing to remove '.', '..' or '/'");
+                continue;
+            }
+            let target = self.resolve(path);
+            let metadata = match std::fs::symlink_metadata(&target) {
+                Ok(metadata) => metadata,
+                Err(e) if e.kind() == io::ErrorKind::NotFound && force => continue,
+                Err(e) => {
+                    fail(&mut result, &e.to_string());
+                    continue;
+                }
+            };
+            let empty = || std::fs::read_dir(&target).is_ok_and(|mut dir| dir.next().is_none());
+            if metadata.is_dir() && !recursive && !(empty_dirs && empty()) {
+                fail(&mut result, "Is a directory");
+                continue;
+            }
+            
+            match trash.put(&target) {
+                Ok(_) if verbose => result.stdout.push_str(&format!("trashed '{}'\n", path)),
+                Ok(_) => {}
+                Err(e) => fail(&mut result, &e.to_string()),
+            }
+        }
+        
+        result
+    }
+    
+    /// The `trash-restore` builtin: list the trash, or put items back where
+    /// they were, found by original path or by name in the trash
+    fn trash_restore_builtin(&self, arg: &str) -> CommandResult {
+        let trash = trash::Trash::new(self.config.trash_dir.as_deref());
+        let entries = match trash.list() {
+            Ok(entries) => entries,
+            Err(e) => {
+                return CommandResult {
+                    stderr: format!("❌ trash-restore: {}\n", e),
+                    exit_code: 1,
+                    ..Default::default()
+                }
+            }
+        };
+        
+        let mut result = CommandResult::default();
+        let targets = parser::split_words(arg);
+        if targets.is_empty() {
+            for entry in &entries {
+                result.stdout.push_str(&format!("{}  {}\n", entry.deleted, entry.original.display()));
+            }
+            if entries.is_empty() {
+                result.stdout.push_str("The trash is empty\n");
+            }
+            return result;
+        }
+        
+        for target in &targets {
+            // The most recent match, if the same path was trashed more than once
+            let original = std::path::absolute(target).ok();
+            let entry = entries
+                .iter()
+                .rev()
+                .find(|entry| Some(&entry.original) == original.as_ref())
+                .or_else(|| entries.iter().find(|entry| &entry.name == target));
+            
+            let restored = match entry {
+                Some(entry) => trash.restore(entry).map(|()| entry),
+                None => Err(io::Error::new(io::ErrorKind::NotFound, "not in the trash")),
+            };
+            match restored {
+                Ok(entry) => result.stdout.push_str(&format!("Restored {}\n", entry.original.display())),
+                Err(e) => {
+                    result.stderr.push_str(&format!("❌ trash-restore: {}: {}\n", target, e));
+                    result.exit_code = 1;
+                }
+            }
+        }
+        
+        result
+    }
+    
+    /// The `trash-empty` builtin
+    fn trash_empty_builtin(&self) -> CommandResult {
+        match trash::Trash::new(self.config.trash_dir.as_deref()).empty() {
+            Ok(count) => CommandResult {
+                stdout: format!("🗑️  Deleted {} items from the trash\n", count),
+                ..Default::default()
+            },
+            Err(e) => CommandResult {
+                stderr: format!("❌ trash-empty: {}\n", e),
+                exit_code: 1,
+                ..Default::default()
+            },
+        }
+    }
+    
+    /// A `?question` pipeline stage: the AI's answer about the piped input
+    async fn ask_builtin(&mut self, question: &str, stdin: Option<String>) -> CommandResult {
+        let fail = |message: String, exit_code| CommandResult {
+            stderr: format!("❌ ?: {}\n", message),
+            exit_code,
+            ..Default::default()
+        };
+        
+        let question = parser::split_words(question).join(" ");
+        if question.is_empty() {
+            return fail("usage: command | ?\"question\"".to_string(), 2);
+        }
+        if !self.ai_available() {
+            return fail("the AI is off".to_string(), 1);
+        }
+        
+        let stdin = stdin.unwrap_or_default();
+        let input = self.ai_engine.input_budget(&stdin);
+        let mut result = match self.ai_engine.ask_about(&question, input).await {
+            Ok(answer) => CommandResult {
+                stdout: format!("{}\n", answer),
+                ..Default::default()
+            },
+            Err(e) => return fail(format!("{:#}", e), 1),
+        };
+        if input.len() < stdin.len() {
+            result.warnings.push(format!(
+                "Only the first {} of {} bytes of input were sent to the AI (ai_config.max_input_tokens)",
+                input.len(),
+                stdin.len()
+            ));
+        }
+        result
+    }
+    
+    /// The `@json` builtin: print what a path filter selects from the JSON
+    /// piped into it
+    fn json_builtin(&self, arg: &str, stdin: Option<String>) -> CommandResult {
+        let fail = |message: String, exit_code| CommandResult {
+            stderr: format!("❌ @json: {}\n", message),
+            exit_code,
+            ..Default::default()
+        };
+        
+        let (mut raw, mut compact, mut filter) = (false, false, None);
+        for word in parser::split_words(arg) {
+            match word.as_str() {
+                "-r" | "--raw-output" => raw = true,
+                "-c" | "--compact-output" => compact = true,
+                _ if filter.is_none() => filter = Some(word),
+                _ => return fail("usage: @json [-r] [-c] [filter]".to_string(), 2),
+            }
+        }
+        let Some(input) = stdin else {
+            return fail("nothing to read; pipe JSON into it, e.g. `cat data.json | @json .items`".to_string(), 2);
+        };
+        
+        match json::filter(&input, filter.as_deref().unwrap_or(".")) {
+            Ok(values) => {
+                let mut stdout = String::new();
+                for value in values {
+                    let text = match value {
+                        serde_json::Value::String(text) if raw => text,
+                        value if compact => value.to_string(),
+                        value => serde_json::to_string_pretty(&value).unwrap_or_default(),
+                    };
+                    stdout.push_str(&text);
+                    stdout.push('\n');
+                }
+                CommandResult {
+                    stdout,
+                    ..Default::default()
+                }
+            }
+            Err(e) => fail(e, 1),
+        }
+    }
+    
+    /// The `paste` builtin: print the clipboard's text
+    fn paste_builtin(&mut self) -> CommandResult {
+        match self.clipboard.paste() {
+            Ok(text) => CommandResult {
+                stdout: text,
+                ..Default::default()
+            },
+            Err(e) => CommandResult {
+                stderr: format!("❌ paste: {:#}\n", e),
+                exit_code: 1,
+                ..Default::default()
+            },
+        }
+    }
+    
+    /// The `unalias` builtin
+    fn unalias_builtin(&mut self, arg: &str) -> CommandResult {
+        if arg == "-a" {
+            self.aliases.clear();
+            return CommandResult::default();
+        }
+        
+        let mut result = CommandResult::default();
+        for name in arg.split_whitespace() {
+            if !self.aliases.remove(name) {
+                result.stderr.push_str(&format!("❌ unalias: {}: not found\n", name));
+                result.exit_code = 1;
+            }
+        }
+        result
+    }
+    
+    /// The `type` builtin: say what each name runs, the first of an alias,
+    /// a builtin and a program on `PATH`, or with -a every one of them
+    fn type_builtin(&self, arg: &str) -> CommandResult {
+        let mut words = arg.split_whitespace().peekable();
+        let all = words.next_if_eq(&"-a").is_some();
+        if words.peek().is_none() {
+            return CommandResult {
+                stderr: "❌ usage: type [-a] <name>...\n".to_string(),
+                exit_code: 2,
+                ..Default::default()
+            };
+        }
+        
+        let mut result = CommandResult::default();
+        for name in words {
+            let mut found = Vec::new();
+            if let Some(value) = self.aliases.get(name) {
+                found.push(format!("{} is aliased to `{}'", name, value));
+            }
+            if self.is_builtin(name) {
+                found.push(format!("{} is a shell builtin", name));
+            }
+            if all || found.is_empty() {
+                let programs: Vec<PathBuf> = match which::which_all(name) {
+                    Ok(programs) => programs.collect(),
+                    Err(_) => Vec::new(),
+                };
+                found.extend(programs.iter().map(|program| format!("{} is {}", name, program.display())));
+            }
+            
+            if found.is_empty() {
+                result.stderr.push_str(&format!("❌ type: {}: not found\n", name));
+                result.exit_code = 1;
+            }
+            let shown = if all { found.len() } else { 1 };
+            for line in found.iter().take(shown) {
+                result.stdout.push_str(line);
+                result.stdout.push('\n');
+            }
+        }
+        result
+    }
+    
+    /// The `complete` builtin: list, register or remove completions for
+    /// commands' arguments
+    fn complete_builtin(&mut self, arg: &str) -> CommandResult {
+        let words = parser::split_words(arg);
+        let Ok(mut completion) = self.completion.lock() else {
+            return CommandResult {
+                stderr: "❌ complete: completion is unavailable\n".to_string(),
+                exit_code: 1,
+                ..Default::default()
+            };
+        };
+        
+        let mut result = CommandResult::default();
+        match words.split_first() {
+            None => result.stdout = completion.specs().concat(),
+            Some((flag, names)) if flag == "-p" || !flag.starts_with('-') => {
+                let names = if flag == "-p" { names } else { &words[..] };
+                if names.is_empty() {
+                    result.stdout = completion.specs().concat();
+                }
+                for name in names {
+                    match completion.spec(name) {
+                        Some(spec) => result.stdout.push_str(&spec),
+                        // Built-in completers have no specification to show
+                        None => {
+                            result.stderr.push_str(&format!("❌ complete: {}: no completion specification\n", name));
+                            result.exit_code = 1;
+                        }
+                    }
+                }
+            }
+            Some((flag, names)) if flag == "-r" && !names.is_empty() => {
+                for name in names {
+                    if !completion.unregister(name) {
+                        result.stderr.push_str(&format!("❌ complete: {}: no completion specification\n", name));
+                        result.exit_code = 1;
+                    }
+                }
+            }
+            Some((flag, rest)) if flag == "-W" && rest.len() > 1 => {
+                for name in &rest[1..] {
+                    completion.register(name, Box::new(completion::WordListCompleter::new(&rest[0])));
+                }
+            }
+            Some((flag, rest)) if flag == "-C" && rest.len() > 1 => {
+                if let Some(e) = line_programs(&rest[0]).iter().find_map(|program| self.command_executor.check(program).err()) {
+                    result.stderr = format!("❌ complete: {}\n", e);
+                    result.exit_code = commands::exit_code_for_error(&e);
+                    return result;
+                }
+                for name in &rest[1..] {
+                    let generator = completion::GeneratorCompleter::new(self.command_executor.clone(), name, &rest[0]);
+                    completion.register(name, Box::new(generator));
+                }
+            }
+            _ => {
+                result.stderr = "❌ usage: complete [-p] [name]... | -W <words> <name>... | -C <command> <name>... | -r <name>...\n".to_string();
+                result.exit_code = 2;
+            }
+        }
+        result
+    }
+    
+    /// Load a `.env` file at startup if it exists, reporting the outcome
+    fn report_dotenv(&self, path: &Path) {
+        if !path.is_file() {
+            return;
+        }
+        
+        match dotenv::load(path) {
+            Ok(names) if !self.config.quiet => {
+                outln!("📄 Loaded {} variables from {}", names.len(), path.display())
+            }
+            Ok(_) => {}
+            Err(e) => eprintln!("⚠️  {:#}", e),
+        }
+    }
+    
+    /// Execute an external command, feeding it `stdin` if given
+    async fn execute_command(&mut self, command: &str, stdin: Option<String>) -> Result<CommandResult> {
+        // Split command into words, removing quotes
+        let words = parser::split_words(command);
+        let parts: Vec<&str> = words.iter().map(String::as_str).collect();
+        
+        // Leading NAME=value tokens only apply to this command's environment
+        let (env, parts) = commands::split_env_assignments(&parts);
+        if parts.is_empty() {
+            return Ok(CommandResult::default());
+        }
+        
+        let (program, args) = parts.split_first().unwrap();
+        
+        // Execute the command
+        match self.command_executor.execute(program, args, &env, stdin).await {
+            Ok(result) => Ok(result),
+            Err(e) => {
+                let mut result = CommandResult {
+                    stderr: format!("❌ Error executing command: {:#}\n", e),
+                    exit_code: commands::exit_code_for_error(&e),
+                    ..Default::default()
+                };
+                if result.exit_code == commands::EXIT_NOT_FOUND {
+                    self.command_not_found(program, &mut result).await?;
+                }
+                Ok(result)
+            }
+        }
+    }
+    
+    /// Run the `command_not_found` handler for `program`, adding what it
+    /// prints to `result`'s stderr
+    async fn command_not_found(&mut self, program: &str, result: &mut CommandResult) -> Result<()> {
+        let Some(handler) = self.config.command_not_found.clone() else {
+            return Ok(());
+        };
+        if std::mem::replace(&mut self.handling_not_found, true) {
+            return Ok(());
+        }
+        
+        // What it prints goes to stderr, so it can't be shown as it comes
+        let line = format!("{} {}", handler, parser::quote(program));
+        let streaming = std::mem::replace(&mut self.streaming, false);
+        let output = Box::pin(self.run_pipeline(&line, None)).await;
+        self.streaming = streaming;
+        self.handling_not_found = false;
+        
+        let output = output?;
+        result.stderr.push_str(&output.stdout);
+        result.stderr.push_str(&output.stderr);
+        result.warnings.extend(output.warnings);
+        Ok(())
+    }
+    
+    /// The `suggest-package` builtin: name the package that provides a
+    /// program, from `package_map`. Prints nothing for unknown programs.
+    fn suggest_package(&self, arg: &str) -> CommandResult {
+        let fail = |message: String, exit_code| CommandResult {
+            stderr: format!("❌ suggest-package: {}\n", message),
+            exit_code,
+            ..Default::default()
+        };
+        let Some(path) = &self.config.package_map else {
+            return fail("no package_map in the configuration".to_string(), 1);
+        };
+        let words = parser::split_words(arg);
+        let [program] = words.as_slice() else {
+            return fail("usage: suggest-package <program>".to_string(), 2);
+        };
+        
+        let packages = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))
+            .and_then(|contents| {
+                toml::from_str::<std::collections::BTreeMap<String, String>>(&contents)
+                    .with_context(|| format!("Invalid package map {}", path.display()))
+            });
+        match packages {
+            Ok(packages) => match packages.get(program) {
+                Some(package) => CommandResult {
+                    stdout: format!("💡 {} is provided by the {} package\n", program, package),
+                    ..Default::default()
+                },
+                None => CommandResult {
+                    exit_code: 1,
+                    ..Default::default()
+                },
+            },
+            Err(e) => fail(format!("{:#}", e), 1),
+        }
+    }
+    
+    /// Run `command` on `host` over SSH; pipes and redirections around it
+    /// were already handled here
+    async fn execute_remote(&self, host: &str, command: &str, stdin: Option<String>) -> Result<CommandResult> {
+        let words = parser::split_words(command);
+        let parts: Vec<&str> = words.iter().map(String::as_str).collect();
+        let (_, parts) = commands::split_env_assignments(&parts);
+        let Some(program) = parts.first() else {
+            return Ok(CommandResult::default());
+        };
+        
+        match self.command_executor.execute_remote(host, program, command, stdin).await {
+            Ok(result) => Ok(result),
+            Err(e) => Ok(CommandResult {
+                stderr: format!("❌ Error executing command on {}: {:#}\n", host, e),
+                exit_code: commands::exit_code_for_error(&e),
+                ..Default::default()
+            }),
+        }
+    }
+    
+    /// The `remote` builtin: show, set or clear the host commands run on
+    fn remote_builtin(&mut self, arg: &str) -> CommandResult {
+        let stdout = match arg {
+            "" => match &self.remote_host {
+                Some(host) => format!("Running commands on {} ({})\n", host, self.config.remote.destination(host)),
+                None => "Running commands locally\n".to_string(),
+            },
+            "off" => {
+                self.remote_host = None;
+                String::new()
+            }
+            host if host.contains(char::is_whitespace) => {
+                return CommandResult {
+                    stderr: "❌ usage: remote [host|off]\n".to_string(),
+                    exit_code: 2,
+                    ..Default::default()
+                };
+            }
+            host => {
+                self.remote_host = Some(host.to_string());
+                String::new()
+            }
+        };
+        
+        CommandResult {
+            stdout,
+            ..Default::default()
+        }
+    }
+    
+    /// Help overview
+    fn help_text(&self) -> String {
+        let mut text = String::new();
+        text.push_str("\n💠 Obsidian Shell Help\n");
+        text.push_str("=====================\n");
+        text.push_str("Built-in commands:\n");
+        text.push_str("  help     - Show this help\n");
+        text.push_str("  help <command> - Show help for a command\n");
+        text.push_str("  palette  - Search builtins, common actions and recent commands (F1)\n");
+        text.push_str("  clear    - Clear the screen\n");
+        text.push_str("  history  - Show command history\n");
+        text.push_str("  !n / !! / !n:p - Edit history entry n or the last one, or just print it\n");
+        text.push_str("  ^old^new - Run the last command with old replaced by new\n");
+        text.push_str("  !! <command> - Run a command line as typed with $SHELL, for syntax this shell lacks\n");
+        text.push_str("  cd [dir] - Change the current directory\n");
+        text.push_str("  pushd [dir] / popd / dirs - Change directory keeping a stack, return, list it\n");
+        text.push_str("  dotenv [--diff] [file] - Load variables from a .env file\n");
+        text.push_str("  source [--diff] <file> - Run a file with sh, keeping its variables and aliases\n");
+        text.push_str("  nopager <command> - Run a command without paging its output\n");
+        text.push_str("  nocorrect <command> - Run a command without fixing typos from corrections\n");
+        text.push_str("  <command> g/pattern/[iv] - Show only output lines matching a regex\n");
+        text.push_str("  time <command> - Run a command and report its run time, CPU and memory use\n");
+        text.push_str("  in <dir> -- <command> - Run a command in another directory, staying in this one\n");
+        text.push_str("  retry [-n N] [--delay S] <command> - Rerun a failing command until it succeeds\n");
+        text.push_str("  on-change [--ignore <pattern>]... <path>... -- <command> - Rerun a command when files change\n");
+        text.push_str("  <command> & / jobs / wait - Run a pipeline in the background, list jobs, wait for them\n");
+        text.push_str("  alias [name[=value]] - List or define aliases\n");
+        text.push_str("  unalias <name> - Remove an alias\n");
+        text.push_str("  type [-a] <name>... - Say whether a name is an alias, a builtin or a program\n");
+        text.push_str("  complete [-W <words> | -C <command> | -r] <name>... - Add or remove completions for a command's arguments\n");
+        text.push_str("  copy / paste - Copy piped output to the clipboard, print the clipboard\n");
+        text.push_str("  tee [-a] <file>... - Pass piped output through, also saving it to files\n");
+        text.push_str("  @host <command> - Run a command on another machine over SSH\n");
+        text.push_str("  <command> | ?\"question\" - Ask the AI about a command's output\n");
+        text.push_str("  @json [-r] [filter] - Select from piped JSON with a path like .items[0].name\n");
+        text.push_str("  trash-restore [path]... / trash-empty - List or restore trashed files, or delete them\n");
+        text.push_str("  remote [host|off] - Run external commands on a host until turned off\n");
+        text.push_str("  suggest-package <program> - Name the package that provides a program\n");
+        text.push_str("  undo [-l] - Reverse the last AI command's mkdir, mv or touch\n");
+        text.push_str("  good / bad [note] - Rate the last AI interpretation, with ai_feedback on\n");
+        text.push_str("  exit [N] - Exit the shell\n");
+        text.push_str("  quit [N] - Exit the shell\n");
+        text.push_str("\nA command word is looked up as an alias, then a builtin, then on PATH.\n");
+        text.push_str("Builtins listed in disabled_builtins are skipped.\n");
+        text.push_str("\nVariables:\n");
+        text.push_str("  $? - Exit status of the last command\n");
+        text.push_str("  $_OUT / $_ERR - Its stdout and stderr, up to output_variable_limit bytes (64 KiB)\n");
+        text.push_str("\nAI Features:\n");
+        text.push_str("  Natural language commands are automatically interpreted\n");
+        text.push_str("  Examples:\n");
+        text.push_str("    'find all text files' -> 'find . -name \"*.txt\"'\n");
+        text.push_str("    'show running processes' -> 'ps aux'\n");
+        text.push_str("    'install python package requests' -> 'pip install requests'\n");
+        text.push('\n');
+        text
+    }
+    
+    /// Help for a single command: builtin usage, local docs, or an AI summary.
+    /// An empty topic gives the overview.
+    async fn command_help(&mut self, topic: &str) -> CommandResult {
+        let (mut ask_ai, mut refresh) = (false, false);
+        let mut topic = topic.trim();
+        loop {
+            let (flag, rest) = topic.split_once(char::is_whitespace).unwrap_or((topic, ""));
+            match flag {
+                "--ai" => ask_ai = true,
+                "--refresh" => refresh = true,
+                _ => break,
+            }
+            topic = rest.trim_start();
+        }
+        
+        let found = |text: String| CommandResult {
+            stdout: format!("{}\n", text),
+            ..Default::default()
+        };
+        let not_found = |detail: String| CommandResult {
+            stderr: format!("❌ No help found for '{}'{}\n", topic, detail),
+            exit_code: 1,
+            ..Default::default()
+        };
+        
+        if topic.is_empty() {
+            return CommandResult {
+                stdout: self.help_text(),
+                ..Default::default()
+            };
+        }
+        
+        if !ask_ai {
+            if let Some(builtin) = help::builtin_help(topic) {
+                return found(builtin.render());
+            }
+            
+            if let Some(text) = help::external_help(&self.command_executor, topic, refresh).await {
+                return found(text);
+            }
+        }
+        
+        if !self.ai_available() {
+            return not_found(String::new());
+        }
+        
+        match self.ai_engine.explain_command(topic).await {
+            Ok(summary) => found(format!("🤖 {}", summary)),
+            Err(e) => not_found(format!(" ({})", e)),
+        }
+    }
+    
+    /// Recent command history
+    fn history_listing(&self) -> CommandResult {
+        match self.history.get_recent(10) {
+            Ok(history) => {
+                // Numbered from the start of the history, as `!n` counts
+                let first = self.history.len() - history.len() + 1;
+                let mut text = String::from("\nCommand History:\n================\n");
+                for (i, command) in history.iter().enumerate() {
+                    text.push_str(&format!("{:3}: {}\n", first + i, self.redactor.redact(command)));
+                }
+                text.push('\n');
+                CommandResult {
+                    stdout: text,
+                    ..Default::default()
+                }
+            }
+            Err(e) => CommandResult {
+                stderr: format!("❌ Error loading history: {}\n", e),
+                exit_code: 1,
+                ..Default::default()
+            },
+        }
+    }
+}
+
+/// The line after a leading `!! `, which runs it with the fallback shell.
+/// `!!` alone recalls the last command instead.
+fn strip_delegate(input: &str) -> Option<&str> {
+    input.strip_prefix("!! ").map(str::trim_start)
+}
+
+/// The program of each command in `line` that this shell's parser finds,
+/// for checking a delegated line against the policy
+fn line_programs(line: &str) -> Vec<String> {
+    parser::split_chain(line)
+        .iter()
+        .flat_map(|(_, command)| parser::split_pipeline(command))
+        .filter_map(|stage| {
+            let words = parser::split_words(&stage);
+            let parts: Vec<&str> = words.iter().map(String::as_str).collect();
+            let (_, parts) = commands::split_env_assignments(&parts);
+            parts.first().map(|program| program.to_string())
+        })
+        .collect()
+}
+
+/// Split off a leading `nopager `, which bypasses the pager for one command
+fn strip_nopager(input: &str) -> (bool, &str) {
+    match input.trim().strip_prefix("nopager ") {
+        Some(rest) => (true, rest.trim_start()),
+        None => (false, input.trim()),
+    }
+}
+
+/// Split a leading `--diff` off a builtin's argument
+fn strip_diff(arg: &str) -> (bool, &str) {
+    match arg.strip_prefix("--diff") {
+        Some(rest) if rest.is_empty() || rest.starts_with(char::is_whitespace) => (true, rest.trim_start()),
+        _ => (false, arg),
+    }
+}
+
+/// Parse `--temperature`, which must be within 0.0..=2.0
+fn parse_temperature(arg: &str) -> Result<f32, String> {
+    let temperature: f32 = arg.parse().map_err(|_| format!("'{}' is not a number", arg))?;
+    if !(0.0..=2.0).contains(&temperature) {
+        return Err(format!("{} is not between 0.0 and 2.0", temperature));
+    }
+    Ok(temperature)
+}
+
+/// Whether `input` itself asks for several commands, with separators or words
+/// like "then"
+fn implies_chaining(input: &str) -> bool {
+    parser::split_chain(input).len() > 1
+        || input
+            .split_whitespace()
+            .any(|word| matches!(word.to_lowercase().as_str(), "then" | "and" | "after" | "afterwards"))
+}
+
+/// A `cd`-style directory argument: empty for $HOME, `~` expanding to it
+fn expand_directory(arg: &str) -> PathBuf {
+    let home = env::var("HOME").unwrap_or_else(|_| "/".to_string());
+    if arg.is_empty() {
+        return PathBuf::from(home);
+    }
+    match arg.strip_prefix('~') {
+        Some(rest) if rest.is_empty() || rest.starts_with('/') => PathBuf::from(format!("{}{}", home, rest)),
+        _ => PathBuf::from(arg),
+    }
+}
+
+/// The start of `text` that fits in `limit` bytes, ending at a line break
+/// if it had to be cut, and whether it was
+fn cap_output(text: &str, limit: usize) -> (&str, bool) {
+    if text.len() <= limit {
+        return (text, false);
+    }
+    let mut end = limit;
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    let end = text[..end].rfind('\n').map_or(end, |newline| newline + 1);
+    (&text[..end], true)
+}
+
+/// Parse the optional argument to `exit`/`quit`.
+///
+/// No argument keeps `last_status`; numbers wrap into 0..=255 like POSIX shells.
+fn parse_exit_status(arg: &str, last_status: i32) -> Result<i32> {
+    if arg.is_empty() {
+        return Ok(last_status);
+    }
+    
+    let status: i64 = arg
+        .parse()
+        .map_err(|_| anyhow::anyhow!("{}: numeric argument required", arg))?;
+    Ok(status.rem_euclid(256) as i32)
+}
+
+/// The directory and the command of `in <dir> -- <command>`
+fn parse_in(arg: &str) -> Result<(String, &str), &'static str> {
+    const USAGE: &str = "usage: in <dir> -- <command>";
+    let tokens = parser::tokenize(arg);
+    let separator = tokens
+        .iter()
+        .position(|token| token.kind == parser::TokenKind::Word("--".to_string()))
+        .ok_or(USAGE)?;
+    let command = arg[tokens[separator].span.end..].trim();
+    match &tokens[..separator] {
+        [parser::Token {
+            kind: parser::TokenKind::Word(dir),
+            ..
+        }] if !command.is_empty() => Ok((dir.clone(), command)),
+        _ => Err(USAGE),
+    }
+}
+
+/// The attempts, the delay between them and the command of
+/// `retry [-n N] [--delay S] <command>`
+fn parse_retry(arg: &str) -> Result<(u32, Duration, &str), String> {
+    const USAGE: &str = "usage: retry [-n N] [--delay S] <command>";
+    fn next_word(text: &str) -> (&str, &str) {
+        text.split_once(char::is_whitespace)
+            .map_or((text, ""), |(word, rest)| (word, rest.trim_start()))
+    }
+    let (mut attempts, mut delay) = (3, Duration::from_secs(1));
+    let mut rest = arg.trim();
+    loop {
+        let (flag, after) = next_word(rest);
+        // `--delay=2` or `--delay 2`
+        let (flag, value, after) = match flag.split_once('=') {
+            Some((flag, value)) => (flag, value, after),
+            None => {
+                let (value, after) = next_word(after);
+                (flag, value, after)
+            }
+        };
+        match flag {
+            "-n" => {
+                attempts = value
+                    .parse()
+                    .ok()
+                    .filter(|&n| n > 0)
+                    .ok_or_else(|| format!("-n needs a number of attempts, not '{}'", value))?;
+            }
+            "--delay" => {
+                delay = value
+                    .parse()
+                    .ok()
+                    .and_then(|seconds| Duration::try_from_secs_f64(seconds).ok())
+                    .ok_or_else(|| format!("--delay needs a number of seconds, not '{}'", value))?;
+            }
+            "--" => {
+                rest = next_word(rest).1;
+                break;
+            }
+            _ => break,
+        }
+        rest = after;
+    }
+    
+    if rest.is_empty() {
+        return Err(USAGE.to_string());
+    }
+    Ok((attempts, delay, rest))
+}
+
+/// Create a shell for `cli`, set up its AI session and recording, and initialize it
+async fn start_shell(cli: &Cli, ai_mode: AiMode) -> Result<ObsidianShell> {
+    let mut shell = ObsidianShell::new(&cli.config, ai_mode)?;
+    shell.config.quiet |= cli.quiet;
+    if cli.no_motd {
+        shell.config.motd = Some(String::new());
+    }
+    shell.ai_engine.override_sampling(cli.max_tokens.map(|n| n as usize), cli.temperature);
+    shell.ai_engine.set_show_prompts(cli.verbose);
+    shell.verbose = cli.verbose;
+    shell.ai_flag = match (cli.ai, cli.no_ai) {
+        (true, _) => Some("--ai"),
+        (_, true) => Some("--no-ai"),
+        _ => None,
+    };
+    shell.ai_engine.start_session(cli.replay.as_deref())?;
+    if let Some(path) = &cli.record {
+        shell.recorder = Some(SessionRecorder::create(path, shell.redactor.clone())?);
+    }
+    shell.initialize().await?;
+    Ok(shell)
+}
+
+/// Print `result`, paging stdout when it is long and `pager_enabled`
+fn show_result(result: &CommandResult, quiet: bool, pager_enabled: bool) {
+    for warning in &result.warnings {
+        eprintln!("⚠️  {}", warning);
+    }
+    // Shown even in quiet mode, as what ran isn't what was typed
+    for correction in &result.corrected {
+        eprintln!("✏️  Corrected {}", correction);
+    }
+    if let Some(shell) = result.delegated_to.as_ref().filter(|_| !quiet) {
+        eprintln!("🐚 Ran with {}", shell);
+    }
+    if let Some(interpreted) = result.interpreted.as_ref().filter(|_| !quiet) {
+        match &result.interpretation_reason {
+            Some(reason) => outln!("🤖 Running `{}` because {}", interpreted, reason),
+            None => outln!("🤖 AI interpretation: {}", interpreted),
+        }
+    }
+    if let Some(e) = &result.ai_error {
+        outln!("⚠️  AI interpretation failed: {}", e);
+    }
+    if let Some(fallback) = &result.ai_fallback {
+        outln!("{}", fallback);
+    }
+    
+    let stdout = match &result.line_filter {
+        Some(filter) => Cow::Owned(filter.apply(&result.stdout)),
+        None => Cow::Borrowed(&result.stdout),
+    };
+    
+    let mut paged = false;
+    if pager_enabled && !result.skip_pager && pager::should_page(&stdout) {
+        match pager::page(&stdout) {
+            Ok(()) => paged = true,
+            Err(e) => eprintln!("⚠️  {:#}", e),
+        }
+    }
+    
+    if !paged {
+        out!("{}", stdout);
+    }
+    eprint!("{}", result.stderr);
+    output::flush();
+}
+
+/// The `--verbose` table of a pipeline's stages and how long each ran.
+/// Stages connected by OS pipes run at the same time, so a fast stage
+/// waiting on a slow neighbour shows about the same time as it.
+fn stage_report(timings: &[commands::StageTiming]) -> String {
+    let durations: Vec<String> = timings.iter().map(|timing| prompt::format_duration(timing.duration)).collect();
+    let width = durations.iter().map(String::len).max().unwrap_or(0);
+    
+    let mut report = "⏱️  Pipeline stages:\n".to_string();
+    for (timing, duration) in timings.iter().zip(&durations) {
+        report.push_str(&format!("  {:>width$}  {}\n", duration, timing.command, width = width));
+    }
+    report
+}
+
+/// The refusal for `program`, one of `shell_only_commands` that reached
+/// `run_external`: run apart from the shell it couldn't change it.
+/// `disabled` says its builtin is in `disabled_builtins`.
+fn shell_only(program: &str, disabled: bool) -> CommandResult {
+    let builtin = if disabled { "the builtin is disabled" } else { "there is no builtin" };
+    let mut stderr = format!("❌ {}: only works inside the shell, and {}, so nothing was run\n", program, builtin);
+    if matches!(program, "export" | "unset" | "set" | "readonly" | "declare" | "typeset" | "local") {
+        stderr.push_str("💡 To set variables, source a script or load a .env file with dotenv\n");
+    }
+    CommandResult {
+        stderr,
+        exit_code: 1,
+        ..Default::default()
+    }
+}
+
+/// What runs for each pipeline stage, from its words with quotes removed.
+/// Every stage has a program, as `streamable` checks.
+fn invocations<'a>(parts: &'a [Vec<&'a str>]) -> Vec<commands::Invocation<'a>> {
+    parts
+        .iter()
+        .map(|parts| {
+            let (env, parts) = commands::split_env_assignments(parts);
+            let (program, args) = parts.split_first().unwrap();
+            commands::Invocation { program, args, env }
+        })
+        .collect()
+}
+
+/// Run `command` for `-c`: no banner, no history, and AI only with `--ai`.
+/// `params` are `$0` and the positional parameters; like sh, `$0` defaults
+/// to the shell's own name.
+async fn run_command_string(cli: &Cli, command: &str, params: &[String]) -> Result<i32> {
+    let ai_mode = if cli.ai { AiMode::Always } else { AiMode::Off };
+    let mut shell = ObsidianShell::new(&cli.config, ai_mode)?;
+    shell.config.quiet = true;
+    shell.history.set_saving(false);
+    shell.ai_engine.override_sampling(cli.max_tokens.map(|n| n as usize), cli.temperature);
+    shell.ai_engine.set_show_prompts(cli.verbose);
+    shell.verbose = cli.verbose;
+    if let Some(path) = &cli.record {
+        shell.recorder = Some(SessionRecorder::create(path, shell.redactor.clone())?);
+    }
+    shell.initialize().await?;
+    shell.use_terminal();
+    
+    let mut params = params.to_vec();
+    if params.is_empty() {
+        params.push(env::args().next().unwrap_or_else(|| "obsidian-shell".to_string()));
+    }
+    let command = parser::expand_positional(command, &params);
+    
+    let result = shell.eval(&command).await?;
+    shell.print_result(&result);
+    Ok(result.exit_code)
+}
+
+/// Print how the offline rules, the main backend and each of `ai_backends`
+/// interpret every input, for `interpret --compare`. The backends are asked
+/// at the same time; one that fails is shown as errored and the status is 1.
+async fn compare_backends(cli: &Cli, config: &ShellConfig, inputs: &[String], context: &str) -> i32 {
+    const RULES: &str = "rules";
+    let main = if cli.no_ai || !config.ai_enabled {
+        Err("AI is disabled".to_string())
+    } else {
+        Ok(config.ai_config.clone())
+    };
+    let mut backends = vec![("default".to_string(), main)];
+    backends.extend(
+        config
+            .ai_backends()
+            .into_iter()
+            .map(|(name, backend)| (name, backend.map_err(|e| format!("{:#}", e)))),
+    );
+    let width = backends.iter().map(|(name, _)| name.len()).max().unwrap_or_default().max(RULES.len());
+    // Errors such as TOML's span lines, which would break up the columns
+    let errored = |error: String| {
+        let lines: Vec<&str> = error.lines().map(str::trim).filter(|line| !line.is_empty()).collect();
+        format!("❌ {}", lines.join(" "))
+    };
+    
+    let mut status = 0;
+    for (i, input) in inputs.iter().enumerate() {
+        let requests: Vec<_> = backends
+            .iter()
+            .map(|(_, backend)| {
+                let (backend, input, context) = (backend.clone(), input.clone(), context.to_string());
+                let (max_tokens, temperature, verbose) = (cli.max_tokens, cli.temperature, cli.verbose);
+                tokio::spawn(async move {
+                    let mut engine = AIEngine::new(&backend.map_err(anyhow::Error::msg)?)?;
+                    engine.override_sampling(max_tokens.map(|n| n as usize), temperature);
+                    engine.set_show_prompts(verbose);
+                    engine.interpret_command(&input, &context).await.map(|interpretation| interpretation.command)
+                })
+            })
+            .collect();
+        
+        if inputs.len() > 1 {
+            if i > 0 {
+                outln!();
+            }
+            outln!("{}", input);
+        }
+        outln!("  {:width$}  {}", RULES, ai::offline_interpretation(input));
+        for ((name, _), request) in backends.iter().zip(requests) {
+            let reply = match request.await {
+                Ok(Ok(interpreted)) => interpreted.replace('\n', &format!("\n{:1$}", "", width + 4)),
+                Ok(Err(e)) => {
+                    status = 1;
+                    errored(format!("{:#}", e))
+                }
+                Err(e) => {
+                    status = 1;
+                    errored(e.to_string())
+                }
+            };
+            outln!("  {:width$}  {}", name, reply);
+        }
+    }
+    status
+}
+
+/// Main function
+#[tokio::main]
+async fn main() -> Result<()> {
+    let cli = Cli::parse();
+    ui::set_override(cli.color);
+    logging::init(cli.log_level.as_deref())?;
+    
+    // Before anything reads the environment, so the profiles' PATH and the
+    // like apply to the config and every command
+    if cli.login || login::invoked_as_login() {
+        if let Err(e) = login::load_profiles() {
+            eprintln!("⚠️  {:#}", e);
+        }
+    }
+    
+    // `-c` takes the place of a subcommand, so tools running `$SHELL -c`
+    // never reach the interactive shell
+    if let Some(command) = &cli.command_string {
+        let status = run_command_string(&cli, &command[0], &command[1..]).await?;
+        output::flush();
+        std::process::exit(status);
+    }
+    
+    // Exit status of the last command run, reported as the process exit code
+    let status = match &cli.command {
+        Some(Commands::Exec { command, interpret }) => {
+            // Execute single command, only using AI interpretation when asked to
+            let ai_mode = if (*interpret || cli.ai) && !cli.no_ai {
+                AiMode::Always
+            } else {
+                AiMode::Off
+            };
+            let mut shell = start_shell(&cli, ai_mode).await?;
+            shell.use_terminal();
+            
+            let result = shell.eval(command).await?;
+            shell.print_result(&result);
+            result.exit_code
+        }
+        
+        Some(Commands::Interpret { inputs, compare }) => {
+            // Only the command goes to stdout, so it can be captured
+            let config = ShellConfig::load(&cli.config)?;
+            if !compare && (cli.no_ai || !config.ai_enabled) {
+                anyhow::bail!("AI is disabled; nothing to interpret with");
+            }
+            
+            // There is no history here, so only the directory and OS are described
+            let redactor = Redactor::new(config.redact_defaults, &config.redact_patterns)?;
+            let context = aicontext::build(&config.ai_config.prompt_context, &[], &redactor);
+            let inputs = if inputs.len() == 1 && inputs[0] == "-" {
+                io::stdin()
+                    .lines()
+                    .collect::<io::Result<Vec<_>>>()
+                    .context("Failed to read inputs from stdin")?
+                    .into_iter()
+                    .filter(|line| !line.trim().is_empty())
+                    .collect()
+            } else {
+                inputs.clone()
+            };
+            
+            if *compare {
+                compare_backends(&cli, &config, &inputs, &context).await
+            } else {
+                let mut ai_engine = AIEngine::new(&config.ai_config)?;
+                ai_engine.override_sampling(cli.max_tokens.map(|n| n as usize), cli.temperature);
+                ai_engine.set_progress(true);
+                ai_engine.set_show_prompts(cli.verbose);
+                let results = match inputs.as_slice() {
+                    [input] => ai_engine
+                        .interpret_command(input, &context)
+                        .await
+                        .map(|interpretation| vec![Ok(interpretation.command)]),
+                    _ => ai_engine.interpret_batch(&inputs, &context).await,
+                };
+                
+                match results {
+                    Ok(results) => {
+                        let mut status = 0;
+                        for (i, result) in results.iter().enumerate() {
+                            match result {
+                                Ok(interpreted) => outln!("{}", interpreted),
+                                Err(e) => {
+                                    // A blank line keeps the output lined up with the inputs
+                                    eprintln!("❌ Line {}: interpretation failed: {:#}", i + 1, e);
+                                    outln!();
+                                    status = 1;
+                                }
+                            }
+                        }
+                        status
+                    }
+                    Err(e) => {
+                        eprintln!("❌ Interpretation failed: {:#}", e);
+                        1
+                    }
+                }
+            }
+        }
+        
+        Some(Commands::Interactive) => {
+            // Run interactive shell
+            let mut shell = start_shell(&cli, AiMode::from_flags(cli.ai, cli.no_ai)).await?;
+            match &cli.command_fifo {
+                Some(path) => fifo::serve(&mut shell, path, cli.output_fifo.as_deref()).await?,
+                None => shell.run(cli.gui).await?,
+            }
+        }
+        
+        Some(Commands::Replay { file, speed, no_delay }) => {
+            // Re-run a recorded session
+            let entries = session::load(file)?;
+            let mut shell = start_shell(&cli, AiMode::from_flags(cli.ai, cli.no_ai)).await?;
+            shell.use_terminal();
+            let speed = if *no_delay { None } else { Some(*speed) };
+            shell.replay(&entries, speed).await?
+        }
+        
+        Some(Commands::ExportSession { file, output, format }) => {
+            let entries = session::load(file)?;
+            let format = format.unwrap_or_else(|| output.as_deref().map_or(export::Format::Markdown, export::Format::for_path));
+            let name = file.file_name().map_or_else(|| file.display().to_string(), |name| name.to_string_lossy().into_owned());
+            let page = export::render(&entries, format, &format!("Session {}", name));
+            match output {
+                Some(path) => {
+                    std::fs::write(path, page).with_context(|| format!("Failed to write {}", path.display()))?;
+                    outln!("📄 Exported {} commands to {}", entries.len(), path.display());
+                }
+                None => out!("{}", page),
+            }
+            0
+        }
+        
+        Some(Commands::Bench { command, iterations, warmup }) => {
+            // Benchmark the execution path only; no banner, history or AI
+            let mut shell = ObsidianShell::new(&cli.config, AiMode::Off)?;
+            bench::run(&mut shell, command, *iterations, *warmup).await?
+        }
+        
+        Some(Commands::Config { action: Some(ConfigAction::Schema) }) => {
+            // Generated from the config structs, so it can't drift from them
+            let schema = schemars::schema_for!(ShellConfig);
+            outln!("{}", serde_json::to_string_pretty(&schema)?);
+            0
+        }
+        
+        Some(Commands::Config { action: None }) => {
+            // Show configuration
+            let config = ShellConfig::load(&cli.config)?;
+            outln!("💠 Obsidian Shell Configuration");
+            outln!("===============================");
+            outln!("AI Enabled: {}", config.ai_enabled);
+            outln!("GUI Enabled: {}", config.gui_enabled);
+            outln!("History Path: {}", config.history_path);
+            outln!("Model Path: {}", config.ai_config.model_path);
+            outln!("Persist AI Context: {}", config.ai_config.persist_context);
+            outln!("Allowed Commands: {:?}", config.command_policy.allowed_commands);
+            outln!("Blocked Commands: {:?}", config.command_policy.blocked_commands);
+            if config.policy_locked {
+                outln!("Command policy is locked by {}", config::SYSTEM_POLICY_PATH);
+            }
+            if config.sandbox.is_active() {
+                outln!("Sandbox Wrapper: {:?}", config.sandbox.wrapper);
+                outln!("Sandbox Environment: {:?}", config.sandbox.env_allowlist);
+                outln!("Sandbox Working Directory: {:?}", config.sandbox.working_dir);
+            }
+            if !config.remote.hosts.is_empty() {
+                outln!("Remote Hosts: {:?}", config.remote.hosts);
+            }
+            0
+        }
+        
+        Some(Commands::UpdateModels { dry_run: true }) => {
+            let config = ShellConfig::load(&cli.config)?;
+            let manifest = models::fetch_manifest(&config.ai_config.models_manifest).await?;
+            outln!("📋 Models in {} compared with {}", config.ai_config.models_manifest, config.ai_config.models_dir.display());
+            models::print_plan(&models::plan(&manifest, &config.ai_config.models_dir)?);
+            0
+        }
+        
+        Some(Commands::UpdateModels { dry_run: false }) => {
+            // Update AI models
+            outln!("🔄 Updating AI models...");
+            let config = ShellConfig::load(&cli.config)?;
+            let ai_engine = AIEngine::new(&config.ai_config)?;
+            ai_engine.update_models().await?;
+            outln!("✅ Models updated successfully!");
+            0
+        }
+        
+        Some(Commands::ImportHistory { from, file }) => {
+            let config = ShellConfig::load(&cli.config)?;
+            let redactor = Redactor::new(config.redact_defaults, &config.redact_patterns)
+                .context("Invalid redact_patterns in configuration")?;
+            let mut history = CommandHistory::new(&config.history_path, config.history_format, redactor)?;
+            history.load()?;
+            
+            let contents = std::fs::read(file).with_context(|| format!("Failed to read {}", file.display()))?;
+            let (imported, skipped) = histimport::parse(*from, &contents);
+            let found = imported.len();
+            let added = history.import(imported)?;
+            outln!("📥 Imported {} of {} commands from {} into {}", added, found, file.display(), config.history_path);
+            if skipped > 0 {
+                eprintln!("⚠️  Skipped {} line{} that couldn't be read", skipped, if skipped == 1 { "" } else { "s" });
+            }
+            0
+        }
+        
+        Some(Commands::Completions { shell }) => {
+            let mut script = Vec::new();
+            clap_complete::generate(*shell, &mut Cli::command(), "obsidian-shell", &mut script);
+            out!("{}", String::from_utf8_lossy(&script));
+            0
+        }
+        
+        None => {
+            // Default to interactive mode
+            let mut shell = start_shell(&cli, AiMode::from_flags(cli.ai, cli.no_ai)).await?;
+            match &cli.command_fifo {
+                Some(path) => fifo::serve(&mut shell, path, cli.output_fifo.as_deref()).await?,
+                None => shell.run(cli.gui).await?,
+            }
+        }
+    };
+    
+    output::flush();
+    std::process::exit(status);
+}
+
+// Module implementations
+mod history {
+    use super::*;
+    use std::collections::HashSet;
+    use std::fs::{self, OpenOptions};
+    use std::io::Write;
+    
+    /// The newest history file format, written by default.
+    ///
+    /// 1. One command per line, with no header.
+    /// 2. A `#obsidian-history v2` header, then one `<unix time>\t<command>`
+    ///    line per entry, with `\` and newlines escaped as `\\` and `\n`.
+    ///    Entries carried over from format 1 have no time.
+    pub const FORMAT_VERSION: u32 = 2;
+    /// Start of the first line of a versioned history file; the version follows
+    const HEADER: &str = "#obsidian-history v";
+    
+    pub struct CommandHistory {
+        path: String,
+        commands: Vec<String>,
+        redactor: Redactor,
+        /// Whether new commands are appended to the file
+        saving: bool,
+        /// Format new entries are written in: `history_format`, or the file's
+        /// own once it is loaded
+        format: u32,
+        /// The file is in a format this shell doesn't know, so it is never
+        /// written to
+        foreign: bool,
+    }
+    
+    impl CommandHistory {
+        pub fn new(path: &str, format: u32, redactor: Redactor) -> Result<Self> {
+            if !(1..=FORMAT_VERSION).contains(&format) {
+                anyhow::bail!("history_format must be between 1 and {}, not {}", FORMAT_VERSION, format);
+            }
+            Ok(CommandHistory {
+                path: path.to_string(),
+                commands: Vec::new(),
+                redactor,
+                saving: true,
+                format,
+                foreign: false,
+            })
+        }
+        
+        /// Stop or resume appending new commands to the history file
+        pub fn set_saving(&mut self, saving: bool) {
+            self.saving = saving;
+        }
+        
+        /// Read the history file, first upgrading a format 1 file unless
+        /// `history_format` is 1. A file from a newer shell is left alone.
+        pub fn load(&mut self) -> Result<()> {
+            let Ok(contents) = fs::read(&self.path) else {
+                return Ok(());
+            };
+            let contents = String::from_utf8_lossy(&contents);
+            
+            match file_version(&contents) {
+                1 => {
+                    self.commands = contents.lines().map(str::to_string).collect();
+                    if self.format == 1 || contents.is_empty() {
+                        return Ok(());
+                    }
+                    if let Err(e) = self.upgrade(&contents) {
+                        // Keep appending in the old format rather than mixing the two
+                        eprintln!("⚠️  Couldn't upgrade history file {}: {:#}", self.path, e);
+                        self.format = 1;
+                    }
+                }
+                2 => {
+                    self.commands = contents.lines().skip(1).map(|line| parse_entry(line).1).collect();
+                    self.format = 2;
+                }
+                version => {
+                    eprintln!(
+                        "⚠️  History file {} is format {}, newer than this shell reads; it won't be read or written",
+                        self.path, version
+                    );
+                    self.foreign = true;
+                }
+            }
+            Ok(())
+        }
+        
+        /// Rewrite a format 1 file as the current format, copying the
+        /// original to `<path>.v1.bak` first
+        fn upgrade(&mut self, contents: &str) -> Result<()> {
+            let backup = (1..)
+                .map(|n| match n {
+                    1 => format!("{}.v1.bak", self.path),
+                    n => format!("{}.v1.bak.{}", self.path, n),
+                })
+                .find(|backup| !Path::new(backup).exists())
+                .expect("unbounded range");
+            fs::copy(&self.path, &backup).with_context(|| format!("Failed to back it up to {}", backup))?;
+            
+            let mut upgraded = header();
+            for command in contents.lines() {
+                upgraded.push_str(&format_entry(None, command));
+            }
+            
+            // Written beside the original and renamed over it, so a failure
+            // leaves the original in place
+            let temporary = format!("{}.upgrading", self.path);
+            let permissions = fs::metadata(&self.path)?.permissions();
+            fs::write(&temporary, upgraded).context("Failed to write the upgraded file")?;
+            fs::set_permissions(&temporary, permissions)?;
+            fs::rename(&temporary, &self.path).context("Failed to replace the original")?;
+            
+            self.format = FORMAT_VERSION;
+            tracing::info!(path = %self.path, backup = %backup, "upgraded history file to format {}", FORMAT_VERSION);
+            Ok(())
+        }
+        
         pub fn add(&mut self, command: &str) {
+            // Kept as typed for this session so `!!` reruns the real command
             self.commands.push(command.to_string());
+            if !self.saving || self.foreign {
+                return;
+            }
             
             // Save to file
             if let Ok(mut file) = OpenOptions::new()
@@ -470,8 +3397,69 @@ mod history {
                 .append(true)
                 .open(&self.path)
             {
-                let _ = writeln!(file, "{}", command);
+                let command = self.redactor.redact(command);
+                let _ = match self.format {
+                    1 => writeln!(file, "{}", command),
+                    _ => {
+                        let new = file.metadata().is_ok_and(|meta| meta.len() == 0);
+                        let header = if new { header() } else { String::new() };
+                        let now = chrono::Utc::now().timestamp();
+                        write!(file, "{}{}", header, format_entry(Some(now), &command))
+                    }
+                };
+            }
+        }
+        
+        /// Merge commands from another shell into the file, in time order
+        /// with those that have no time first, returning how many were new.
+        /// A timed command is new unless the same command has that time; one
+        /// without a time, unless the command is there already.
+        pub fn import(&mut self, imported: Vec<histimport::Imported>) -> Result<usize> {
+            if self.foreign {
+                anyhow::bail!("history file {} is in a format this shell doesn't write", self.path);
+            }
+            if self.format == 1 {
+                anyhow::bail!("importing needs history_format 2, which records when commands ran");
+            }
+            
+            let contents = fs::read(&self.path).unwrap_or_default();
+            let contents = String::from_utf8_lossy(&contents);
+            let mut entries: Vec<(Option<i64>, String)> = contents.lines().skip(1).map(parse_entry).collect();
+            let mut timed: HashSet<(i64, String)> = entries
+                .iter()
+                .filter_map(|(time, command)| Some(((*time)?, command.clone())))
+                .collect();
+            let mut commands: HashSet<String> = entries.iter().map(|(_, command)| command.clone()).collect();
+            
+            let mut added = 0;
+            for entry in imported {
+                let command = self.redactor.redact(&entry.command).into_owned();
+                let new = match entry.time {
+                    Some(time) => timed.insert((time, command.clone())),
+                    None => !commands.contains(&command),
+                };
+                if new {
+                    commands.insert(command.clone());
+                    entries.push((entry.time, command));
+                    added += 1;
+                }
+            }
+            entries.sort_by_key(|(time, _)| time.unwrap_or(i64::MIN));
+            
+            let mut merged = header();
+            for (time, command) in &entries {
+                merged.push_str(&format_entry(*time, command));
+            }
+            // As in `upgrade`, renamed over the original once written
+            let temporary = format!("{}.importing", self.path);
+            fs::write(&temporary, merged).context("Failed to write the merged history")?;
+            if let Ok(meta) = fs::metadata(&self.path) {
+                fs::set_permissions(&temporary, meta.permissions())?;
             }
+            fs::rename(&temporary, &self.path).with_context(|| format!("Failed to replace {}", self.path))?;
+            
+            self.commands = entries.into_iter().map(|(_, command)| command).collect();
+            Ok(added)
         }
         
         pub fn get_recent(&self, count: usize) -> Result<Vec<String>> {
@@ -482,60 +3470,498 @@ mod history {
             };
             Ok(self.commands[start..].to_vec())
         }
+        
+        pub fn len(&self) -> usize {
+            self.commands.len()
+        }
+        
+        /// Resolve `line` if it is a history reference on its own: `!!`, `!n`
+        /// or `!-n`, each optionally with `:p`, or `^old^new`. `None` for any
+        /// other line.
+        pub fn recall(&self, line: &str) -> Option<Result<Recall, String>> {
+            let line = line.trim();
+            if let Some(rest) = line.strip_prefix('^') {
+                return Some(self.substitute(rest));
+            }
+            
+            let reference = line.strip_prefix('!')?;
+            let (reference, print) = match reference.strip_suffix(":p") {
+                Some(reference) => (reference, true),
+                None => (reference, false),
+            };
+            let index = match reference {
+                "!" => self.commands.len().checked_sub(1),
+                _ => match reference.parse::<i64>().ok()? {
+                    n if n > 0 => Some(n as usize - 1).filter(|&i| i < self.commands.len()),
+                    n if n < 0 => self.commands.len().checked_sub(n.unsigned_abs() as usize),
+                    _ => None,
+                },
+            };
+            
+            let Some(entry) = index.map(|i| self.commands[i].clone()) else {
+                return Some(Err(format!("{}: event not found", line)));
+            };
+            Some(Ok(if print { Recall::Print(entry) } else { Recall::Edit(entry) }))
+        }
+        
+        /// `^old^new[^]`: the last command with the first `old` replaced
+        fn substitute(&self, spec: &str) -> Result<Recall, String> {
+            let spec = spec.strip_suffix('^').unwrap_or(spec);
+            let (old, new) = spec.split_once('^').unwrap_or((spec, ""));
+            
+            let last = self.commands.last().ok_or("^: no previous command")?;
+            if old.is_empty() || !last.contains(old) {
+                return Err(format!("^{}: substitution failed", spec));
+            }
+            Ok(Recall::Run(last.replacen(old, new, 1)))
+        }
+    }
+    
+    /// The format of a history file's contents; files without a header are
+    /// format 1
+    fn file_version(contents: &str) -> u32 {
+        let first = contents.lines().next().unwrap_or_default();
+        match first.strip_prefix(HEADER) {
+            // A format 1 file may start with a command that happens to match
+            Some(version) => version.trim().parse().unwrap_or(1),
+            None => 1,
+        }
+    }
+    
+    fn header() -> String {
+        format!("{}{}\n", HEADER, FORMAT_VERSION)
+    }
+    
+    /// One format 2 line, newline included
+    fn format_entry(time: Option<i64>, command: &str) -> String {
+        let time = time.map(|time| time.to_string()).unwrap_or_default();
+        let escaped = command.replace('\\', "\\\\").replace('\n', "\\n");
+        format!("{}\t{}\n", time, escaped)
+    }
+    
+    /// The time and command of a format 2 line
+    fn parse_entry(line: &str) -> (Option<i64>, String) {
+        let (time, escaped) = line.split_once('\t').map_or((None, line), |(time, command)| (time.parse().ok(), command));
+        let mut command = String::with_capacity(escaped.len());
+        let mut chars = escaped.chars();
+        while let Some(c) = chars.next() {
+            match (c, chars.clone().next()) {
+                ('\\', Some('n')) => {
+                    chars.next();
+                    command.push('\n');
+                }
+                ('\\', Some('\\')) => {
+                    chars.next();
+                    command.push('\\');
+                }
+                (c, _) => command.push(c),
+            }
+        }
+        (time, command)
     }
-}
-
-mod completion {
-    use super::*;
     
-    pub struct CommandCompletion;
+    /// What a history reference asks for
+    #[derive(Debug, PartialEq, Eq)]
+    pub enum Recall {
+        /// Put the entry in the input line to be edited before it runs
+        Edit(String),
+        /// Print the entry without running it (`:p`)
+        Print(String),
+        /// Run this command straight away (`^old^new`)
+        Run(String),
+    }
     
-    impl CommandCompletion {
-        pub fn new() -> Self {
-            CommandCompletion
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        
+        /// A history of `commands` that isn't saved anywhere
+        fn unsaved(commands: &[&str]) -> CommandHistory {
+            let mut history = CommandHistory::new("/nonexistent/history", FORMAT_VERSION, Redactor::default()).unwrap();
+            history.set_saving(false);
+            for command in commands {
+                history.add(command);
+            }
+            history
+        }
+        
+        fn recall(history: &CommandHistory, line: &str) -> Result<Recall, String> {
+            history.recall(line).expect("a history reference")
+        }
+        
+        #[test]
+        fn caret_substitution_reruns_the_last_command() {
+            let history = unsaved(&["cargo build", "git comit -m 'fix comit'"]);
+            assert_eq!(recall(&history, "^comit^commit"), Ok(Recall::Run("git commit -m 'fix comit'".to_string())));
+            assert_eq!(recall(&history, " ^comit^commit^ "), Ok(Recall::Run("git commit -m 'fix comit'".to_string())));
+            // Without a replacement the text is removed
+            assert_eq!(recall(&history, "^ -m 'fix comit'"), Ok(Recall::Run("git comit".to_string())));
+            assert_eq!(recall(&history, "^-m^"), Ok(Recall::Run("git comit  'fix comit'".to_string())));
+        }
+        
+        #[test]
+        fn caret_substitution_fails_without_a_match() {
+            let history = unsaved(&["ls -l"]);
+            assert_eq!(recall(&history, "^-a^-la"), Err("^-a^-la: substitution failed".to_string()));
+            assert_eq!(recall(&history, "^^x"), Err("^^x: substitution failed".to_string()));
+            assert_eq!(recall(&unsaved(&[]), "^a^b"), Err("^: no previous command".to_string()));
+        }
+        
+        #[test]
+        fn bang_references_are_edited_or_printed() {
+            let history = unsaved(&["one", "two", "three"]);
+            assert_eq!(recall(&history, "!!"), Ok(Recall::Edit("three".to_string())));
+            assert_eq!(recall(&history, "!1"), Ok(Recall::Edit("one".to_string())));
+            assert_eq!(recall(&history, "!-2:p"), Ok(Recall::Print("two".to_string())));
+            assert_eq!(recall(&history, "!4"), Err("!4: event not found".to_string()));
+            assert_eq!(recall(&history, "!-4"), Err("!-4: event not found".to_string()));
+            assert_eq!(recall(&history, "!0"), Err("!0: event not found".to_string()));
+            
+            assert!(history.recall("echo !!").is_none());
+            assert!(history.recall("!cargo").is_none());
+        }
+        
+        #[test]
+        fn secrets_never_reach_the_history_file() {
+            let tmp = tempfile::tempdir().unwrap();
+            let secret = "curl -H 'Authorization: Bearer s3cr3t-value' https://api.example.com";
+            for format in 1..=FORMAT_VERSION {
+                let path = tmp.path().join(format!("history.v{}", format));
+                let path = path.to_str().unwrap();
+                let redactor = Redactor::new(true, &[]).unwrap();
+                let mut history = CommandHistory::new(path, format, redactor.clone()).unwrap();
+                history.add(secret);
+                history.add("GITHUB_TOKEN=ghp_0123456789abcdefghij0123 gh pr list");
+                
+                // Kept as typed for this session, so `!!` runs the real command
+                assert_eq!(history.get_recent(2).unwrap()[0], secret);
+                
+                let contents = fs::read_to_string(path).unwrap();
+                assert!(!contents.contains("s3cr3t"), "{}", contents);
+                assert!(!contents.contains("ghp_"), "{}", contents);
+                assert!(contents.contains("Authorization: Bearer ***"), "{}", contents);
+                
+                if format == FORMAT_VERSION {
+                    let imported = histimport::Imported {
+                        time: Some(1),
+                        command: "mysql --password=hunter2".to_string(),
+                    };
+                    history.import(vec![imported]).unwrap();
+                    assert!(!fs::read_to_string(path).unwrap().contains("hunter2"));
+                }
+            }
+        }
+        
+        fn load(path: &Path, format: u32) -> CommandHistory {
+            let mut history = CommandHistory::new(path.to_str().unwrap(), format, Redactor::default()).unwrap();
+            history.load().unwrap();
+            history
+        }
+        
+        #[test]
+        fn format_1_files_are_upgraded_with_a_backup() {
+            let tmp = tempfile::tempdir().unwrap();
+            let path = tmp.path().join("history");
+            let original = "ls -la\necho 'a\\b'\n";
+            fs::write(&path, original).unwrap();
+            
+            let mut history = load(&path, FORMAT_VERSION);
+            assert_eq!(history.get_recent(10).unwrap(), ["ls -la", "echo 'a\\b'"]);
+            assert_eq!(fs::read_to_string(tmp.path().join("history.v1.bak")).unwrap(), original);
+            assert_eq!(
+                fs::read_to_string(&path).unwrap(),
+                "#obsidian-history v2\n\tls -la\n\techo 'a\\\\b'\n"
+            );
+            
+            // New entries follow in the same format, and read back as written
+            history.add("printf 'x\ny'");
+            let reloaded = load(&path, FORMAT_VERSION);
+            assert_eq!(reloaded.get_recent(10).unwrap(), ["ls -la", "echo 'a\\b'", "printf 'x\ny'"]);
+            assert!(!tmp.path().join("history.upgrading").exists());
+        }
+        
+        #[test]
+        fn earlier_backups_are_kept() {
+            let tmp = tempfile::tempdir().unwrap();
+            let path = tmp.path().join("history");
+            fs::write(tmp.path().join("history.v1.bak"), "older\n").unwrap();
+            fs::write(&path, "newer\n").unwrap();
+            
+            load(&path, FORMAT_VERSION);
+            assert_eq!(fs::read_to_string(tmp.path().join("history.v1.bak")).unwrap(), "older\n");
+            assert_eq!(fs::read_to_string(tmp.path().join("history.v1.bak.2")).unwrap(), "newer\n");
+        }
+        
+        #[test]
+        fn format_1_stays_when_configured() {
+            let tmp = tempfile::tempdir().unwrap();
+            let path = tmp.path().join("history");
+            // Only looks like a header; a format 1 file may start with anything
+            fs::write(&path, "#obsidian-history vnext\nls\n").unwrap();
+            
+            let mut history = load(&path, 1);
+            history.add("pwd");
+            assert_eq!(history.get_recent(10).unwrap(), ["#obsidian-history vnext", "ls", "pwd"]);
+            assert_eq!(fs::read_to_string(&path).unwrap(), "#obsidian-history vnext\nls\npwd\n");
+            assert!(!tmp.path().join("history.v1.bak").exists());
+        }
+        
+        #[test]
+        fn format_2_files_load_as_they_are() {
+            let tmp = tempfile::tempdir().unwrap();
+            let path = tmp.path().join("history");
+            let contents = "#obsidian-history v2\n1700000000\tmake\n\tcat <<EOF\\nhi\\nEOF\nbad line\n";
+            fs::write(&path, contents).unwrap();
+            
+            // A format 1 setting doesn't downgrade a newer file
+            let history = load(&path, 1);
+            assert_eq!(history.get_recent(10).unwrap(), ["make", "cat <<EOF\nhi\nEOF", "bad line"]);
+            assert_eq!(history.format, 2);
+            assert_eq!(fs::read_to_string(&path).unwrap(), contents);
         }
         
-        pub fn complete(&self, _input: &str) -> Vec<String> {
-            // Implementation for command completion
-            vec![]
+        #[test]
+        fn newer_formats_are_neither_read_nor_written() {
+            let tmp = tempfile::tempdir().unwrap();
+            let path = tmp.path().join("history");
+            let contents = "#obsidian-history v9\n{\"command\": \"ls\"}\n";
+            fs::write(&path, contents).unwrap();
+            
+            let mut history = load(&path, FORMAT_VERSION);
+            history.add("pwd");
+            assert_eq!(history.get_recent(10).unwrap(), ["pwd"]);
+            assert!(history.import(Vec::new()).is_err());
+            assert_eq!(fs::read_to_string(&path).unwrap(), contents);
+        }
+        
+        #[test]
+        fn entries_round_trip_through_format_2() {
+            for command in ["plain", "a\\nb", "two\nlines", "tab\there", "\\", ""] {
+                let line = format_entry(Some(42), command);
+                assert_eq!(line.matches('\n').count(), 1, "{:?}", line);
+                assert_eq!(parse_entry(line.trim_end_matches('\n')), (Some(42), command.to_string()));
+            }
+            assert_eq!(parse_entry("\tls"), (None, "ls".to_string()));
         }
     }
 }
 
-mod config {
+#[cfg(test)]
+mod tests {
     use super::*;
-    use serde::{Deserialize, Serialize};
-    
-    #[derive(Debug, Serialize, Deserialize)]
-    pub struct ShellConfig {
-        pub ai_enabled: bool,
-        pub gui_enabled: bool,
-        pub history_path: String,
-        pub ai_config: ai::AIConfig,
-    }
-    
-    impl ShellConfig {
-        pub fn load(path: &str) -> Result<Self> {
-            // Default configuration
-            let config = ShellConfig {
-                ai_enabled: true,
-                gui_enabled: false,
-                history_path: "~/.obsidian-shell-history".to_string(),
-                ai_config: ai::AIConfig {
-                    model_path: "/usr/share/obsidian/models/llm.onnx".to_string(),
-                    api_endpoint: "http://localhost:8000/ai".to_string(),
-                    max_tokens: 512,
-                    temperature: 0.7,
-                },
-            };
-            
-            // Try to load from file if it exists
-            if let Ok(contents) = std::fs::read_to_string(path) {
-                return toml::from_str(&contents)
-                    .context("Failed to parse configuration file");
-            }
-            
-            Ok(config)
+    use std::fs;
+    
+    /// A shell with AI off, keeping its history in `dir`, configured with
+    /// `settings` on top
+    fn shell(dir: &Path, settings: &str) -> ObsidianShell {
+        let path = dir.join("config.toml");
+        let history = dir.join("history");
+        let config = format!(
+            "ai_enabled = false\ngui_enabled = false\nhistory_path = {:?}\n{}\n[ai_config]\nmodel_path = \"\"\napi_endpoint = \"\"\nmax_tokens = 1\ntemperature = 0.1\n",
+            history, settings
+        );
+        fs::write(&path, config).unwrap();
+        ObsidianShell::new(path.to_str().unwrap(), AiMode::Off).unwrap()
+    }
+    
+    /// A frontend giving `answer` to every question, keeping what it was
+    /// shown and asked in `seen`
+    struct Scripted {
+        answer: bool,
+        seen: Arc<Mutex<Vec<String>>>,
+    }
+    
+    impl Frontend for Scripted {
+        fn progress(&mut self, output: &CommandResult) {
+            self.seen.lock().unwrap().push(format!("{}{}", output.stdout, output.stderr));
+        }
+        
+        fn confirm(&mut self, question: &str) -> bool {
+            self.seen.lock().unwrap().push(question.to_string());
+            self.answer
+        }
+        
+        fn streams_output(&self) -> bool {
+            false
+        }
+    }
+    
+    fn script(shell: &mut ObsidianShell, answer: bool) -> Arc<Mutex<Vec<String>>> {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        shell.frontend = Some(Box::new(Scripted { answer, seen: seen.clone() }));
+        seen
+    }
+    
+    #[tokio::test]
+    async fn chained_ai_commands_run_only_when_confirmed() {
+        let tmp = tempfile::tempdir().unwrap();
+        let mut shell = shell(tmp.path(), "ai_allow_chaining = true");
+        shell.ai_mode = AiMode::Always;
+        let fixtures = "[responses]\n\"greet twice\" = \"echo one; echo two\"";
+        shell.ai_engine.set_backend(ai::Backend::Mock(aimock::MockBackend::from_fixtures(fixtures).unwrap()));
+        
+        // Without a frontend there is no one to ask, so nothing runs
+        let result = shell.eval("greet twice").await.unwrap();
+        assert_eq!((result.exit_code, result.stdout.as_str()), (1, ""));
+        assert_eq!(result.ai_error.as_deref(), Some("chained commands were not confirmed"));
+        
+        let seen = script(&mut shell, false);
+        let result = shell.eval("greet twice").await.unwrap();
+        assert_eq!((result.exit_code, result.stdout.as_str()), (1, ""));
+        assert_eq!(*seen.lock().unwrap(), ["🤖 The AI chained 2 commands: 'echo one; echo two'. Run them? [y/N] "]);
+        
+        script(&mut shell, true);
+        let result = shell.eval("greet twice").await.unwrap();
+        assert_eq!((result.exit_code, result.stdout.as_str()), (0, "one\ntwo\n"));
+    }
+    
+    #[tokio::test]
+    async fn failed_retries_go_to_the_frontend_or_the_result() {
+        let tmp = tempfile::tempdir().unwrap();
+        let mut shell = shell(tmp.path(), "");
+        let command = "retry -n 3 --delay 0 sh -c 'echo try; exit 4'";
+        let report = |attempt| format!("🔁 retry: attempt {}/3 failed with status 4; retrying in 0ms\n", attempt);
+        
+        let result = shell.eval(command).await.unwrap();
+        assert_eq!((result.exit_code, result.stdout.as_str()), (4, "try\ntry\ntry\n"));
+        assert_eq!(result.stderr, report(1) + &report(2));
+        
+        let seen = script(&mut shell, false);
+        let result = shell.eval(command).await.unwrap();
+        assert_eq!((result.exit_code, result.stdout.as_str(), result.stderr.as_str()), (4, "try\n", ""));
+        assert_eq!(*seen.lock().unwrap(), ["try\n".to_string(), report(1), "try\n".to_string(), report(2)]);
+    }
+    
+    #[tokio::test]
+    async fn on_change_needs_a_frontend() {
+        let tmp = tempfile::tempdir().unwrap();
+        let mut shell = shell(tmp.path(), "");
+        let result = shell.eval(&format!("on-change {} -- true", tmp.path().display())).await.unwrap();
+        assert_eq!(result.exit_code, 1);
+        assert_eq!(result.stderr, "❌ on-change: nothing here can show each run's output\n");
+    }
+    
+    #[tokio::test]
+    async fn rm_refuses_options_the_trash_cant_honour() {
+        let tmp = tempfile::tempdir().unwrap();
+        let trash = tmp.path().join("trash");
+        let mut shell = shell(tmp.path(), &format!("trash_rm = true\ntrash_dir = \"{}\"", trash.display()));
+        let file = tmp.path().join("f");
+        fs::write(&file, "data").unwrap();
+        
+        for option in ["--interactive", "--one-file-system", "--preserve-root=all", "-i"] {
+            let result = shell.eval(&format!("rm {} {}", option, file.display())).await.unwrap();
+            assert_eq!(result.exit_code, 1, "{}", option);
+            assert!(result.stderr.starts_with(&format!("❌ rm: {} is not supported", option)), "{}", result.stderr);
+            assert!(file.exists(), "{}", option);
         }
+        
+        let result = shell.eval(&format!("rm --verbose {}", file.display())).await.unwrap();
+        assert_eq!((result.exit_code, result.stdout), (0, format!("trashed '{}'\n", file.display())));
+        assert!(!file.exists());
+        assert!(trash.join("files/f").exists());
+    }
+    
+    #[tokio::test]
+    async fn in_takes_builtin_and_redirect_paths_from_its_directory() {
+        let tmp = tempfile::tempdir().unwrap();
+        let trash = tmp.path().join("trash");
+        let mut sh = shell(tmp.path(), &format!("trash_rm = true\ntrash_dir = \"{}\"", trash.display()));
+        let build = tmp.path().join("build");
+        fs::create_dir(&build).unwrap();
+        fs::write(build.join("f"), "data").unwrap();
+        let here = env::current_dir().unwrap();
+        let in_build = |command: &str| format!("in {} -- {}", build.display(), command);
+        
+        let result = sh.eval(&in_build("rm f")).await.unwrap();
+        assert_eq!(result.exit_code, 0, "{}", result.stderr);
+        assert!(!build.join("f").exists());
+        assert!(trash.join("files/f").exists());
+        
+        let result = sh.eval(&in_build("echo out > out")).await.unwrap();
+        assert_eq!(result.exit_code, 0, "{}", result.stderr);
+        let result = sh.eval(&format!("echo tee | {}", in_build("tee copy"))).await.unwrap();
+        assert_eq!(result.exit_code, 0, "{}", result.stderr);
+        assert_eq!(fs::read_to_string(build.join("out")).unwrap(), "out\n");
+        assert_eq!(fs::read_to_string(build.join("copy")).unwrap(), "tee\n");
+        assert!(!here.join("out").exists() && !here.join("copy").exists());
+        
+        let result = sh.eval(&in_build("cd ..")).await.unwrap();
+        assert_eq!(result.exit_code, 1);
+        assert!(result.stderr.starts_with("❌ in: cd works in the shell's own directory"), "{}", result.stderr);
+        assert_eq!(env::current_dir().unwrap(), here);
+    }
+    
+    #[tokio::test]
+    async fn comments_can_be_turned_off() {
+        let tmp = tempfile::tempdir().unwrap();
+        let mut on = shell(tmp.path(), "");
+        assert_eq!(on.eval("echo a # b").await.unwrap().stdout, "a\n");
+        assert_eq!(on.eval("# nothing").await.unwrap().stdout, "");
+        
+        let mut off = shell(tmp.path(), "comment_char = \"\"");
+        assert_eq!(off.eval("echo a # b").await.unwrap().stdout, "a # b\n");
     }
-} 
\ No newline at end of file
+    
+    #[tokio::test]
+    async fn background_jobs_queue_past_the_limit() {
+        let tmp = tempfile::tempdir().unwrap();
+        let mut shell = shell(tmp.path(), "max_background_jobs = 1");
+        let out = tmp.path().join("out");
+        
+        let line = format!("sleep 1 & echo queued & echo saved > {} &", out.display());
+        let result = shell.eval(&line).await.unwrap();
+        assert_eq!(result.exit_code, 0);
+        assert_eq!(result.stderr, format!("[1] sleep 1\n[2] echo queued\n[3] echo saved > {}\n", out.display()));
+        
+        // Give the first job time to take the only slot
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let result = shell.eval("jobs").await.unwrap();
+        let queued = format!("[1] running  sleep 1\n[2] queued   echo queued\n[3] queued   echo saved > {}\n", out.display());
+        assert_eq!(result.stdout, queued);
+        assert!(!out.exists());
+        
+        let result = shell.eval("wait").await.unwrap();
+        assert_eq!((result.exit_code, result.stdout.as_str()), (0, "queued\n"));
+        assert_eq!(fs::read_to_string(&out).unwrap(), "saved\n");
+        assert_eq!(shell.eval("jobs").await.unwrap().stdout, "");
+        
+        let result = shell.eval("sh -c 'exit 3' & wait").await.unwrap();
+        assert_eq!(result.exit_code, 3);
+        let result = shell.eval("cd / &").await.unwrap();
+        assert_eq!((result.exit_code, result.stderr.as_str()), (2, "❌ &: only programs can run in the background, not `cd /`\n"));
+    }
+    
+    #[tokio::test]
+    async fn nested_commands_stop_at_the_limit() {
+        let tmp = tempfile::tempdir().unwrap();
+        let mut shell = shell(tmp.path(), "max_expansion_depth = 4");
+        
+        // A leading `time` times the line itself; each one after it runs a pipeline
+        let result = shell.eval(&format!("{}echo hi", "time ".repeat(4))).await.unwrap();
+        assert_eq!((result.exit_code, result.stdout.as_str()), (0, "hi\n"));
+        
+        let result = shell.eval(&format!("{}echo hi", "time ".repeat(5))).await.unwrap();
+        assert_eq!(result.exit_code, 2);
+        assert!(result.stderr.contains("nested more than 4 deep"), "{}", result.stderr);
+        
+        // The shell is usable afterwards
+        let result = shell.eval("time echo ok").await.unwrap();
+        assert_eq!((result.exit_code, result.stdout.as_str()), (0, "ok\n"));
+    }
+    
+    #[tokio::test]
+    async fn long_alias_chains_fail_without_overflowing() {
+        let tmp = tempfile::tempdir().unwrap();
+        let chain = (0..5000).map(|i| format!("a{} = \"a{}\"\n", i, i + 1)).collect::<String>();
+        let mut shell = shell(tmp.path(), &format!("max_expansion_depth = 32\n[aliases]\n{}a5000 = \"echo end\"", chain));
+        
+        let result = shell.eval("a0").await.unwrap();
+        assert_eq!(result.exit_code, 2);
+        assert!(result.stderr.starts_with("❌ alias:"), "{}", result.stderr);
+        assert_eq!(shell.last_status, 2);
+        
+        let result = shell.eval("a4990 && a4999").await.unwrap();
+        assert_eq!((result.exit_code, result.stdout.as_str()), (0, "end\nend\n"));
+    }
+}
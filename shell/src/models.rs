@@ -0,0 +1,135 @@
+use super::*;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::fs::File;
+
+/// List of models published by the model server, as JSON:
+/// `{"models": [{"name": "llm.onnx", "url": "...", "sha256": "...", "size": 123}]}`.
+/// `url` is only needed for the download itself.
+#[derive(Debug, Deserialize)]
+pub struct Manifest {
+    pub models: Vec<ModelEntry>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ModelEntry {
+    /// File name under `models_dir`
+    pub name: String,
+    /// Hex-encoded SHA-256 of the file
+    pub sha256: String,
+    /// Size in bytes
+    pub size: u64,
+}
+
+/// What an update would do with one model
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    /// Not present locally
+    Download,
+    /// Present but differs from the manifest
+    Update,
+    /// Already matches the manifest
+    Skip,
+}
+
+/// Fetch the manifest from a local path, `file://` URL or `http(s)://` URL
+pub async fn fetch_manifest(location: &str) -> Result<Manifest> {
+    if location.is_empty() {
+        anyhow::bail!("No model manifest configured; set ai_config.models_manifest");
+    }
+    
+    let contents = match reqwest::Url::parse(location) {
+        Ok(url) if url.scheme() == "http" || url.scheme() == "https" => reqwest::get(url)
+            .await
+            .and_then(|response| response.error_for_status())
+            .with_context(|| format!("Failed to fetch model manifest {}", location))?
+            .text()
+            .await?,
+        Ok(url) if url.scheme() == "file" => {
+            let path = url
+                .to_file_path()
+                .map_err(|()| anyhow::anyhow!("Invalid models_manifest URL {}", location))?;
+            std::fs::read_to_string(path)
+                .with_context(|| format!("Failed to read model manifest {}", location))?
+        }
+        _ => std::fs::read_to_string(location)
+            .with_context(|| format!("Failed to read model manifest {}", location))?,
+    };
+    
+    serde_json::from_str(&contents).with_context(|| format!("Invalid model manifest {}", location))
+}
+
+/// Compare each model in `manifest` with its copy in `dir`
+pub fn plan<'m>(manifest: &'m Manifest, dir: &Path) -> Result<Vec<(&'m ModelEntry, Action)>> {
+    manifest
+        .models
+        .iter()
+        .map(|model| Ok((model, action(model, dir)?)))
+        .collect()
+}
+
+fn action(model: &ModelEntry, dir: &Path) -> Result<Action> {
+    // Names come from the server; never let one point outside `dir`
+    if matches!(model.name.as_str(), "" | "." | "..") || model.name.contains(['/', '\\']) {
+        anyhow::bail!("Invalid model name '{}' in manifest", model.name);
+    }
+    
+    let path = dir.join(&model.name);
+    let metadata = match std::fs::metadata(&path) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Action::Download),
+        Err(e) => return Err(e).with_context(|| format!("Failed to read {}", path.display())),
+    };
+    
+    // Only hash when the size can't already tell the files apart
+    if metadata.len() != model.size || !sha256_file(&path)?.eq_ignore_ascii_case(&model.sha256) {
+        return Ok(Action::Update);
+    }
+    Ok(Action::Skip)
+}
+
+fn sha256_file(path: &Path) -> Result<String> {
+    let mut file = File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+    let mut hasher = Sha256::new();
+    io::copy(&mut file, &mut hasher).with_context(|| format!("Failed to read {}", path.display()))?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Print what an update would do, without changing anything
+pub fn print_plan(plan: &[(&ModelEntry, Action)]) {
+    let width = plan.iter().map(|(model, _)| model.name.len()).max().unwrap_or(0);
+    
+    for (model, action) in plan {
+        let label = match action {
+            Action::Download => "download",
+            Action::Update => "update",
+            Action::Skip => "skip",
+        };
+        outln!("  {:8}  {:width$}  {}", label, model.name, format_size(model.size), width = width);
+    }
+    
+    let fetched: Vec<_> = plan.iter().filter(|(_, action)| *action != Action::Skip).collect();
+    let total = fetched.iter().map(|(model, _)| model.size).sum();
+    outln!(
+        "Would download {} of {} models ({}); nothing was changed",
+        fetched.len(),
+        plan.len(),
+        format_size(total)
+    );
+}
+
+/// `512 B`, `3.4 MiB`, `1.2 GiB`
+fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", size, UNITS[unit])
+}
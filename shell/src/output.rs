@@ -0,0 +1,91 @@
+use std::fmt;
+use std::io::{self, Write};
+
+/// Exit status when stdout is closed by the reader, as if killed by SIGPIPE
+pub const EXIT_BROKEN_PIPE: i32 = 128 + 13;
+
+/// `print!` that doesn't panic when stdout is gone; see `write_stdout`
+macro_rules! out {
+    ($($arg:tt)*) => {
+        $crate::output::write_stdout(format_args!($($arg)*))
+    };
+}
+
+/// `println!` that doesn't panic when stdout is gone; see `write_stdout`
+macro_rules! outln {
+    () => {
+        $crate::output::write_stdout(format_args!("\n"))
+    };
+    ($($arg:tt)*) => {
+        $crate::output::write_stdout(format_args!("{}\n", format_args!($($arg)*)))
+    };
+}
+
+/// Write to stdout. If the reader has gone away (e.g. output piped to `head`)
+/// the shell exits quietly; other write errors are reported on stderr.
+pub fn write_stdout(args: fmt::Arguments) {
+    if let Err(e) = io::stdout().lock().write_fmt(args) {
+        handle_error(e);
+    }
+}
+
+/// Flush stdout, handling errors like `write_stdout`
+pub fn flush() {
+    if let Err(e) = io::stdout().flush() {
+        handle_error(e);
+    }
+}
+
+fn handle_error(e: io::Error) {
+    if e.kind() == io::ErrorKind::BrokenPipe {
+        std::process::exit(EXIT_BROKEN_PIPE);
+    }
+    eprintln!("⚠️  Failed to write output: {}", e);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader};
+    use std::process::{Command, Stdio};
+    
+    /// Set in the copy of the test binary that does the writing
+    const CHILD_VAR: &str = "OBSIDIAN_OUTPUT_TEST_CHILD";
+    
+    #[test]
+    fn a_closed_stdout_exits_quietly() {
+        if std::env::var_os(CHILD_VAR).is_some() {
+            outln!("ready");
+            flush();
+            // Keeps writing until the pipe is gone, so only exiting ends it
+            for _ in 0..10_000 {
+                outln!("{}", "x".repeat(1024));
+                std::thread::sleep(std::time::Duration::from_millis(1));
+            }
+            return;
+        }
+        
+        let mut child = Command::new(std::env::current_exe().unwrap())
+            .args(["--exact", "output::tests::a_closed_stdout_exits_quietly", "--nocapture", "--test-threads=1"])
+            .env(CHILD_VAR, "1")
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap();
+        
+        // Like `head -1`: read up to the marker, which libtest may put after
+        // the test name, then go away
+        let mut stdout = BufReader::new(child.stdout.take().unwrap());
+        let mut line = String::new();
+        while !line.trim_end().ends_with("ready") {
+            line.clear();
+            assert_ne!(stdout.read_line(&mut line).unwrap(), 0, "the child never got going");
+        }
+        drop(stdout);
+        
+        let output = child.wait_with_output().unwrap();
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert_eq!(output.status.code(), Some(EXIT_BROKEN_PIPE), "{}", stderr);
+        assert!(!stderr.contains("panicked"), "{}", stderr);
+    }
+}
@@ -0,0 +1,46 @@
+use super::*;
+use std::io::IsTerminal;
+use std::process::Stdio;
+
+/// Pager used when `$PAGER` is not set
+const DEFAULT_PAGER: &str = "less -R";
+
+/// Whether `text` is too tall for the terminal and stdout is a TTY
+pub fn should_page(text: &str) -> bool {
+    if !io::stdout().is_terminal() {
+        return false;
+    }
+    
+    match crossterm::terminal::size() {
+        // Leave a row for the prompt that follows the output
+        Ok((_, rows)) => text.lines().count() >= rows as usize,
+        Err(_) => false,
+    }
+}
+
+/// Show `text` through `$PAGER` (default `less -R`), waiting for it to exit
+pub fn page(text: &str) -> Result<()> {
+    let pager = env::var("PAGER")
+        .ok()
+        .filter(|pager| !pager.trim().is_empty())
+        .unwrap_or_else(|| DEFAULT_PAGER.to_string());
+    let mut parts = pager.split_whitespace();
+    let program = parts.next().unwrap_or("less");
+    
+    let mut child = Command::new(program)
+        .args(parts)
+        .stdin(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("Failed to start pager '{}'", pager))?;
+    
+    if let Some(mut stdin) = child.stdin.take() {
+        // The pager closing early (e.g. `q` in less) is not an error
+        match stdin.write_all(text.as_bytes()) {
+            Err(e) if e.kind() != io::ErrorKind::BrokenPipe => return Err(e.into()),
+            _ => {}
+        }
+    }
+    
+    child.wait()?;
+    Ok(())
+}
@@ -0,0 +1,126 @@
+//! The command palette: a searchable list of builtins, common actions and
+//! recent commands, opened with `palette` or `keybindings.palette`. It uses
+//! the history finder's screen, and descriptions come from `help::BUILTINS`.
+
+use super::*;
+use crossterm::terminal;
+use rustyline::{Cmd, ConditionalEventHandler, EventContext, Movement, RepeatCount};
+use std::collections::HashSet;
+
+/// Useful lines that aren't a builtin on their own
+const ACTIONS: &[(&str, &str)] = &[
+    ("cd -", "Go back to the previous directory"),
+    ("cd ~", "Go to the home directory"),
+    ("help --ai ", "Ask the AI what a command does"),
+    ("undo -l", "List the AI commands undo can reverse"),
+    ("trash-restore", "List files in the trash"),
+    ("source --diff ", "Run a script and show what it changed"),
+];
+
+/// How many recent commands are listed
+const RECENT: usize = 10;
+/// Widest the command column is padded to; longer commands push their
+/// description along
+const COMMAND_COLUMNS: usize = 20;
+
+/// One line of the palette: what goes in the input line, and what it does
+pub struct Item {
+    pub command: String,
+    pub description: String,
+}
+
+/// Everything the palette offers, skipping `disabled` builtins. `history` is
+/// oldest first; commands already listed aren't repeated from it.
+pub fn items(history: &[String], disabled: &[String]) -> Vec<Item> {
+    let mut items: Vec<Item> = ACTIONS
+        .iter()
+        .map(|(command, description)| Item {
+            command: command.to_string(),
+            description: description.to_string(),
+        })
+        .collect();
+    
+    for builtin in help::BUILTINS {
+        if disabled.iter().any(|name| name == builtin.name) {
+            continue;
+        }
+        // Pipe stages such as `@json` follow whatever is typed
+        let command = match builtin.usage.strip_prefix(builtin.name) {
+            Some("") => builtin.name.to_string(),
+            Some(_) => format!("{} ", builtin.name),
+            None => format!("| {} ", builtin.name),
+        };
+        items.push(Item {
+            command,
+            description: format!("{} ({})", builtin.summary, builtin.usage),
+        });
+    }
+    
+    let mut seen: HashSet<&str> = items.iter().map(|item| item.command.trim()).collect();
+    let recent: Vec<&String> = history
+        .iter()
+        .rev()
+        .filter(|command| seen.insert(command.trim()))
+        .take(RECENT)
+        .collect();
+    items.extend(recent.into_iter().map(|command| Item {
+        command: command.clone(),
+        description: "Recent command".to_string(),
+    }));
+    items
+}
+
+/// Let the user pick from `items`; the chosen command, joined to `line` if it
+/// is a pipe stage. The terminal must already be in raw mode.
+pub fn pick(items: &[Item], line: &str) -> Result<Option<String>> {
+    let width = items.iter().map(|item| item.command.chars().count()).max().unwrap_or(0).min(COMMAND_COLUMNS);
+    let shown: Vec<String> = items
+        .iter()
+        .map(|item| format!("{:width$}  {}", item.command, item.description, width = width))
+        .collect();
+    let candidates: Vec<&str> = shown.iter().map(String::as_str).collect();
+    
+    let Some(i) = finder::pick(&candidates, "")? else {
+        return Ok(None);
+    };
+    let command = &items[i].command;
+    if command.starts_with('|') && !line.trim().is_empty() {
+        return Ok(Some(format!("{} {}", line.trim_end(), command)));
+    }
+    Ok(Some(command.trim_start_matches("| ").to_string()))
+}
+
+/// The `palette` builtin, run from the prompt: the chosen command, to be
+/// edited before it runs
+pub fn run(history: &[String], disabled: &[String]) -> Result<Option<String>> {
+    anyhow::ensure!(finder::fits(), "the terminal is too small");
+    terminal::enable_raw_mode()?;
+    let chosen = pick(&items(history, disabled), "");
+    terminal::disable_raw_mode()?;
+    chosen
+}
+
+/// The key handler for `keybindings.palette`; `entries` is the history,
+/// oldest first
+pub struct PaletteKey {
+    pub entries: Arc<Mutex<Vec<String>>>,
+    pub disabled: Vec<String>,
+}
+
+impl ConditionalEventHandler for PaletteKey {
+    fn handle(&self, _event: &rustyline::Event, _n: RepeatCount, _positive: bool, ctx: &EventContext) -> Option<Cmd> {
+        if !finder::fits() {
+            return Some(Cmd::Noop);
+        }
+        
+        let entries = self.entries.lock().ok()?.clone();
+        match pick(&items(&entries, &self.disabled), ctx.line()) {
+            Ok(Some(command)) => Some(Cmd::Replace(Movement::WholeBuffer, Some(command))),
+            Ok(None) => Some(Cmd::Repaint),
+            Err(e) => {
+                tracing::warn!(error = %e, "command palette failed");
+                Some(Cmd::Repaint)
+            }
+        }
+    }
+}
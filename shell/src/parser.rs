@@ -0,0 +1,605 @@
+use std::ops::Range;
+
+/// How a command in a chain depends on the one before it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Connector {
+    /// First command, or one following `;`
+    Always,
+    /// Follows `&&`: runs only if the previous command succeeded
+    And,
+    /// Follows `||`: runs only if the previous command failed
+    Or,
+}
+
+impl Connector {
+    /// Whether a command with this connector runs after a command that exited with `status`
+    pub fn should_run(self, status: i32) -> bool {
+        match self {
+            Connector::Always => true,
+            Connector::And => status == 0,
+            Connector::Or => status != 0,
+        }
+    }
+}
+
+/// Split a line on `;`, `&&` and `||`, ignoring separators inside quotes.
+/// A `&` on its own also ends a command, which keeps it as its last word
+/// for `strip_background`.
+///
+/// Empty commands (e.g. from a trailing `;`) are dropped.
+pub fn split_chain(line: &str) -> Vec<(Connector, String)> {
+    let mut chain = Vec::new();
+    let mut current = String::new();
+    let mut connector = Connector::Always;
+    let mut quote: Option<char> = None;
+    let mut chars = line.chars().peekable();
+    
+    while let Some(c) = chars.next() {
+        match (quote, c) {
+            (Some(q), c) if c == q => {
+                quote = None;
+                current.push(c);
+            }
+            (None, '\'' | '"') => {
+                quote = Some(c);
+                current.push(c);
+            }
+            (q, '\\') if q != Some('\'') => {
+                current.push(c);
+                if let Some(escaped) = chars.next() {
+                    current.push(escaped);
+                }
+            }
+            (None, ';') => {
+                push_command(&mut chain, connector, &mut current);
+                connector = Connector::Always;
+            }
+            (None, '&' | '|') if chars.peek() == Some(&c) => {
+                chars.next();
+                push_command(&mut chain, connector, &mut current);
+                connector = if c == '&' { Connector::And } else { Connector::Or };
+            }
+            // Not part of `&>`, `2>&1` or `>&2`
+            (None, '&') if chars.peek() != Some(&'>') && !current.ends_with('>') => {
+                current.truncate(current.trim_end().len());
+                current.push_str(" &");
+                push_command(&mut chain, connector, &mut current);
+                connector = Connector::Always;
+            }
+            _ => current.push(c),
+        }
+    }
+    
+    push_command(&mut chain, connector, &mut current);
+    chain
+}
+
+/// `command` without the `&` that ends it, if it is to run in the background
+pub fn strip_background(command: &str) -> Option<&str> {
+    if !command.ends_with('&') {
+        return None;
+    }
+    let last = tokenize(command).pop()?;
+    (last.kind == TokenKind::Word("&".to_string()) && &command[last.span.clone()] == "&")
+        .then(|| command[..last.span.start].trim_end())
+        .filter(|rest| !rest.is_empty())
+}
+
+fn push_command(chain: &mut Vec<(Connector, String)>, connector: Connector, current: &mut String) {
+    let command = current.trim();
+    if !command.is_empty() {
+        chain.push((connector, command.to_string()));
+    }
+    current.clear();
+}
+
+/// Split one command of a chain into pipeline stages on unquoted `|`.
+///
+/// Run this after `split_chain`, which has already consumed `||`.
+pub fn split_pipeline(command: &str) -> Vec<String> {
+    let mut stages = Vec::new();
+    let mut current = String::new();
+    let mut quote: Option<char> = None;
+    let mut chars = command.chars();
+    
+    while let Some(c) = chars.next() {
+        match (quote, c) {
+            (Some(q), c) if c == q => {
+                quote = None;
+                current.push(c);
+            }
+            (None, '\'' | '"') => {
+                quote = Some(c);
+                current.push(c);
+            }
+            (q, '\\') if q != Some('\'') => {
+                current.push(c);
+                if let Some(escaped) = chars.next() {
+                    current.push(escaped);
+                }
+            }
+            (None, '|') => {
+                stages.push(current.trim().to_string());
+                current.clear();
+            }
+            _ => current.push(c),
+        }
+    }
+    
+    stages.push(current.trim().to_string());
+    stages
+}
+
+/// `line` without its comment: everything from the first unquoted,
+/// unescaped `comment` character that starts a word, at the start of the
+/// line or after whitespace, `;`, `&` or `|`. One inside a word, as in
+/// `a#b` or `$#`, is kept.
+pub fn strip_comment(line: &str, comment: char) -> &str {
+    let mut quote: Option<char> = None;
+    let mut word_start = true;
+    let mut chars = line.char_indices();
+    
+    while let Some((i, c)) = chars.next() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some(_), _) => {}
+            (None, '\'' | '"') => quote = Some(c),
+            (None, '\\') => {
+                chars.next();
+            }
+            (None, c) if c == comment && word_start => return line[..i].trim_end(),
+            _ => {}
+        }
+        word_start = quote.is_none() && (c.is_whitespace() || matches!(c, ';' | '&' | '|'));
+    }
+    line
+}
+
+/// An output redirection operator
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Redirect {
+    /// `>` or `1>`, `>>` to append
+    Stdout { append: bool },
+    /// `2>`, `2>>` to append
+    Stderr { append: bool },
+    /// `&>`, `&>>` to append: both streams to one file
+    Both { append: bool },
+    /// `2>&1`: stderr goes wherever stdout goes at this point
+    StderrToStdout,
+    /// `>&2` or `1>&2`: stdout goes wherever stderr goes at this point
+    StdoutToStderr,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TokenKind {
+    /// A word with quotes and backslash escapes removed
+    Word(String),
+    Redirect(Redirect),
+}
+
+/// A token and where it came from in the command
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Token {
+    pub kind: TokenKind,
+    pub span: Range<usize>,
+}
+
+/// Split a command into words and redirection operators, honouring quotes.
+///
+/// Quoted text never forms an operator, so `echo ">"` prints `>` and
+/// `> "my file.txt"` names a single file.
+pub fn tokenize(command: &str) -> Vec<Token> {
+    let mut tokens = Vec::new();
+    let mut word = String::new();
+    // Start of the current word, if one has begun; `""` is still a word
+    let mut start: Option<usize> = None;
+    let mut quoted = false;
+    let mut chars = command.char_indices().peekable();
+    
+    while let Some((i, c)) = chars.next() {
+        match c {
+            c if c.is_whitespace() => {
+                push_word(&mut tokens, &mut word, &mut start, i);
+                quoted = false;
+            }
+            '\'' => {
+                start.get_or_insert(i);
+                quoted = true;
+                for (_, c) in chars.by_ref() {
+                    if c == '\'' {
+                        break;
+                    }
+                    word.push(c);
+                }
+            }
+            '"' => {
+                start.get_or_insert(i);
+                quoted = true;
+                while let Some((_, c)) = chars.next() {
+                    match c {
+                        '"' => break,
+                        '\\' if matches!(chars.peek(), Some((_, '"' | '\\' | '$' | '`'))) => {
+                            word.push(chars.next().unwrap().1);
+                        }
+                        c => word.push(c),
+                    }
+                }
+            }
+            '\\' => {
+                start.get_or_insert(i);
+                quoted = true;
+                if let Some((_, escaped)) = chars.next() {
+                    word.push(escaped);
+                }
+            }
+            '>' | '&' if c == '>' || matches!(chars.peek(), Some((_, '>'))) => {
+                // A bare `1` or `2` right before `>` is the stream number
+                let stream = match (c, start, quoted, word.as_str()) {
+                    ('>', Some(s), false, "1" | "2") if s + 1 == i => word.pop(),
+                    _ => None,
+                };
+                let op_start = if stream.is_some() { i - 1 } else { i };
+                if stream.is_some() {
+                    start = None;
+                }
+                push_word(&mut tokens, &mut word, &mut start, i);
+                quoted = false;
+                
+                if c == '&' {
+                    chars.next();
+                }
+                let append = chars.next_if(|(_, c)| *c == '>').is_some();
+                
+                // `>&1` or `>&2` names the stream to duplicate
+                let mut lookahead = chars.clone();
+                let duplicate = match (c, append, lookahead.next(), lookahead.next()) {
+                    ('>', false, Some((_, '&')), Some((_, n @ ('1' | '2')))) => Some(n),
+                    _ => None,
+                };
+                let redirect = match (c, stream, duplicate) {
+                    ('&', _, _) => Redirect::Both { append },
+                    (_, Some('2'), Some('1')) => Redirect::StderrToStdout,
+                    (_, None | Some('1'), Some('2')) => Redirect::StdoutToStderr,
+                    (_, Some('2'), _) => Redirect::Stderr { append },
+                    _ => Redirect::Stdout { append },
+                };
+                if matches!(redirect, Redirect::StderrToStdout | Redirect::StdoutToStderr) {
+                    chars.next();
+                    chars.next();
+                }
+                
+                let end = chars.peek().map_or(command.len(), |(i, _)| *i);
+                tokens.push(Token {
+                    kind: TokenKind::Redirect(redirect),
+                    span: op_start..end,
+                });
+            }
+            c => {
+                start.get_or_insert(i);
+                word.push(c);
+            }
+        }
+    }
+    
+    push_word(&mut tokens, &mut word, &mut start, command.len());
+    tokens
+}
+
+fn push_word(tokens: &mut Vec<Token>, word: &mut String, start: &mut Option<usize>, end: usize) {
+    if let Some(start) = start.take() {
+        tokens.push(Token {
+            kind: TokenKind::Word(std::mem::take(word)),
+            span: start..end,
+        });
+    }
+}
+
+/// Quote `word` so `tokenize` reads it back as a single word, unchanged
+pub fn quote(word: &str) -> String {
+    let plain = |c: char| c.is_ascii_alphanumeric() || "-_./+,:=@%".contains(c);
+    if !word.is_empty() && word.chars().all(plain) {
+        return word.to_string();
+    }
+    format!("'{}'", word.replace('\'', r"'\''"))
+}
+
+/// A reference to a positional parameter
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Positional {
+    /// `$0`…`$9` or `${n}`
+    Index(usize),
+    /// `$#`
+    Count,
+    /// `$@`, or `$*` when `joined`
+    All { joined: bool },
+}
+
+/// Substitute `$0`…`$9`, `${n}`, `$#`, `$@` and `$*` in `line`, where
+/// `params[0]` is `$0`, quoting each value so it reaches the command as it
+/// was. Nothing is substituted inside single quotes, and `"$@"` gives one
+/// word per argument, as in POSIX shells.
+///
+/// This shell doesn't run `$(…)` itself, but lines handed to
+/// `fallback_shell` with `!!` may hold it, so as in POSIX shells its inside
+/// is quoted afresh: in `"$(echo "$1")"` the inner quotes hold `$1`.
+pub fn expand_positional(line: &str, params: &[String]) -> String {
+    let args = params.get(1..).unwrap_or_default();
+    let mut expanded = String::with_capacity(line.len());
+    let mut quoting: Option<char> = None;
+    // The quoting outside each `$(` and `(` still open inside one
+    let mut outer: Vec<Option<char>> = Vec::new();
+    let mut rest = line;
+    
+    while let Some(c) = rest.chars().next() {
+        match (quoting, c) {
+            (q, '$') if q != Some('\'') && rest[1..].starts_with('(') => {
+                outer.push(q);
+                quoting = None;
+                expanded.push_str("$(");
+                rest = &rest[2..];
+                continue;
+            }
+            (None, '(') if !outer.is_empty() => outer.push(None),
+            (None, ')') if !outer.is_empty() => quoting = outer.pop().flatten(),
+            (None, '"') if rest[1..].starts_with("$@\"") => {
+                expanded.push_str(&join(args, quote, " "));
+                rest = &rest[4..];
+                continue;
+            }
+            (Some(q), c) if c == q => quoting = None,
+            (None, '\'' | '"') => quoting = Some(c),
+            (q, '\\') if q != Some('\'') => {
+                let escaped = rest[1..].chars().next().map_or(0, char::len_utf8);
+                expanded.push_str(&rest[..1 + escaped]);
+                rest = &rest[1 + escaped..];
+                continue;
+            }
+            (q, '$') if q != Some('\'') => {
+                if let Some((param, len)) = positional_at(&rest[1..]) {
+                    let quoted = q == Some('"');
+                    expanded.push_str(&match param {
+                        Positional::Index(i) => {
+                            let value = params.get(i).map_or("", String::as_str);
+                            if quoted {
+                                escape_in_double_quotes(value)
+                            } else if value.is_empty() {
+                                String::new()
+                            } else {
+                                quote(value)
+                            }
+                        }
+                        Positional::Count => args.len().to_string(),
+                        Positional::All { joined: false } if quoted => join(args, escape_in_double_quotes, "\" \""),
+                        Positional::All { .. } if quoted => escape_in_double_quotes(&args.join(" ")),
+                        Positional::All { .. } => join(args, quote, " "),
+                    });
+                    rest = &rest[1 + len..];
+                    continue;
+                }
+            }
+            _ => {}
+        }
+        expanded.push(c);
+        rest = &rest[c.len_utf8()..];
+    }
+    
+    expanded
+}
+
+/// Substitute `$name` and `${name}` in `line` with `value(name)`, leaving
+/// names it doesn't know as they are. `$?` is the name `?`. Quoting is as
+/// in POSIX shells: nothing inside single quotes, one word inside double
+/// quotes, and otherwise a word for each whitespace-separated part.
+pub fn expand_variables(line: &str, mut value: impl FnMut(&str) -> Option<String>) -> String {
+    let mut expanded = String::with_capacity(line.len());
+    let mut quoting: Option<char> = None;
+    let mut rest = line;
+    
+    while let Some(c) = rest.chars().next() {
+        match (quoting, c) {
+            (Some(q), c) if c == q => quoting = None,
+            (None, '\'' | '"') => quoting = Some(c),
+            (q, '\\') if q != Some('\'') => {
+                let escaped = rest[1..].chars().next().map_or(0, char::len_utf8);
+                expanded.push_str(&rest[..1 + escaped]);
+                rest = &rest[1 + escaped..];
+                continue;
+            }
+            (q, '$') if q != Some('\'') => {
+                let known = variable_at(&rest[1..]).and_then(|(name, len)| Some((value(name)?, len)));
+                if let Some((found, len)) = known {
+                    if q == Some('"') {
+                        expanded.push_str(&escape_in_double_quotes(&found));
+                    } else {
+                        expanded.push_str(&found.split_whitespace().map(quote).collect::<Vec<_>>().join(" "));
+                    }
+                    rest = &rest[1 + len..];
+                    continue;
+                }
+            }
+            _ => {}
+        }
+        expanded.push(c);
+        rest = &rest[c.len_utf8()..];
+    }
+    
+    expanded
+}
+
+/// The variable named at the start of `text`, which follows a `$`, and how
+/// many bytes name it
+fn variable_at(text: &str) -> Option<(&str, usize)> {
+    if text.starts_with('?') {
+        return Some(("?", 1));
+    }
+    if let Some(braced) = text.strip_prefix('{') {
+        let end = braced.find('}')?;
+        return Some((&braced[..end], end + 2));
+    }
+    
+    let len = text
+        .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+        .unwrap_or(text.len());
+    let name = &text[..len];
+    name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_').then_some((name, len))
+}
+
+/// The positional parameter named at the start of `text`, which follows a
+/// `$`, and how many bytes name it
+fn positional_at(text: &str) -> Option<(Positional, usize)> {
+    match text.chars().next()? {
+        c @ '0'..='9' => Some((Positional::Index(c as usize - '0' as usize), 1)),
+        '#' => Some((Positional::Count, 1)),
+        '@' => Some((Positional::All { joined: false }, 1)),
+        '*' => Some((Positional::All { joined: true }, 1)),
+        '{' => {
+            let end = text.find('}')?;
+            let index = text[1..end].parse().ok()?;
+            Some((Positional::Index(index), end + 1))
+        }
+        _ => None,
+    }
+}
+
+/// `value` escaped to appear literally between double quotes
+fn escape_in_double_quotes(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if matches!(c, '"' | '\\' | '$' | '`') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+fn join(words: &[String], convert: fn(&str) -> String, separator: &str) -> String {
+    words.iter().map(|word| convert(word)).collect::<Vec<_>>().join(separator)
+}
+
+/// The words of a command with quotes removed; any redirection operators are
+/// kept as they were written
+pub fn split_words(command: &str) -> Vec<String> {
+    tokenize(command)
+        .into_iter()
+        .map(|token| match token.kind {
+            TokenKind::Word(word) => word,
+            TokenKind::Redirect(_) => command[token.span].to_string(),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, Instant};
+    
+    const MEGABYTE: usize = 1 << 20;
+    
+    /// `unit` repeated to make about `bytes`, and how many times it was
+    fn long_line(unit: &str, bytes: usize) -> (String, usize) {
+        let repeats = bytes / unit.len();
+        (unit.repeat(repeats), repeats)
+    }
+    
+    /// Everything the input path does to a line before running it, returning
+    /// how many words it came to and how long that took
+    fn process(line: &str) -> (usize, Duration) {
+        let start = Instant::now();
+        let line = strip_comment(line, '#');
+        let line = expand_variables(line, |name| (name == "HOME").then(|| "/home/me".to_string()));
+        let line = expand_positional(&line, &["sh".to_string(), "one two".to_string()]);
+        let mut words = 0;
+        for (_, command) in split_chain(&line) {
+            for stage in split_pipeline(&command) {
+                words += tokenize(&stage).len();
+            }
+        }
+        (words, start.elapsed())
+    }
+    
+    #[test]
+    fn comments_start_only_at_a_word() {
+        let cases = [
+            ("ls # c", "ls"),
+            ("ls -l   #c", "ls -l"),
+            ("# only a comment", ""),
+            ("  # indented", ""),
+            ("ls;#c", "ls;"),
+            ("a|#c", "a|"),
+            ("a &#c", "a &"),
+            // Inside a word, quoted or escaped, it is kept
+            ("echo a#b", "echo a#b"),
+            ("echo $#", "echo $#"),
+            (r#"echo "x # y""#, r#"echo "x # y""#),
+            ("echo '#q'", "echo '#q'"),
+            (r"echo \#e", r"echo \#e"),
+            (r#"echo "it's" # c"#, r#"echo "it's""#),
+        ];
+        for (line, expected) in cases {
+            assert_eq!(strip_comment(line, '#'), expected, "{}", line);
+        }
+        assert_eq!(strip_comment("ls % c # d", '%'), "ls");
+    }
+    
+    #[test]
+    fn a_lone_ampersand_ends_a_background_command() {
+        let chain = split_chain("sleep 1& make > log 2>&1 & echo '&' && ls &>all");
+        let commands: Vec<&str> = chain.iter().map(|(_, command)| command.as_str()).collect();
+        assert_eq!(commands, ["sleep 1 &", "make > log 2>&1 &", "echo '&'", "ls &>all"]);
+        assert_eq!(chain[3].0, Connector::And);
+        
+        assert_eq!(strip_background("sleep 1 &"), Some("sleep 1"));
+        assert_eq!(strip_background("make > log 2>&1 &"), Some("make > log 2>&1"));
+        for command in ["echo '&'", "echo \\&", "ls &>all", "&"] {
+            assert_eq!(strip_background(command), None, "{}", command);
+        }
+    }
+    
+    fn positional(line: &str, args: &[&str]) -> String {
+        let params: Vec<String> = ["sh"].iter().chain(args).map(|arg| arg.to_string()).collect();
+        expand_positional(line, &params)
+    }
+    
+    #[test]
+    fn command_substitutions_are_quoted_afresh() {
+        assert_eq!(positional("echo $(echo $1)", &["a b"]), "echo $(echo 'a b')");
+        assert_eq!(positional(r#"echo "$(echo "$1")""#, &["a b"]), r#"echo "$(echo "a b")""#);
+        assert_eq!(positional(r#"echo "$(printf '%s' "$@")""#, &["a", "b c"]), r#"echo "$(printf '%s' a 'b c')""#);
+        assert_eq!(positional(r#"echo "$(echo '$1')" $1"#, &["x"]), r#"echo "$(echo '$1')" x"#);
+        
+        // Nested parentheses and substitutions close in order
+        assert_eq!(positional(r#"echo "$( (echo $(echo "$1")) ) $1""#, &["x"]), r#"echo "$( (echo $(echo "x")) ) x""#);
+        assert_eq!(positional("echo $((1 + $1))", &["2"]), "echo $((1 + 2))");
+        
+        // Outside single quotes only, and plain parentheses are left alone
+        assert_eq!(positional("echo '$(echo $1)' ($1)", &["x"]), "echo '$(echo $1)' (x)");
+    }
+    
+    #[test]
+    fn a_one_megabyte_line_is_processed_whole() {
+        // One command with a few hundred thousand words
+        let (args, repeats) = long_line("'a b' \"c $HOME\" x\\ y $1 ", MEGABYTE);
+        let (words, _) = process(&format!("echo {}", args));
+        assert_eq!(words, 1 + 4 * repeats);
+        
+        // Or many short commands
+        let (line, repeats) = long_line("ls 'a;b' | grep \"|\" > out 2>&1 && ", MEGABYTE);
+        let chain = split_chain(&line);
+        assert_eq!(chain.len(), repeats);
+        // `grep`, `|`, `>`, `out` and `2>&1`
+        assert_eq!(tokenize(&split_pipeline(&chain[repeats - 1].1)[1]).len(), 5);
+    }
+    
+    #[test]
+    fn a_megabyte_of_commands_is_processed_in_time() {
+        let unit = "echo 'x y' \"$HOME\" a\\ b | wc -l > \"f g\" && ";
+        let (line, repeats) = long_line(unit, MEGABYTE);
+        let (words, took) = process(&line);
+        
+        // `echo` and its three arguments, then `wc`, `-l`, `>` and `f g`
+        assert_eq!(words, 8 * repeats);
+        // Milliseconds when linear; quadratic work on a megabyte takes hours
+        assert!(took < Duration::from_secs(20), "took {:?}", took);
+    }
+}
@@ -0,0 +1,305 @@
+use super::*;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::time::Duration;
+use unicode_width::UnicodeWidthChar;
+
+/// Values available to prompt placeholders
+pub struct PromptContext<'a> {
+    pub cwd: Option<&'a Path>,
+    pub last_status: i32,
+    pub last_duration: Option<Duration>,
+    pub cache: &'a PromptCache,
+    /// `prompt_segments` from the config
+    pub segments: &'a BTreeMap<String, String>,
+}
+
+/// Recently computed prompt components, so drawing several prompts in quick
+/// succession doesn't repeat slow lookups. Clear it when the directory changes.
+pub struct PromptCache {
+    ttl: Duration,
+    entries: Mutex<HashMap<&'static str, (Instant, String)>>,
+}
+
+impl PromptCache {
+    pub fn new(ttl: Duration) -> Self {
+        PromptCache {
+            ttl,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+    
+    /// `name`'s value from the last `ttl`, or a fresh one from `compute`
+    fn get(&self, name: &'static str, compute: impl FnOnce() -> String) -> String {
+        let Ok(mut entries) = self.entries.lock() else {
+            return compute();
+        };
+        if let Some((at, value)) = entries.get(name) {
+            if at.elapsed() < self.ttl {
+                return value.clone();
+            }
+        }
+        
+        let value = compute();
+        entries.insert(name, (Instant::now(), value.clone()));
+        value
+    }
+    
+    pub fn clear(&self) {
+        if let Ok(mut entries) = self.entries.lock() {
+            entries.clear();
+        }
+    }
+}
+
+/// Expand placeholders in a prompt format string.
+///
+/// Supported: `{status}` (last exit code), `{duration}` (run time of the last
+/// command), `{git_branch}`, `{cwd}`, `{user}`, `{time}` (HH:MM:SS), `{venv}`
+/// and `{conda_env}` (the active Python environment's name, if any), and the
+/// name of any configured segment, which wins over a placeholder of the
+/// same name. Unknown placeholders are left as written. Control characters
+/// in the expanded values are shown as `?`, so only the format itself can
+/// hold escape sequences.
+pub fn render(format: &str, ctx: &PromptContext) -> String {
+    substitute(format, |name| match ctx.segments.get(name) {
+        Some(segment) => Some(render_segment(segment, ctx)),
+        None => expand(name, ctx),
+    })
+}
+
+/// A segment's format expanded, or nothing if one of its placeholders came
+/// out empty, e.g. `{git_branch}` outside a repository. Segments can't
+/// contain other segments.
+fn render_segment(format: &str, ctx: &PromptContext) -> String {
+    let mut applicable = true;
+    let out = substitute(format, |name| {
+        let value = expand(name, ctx)?;
+        applicable &= !value.is_empty();
+        Some(value)
+    });
+    if applicable {
+        out
+    } else {
+        String::new()
+    }
+}
+
+/// `format` with each `{name}` replaced by `lookup(name)`, or kept as written
+/// where that is `None`
+fn substitute(format: &str, mut lookup: impl FnMut(&str) -> Option<String>) -> String {
+    let mut out = String::with_capacity(format.len());
+    let mut rest = format;
+    
+    while let Some(start) = rest.find('{') {
+        out.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+        
+        let Some(end) = after.find('}') else {
+            out.push_str(&rest[start..]);
+            return out;
+        };
+        
+        let name = &after[..end];
+        match lookup(name) {
+            Some(value) => out.push_str(&value),
+            None => out.push_str(&rest[start..start + end + 2]),
+        }
+        rest = &after[end + 1..];
+    }
+    
+    out.push_str(rest);
+    out
+}
+
+fn expand(name: &str, ctx: &PromptContext) -> Option<String> {
+    let value = match name {
+        "status" => ctx.last_status.to_string(),
+        "duration" => ctx.last_duration.map(format_duration).unwrap_or_default(),
+        "git_branch" => ctx
+            .cache
+            .get("git_branch", || printable(&ctx.cwd.and_then(git_branch).unwrap_or_default())),
+        "cwd" => printable(&ctx.cwd.map(|cwd| cwd.display().to_string()).unwrap_or_default()),
+        "user" => printable(&env::var("USER").unwrap_or_default()),
+        "time" => chrono::Local::now().format("%H:%M:%S").to_string(),
+        "venv" => env_name("VIRTUAL_ENV"),
+        "conda_env" => env_name("CONDA_DEFAULT_ENV"),
+        _ => return None,
+    };
+    Some(value)
+}
+
+/// The last part of the path in `variable`, as set by activating a Python
+/// environment; empty when it isn't set
+fn env_name(variable: &str) -> String {
+    let Some(value) = env::var_os(variable).filter(|value| !value.is_empty()) else {
+        return String::new();
+    };
+    let path = PathBuf::from(value);
+    let name = path.file_name().unwrap_or(path.as_os_str());
+    printable(&name.to_string_lossy())
+}
+
+/// The directory the prompt shows: the current one, or when that can't be
+/// read, e.g. after it was deleted, the logical path `cd` keeps in `$PWD`
+pub fn working_dir() -> Option<PathBuf> {
+    choose_dir(env::current_dir(), env::var_os("PWD"))
+}
+
+fn choose_dir(current: io::Result<PathBuf>, pwd: Option<std::ffi::OsString>) -> Option<PathBuf> {
+    current.ok().or_else(|| pwd.filter(|pwd| !pwd.is_empty()).map(PathBuf::from))
+}
+
+/// Working directory as shown in the prompt
+pub fn display_dir(dir: &Path, style: config::PromptDir) -> String {
+    let dir = match style {
+        config::PromptDir::Full => dir.display().to_string(),
+        config::PromptDir::Name => match dir.file_name() {
+            Some(name) => name.to_string_lossy().into_owned(),
+            None => dir.display().to_string(),
+        },
+        config::PromptDir::Home => {
+            let home = env::var_os("HOME").map(PathBuf::from);
+            match home.as_deref().and_then(|home| dir.strip_prefix(home).ok()) {
+                Some(rest) if rest.as_os_str().is_empty() => "~".to_string(),
+                Some(rest) => format!("~/{}", rest.display()),
+                None => dir.display().to_string(),
+            }
+        }
+    };
+    printable(&dir)
+}
+
+/// `text` with control characters replaced by `?`, so a file name can't
+/// move the cursor or change colours
+fn printable(text: &str) -> String {
+    text.chars().map(|c| if c.is_control() { '?' } else { c }).collect()
+}
+
+/// Columns `text` takes on screen: wide characters such as emoji and CJK
+/// count twice, and escape sequences count for nothing. Text between `\x01`
+/// and `\x02` (readline's ignore markers) is skipped as well, for sequences
+/// this doesn't recognise.
+pub fn display_width(text: &str) -> usize {
+    let mut width = 0;
+    let mut chars = text.chars().peekable();
+    
+    while let Some(c) = chars.next() {
+        match c {
+            '\x01' => {
+                chars.by_ref().find(|&c| c == '\x02');
+            }
+            '\x1b' => match chars.next() {
+                // CSI: parameters, then a final byte in `@`..=`~`
+                Some('[') => {
+                    chars.by_ref().find(|c| ('@'..='~').contains(c));
+                }
+                // OSC, e.g. a window title: ended by BEL or ESC \
+                Some(']') => {
+                    while let Some(c) = chars.next() {
+                        if c == '\x07' || (c == '\x1b' && chars.next_if_eq(&'\\').is_some()) {
+                            break;
+                        }
+                    }
+                }
+                // Anything else is a two-character sequence
+                _ => {}
+            },
+            c => width += c.width().unwrap_or(0),
+        }
+    }
+    
+    width
+}
+
+/// Short human-readable duration: `850ms`, `4.2s`, `3m12s`
+pub fn format_duration(duration: Duration) -> String {
+    let millis = duration.as_millis();
+    if millis < 1000 {
+        format!("{}ms", millis)
+    } else if millis < 60_000 {
+        format!("{:.1}s", duration.as_secs_f64())
+    } else {
+        format!("{}m{}s", millis / 60_000, (millis % 60_000) / 1000)
+    }
+}
+
+/// Branch checked out in the repository containing `dir`, or the short
+/// commit hash for a detached HEAD
+pub fn git_branch(dir: &Path) -> Option<String> {
+    let git_dir = dir.ancestors().find_map(|ancestor| {
+        let dot_git = ancestor.join(".git");
+        if dot_git.is_dir() {
+            return Some(dot_git);
+        }
+        
+        // Worktrees and submodules use a `.git` file pointing at the real directory
+        let contents = fs::read_to_string(&dot_git).ok()?;
+        let target = contents.strip_prefix("gitdir:")?.trim();
+        Some(ancestor.join(target))
+    })?;
+    
+    let head = fs::read_to_string(git_dir.join("HEAD")).ok()?;
+    let head = head.trim();
+    match head.strip_prefix("ref: refs/heads/") {
+        Some(branch) => Some(branch.to_string()),
+        None => Some(head.chars().take(7).collect()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    fn render_with(format: &str, cwd: Option<&Path>, segments: &[(&str, &str)]) -> String {
+        let cache = PromptCache::new(Duration::ZERO);
+        let segments = segments.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect();
+        let ctx = PromptContext {
+            cwd,
+            last_status: 2,
+            last_duration: Some(Duration::from_millis(4200)),
+            cache: &cache,
+            segments: &segments,
+        };
+        render(format, &ctx)
+    }
+    
+    #[test]
+    fn a_deleted_directory_falls_back_to_pwd() {
+        let tmp = tempfile::tempdir().unwrap();
+        let deleted = tmp.path().join("gone");
+        let failed = || Err(io::Error::from(io::ErrorKind::NotFound));
+        
+        assert_eq!(choose_dir(failed(), Some(deleted.clone().into())), Some(deleted.clone()));
+        assert_eq!(choose_dir(failed(), Some("".into())), None);
+        assert_eq!(choose_dir(failed(), None), None);
+        assert_eq!(choose_dir(Ok(tmp.path().to_path_buf()), Some(deleted.into())), Some(tmp.path().to_path_buf()));
+        
+        // Nothing to show still renders
+        assert_eq!(render_with("[{cwd}] {git_branch}", None, &[]), "[] ");
+    }
+    
+    #[test]
+    fn directories_show_in_each_style() {
+        let dir = Path::new("/srv/data/logs");
+        assert_eq!(display_dir(dir, config::PromptDir::Full), "/srv/data/logs");
+        assert_eq!(display_dir(dir, config::PromptDir::Name), "logs");
+        assert_eq!(display_dir(Path::new("/"), config::PromptDir::Name), "/");
+        assert_eq!(display_dir(Path::new("/tmp/a\x1b[31m"), config::PromptDir::Full), "/tmp/a?[31m");
+        
+        if let Some(home) = env::var_os("HOME").map(PathBuf::from).filter(|home| home.has_root()) {
+            assert_eq!(display_dir(&home, config::PromptDir::Home), "~");
+            assert_eq!(display_dir(&home.join("src"), config::PromptDir::Home), "~/src");
+        }
+    }
+    
+    #[test]
+    fn placeholders_and_segments_expand() {
+        assert_eq!(render_with("{status} {duration} {nope} {", None, &[]), "2 4.2s {nope} {");
+        assert_eq!(render_with("{cwd}", Some(Path::new("/srv")), &[]), "/srv");
+        
+        // A segment drops out when a placeholder in it is empty
+        let segments = [("st", "exit {status}"), ("br", "on {git_branch}")];
+        assert_eq!(render_with("<{st}|{br}>", None, &segments), "<exit 2|>");
+    }
+}
@@ -0,0 +1,275 @@
+use super::*;
+use commands::CommandResult;
+use parser::{Redirect, TokenKind};
+use std::fs::OpenOptions;
+
+/// Where one of a command's output streams ends up
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Target {
+    /// The command's stdout: shown, or piped to the next stage
+    Stdout,
+    /// The command's stderr
+    Stderr,
+    /// A file, truncated first unless `append`
+    File { path: String, append: bool },
+}
+
+/// Output redirections of one command, applied left to right as in POSIX
+/// shells: `> out 2>&1` sends both streams to `out`, `2>&1 > out` only stdout.
+/// Whatever isn't sent to a file stays on the stream it ends up on, so a
+/// result's stderr is what `2>` and `&>` didn't take.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Redirections {
+    pub stdout: Target,
+    pub stderr: Target,
+}
+
+impl Default for Redirections {
+    fn default() -> Self {
+        Redirections {
+            stdout: Target::Stdout,
+            stderr: Target::Stderr,
+        }
+    }
+}
+
+/// Take the redirections out of `command`, returning the rest of it with its
+/// quoting intact.
+///
+/// Targets come from the quote-aware tokens, so `> "my file.txt"` is one path.
+pub fn parse(command: &str) -> Result<(String, Redirections), String> {
+    let mut rest = String::new();
+    let mut copied = 0;
+    let mut redirections = Redirections::default();
+    let mut tokens = parser::tokenize(command).into_iter();
+    
+    while let Some(token) = tokens.next() {
+        let TokenKind::Redirect(redirect) = token.kind else {
+            continue;
+        };
+        rest.push_str(&command[copied..token.span.start]);
+        copied = token.span.end;
+        
+        match redirect {
+            Redirect::StderrToStdout => {
+                redirections.stderr = redirections.stdout.clone();
+                continue;
+            }
+            Redirect::StdoutToStderr => {
+                redirections.stdout = redirections.stderr.clone();
+                continue;
+            }
+            _ => {}
+        }
+        
+        let operator = &command[token.span];
+        let path = match tokens.next() {
+            Some(target) => match target.kind {
+                TokenKind::Word(path) if !path.is_empty() => {
+                    copied = target.span.end;
+                    path
+                }
+                _ => return Err(format!("expected a file name after `{}`", operator)),
+            },
+            None => return Err(format!("expected a file name after `{}`", operator)),
+        };
+        
+        match redirect {
+            Redirect::Stdout { append } => redirections.stdout = Target::File { path, append },
+            Redirect::Stderr { append } => redirections.stderr = Target::File { path, append },
+            Redirect::Both { append } => {
+                redirections.stdout = Target::File { path, append };
+                redirections.stderr = redirections.stdout.clone();
+            }
+            Redirect::StderrToStdout | Redirect::StdoutToStderr => unreachable!(),
+        }
+    }
+    
+    rest.push_str(&command[copied..]);
+    Ok((rest.trim().to_string(), redirections))
+}
+
+/// Send `result`'s output where `redirections` say, writing any files;
+/// relative paths are taken from `dir` when there is one.
+///
+/// Streams sent to the same file are written to it once, stdout first.
+pub fn apply(result: &mut CommandResult, redirections: &Redirections, dir: Option<&Path>) {
+    if *redirections == Redirections::default() {
+        return;
+    }
+    
+    let stdout = std::mem::take(&mut result.stdout);
+    let stderr = std::mem::take(&mut result.stderr);
+    let mut files: Vec<(&str, bool, String)> = Vec::new();
+    
+    for (text, target) in [(stdout, &redirections.stdout), (stderr, &redirections.stderr)] {
+        match target {
+            Target::Stdout => result.stdout.push_str(&text),
+            Target::Stderr => result.stderr.push_str(&text),
+            Target::File { path, append } => match files.iter_mut().find(|(p, _, _)| p == path) {
+                Some((_, _, contents)) => contents.push_str(&text),
+                None => files.push((path, *append, text)),
+            },
+        }
+    }
+    
+    for (path, append, contents) in files {
+        let file = dir.map_or_else(|| PathBuf::from(path), |dir| dir.join(path));
+        if let Err(e) = write_file(&file, &contents, append) {
+            result.stderr.push_str(&format!("❌ {}: {}\n", path, e));
+            result.exit_code = 1;
+        }
+    }
+}
+
+/// Write `contents` to `path`, creating it, and truncating it unless `append`
+pub fn write_file(path: &Path, contents: &str, append: bool) -> io::Result<()> {
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .append(append)
+        .truncate(!append)
+        .open(path)?;
+    file.write_all(contents.as_bytes())?;
+    file.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    
+    fn file(path: &str, append: bool) -> Target {
+        Target::File {
+            path: path.to_string(),
+            append,
+        }
+    }
+    
+    fn parsed(command: &str) -> (String, Target, Target) {
+        let (rest, redirections) = parse(command).unwrap();
+        (rest, redirections.stdout, redirections.stderr)
+    }
+    
+    #[test]
+    fn quoted_targets_are_one_path() {
+        assert_eq!(parsed("echo hi > \"my file.txt\""), ("echo hi".to_string(), file("my file.txt", false), Target::Stderr));
+        assert_eq!(parsed("echo hi >'a b'"), ("echo hi".to_string(), file("a b", false), Target::Stderr));
+        assert_eq!(parsed("echo hi>out\\ put"), ("echo hi".to_string(), file("out put", false), Target::Stderr));
+        
+        // Quoted operators are arguments, and the rest keeps its quoting
+        assert_eq!(parsed("echo '>' \"a  b\""), ("echo '>' \"a  b\"".to_string(), Target::Stdout, Target::Stderr));
+    }
+    
+    #[test]
+    fn stderr_can_go_to_a_file_or_follow_stdout() {
+        assert_eq!(parsed("make 2> \"build errors.log\"").2, file("build errors.log", false));
+        assert_eq!(parsed("make 2>&1"), ("make".to_string(), Target::Stdout, Target::Stdout));
+        assert_eq!(parsed("make &> all.log"), ("make".to_string(), file("all.log", false), file("all.log", false)));
+        assert_eq!(parsed("make > out 2>&1"), ("make".to_string(), file("out", false), file("out", false)));
+        
+        // Applied left to right, so only stdout reaches the file
+        assert_eq!(parsed("make 2>&1 > out"), ("make".to_string(), file("out", false), Target::Stdout));
+    }
+    
+    #[test]
+    fn every_operator_parses() {
+        let cases = [
+            ("> out", file("out", false), Target::Stderr),
+            ("1> out", file("out", false), Target::Stderr),
+            (">> out", file("out", true), Target::Stderr),
+            ("1>>out", file("out", true), Target::Stderr),
+            ("2> err", Target::Stdout, file("err", false)),
+            ("2>> err", Target::Stdout, file("err", true)),
+            ("&> all", file("all", false), file("all", false)),
+            ("&>> all", file("all", true), file("all", true)),
+            ("2>&1", Target::Stdout, Target::Stdout),
+            (">&2", Target::Stderr, Target::Stderr),
+            ("1>&2", Target::Stderr, Target::Stderr),
+            ("2> err >&2", file("err", false), file("err", false)),
+            ("> a >> b", file("b", true), Target::Stderr),
+            (">> log 2>&1", file("log", true), file("log", true)),
+        ];
+        for (redirections, stdout, stderr) in cases {
+            let command = format!("make {}", redirections);
+            assert_eq!(parsed(&command), ("make".to_string(), stdout, stderr), "{}", command);
+        }
+        
+        // A stream number only counts right before the operator
+        assert_eq!(parsed("echo 2 > out").0, "echo 2");
+        assert_eq!(parsed("echo a2> out"), ("echo a2".to_string(), file("out", false), Target::Stderr));
+    }
+    
+    /// A result of `out` and `err` after `redirections` are applied, with
+    /// `{dir}` standing for `dir`
+    fn applied(dir: &Path, redirections: &str) -> CommandResult {
+        let redirections = redirections.replace("{dir}", &dir.display().to_string());
+        let (_, redirections) = parse(&format!("cmd {}", redirections)).unwrap();
+        let mut result = CommandResult {
+            stdout: "out\n".to_string(),
+            stderr: "err\n".to_string(),
+            ..Default::default()
+        };
+        apply(&mut result, &redirections, None);
+        result
+    }
+    
+    fn read(path: PathBuf) -> String {
+        fs::read_to_string(path).unwrap()
+    }
+    
+    #[test]
+    fn streams_are_written_to_files() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        
+        let result = applied(dir, "> {dir}/out 2> {dir}/err");
+        assert_eq!((result.stdout.as_str(), result.stderr.as_str(), result.exit_code), ("", "", 0));
+        assert_eq!((read(dir.join("out")), read(dir.join("err"))), ("out\n".to_string(), "err\n".to_string()));
+        
+        // Truncated, then appended to
+        applied(dir, "> {dir}/out");
+        applied(dir, ">> {dir}/out 2>> {dir}/err");
+        assert_eq!((read(dir.join("out")), read(dir.join("err"))), ("out\nout\n".to_string(), "err\nerr\n".to_string()));
+        
+        // The same file gets both streams once, stdout first
+        for redirections in ["&> {dir}/all", "> {dir}/all 2>&1", "2> {dir}/all >&2"] {
+            let result = applied(dir, redirections);
+            assert_eq!((result.stdout.as_str(), result.stderr.as_str()), ("", ""), "{}", redirections);
+            assert_eq!(read(dir.join("all")), "out\nerr\n", "{}", redirections);
+        }
+        applied(dir, "&>> {dir}/all");
+        assert_eq!(read(dir.join("all")), "out\nerr\nout\nerr\n");
+    }
+    
+    #[test]
+    fn streams_can_swap_without_files() {
+        let tmp = tempfile::tempdir().unwrap();
+        let result = applied(tmp.path(), "2>&1");
+        assert_eq!((result.stdout.as_str(), result.stderr.as_str()), ("out\nerr\n", ""));
+        let result = applied(tmp.path(), ">&2");
+        assert_eq!((result.stdout.as_str(), result.stderr.as_str()), ("", "out\nerr\n"));
+        
+        // Only the stream sent to the file leaves the result
+        let result = applied(tmp.path(), "2>&1 > {dir}/out");
+        assert_eq!((result.stdout.as_str(), result.stderr.as_str()), ("err\n", ""));
+        assert_eq!(read(tmp.path().join("out")), "out\n");
+    }
+    
+    #[test]
+    fn files_that_cant_be_written_fail_the_command() {
+        let tmp = tempfile::tempdir().unwrap();
+        let result = applied(tmp.path(), "> {dir}/missing/out");
+        assert_eq!(result.exit_code, 1);
+        assert!(result.stderr.starts_with("err\n❌ "), "{}", result.stderr);
+        assert!(result.stderr.contains("/missing/out: "), "{}", result.stderr);
+        assert_eq!(result.stdout, "");
+    }
+    
+    #[test]
+    fn a_target_is_required() {
+        assert_eq!(parse("echo hi >").unwrap_err(), "expected a file name after `>`");
+        assert_eq!(parse("echo hi 2> ''").unwrap_err(), "expected a file name after `2>`");
+        assert_eq!(parse("echo hi &> > x").unwrap_err(), "expected a file name after `&>`");
+    }
+}
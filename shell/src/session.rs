@@ -0,0 +1,69 @@
+use super::*;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader};
+
+/// One evaluated line of a recorded session; `timestamp` is when it was entered
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SessionEntry {
+    pub timestamp: DateTime<Utc>,
+    pub input: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub interpreted: Option<String>,
+    pub stdout: String,
+    pub stderr: String,
+    pub exit_code: i32,
+    pub duration_ms: u128,
+}
+
+/// Appends every evaluated line to a JSONL session file
+pub struct SessionRecorder {
+    file: File,
+    redactor: Redactor,
+}
+
+impl SessionRecorder {
+    pub fn create(path: &Path, redactor: Redactor) -> Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("Failed to open session file {}", path.display()))?;
+        Ok(SessionRecorder { file, redactor })
+    }
+    
+    pub fn record(&mut self, input: &str, result: &CommandResult) -> Result<()> {
+        // Timestamp the moment the input was entered, not when it finished
+        let started = Utc::now() - chrono::Duration::from_std(result.duration).unwrap_or_default();
+        let entry = SessionEntry {
+            timestamp: started,
+            input: self.redactor.redact(input).into_owned(),
+            interpreted: result.interpreted.as_deref().map(|command| self.redactor.redact(command).into_owned()),
+            stdout: self.redactor.redact(&result.all_stdout()).into_owned(),
+            stderr: self.redactor.redact(&result.all_stderr()).into_owned(),
+            exit_code: result.exit_code,
+            duration_ms: result.duration.as_millis(),
+        };
+        
+        writeln!(self.file, "{}", serde_json::to_string(&entry)?)?;
+        self.file.flush()?;
+        Ok(())
+    }
+}
+
+/// Read a session recorded with `--record`
+pub fn load(path: &Path) -> Result<Vec<SessionEntry>> {
+    let file = File::open(path)
+        .with_context(|| format!("Failed to open session file {}", path.display()))?;
+    
+    BufReader::new(file)
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| line.as_ref().map_or(true, |line| !line.trim().is_empty()))
+        .map(|(i, line)| {
+            serde_json::from_str(&line?)
+                .with_context(|| format!("Invalid session entry at {}:{}", path.display(), i + 1))
+        })
+        .collect()
+}
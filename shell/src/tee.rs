@@ -0,0 +1,256 @@
+//! Output of commands shown as they run. Each chunk a child writes is passed
+//! straight through to the terminal, and only the start and end of the
+//! stream are kept, so a command printing gigabytes still runs in bounded
+//! memory while `$_OUT`, session recordings and the AI see how it began and
+//! how it ended. What reaches the terminal is converted to UTF-8 as it goes,
+//! as captured output is.
+
+use super::*;
+use encoding_rs::{CoderResult, Decoder, Encoding};
+use std::collections::VecDeque;
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::task::JoinHandle;
+
+/// Size of the reads passed through to the terminal
+const CHUNK: usize = 8 * 1024;
+
+/// The first and last bytes of a stream, at most `limit` of them in all:
+/// half from the start, the rest from the end
+pub struct HeadTail {
+    limit: usize,
+    head: Vec<u8>,
+    tail: VecDeque<u8>,
+    /// Bytes between `head` and `tail` that weren't kept
+    dropped: u64,
+}
+
+impl HeadTail {
+    pub fn new(limit: usize) -> Self {
+        HeadTail {
+            limit,
+            head: Vec::new(),
+            tail: VecDeque::new(),
+            dropped: 0,
+        }
+    }
+    
+    pub fn push(&mut self, mut bytes: &[u8]) {
+        let head_limit = self.limit / 2;
+        let take = head_limit.saturating_sub(self.head.len()).min(bytes.len());
+        self.head.extend_from_slice(&bytes[..take]);
+        bytes = &bytes[take..];
+        
+        let capacity = self.limit - head_limit;
+        if bytes.len() >= capacity {
+            self.dropped += (self.tail.len() + bytes.len() - capacity) as u64;
+            self.tail.clear();
+            self.tail.extend(&bytes[bytes.len() - capacity..]);
+        } else {
+            let overflow = (self.tail.len() + bytes.len()).saturating_sub(capacity);
+            self.tail.drain(..overflow);
+            self.dropped += overflow as u64;
+            self.tail.extend(bytes);
+        }
+    }
+    
+    /// What was kept, with a line in place of what wasn't. The cuts are
+    /// moved to character boundaries, so UTF-8 text stays valid.
+    pub fn into_bytes(self) -> Vec<u8> {
+        let mut bytes = self.head;
+        let mut tail = Vec::from(self.tail);
+        let mut dropped = self.dropped;
+        
+        if dropped > 0 {
+            if let Err(e) = std::str::from_utf8(&bytes) {
+                if e.error_len().is_none() {
+                    dropped += (bytes.len() - e.valid_up_to()) as u64;
+                    bytes.truncate(e.valid_up_to());
+                }
+            }
+            let continuation = tail.iter().take(3).take_while(|&&byte| byte & 0xC0 == 0x80).count();
+            tail.drain(..continuation);
+            dropped += continuation as u64;
+            
+            if !bytes.is_empty() && !bytes.ends_with(b"\n") {
+                bytes.push(b'\n');
+            }
+            bytes.extend_from_slice(format!("… {} bytes not kept …\n", dropped).as_bytes());
+        }
+        bytes.extend(tail);
+        bytes
+    }
+}
+
+/// A stream's bytes as UTF-8 text, chunk by chunk: passed on as they are
+/// while they are UTF-8, and converted from `encoding` from the first bytes
+/// that aren't
+pub struct ToUtf8 {
+    encoding: &'static Encoding,
+    /// The start of a character split between chunks
+    pending: Vec<u8>,
+    decoder: Option<Decoder>,
+}
+
+impl ToUtf8 {
+    pub fn new(encoding: &'static Encoding) -> Self {
+        ToUtf8 {
+            encoding,
+            pending: Vec::new(),
+            decoder: None,
+        }
+    }
+    
+    /// The text of `chunk`; with `last`, of whatever is still pending too
+    pub fn convert(&mut self, chunk: &[u8], last: bool) -> String {
+        let mut text = String::new();
+        if self.decoder.is_none() {
+            self.pending.extend_from_slice(chunk);
+            let valid = match std::str::from_utf8(&self.pending) {
+                Ok(_) => self.pending.len(),
+                Err(e) if e.error_len().is_none() && !last => e.valid_up_to(),
+                Err(e) => {
+                    self.decoder = Some(self.encoding.new_decoder_without_bom_handling());
+                    e.valid_up_to()
+                }
+            };
+            let rest = self.pending.split_off(valid);
+            text.push_str(std::str::from_utf8(&self.pending).expect("checked above"));
+            self.pending = rest;
+            if self.decoder.is_none() {
+                return text;
+            }
+        }
+        
+        let pending = std::mem::take(&mut self.pending);
+        let mut input = if pending.is_empty() { chunk } else { &pending[..] };
+        let decoder = self.decoder.as_mut().expect("set above");
+        loop {
+            text.reserve(decoder.max_utf8_buffer_length(input.len()).unwrap_or(input.len() * 3 + 16));
+            let (result, read, _) = decoder.decode_to_string(input, &mut text, last);
+            input = &input[read..];
+            if result == CoderResult::InputEmpty {
+                return text;
+            }
+        }
+    }
+}
+
+/// Copy `stream` to `terminal` as UTF-8 text as it arrives, in the
+/// background, keeping a `HeadTail` of `limit` bytes of it as they were. A
+/// terminal that can't be written to any more doesn't stop the copy, so the
+/// command isn't left blocked on a full pipe.
+pub fn tee(
+    stream: Option<impl AsyncRead + Unpin + Send + 'static>,
+    mut terminal: impl Write + Send + 'static,
+    limit: usize,
+    encoding: &'static Encoding,
+) -> JoinHandle<Vec<u8>> {
+    tokio::spawn(async move {
+        let mut kept = HeadTail::new(limit);
+        let Some(mut stream) = stream else {
+            return Vec::new();
+        };
+        
+        let mut text = ToUtf8::new(encoding);
+        let mut chunk = vec![0; CHUNK];
+        let mut open = true;
+        loop {
+            let n = stream.read(&mut chunk).await.unwrap_or(0);
+            if open {
+                let text = text.convert(&chunk[..n], n == 0);
+                open = terminal.write_all(text.as_bytes()).and_then(|()| terminal.flush()).is_ok();
+            }
+            if n == 0 {
+                break;
+            }
+            kept.push(&chunk[..n]);
+        }
+        kept.into_bytes()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::io::Cursor;
+    
+    fn kept(limit: usize, chunks: &[&[u8]]) -> Vec<u8> {
+        let mut kept = HeadTail::new(limit);
+        for chunk in chunks {
+            kept.push(chunk);
+        }
+        kept.into_bytes()
+    }
+    
+    #[test]
+    fn short_streams_are_kept_whole() {
+        assert_eq!(kept(10, &[b"0123", b"456", b"789"]), b"0123456789");
+        assert_eq!(kept(10, &[]), b"");
+        assert_eq!(kept(0, &[]), b"");
+    }
+    
+    #[test]
+    fn long_streams_keep_their_start_and_end() {
+        let expected = "01234\n… 10 bytes not kept …\nfghij".as_bytes().to_vec();
+        assert_eq!(kept(10, &[b"0123456789abcdefghij"]), expected);
+        assert_eq!(kept(10, &[b"01", b"23456", b"789abcd", b"efghij"]), expected);
+        assert_eq!(kept(8, &[b"a\nb\nc\nd\ne\nf\n"]), "a\nb\n… 4 bytes not kept …\ne\nf\n".as_bytes());
+        
+        let bytes: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8).collect();
+        let kept = kept(1000, &bytes.chunks(777).collect::<Vec<_>>());
+        assert!(kept.starts_with(&bytes[..500]));
+        assert!(kept.ends_with(&bytes[bytes.len() - 500..]));
+        assert!(kept.len() < 1100);
+    }
+    
+    #[test]
+    fn cuts_fall_between_characters() {
+        let text = "ééééé".repeat(4);
+        let kept = String::from_utf8(kept(9, &[text.as_bytes()])).unwrap();
+        assert_eq!(kept, "éé\n… 32 bytes not kept …\néé");
+    }
+    
+    #[tokio::test]
+    async fn everything_reaches_the_terminal() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("terminal");
+        let bytes: Vec<u8> = (0..200_000u32).map(|i| (i % 97) as u8).collect();
+        
+        let kept = tee(Some(Cursor::new(bytes.clone())), fs::File::create(&path).unwrap(), 100, encoding_rs::UTF_8).await.unwrap();
+        assert_eq!(fs::read(&path).unwrap(), bytes);
+        assert!(kept.starts_with(&bytes[..50]) && kept.ends_with(&bytes[bytes.len() - 50..]));
+        
+        let kept = tee(None::<Cursor<Vec<u8>>>, fs::File::create(&path).unwrap(), 100, encoding_rs::UTF_8).await.unwrap();
+        assert!(kept.is_empty());
+    }
+    
+    #[tokio::test]
+    async fn the_terminal_is_given_utf8() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("terminal");
+        let latin1 = b"caf\xe9 na\xefve\n".repeat(5000);
+        
+        let kept = tee(Some(Cursor::new(latin1.clone())), fs::File::create(&path).unwrap(), 100, encoding_rs::WINDOWS_1252).await.unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "café naïve\n".repeat(5000));
+        assert!(latin1.starts_with(&kept[..50]));
+    }
+    
+    #[test]
+    fn utf8_is_passed_on_as_it_is() {
+        // A character split between chunks is held until it is whole
+        let mut text = ToUtf8::new(encoding_rs::WINDOWS_1252);
+        let bytes = "née".as_bytes();
+        assert_eq!(text.convert(&bytes[..2], false), "n");
+        assert_eq!(text.convert(&bytes[2..], false), "ée");
+        assert_eq!(text.convert(b"", true), "");
+        
+        // Bytes that aren't UTF-8 switch to the encoding from there on
+        assert_eq!(text.convert(b"caf\xe9 ", false), "café ");
+        assert_eq!(text.convert(b"\xc3\xa9", true), "Ã©");
+        
+        let mut text = ToUtf8::new(encoding_rs::WINDOWS_1252);
+        assert_eq!(text.convert(b"ok\xc3", false), "ok");
+        assert_eq!(text.convert(b"", true), "Ã");
+    }
+}
@@ -0,0 +1,274 @@
+use super::*;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+
+/// A trash directory laid out like the freedesktop.org one, so desktop file
+/// managers can see and restore what the shell put there: each item is moved
+/// to `files/` and described by `info/<name>.trashinfo`.
+pub struct Trash {
+    dir: PathBuf,
+}
+
+/// One trashed item
+pub struct Entry {
+    /// File name under `files/`
+    pub name: String,
+    pub original: PathBuf,
+    /// When it was trashed, as `YYYY-MM-DDThh:mm:ss`
+    pub deleted: String,
+}
+
+impl Trash {
+    /// `dir`, or the user's trash: `$XDG_DATA_HOME/Trash`, by default
+    /// `~/.local/share/Trash`
+    pub fn new(dir: Option<&Path>) -> Self {
+        let dir = match dir {
+            Some(dir) => dir.to_path_buf(),
+            None => match env::var_os("XDG_DATA_HOME").filter(|dir| !dir.is_empty()) {
+                Some(data) => PathBuf::from(data).join("Trash"),
+                None => PathBuf::from(env::var_os("HOME").unwrap_or_default()).join(".local/share/Trash"),
+            },
+        };
+        Trash { dir }
+    }
+    
+    /// Move `path` into the trash, returning its name there
+    pub fn put(&self, path: &Path) -> io::Result<String> {
+        let original = std::path::absolute(path)?;
+        let file_name = original
+            .file_name()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "cannot trash this path"))?
+            .to_string_lossy()
+            .into_owned();
+        
+        fs::create_dir_all(self.files())?;
+        fs::create_dir_all(self.info())?;
+        
+        // Claiming the info file first reserves the name against other shells.
+        // A name still taken under `files/`, e.g. by an item whose info file
+        // was lost, is skipped so that item isn't replaced.
+        let (name, mut info) = (1..)
+            .map(|n| if n == 1 { file_name.clone() } else { format!("{}.{}", file_name, n) })
+            .find_map(|name| {
+                if fs::symlink_metadata(self.files().join(&name)).is_ok() {
+                    return None;
+                }
+                let info = OpenOptions::new()
+                    .write(true)
+                    .create_new(true)
+                    .open(self.info_path(&name));
+                match info {
+                    Err(e) if e.kind() == io::ErrorKind::AlreadyExists => None,
+                    info => Some(info.map(|info| (name, info))),
+                }
+            })
+            .expect("unbounded range")?;
+        
+        let written = write!(
+            info,
+            "[Trash Info]\nPath={}\nDeletionDate={}\n",
+            percent_encode(&original.to_string_lossy()),
+            chrono::Local::now().format("%Y-%m-%dT%H:%M:%S")
+        );
+        if let Err(e) = written.and_then(|()| move_path(&original, &self.files().join(&name))) {
+            let _ = fs::remove_file(self.info_path(&name));
+            return Err(e);
+        }
+        Ok(name)
+    }
+    
+    /// Everything in the trash, oldest first
+    pub fn list(&self) -> io::Result<Vec<Entry>> {
+        let dir = match fs::read_dir(self.info()) {
+            Ok(dir) => dir,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+        
+        let mut entries = Vec::new();
+        for file in dir {
+            let file = file?;
+            let path = file.path();
+            let Some(name) = path.file_name().and_then(|name| name.to_str()?.strip_suffix(".trashinfo")) else {
+                continue;
+            };
+            let Ok(contents) = fs::read_to_string(&path) else {
+                continue;
+            };
+            
+            let field = |key: &str| {
+                contents
+                    .lines()
+                    .find_map(|line| line.strip_prefix(key)?.strip_prefix('='))
+                    .map(str::to_string)
+            };
+            if let Some(original) = field("Path") {
+                // Dates only go down to the second; the info file's
+                // modification time orders items trashed within one
+                let written = file.metadata().and_then(|metadata| metadata.modified()).ok();
+                let entry = Entry {
+                    name: name.to_string(),
+                    original: PathBuf::from(percent_decode(&original)),
+                    deleted: field("DeletionDate").unwrap_or_default(),
+                };
+                entries.push((entry, written));
+            }
+        }
+        
+        entries.sort_by(|(a, a_written), (b, b_written)| (&a.deleted, a_written).cmp(&(&b.deleted, b_written)));
+        Ok(entries.into_iter().map(|(entry, _)| entry).collect())
+    }
+    
+    /// Move `entry` back to where it came from, unless something is there now
+    pub fn restore(&self, entry: &Entry) -> io::Result<()> {
+        if fs::symlink_metadata(&entry.original).is_ok() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} already exists", entry.original.display()),
+            ));
+        }
+        if let Some(parent) = entry.original.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        
+        move_path(&self.files().join(&entry.name), &entry.original)?;
+        fs::remove_file(self.info_path(&entry.name))
+    }
+    
+    /// Permanently delete everything in the trash, returning how many items
+    /// there were
+    pub fn empty(&self) -> io::Result<usize> {
+        let entries = self.list()?;
+        for entry in &entries {
+            let path = self.files().join(&entry.name);
+            match fs::symlink_metadata(&path) {
+                Ok(metadata) if metadata.is_dir() => fs::remove_dir_all(&path)?,
+                Ok(_) => fs::remove_file(&path)?,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e),
+            }
+            fs::remove_file(self.info_path(&entry.name))?;
+        }
+        Ok(entries.len())
+    }
+    
+    fn files(&self) -> PathBuf {
+        self.dir.join("files")
+    }
+    
+    fn info(&self) -> PathBuf {
+        self.dir.join("info")
+    }
+    
+    fn info_path(&self, name: &str) -> PathBuf {
+        self.info().join(format!("{}.trashinfo", name))
+    }
+}
+
+/// Rename `from` to `to`, copying and then deleting it when they are on
+/// different filesystems
+fn move_path(from: &Path, to: &Path) -> io::Result<()> {
+    match fs::rename(from, to) {
+        Err(e) if e.kind() == io::ErrorKind::CrossesDevices => {
+            copy_recursive(from, to)?;
+            if fs::symlink_metadata(from)?.is_dir() {
+                fs::remove_dir_all(from)
+            } else {
+                fs::remove_file(from)
+            }
+        }
+        result => result,
+    }
+}
+
+fn copy_recursive(from: &Path, to: &Path) -> io::Result<()> {
+    let metadata = fs::symlink_metadata(from)?;
+    if metadata.is_symlink() {
+        #[cfg(unix)]
+        return std::os::unix::fs::symlink(fs::read_link(from)?, to);
+    }
+    if !metadata.is_dir() {
+        return fs::copy(from, to).map(|_| ());
+    }
+    
+    fs::create_dir(to)?;
+    for entry in fs::read_dir(from)? {
+        let entry = entry?;
+        copy_recursive(&entry.path(), &to.join(entry.file_name()))?;
+    }
+    fs::set_permissions(to, metadata.permissions())
+}
+
+/// Escape a path for a `.trashinfo` file, as a URL path would be
+fn percent_encode(path: &str) -> String {
+    let mut out = String::with_capacity(path.len());
+    for byte in path.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => out.push(byte as char),
+            _ => out.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    out
+}
+
+fn percent_decode(path: &str) -> String {
+    let bytes = path.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes.get(i + 1..i + 3).and_then(|hex| u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(byte)) => {
+                out.push(byte);
+                i += 3;
+            }
+            (byte, _) => {
+                out.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn items_are_put_back_where_they_were() {
+        let tmp = tempfile::tempdir().unwrap();
+        let trash = Trash::new(Some(&tmp.path().join("trash")));
+        let file = tmp.path().join("notes 100%.txt");
+        fs::write(&file, "notes").unwrap();
+        
+        assert_eq!(trash.put(&file).unwrap(), "notes 100%.txt");
+        assert!(!file.exists());
+        let entries = trash.list().unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].original, file);
+        
+        trash.restore(&entries[0]).unwrap();
+        assert_eq!(fs::read_to_string(&file).unwrap(), "notes");
+        assert!(trash.list().unwrap().is_empty());
+    }
+    
+    #[test]
+    fn names_in_use_are_skipped() {
+        let tmp = tempfile::tempdir().unwrap();
+        let trash = Trash::new(Some(&tmp.path().join("trash")));
+        let file = tmp.path().join("a");
+        
+        fs::write(&file, "first").unwrap();
+        assert_eq!(trash.put(&file).unwrap(), "a");
+        fs::write(&file, "second").unwrap();
+        assert_eq!(trash.put(&file).unwrap(), "a.2");
+        
+        // An item left under files/ without its info file isn't replaced
+        fs::write(trash.files().join("a.3"), "orphan").unwrap();
+        fs::write(&file, "third").unwrap();
+        assert_eq!(trash.put(&file).unwrap(), "a.4");
+        assert_eq!(fs::read_to_string(trash.files().join("a.3")).unwrap(), "orphan");
+        assert_eq!(fs::read_to_string(trash.files().join("a")).unwrap(), "first");
+    }
+}
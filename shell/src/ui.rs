@@ -0,0 +1,83 @@
+//! Whether the shell colours what it prints, decided in one place from
+//! `--color`, the `color` setting, `NO_COLOR` and whether the stream is a
+//! terminal. Everything that emits colour asks here.
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::io::{self, IsTerminal};
+use std::sync::atomic::{AtomicU8, Ordering};
+
+/// When output is coloured
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, JsonSchema, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum ColorChoice {
+    /// Always, even into pipes and files; programs run from the shell are
+    /// asked to colour too
+    Always,
+    /// Only on a terminal, and not when `NO_COLOR` is set
+    #[default]
+    Auto,
+    /// Never; programs run from the shell are given `NO_COLOR=1`
+    Never,
+}
+
+/// `color` from the config file
+static CONFIGURED: AtomicU8 = AtomicU8::new(ColorChoice::Auto as u8);
+/// `--color`, which wins over the config; `UNSET` when not given
+static OVERRIDE: AtomicU8 = AtomicU8::new(UNSET);
+const UNSET: u8 = u8::MAX;
+
+impl ColorChoice {
+    fn from_u8(value: u8) -> Option<Self> {
+        [ColorChoice::Always, ColorChoice::Auto, ColorChoice::Never]
+            .into_iter()
+            .find(|choice| *choice as u8 == value)
+    }
+}
+
+/// Apply `--color` for the rest of the run
+pub fn set_override(choice: Option<ColorChoice>) {
+    OVERRIDE.store(choice.map_or(UNSET, |choice| choice as u8), Ordering::Relaxed);
+}
+
+/// Apply the config file's `color`
+pub fn set_configured(choice: ColorChoice) {
+    CONFIGURED.store(choice as u8, Ordering::Relaxed);
+}
+
+pub fn choice() -> ColorChoice {
+    ColorChoice::from_u8(OVERRIDE.load(Ordering::Relaxed))
+        .or_else(|| ColorChoice::from_u8(CONFIGURED.load(Ordering::Relaxed)))
+        .unwrap_or_default()
+}
+
+/// Whether to colour what is written to stdout
+pub fn stdout_color() -> bool {
+    enabled(io::stdout().is_terminal())
+}
+
+/// Whether to colour what is written to stderr
+pub fn stderr_color() -> bool {
+    enabled(io::stderr().is_terminal())
+}
+
+fn enabled(terminal: bool) -> bool {
+    match choice() {
+        ColorChoice::Always => true,
+        ColorChoice::Never => false,
+        // https://no-color.org: set to anything but the empty string
+        ColorChoice::Auto => terminal && env::var_os("NO_COLOR").is_none_or(|value| value.is_empty()),
+    }
+}
+
+/// Variables that pass the choice on to programs the shell runs. Their
+/// output is captured rather than going to the terminal, so `auto` leaves
+/// them to decide.
+pub fn child_env() -> &'static [(&'static str, &'static str)] {
+    match choice() {
+        ColorChoice::Always => &[("CLICOLOR_FORCE", "1"), ("FORCE_COLOR", "1")],
+        ColorChoice::Auto => &[],
+        ColorChoice::Never => &[("NO_COLOR", "1")],
+    }
+}
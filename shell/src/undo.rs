@@ -0,0 +1,492 @@
+//! Best-effort undo for AI commands that change files. Only a few commands
+//! are understood well enough to reverse; anything else that changes files
+//! is recorded as something `undo` can't take back.
+
+use super::*;
+use commands::CommandResult;
+use redirect::Target;
+use std::fs;
+
+/// How many AI commands `undo` remembers
+const MAX_ENTRIES: usize = 20;
+
+/// Commands known to change files in ways the shell can't reverse
+const IRREVERSIBLE: &[(&str, &str)] = &[
+    ("rm", "it deletes files"),
+    ("rmdir", "it deletes directories"),
+    ("shred", "it destroys file contents"),
+    ("cp", "it may overwrite files"),
+    ("install", "it may overwrite files"),
+    ("rsync", "it may overwrite or delete files"),
+    ("ln", "it may replace files"),
+    ("dd", "it may overwrite files"),
+    ("truncate", "it discards file contents"),
+    ("chmod", "the old permissions aren't recorded"),
+    ("chown", "the old owners aren't recorded"),
+    ("chgrp", "the old groups aren't recorded"),
+    ("tar", "it may overwrite files"),
+    ("unzip", "it may overwrite files"),
+];
+
+/// A change the shell knows how to reverse
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Change {
+    /// Directories `mkdir` created, parents before children
+    CreatedDirs(Vec<PathBuf>),
+    /// Empty files `touch` created, and existing files it only touched
+    CreatedFiles { created: Vec<PathBuf>, touched: Vec<PathBuf> },
+    /// Paths `mv` moved, as `(from, to)`
+    Moved(Vec<(PathBuf, PathBuf)>),
+}
+
+/// What a command will change, worked out before it runs
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Plan {
+    /// Not a command known to change files
+    Unknown,
+    /// Changes files in a way `undo` can't reverse, and why
+    Irreversible(String),
+    /// Paths the command may create or move; only those that actually
+    /// changed once it has run are recorded
+    Reversible(Change),
+}
+
+/// An AI command that changed files
+struct Entry {
+    command: String,
+    /// What to reverse, or why it can't be
+    change: Result<Change, String>,
+}
+
+/// AI commands that changed files, most recent last
+#[derive(Default)]
+pub struct UndoStack {
+    entries: Vec<Entry>,
+}
+
+impl Plan {
+    /// Work out what `command`, a single command of a chain, will change.
+    /// Relative paths are resolved against `dir`, where it runs.
+    pub fn new(command: &str, dir: &Path, remote: bool) -> Plan {
+        let stages = parser::split_pipeline(command);
+        let mut plans = Vec::new();
+        for stage in &stages {
+            let Ok((stage, redirections)) = redirect::parse(stage) else {
+                return Plan::Unknown;
+            };
+            let writes_file = [&redirections.stdout, &redirections.stderr]
+                .into_iter()
+                .any(|target| matches!(target, Target::File { .. }));
+            if writes_file {
+                return Plan::Irreversible("its output is redirected into a file".to_string());
+            }
+            plans.push(plan_simple(&stage, dir));
+        }
+        
+        let remote = remote || command.starts_with('@');
+        match plans.into_iter().find(|plan| *plan != Plan::Unknown) {
+            None => Plan::Unknown,
+            Some(Plan::Reversible(_)) if remote => Plan::Irreversible("it ran on another host".to_string()),
+            Some(Plan::Reversible(_)) if stages.len() > 1 => {
+                Plan::Irreversible("it is part of a pipeline".to_string())
+            }
+            Some(plan) => plan,
+        }
+    }
+}
+
+fn plan_simple(command: &str, dir: &Path) -> Plan {
+    let mut words = parser::split_words(command);
+    // `@host command` runs the rest
+    if words.first().is_some_and(|word| word.starts_with('@')) {
+        words.remove(0);
+    }
+    let parts: Vec<&str> = words.iter().map(String::as_str).collect();
+    let (_, parts) = commands::split_env_assignments(&parts);
+    let Some((&program, args)) = parts.split_first() else {
+        return Plan::Unknown;
+    };
+    
+    let name = program.rsplit('/').next().unwrap_or(program);
+    let resolve = |arg: &str| dir.join(arg);
+    match name {
+        "mkdir" => plan_mkdir(args, resolve),
+        "touch" => plan_touch(args, resolve),
+        "mv" => plan_mv(args, resolve),
+        "sudo" | "doas" => Plan::Irreversible(format!("it runs with {}", name)),
+        _ => match IRREVERSIBLE.iter().find(|(irreversible, _)| *irreversible == name) {
+            Some((_, reason)) => Plan::Irreversible(reason.to_string()),
+            None => Plan::Unknown,
+        },
+    }
+}
+
+/// Split `args` into options and operands, stopping options at `--`.
+/// `with_value` lists the options that take the next argument.
+fn split_options<'a>(args: &[&'a str], with_value: &[&str]) -> (Vec<&'a str>, Vec<&'a str>) {
+    let (mut options, mut operands) = (Vec::new(), Vec::new());
+    let mut args = args.iter();
+    while let Some(&arg) = args.next() {
+        if arg == "--" {
+            operands.extend(args.by_ref());
+        } else if arg.starts_with('-') && arg != "-" {
+            options.push(arg);
+            if with_value.contains(&arg) {
+                args.next();
+            }
+        } else {
+            operands.push(arg);
+        }
+    }
+    (options, operands)
+}
+
+/// The first of `options` not in `known`, as a reason the command can't be undone
+fn unknown_option(options: &[&str], known: &[&str]) -> Option<Plan> {
+    let unknown = options.iter().find(|option| {
+        !known.iter().any(|known| option == &known || option.starts_with(&format!("{}=", known)))
+    })?;
+    Some(Plan::Irreversible(format!("undo doesn't know what '{}' does", unknown)))
+}
+
+fn plan_mkdir(args: &[&str], resolve: impl Fn(&str) -> PathBuf) -> Plan {
+    let (options, operands) = split_options(args, &["-m"]);
+    if let Some(plan) = unknown_option(&options, &["-p", "--parents", "-v", "--verbose", "-m", "--mode"]) {
+        return plan;
+    }
+    let parents = options.iter().any(|option| matches!(*option, "-p" | "--parents"));
+    
+    let mut dirs = Vec::new();
+    for operand in operands {
+        let dir = resolve(operand);
+        let missing: Vec<PathBuf> = if parents {
+            dir.ancestors()
+                .take_while(|path| fs::symlink_metadata(path).is_err())
+                .map(Path::to_path_buf)
+                .collect()
+        } else {
+            fs::symlink_metadata(&dir).is_err().then_some(dir).into_iter().collect()
+        };
+        for path in missing.into_iter().rev() {
+            if !dirs.contains(&path) {
+                dirs.push(path);
+            }
+        }
+    }
+    Plan::Reversible(Change::CreatedDirs(dirs))
+}
+
+fn plan_touch(args: &[&str], resolve: impl Fn(&str) -> PathBuf) -> Plan {
+    let (options, operands) = split_options(args, &["-d", "-r", "-t"]);
+    let known = ["-a", "-m", "-c", "--no-create", "-d", "--date", "-r", "--reference", "-t"];
+    if let Some(plan) = unknown_option(&options, &known) {
+        return plan;
+    }
+    
+    let (mut created, mut touched) = (Vec::new(), Vec::new());
+    for operand in operands {
+        let file = resolve(operand);
+        if fs::symlink_metadata(&file).is_err() {
+            created.push(file);
+        } else {
+            touched.push(file);
+        }
+    }
+    Plan::Reversible(Change::CreatedFiles { created, touched })
+}
+
+fn plan_mv(args: &[&str], resolve: impl Fn(&str) -> PathBuf) -> Plan {
+    let (options, operands) = split_options(args, &[]);
+    let known = ["-v", "--verbose", "-n", "--no-clobber", "-f", "--force", "-i", "--interactive"];
+    if let Some(plan) = unknown_option(&options, &known) {
+        return plan;
+    }
+    let no_clobber = options.iter().any(|option| matches!(*option, "-n" | "--no-clobber"));
+    
+    let Some((target, sources)) = operands.split_last() else {
+        return Plan::Unknown;
+    };
+    let target = resolve(target);
+    let into_dir = target.is_dir();
+    if !into_dir && sources.len() > 1 {
+        // mv refuses this
+        return Plan::Unknown;
+    }
+    
+    let mut moves = Vec::new();
+    for source in sources {
+        let from = resolve(source);
+        let to = match from.file_name() {
+            Some(name) if into_dir => target.join(name),
+            _ => target.clone(),
+        };
+        if fs::symlink_metadata(&to).is_ok() {
+            if no_clobber {
+                continue;
+            }
+            return Plan::Irreversible(format!("it replaces '{}'", to.display()));
+        }
+        moves.push((from, to));
+    }
+    Plan::Reversible(Change::Moved(moves))
+}
+
+impl Change {
+    /// The part of what was planned that actually happened, checked against
+    /// the filesystem after the command ran
+    fn confirm(self) -> Option<Change> {
+        let exists = |path: &Path| fs::symlink_metadata(path).is_ok();
+        let change = match self {
+            Change::CreatedDirs(dirs) => {
+                Change::CreatedDirs(dirs.into_iter().filter(|dir| dir.is_dir()).collect())
+            }
+            Change::CreatedFiles { created, touched } => Change::CreatedFiles {
+                created: created.into_iter().filter(|file| file.is_file()).collect(),
+                touched,
+            },
+            Change::Moved(moves) => {
+                Change::Moved(moves.into_iter().filter(|(from, to)| !exists(from) && exists(to)).collect())
+            }
+        };
+        match &change {
+            Change::CreatedDirs(paths) | Change::CreatedFiles { created: paths, .. } if paths.is_empty() => None,
+            Change::Moved(moves) if moves.is_empty() => None,
+            _ => Some(change),
+        }
+    }
+    
+    /// Reverse the change, describing each step in `result`
+    fn reverse(&self, result: &mut CommandResult) {
+        let fail = |result: &mut CommandResult, message: String| {
+            result.stderr.push_str(&format!("❌ undo: {}\n", message));
+            result.exit_code = 1;
+        };
+        
+        match self {
+            Change::CreatedDirs(dirs) => {
+                // Children first; a directory that has been filled is left alone
+                for dir in dirs.iter().rev() {
+                    match fs::remove_dir(dir) {
+                        Ok(()) => result.stdout.push_str(&format!("removed directory '{}'\n", dir.display())),
+                        Err(e) => fail(result, format!("left '{}' in place: {}", dir.display(), e)),
+                    }
+                }
+            }
+            Change::CreatedFiles { created, touched } => {
+                for file in created {
+                    match fs::symlink_metadata(file) {
+                        Ok(meta) if meta.is_file() && meta.len() == 0 => match fs::remove_file(file) {
+                            Ok(()) => result.stdout.push_str(&format!("removed '{}'\n", file.display())),
+                            Err(e) => fail(result, format!("left '{}' in place: {}", file.display(), e)),
+                        },
+                        Ok(_) => fail(result, format!("left '{}' in place: it has been written to since", file.display())),
+                        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                            fail(result, format!("'{}' is no longer there", file.display()))
+                        }
+                        Err(e) => fail(result, format!("'{}': {}", file.display(), e)),
+                    }
+                }
+                for file in touched {
+                    result.stdout.push_str(&format!("the timestamps of '{}' were not restored\n", file.display()));
+                }
+            }
+            Change::Moved(moves) => {
+                for (from, to) in moves.iter().rev() {
+                    if fs::symlink_metadata(from).is_ok() {
+                        fail(result, format!("left '{}' in place: '{}' exists again", to.display(), from.display()));
+                        continue;
+                    }
+                    match fs::rename(to, from) {
+                        Ok(()) => result.stdout.push_str(&format!("moved '{}' back to '{}'\n", to.display(), from.display())),
+                        Err(e) => fail(result, format!("couldn't move '{}' back: {}", to.display(), e)),
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl UndoStack {
+    /// Note what `command` changed once it has run. Returns a warning if it
+    /// changed files in a way that can't be undone.
+    pub fn record(&mut self, command: &str, plan: Plan) -> Option<String> {
+        let change = match plan {
+            Plan::Unknown => return None,
+            Plan::Irreversible(reason) => Err(reason),
+            Plan::Reversible(change) => Ok(change.confirm()?),
+        };
+        let warning = change
+            .as_ref()
+            .err()
+            .map(|reason| format!("'{}' can't be undone: {}", command, reason));
+        
+        if self.entries.len() == MAX_ENTRIES {
+            self.entries.remove(0);
+        }
+        self.entries.push(Entry {
+            command: command.to_string(),
+            change,
+        });
+        warning
+    }
+    
+    /// The `undo` builtin: reverse the most recent AI command that changed
+    /// files, or list them with `-l`
+    pub fn builtin(&mut self, arg: &str) -> CommandResult {
+        match arg {
+            "" => self.undo_last(),
+            "-l" => CommandResult {
+                stdout: self.listing(),
+                ..Default::default()
+            },
+            _ => CommandResult {
+                stderr: "❌ usage: undo [-l]\n".to_string(),
+                exit_code: 2,
+                ..Default::default()
+            },
+        }
+    }
+    
+    fn undo_last(&mut self) -> CommandResult {
+        let Some(entry) = self.entries.pop() else {
+            return CommandResult {
+                stderr: "❌ undo: no AI command has changed any files\n".to_string(),
+                exit_code: 1,
+                ..Default::default()
+            };
+        };
+        
+        let mut result = CommandResult::default();
+        match &entry.change {
+            Ok(change) => {
+                result.stdout.push_str(&format!("↩️  Undoing '{}'\n", entry.command));
+                change.reverse(&mut result);
+            }
+            Err(reason) => {
+                result.stderr = format!(
+                    "❌ undo: '{}' can't be undone: {}; nothing was changed\n",
+                    entry.command, reason
+                );
+                result.exit_code = 1;
+            }
+        }
+        result
+    }
+    
+    /// The recorded commands, most recent first
+    fn listing(&self) -> String {
+        if self.entries.is_empty() {
+            return "No AI command has changed any files\n".to_string();
+        }
+        let mut listing = String::new();
+        for entry in self.entries.iter().rev() {
+            match &entry.change {
+                Ok(_) => listing.push_str(&format!("  {}\n", entry.command)),
+                Err(reason) => listing.push_str(&format!("✗ {}  (can't be undone: {})\n", entry.command, reason)),
+            }
+        }
+        listing
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    /// `plan` for `command` in `dir`, recorded after `run` makes its change
+    fn recorded(stack: &mut UndoStack, dir: &Path, command: &str, run: impl FnOnce()) -> Option<String> {
+        let plan = Plan::new(command, dir, false);
+        run();
+        stack.record(command, plan)
+    }
+    
+    fn irreversible(command: &str, dir: &Path) -> String {
+        match Plan::new(command, dir, false) {
+            Plan::Irreversible(reason) => reason,
+            plan => panic!("{}: {:?}", command, plan),
+        }
+    }
+    
+    #[test]
+    fn created_directories_are_removed() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        fs::create_dir(dir.join("a")).unwrap();
+        
+        let plan = Plan::new("mkdir -p a/b/c", dir, false);
+        assert_eq!(plan, Plan::Reversible(Change::CreatedDirs(vec![dir.join("a/b"), dir.join("a/b/c")])));
+        
+        let mut stack = UndoStack::default();
+        let warning = recorded(&mut stack, dir, "mkdir -p a/b/c", || fs::create_dir_all(dir.join("a/b/c")).unwrap());
+        assert_eq!(warning, None);
+        let result = stack.builtin("");
+        assert_eq!(result.exit_code, 0, "{}", result.stderr);
+        assert!(!dir.join("a/b").exists());
+        assert!(dir.join("a").is_dir());
+        
+        // Nothing is left to undo
+        assert_eq!(stack.builtin("").exit_code, 1);
+    }
+    
+    #[test]
+    fn filled_directories_are_left_in_place() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let mut stack = UndoStack::default();
+        recorded(&mut stack, dir, "mkdir out", || fs::create_dir(dir.join("out")).unwrap());
+        fs::write(dir.join("out/kept"), "data").unwrap();
+        
+        let result = stack.builtin("");
+        assert_eq!(result.exit_code, 1);
+        assert!(result.stderr.starts_with(&format!("❌ undo: left '{}' in place: ", dir.join("out").display())));
+        assert_eq!(fs::read_to_string(dir.join("out/kept")).unwrap(), "data");
+    }
+    
+    #[test]
+    fn moves_into_a_directory_are_moved_back() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        fs::create_dir(dir.join("old")).unwrap();
+        fs::write(dir.join("a.txt"), "a").unwrap();
+        fs::write(dir.join("b.txt"), "b").unwrap();
+        
+        let mut stack = UndoStack::default();
+        let warning = recorded(&mut stack, dir, "mv a.txt b.txt old", || {
+            for name in ["a.txt", "b.txt"] {
+                fs::rename(dir.join(name), dir.join("old").join(name)).unwrap();
+            }
+        });
+        assert_eq!(warning, None);
+        assert_eq!(stack.listing(), "  mv a.txt b.txt old\n");
+        
+        let result = stack.builtin("");
+        assert_eq!(result.exit_code, 0, "{}", result.stderr);
+        assert_eq!(fs::read_to_string(dir.join("a.txt")).unwrap(), "a");
+        assert_eq!(fs::read_to_string(dir.join("b.txt")).unwrap(), "b");
+        assert_eq!(fs::read_dir(dir.join("old")).unwrap().count(), 0);
+    }
+    
+    #[test]
+    fn changes_that_lose_data_are_irreversible() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        fs::write(dir.join("a"), "a").unwrap();
+        fs::write(dir.join("b"), "b").unwrap();
+        
+        assert_eq!(irreversible("mv a b", dir), format!("it replaces '{}'", dir.join("b").display()));
+        assert_eq!(Plan::new("mv -n a b", dir, false), Plan::Reversible(Change::Moved(Vec::new())));
+        assert_eq!(irreversible("echo hi > a", dir), "its output is redirected into a file");
+        assert_eq!(irreversible("mkdir --context=x d", dir), "undo doesn't know what '--context=x' does");
+        assert_eq!(irreversible("mv -t old a", dir), "undo doesn't know what '-t' does");
+        assert_eq!(irreversible("rm a", dir), "it deletes files");
+        
+        // Recorded as a command undo refuses, changing nothing
+        let mut stack = UndoStack::default();
+        let warning = recorded(&mut stack, dir, "echo hi > a", || fs::write(dir.join("a"), "hi\n").unwrap());
+        assert_eq!(warning.unwrap(), "'echo hi > a' can't be undone: its output is redirected into a file");
+        let result = stack.builtin("");
+        assert_eq!(result.exit_code, 1);
+        assert!(result.stderr.ends_with("nothing was changed\n"));
+        assert_eq!(fs::read_to_string(dir.join("a")).unwrap(), "hi\n");
+    }
+}
@@ -0,0 +1,216 @@
+//! File watching for the `on-change` builtin. The watched paths are
+//! subscribed to through the OS (inotify, FSEvents, ReadDirectoryChangesW),
+//! and events are debounced, so a save that touches several files triggers
+//! one run.
+
+use super::*;
+use notify_debouncer_mini::notify::{RecommendedWatcher, RecursiveMode};
+use notify_debouncer_mini::{new_debouncer, DebounceEventResult, Debouncer};
+use parser::{Token, TokenKind};
+use std::collections::BTreeSet;
+use tokio::sync::mpsc;
+
+/// How long a path must stay unchanged after an event before the command
+/// runs
+pub const DEBOUNCE: Duration = Duration::from_millis(200);
+/// Most changed paths named in a trigger's header
+const NAMED_CHANGES: usize = 3;
+
+/// What `on-change` watches and runs
+pub struct Spec {
+    pub watch: Watch,
+    pub command: String,
+}
+
+/// Paths to watch, and the entries under them to leave out
+pub struct Watch {
+    pub roots: Vec<PathBuf>,
+    ignore: Vec<glob::Pattern>,
+}
+
+/// Parse `[--ignore <pattern>]... <path>... -- <command>`. A single quoted
+/// word after `--` is unquoted, so it can hold `&&` and `;`.
+pub fn parse(arg: &str) -> Result<Spec, String> {
+    const USAGE: &str = "usage: on-change [--ignore <pattern>]... <path>... -- <command>";
+    let tokens = parser::tokenize(arg);
+    let separator = tokens
+        .iter()
+        .position(|token| token.kind == TokenKind::Word("--".to_string()))
+        .ok_or(USAGE)?;
+    
+    let mut roots = Vec::new();
+    let mut ignore = Vec::new();
+    let mut words = tokens[..separator].iter().map(|token| match &token.kind {
+        TokenKind::Word(word) => word.as_str(),
+        TokenKind::Redirect(_) => &arg[token.span.clone()],
+    });
+    while let Some(word) = words.next() {
+        match word {
+            "--ignore" => {
+                let pattern = words.next().ok_or("--ignore needs a pattern")?;
+                let pattern = glob::Pattern::new(pattern.trim_end_matches('/'))
+                    .map_err(|e| format!("invalid pattern '{}': {}", pattern, e))?;
+                ignore.push(pattern);
+            }
+            path => roots.push(expand_directory(path)),
+        }
+    }
+    
+    let command = match &tokens[separator + 1..] {
+        [Token { kind: TokenKind::Word(word), .. }] => word.clone(),
+        _ => arg[tokens[separator].span.end..].trim().to_string(),
+    };
+    if roots.is_empty() || command.is_empty() {
+        return Err(USAGE.to_string());
+    }
+    Ok(Spec {
+        watch: Watch { roots, ignore },
+        command,
+    })
+}
+
+/// Changes under the watched paths, as they happen
+pub struct Changes {
+    watch: Watch,
+    /// Each root as given, and where it is, as events name it
+    roots: Vec<(PathBuf, PathBuf)>,
+    events: mpsc::UnboundedReceiver<DebounceEventResult>,
+    _debouncer: Debouncer<RecommendedWatcher>,
+}
+
+impl Watch {
+    /// Start watching, directories recursively
+    pub fn subscribe(self) -> notify_debouncer_mini::notify::Result<Changes> {
+        let (sender, events) = mpsc::unbounded_channel();
+        let mut debouncer = new_debouncer(DEBOUNCE, move |result| {
+            let _ = sender.send(result);
+        })?;
+        let cwd = env::current_dir().unwrap_or_default();
+        let mut roots = Vec::new();
+        for root in &self.roots {
+            debouncer.watcher().watch(root, RecursiveMode::Recursive)?;
+            roots.push((root.clone(), cwd.join(root)));
+        }
+        Ok(Changes {
+            watch: self,
+            roots,
+            events,
+            _debouncer: debouncer,
+        })
+    }
+    
+    /// Whether `path` under `root`, or a directory between them, matches an
+    /// `--ignore` pattern, by its name or its path from `root`
+    fn ignored(&self, root: &Path, path: &Path) -> bool {
+        let Ok(relative) = path.strip_prefix(root) else {
+            return false;
+        };
+        relative.ancestors().filter(|entry| !entry.as_os_str().is_empty()).any(|entry| {
+            let name = entry.file_name().map(Path::new).unwrap_or(entry);
+            self.ignore
+                .iter()
+                .any(|pattern| pattern.matches_path(name) || pattern.matches_path(entry))
+        })
+    }
+}
+
+impl Changes {
+    /// The paths changed since the last call, waiting for some that aren't
+    /// ignored. Events already queued are taken too, so a burst that spans
+    /// several debounce windows is one set. `None` once the watcher stops.
+    pub async fn next(&mut self) -> Option<notify_debouncer_mini::notify::Result<Vec<PathBuf>>> {
+        loop {
+            let mut changed = BTreeSet::new();
+            let mut result = self.events.recv().await?;
+            loop {
+                match result {
+                    Ok(events) => changed.extend(events.iter().filter_map(|event| self.shown(&event.path))),
+                    Err(e) => return Some(Err(e)),
+                }
+                match self.events.try_recv() {
+                    Ok(next) => result = next,
+                    Err(_) => break,
+                }
+            }
+            if !changed.is_empty() {
+                return Some(Ok(changed.into_iter().collect()));
+            }
+        }
+    }
+    
+    /// `path` as under the root it was given as, unless it is ignored
+    fn shown(&self, path: &Path) -> Option<PathBuf> {
+        let (given, root) = self.roots.iter().find(|(_, root)| path.starts_with(root))?;
+        if self.watch.ignored(root, path) {
+            return None;
+        }
+        let relative = path.strip_prefix(root).ok()?;
+        Some(if relative.as_os_str().is_empty() { given.clone() } else { given.join(relative) })
+    }
+}
+
+/// The header printed before a triggered run, naming what changed
+pub fn header(changes: &[PathBuf], command: &str) -> String {
+    let mut named: Vec<String> = changes
+        .iter()
+        .take(NAMED_CHANGES)
+        .map(|path| path.display().to_string())
+        .collect();
+    if changes.len() > NAMED_CHANGES {
+        named.push(format!("{} more", changes.len() - NAMED_CHANGES));
+    }
+    format!(
+        "🔁 [{}] {} changed; running {}",
+        chrono::Local::now().format("%H:%M:%S"),
+        named.join(", "),
+        command
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    
+    #[test]
+    fn ignored_paths_match_by_name_or_from_the_root() {
+        let spec = parse("--ignore target/ --ignore '*.swp' --ignore docs/build src -- make").unwrap();
+        assert_eq!(spec.command, "make");
+        assert_eq!(spec.watch.roots, vec![PathBuf::from("src")]);
+        
+        let root = Path::new("/p");
+        let ignored = |path: &str| spec.watch.ignored(root, Path::new(path));
+        assert!(ignored("/p/target"));
+        assert!(ignored("/p/target/debug/main.o"));
+        assert!(ignored("/p/sub/target/x"));
+        assert!(ignored("/p/.main.rs.swp"));
+        assert!(ignored("/p/docs/build/index.html"));
+        assert!(!ignored("/p/main.rs"));
+        assert!(!ignored("/p/targets/x"));
+        assert!(!ignored("/p/sub/docs/build/x"));
+        assert!(!ignored("/elsewhere/target"));
+    }
+    
+    #[test]
+    fn a_command_is_required() {
+        assert!(parse("src").is_err());
+        assert!(parse("src --").is_err());
+        assert!(parse("-- make").is_err());
+        assert_eq!(parse("--ignore").err().unwrap(), "usage: on-change [--ignore <pattern>]... <path>... -- <command>");
+        assert_eq!(parse("src -- 'make && make test'").unwrap().command, "make && make test");
+    }
+    
+    #[tokio::test]
+    async fn changes_are_reported_once_they_settle() {
+        let tmp = tempfile::tempdir().unwrap();
+        fs::create_dir(tmp.path().join("target")).unwrap();
+        let spec = parse(&format!("--ignore target {} -- true", tmp.path().display())).unwrap();
+        let mut changes = spec.watch.subscribe().unwrap();
+        
+        fs::write(tmp.path().join("target/out"), "ignored").unwrap();
+        fs::write(tmp.path().join("a"), "1").unwrap();
+        fs::write(tmp.path().join("a"), "2").unwrap();
+        let changed = tokio::time::timeout(Duration::from_secs(5), changes.next()).await.unwrap();
+        assert_eq!(changed.unwrap().unwrap(), vec![tmp.path().join("a")]);
+    }
+}